@@ -1,5 +1,11 @@
-use clap::{Parser, Subcommand};
-use std::path::PathBuf;
+use chrono::{DateTime, FixedOffset};
+use clap::{Parser, Subcommand, ValueEnum};
+use rap_core::resolve::{ResolveError, Resolver};
+use rap_core::signing::{build_signing_string, sign_request, PemKey};
+use rap_core::types::Actor;
+use serde_json::Value;
+use std::error::Error;
+use std::path::{Path, PathBuf};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -11,40 +17,741 @@ struct Cli {
     #[arg(short, long, value_name = "FILE")]
     config: Option<PathBuf>,
 
-    /// Turn debugging information on
-    #[arg(short, long, action = clap::ArgAction::Count)]
+    /// Log more, may be repeated: -d for info, -dd for debug, -ddd for trace.
+    /// RUST_LOG takes precedence when set
+    #[arg(short, long, visible_alias = "verbose", action = clap::ArgAction::Count)]
     debug: u8,
 
+    /// How to print what we fetch
+    #[arg(long, global = true, value_enum, default_value_t = Format::Pretty)]
+    format: Format,
+
     #[command(subcommand)]
     command: Option<Commands>,
 }
 
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Format {
+    /// One JSON document per line, e.g. for piping into `jq`
+    Json,
+    /// Indented, for reading: Rust debug formatting for actors, and JSON
+    /// for anything we don't have a type for, e.g. outbox activities
+    Pretty,
+}
+
 #[derive(Subcommand, Debug)]
 enum Commands {
-    /// Get the actor profile from an ID
+    /// Get actor profiles from their IDs or `user@domain` addresses
     Actor {
-        #[arg(short, long)]
-        id: String,
+        /// Actor ID or address to fetch, may be given multiple times
+        #[arg(short, long, required_unless_present = "file")]
+        id: Vec<String>,
+
+        /// File with one actor ID or address per line
+        #[arg(short, long, value_name = "FILE")]
+        file: Option<PathBuf>,
     },
+    /// Print the activities in an actor's outbox, newest first
+    Outbox {
+        /// Actor ID or address whose outbox to page through
+        #[arg(long)]
+        actor: String,
+
+        /// Only print activities published after this RFC 3339 time, e.g.
+        /// 2023-09-04T20:00:00Z. Paging stops at the first older one
+        #[arg(long, value_parser = parse_timestamp)]
+        since: Option<DateTime<FixedOffset>>,
+    },
+    /// Rebuild the string a captured request's signature covers, the way the
+    /// server does before verifying it
+    SigningString {
+        /// File with the request's headers, one `name: value` per line
+        #[arg(long, value_name = "FILE")]
+        headers: PathBuf,
+
+        /// The request's `(request-target)`, e.g. "post /users/alice/inbox"
+        #[arg(long)]
+        target: String,
+
+        /// The `Signature` header, if it isn't in the headers file
+        #[arg(long)]
+        signature: Option<String>,
+    },
+    /// Print a signed `Follow` request, ready to replay with curl, without
+    /// sending it
+    FollowRequest {
+        /// PKCS#8 PEM file with the following actor's private key
+        #[arg(long, value_name = "FILE")]
+        key: PathBuf,
+
+        /// The following actor's ID
+        #[arg(long)]
+        actor: String,
+
+        /// The `keyId` to sign with, by default the actor's `/#main-key`, the
+        /// id rap-server gives its users' keys
+        #[arg(long)]
+        key_id: Option<String>,
+
+        /// The ID of the actor to follow
+        #[arg(long)]
+        object: String,
+
+        /// The inbox to address the request to
+        #[arg(long)]
+        inbox: String,
+
+        /// The `Follow`'s ID, by default one under the actor made up from
+        /// the current time
+        #[arg(long)]
+        id: Option<String>,
+    },
+}
+
+fn render(actor: &Actor, format: Format) -> String {
+    match format {
+        Format::Json => serde_json::to_string(actor).unwrap(),
+        Format::Pretty => format!("{:#?}", actor),
+    }
+}
+
+/// Parses `name: value` lines into lowercased names and their values.
+/// Blank lines are skipped, and only the first of repeated headers is kept.
+fn parse_headers(contents: &str) -> Result<Vec<(String, String)>, String> {
+    let mut headers: Vec<(String, String)> = Vec::new();
+    for line in contents.lines().filter(|l| !l.trim().is_empty()) {
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| format!("Not a header: {}", line))?;
+        let name = name.trim().to_lowercase();
+        if !headers.iter().any(|(n, _)| *n == name) {
+            headers.push((name, value.trim().to_string()));
+        }
+    }
+    Ok(headers)
+}
+
+/// The headers a `Signature` header says it covers, in order.
+fn covered_headers(signature: &str) -> Result<Vec<String>, String> {
+    signature
+        .split(',')
+        .filter_map(|param| param.trim().split_once('='))
+        .find(|(name, _)| *name == "headers")
+        .map(|(_, value)| {
+            value
+                .trim_matches('"')
+                .split(' ')
+                .map(String::from)
+                .collect()
+        })
+        .ok_or_else(|| "Signature has no headers parameter".to_string())
+}
+
+/// The covered headers and the string signed over them, for a request with
+/// `headers` to `target`.
+fn signing_string(
+    headers: &[(String, String)],
+    target: &str,
+    signature: Option<&str>,
+) -> Result<(Vec<String>, String), String> {
+    let signature = signature
+        .or_else(|| {
+            headers
+                .iter()
+                .find(|(name, _)| name == "signature")
+                .map(|(_, value)| value.as_str())
+        })
+        .ok_or("No signature header given")?;
+    let covered = covered_headers(signature)?;
+    let signing_string = build_signing_string(&covered, target, |name| {
+        headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_str())
+    });
+    Ok((covered, signing_string))
+}
+
+/// The id rap-server gives the key of its user `actor`.
+fn main_key_id(actor: &str) -> String {
+    format!("{}/#main-key", actor)
+}
+
+/// A `Follow` of `object` by `actor` as an HTTP request to `inbox`: the
+/// request line, the headers, including the signature made with `key`, then
+/// a blank line and the body.
+fn follow_request(
+    key: &PemKey,
+    actor: &str,
+    object: &str,
+    inbox: &str,
+    id: &str,
+) -> Result<String, Box<dyn Error>> {
+    let body = serde_json::json!({
+        "@context": "https://www.w3.org/ns/activitystreams",
+        "id": id,
+        "type": "Follow",
+        "actor": actor,
+        "object": object,
+    })
+    .to_string();
+    let headers = sign_request("POST", inbox, body.as_bytes(), key)?;
+
+    let mut request = format!("POST {}\n", inbox);
+    request.push_str("content-type: application/activity+json\n");
+    for (name, value) in headers {
+        request.push_str(&format!("{}: {}\n", name, value));
+    }
+    request.push('\n');
+    request.push_str(&body);
+    Ok(request)
 }
-fn main() {
+
+fn parse_timestamp(s: &str) -> Result<DateTime<FixedOffset>, String> {
+    DateTime::parse_from_rfc3339(s).map_err(|e| format!("{}: {}", s, e))
+}
+
+const ACTIVITY_JSON_ACCEPT: &str =
+    "application/ld+json; profile=\"https://www.w3.org/ns/activitystreams\", application/activity+json";
+
+async fn fetch_json(client: &reqwest::Client, url: &str) -> Result<Value, reqwest::Error> {
+    log::debug!("Fetching {}", url);
+    client
+        .get(url)
+        .header("Accept", ACTIVITY_JSON_ACCEPT)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await
+}
+
+/// When an activity, or the object it wraps, was published. Not every
+/// server says, so this may be `None`.
+fn published(activity: &Value) -> Option<DateTime<FixedOffset>> {
+    [&activity["published"], &activity["object"]["published"]]
+        .into_iter()
+        .find_map(|published| DateTime::parse_from_rfc3339(published.as_str()?).ok())
+}
+
+/// Pages through the `OrderedCollection` at `url`, collecting its items.
+/// Outboxes are newest first, so with `since` paging stops at the first
+/// item published at or before it. Items without a publication date can't
+/// be placed, so they are kept and don't stop anything.
+async fn collection_since(
+    client: &reqwest::Client,
+    url: &str,
+    since: Option<DateTime<FixedOffset>>,
+) -> Result<Vec<Value>, Box<dyn Error>> {
+    let collection = fetch_json(client, url).await?;
+    let mut page = match &collection["first"] {
+        Value::String(first) => fetch_json(client, first).await?,
+        Value::Object(_) => collection["first"].clone(),
+        // everything is on the collection itself
+        _ => collection,
+    };
+
+    let mut items = vec![];
+    let mut seen = std::collections::HashSet::new();
+    loop {
+        let page_items = match &page["orderedItems"] {
+            Value::Array(page_items) => page_items,
+            _ => page["items"]
+                .as_array()
+                .map(Vec::as_slice)
+                .unwrap_or_default(),
+        };
+        for item in page_items {
+            match (published(item), since) {
+                (Some(published), Some(since)) if published <= since => return Ok(items),
+                _ => items.push(item.clone()),
+            }
+        }
+        let next = match &page["next"] {
+            Value::String(next) => next.clone(),
+            Value::Object(next) => match next.get("id").and_then(Value::as_str) {
+                Some(next) => next.to_string(),
+                None => break,
+            },
+            _ => break,
+        };
+        // a broken server could send us around in circles
+        if !seen.insert(next.clone()) {
+            break;
+        }
+        page = fetch_json(client, &next).await?;
+    }
+    Ok(items)
+}
+
+/// Maps the number of `-d` flags to how much we log.
+fn log_level(count: u8) -> log::LevelFilter {
+    match count {
+        0 => log::LevelFilter::Warn,
+        1 => log::LevelFilter::Info,
+        2 => log::LevelFilter::Debug,
+        _ => log::LevelFilter::Trace,
+    }
+}
+
+/// Fetches the actor `id`, or looks it up with WebFinger first if it is a
+/// `user@domain` address rather than a URL.
+async fn fetch_actor(resolver: &Resolver, id: &str) -> Result<Actor, ResolveError> {
+    log::debug!("Fetching actor {}", id);
+    if id.contains("://") {
+        resolver.actor(id).await
+    } else {
+        resolver.resolve(id).await
+    }
+}
+
+/// Fetches all actors concurrently, returning the results in the same order
+/// as `ids`.
+async fn fetch_actors(resolver: &Resolver, ids: &[String]) -> Vec<Result<Actor, ResolveError>> {
+    futures::future::join_all(ids.iter().map(|id| fetch_actor(resolver, id))).await
+}
+
+/// The contents of `path`. Nothing can be done without them, so failing to
+/// read it exits with the error.
+fn read_file(path: &Path) -> String {
+    std::fs::read_to_string(path).unwrap_or_else(|e| {
+        eprintln!("Error reading {}: {}", path.display(), e);
+        std::process::exit(1);
+    })
+}
+
+#[tokio::main]
+async fn main() {
     let cli = Cli::parse();
+    // also covers reqwest, which logs its connections and requests
+    env_logger::Builder::new()
+        .filter_level(log_level(cli.debug))
+        .parse_default_env()
+        .init();
 
     match cli.command {
-        Some(Commands::Actor { id }) => {
-            let resp = reqwest::blocking::Client::new()
-                .get(&id)
-                .header(
-                    "Accept",
-                    "application/ld+json; profile=\"https://www.w3.org/ns/activitystreams\"",
-                )
-                .send()
-                .unwrap();
-            let resp = resp.json::<rap_core::types::Actor>().unwrap();
-            println!("{:#?}", resp)
+        Some(Commands::Actor { mut id, file }) => {
+            if let Some(file) = file {
+                let contents = read_file(&file);
+                id.extend(
+                    contents
+                        .lines()
+                        .map(str::trim)
+                        .filter(|l| !l.is_empty())
+                        .map(String::from),
+                );
+            }
+
+            let resolver = Resolver::new(reqwest::Client::new());
+            // one failure doesn't stop the rest, but still fails the command
+            let mut failed = 0;
+            for (id, actor) in id.iter().zip(fetch_actors(&resolver, &id).await) {
+                match actor {
+                    Ok(actor) => println!("{}", render(&actor, cli.format)),
+                    Err(e) => {
+                        eprintln!("Error fetching {}: {}", id, e);
+                        failed += 1;
+                    }
+                }
+            }
+            if failed > 0 {
+                eprintln!("Could not fetch {} of {} actors", failed, id.len());
+                std::process::exit(1);
+            }
+        }
+        Some(Commands::Outbox { actor, since }) => {
+            let client = reqwest::Client::new();
+            let resolver = Resolver::new(client.clone());
+            let result = match fetch_actor(&resolver, &actor).await {
+                Ok(actor) => collection_since(&client, actor.outbox(), since).await,
+                Err(e) => Err(e.into()),
+            };
+            match result {
+                Ok(activities) => {
+                    for activity in activities {
+                        match cli.format {
+                            Format::Json => println!("{}", activity),
+                            Format::Pretty => println!("{:#}", activity),
+                        }
+                    }
+                }
+                Err(e) => {
+                    eprintln!("Error fetching the outbox of {}: {}", actor, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Some(Commands::SigningString {
+            headers,
+            target,
+            signature,
+        }) => {
+            let contents = read_file(&headers);
+            let result = parse_headers(&contents)
+                .and_then(|headers| signing_string(&headers, &target, signature.as_deref()));
+            match result {
+                Ok((covered, signing_string)) => match cli.format {
+                    Format::Json => println!(
+                        "{}",
+                        serde_json::json!({"headers": covered, "signingString": signing_string})
+                    ),
+                    Format::Pretty => {
+                        println!("Covered headers: {}\n", covered.join(" "));
+                        println!("{}", signing_string);
+                    }
+                },
+                Err(e) => {
+                    eprintln!("Error rebuilding the signing string: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Some(Commands::FollowRequest {
+            key,
+            actor,
+            key_id,
+            object,
+            inbox,
+            id,
+        }) => {
+            let key = PemKey {
+                key_id: key_id.unwrap_or_else(|| main_key_id(&actor)),
+                private_key_pem: read_file(&key),
+            };
+            let id = id.unwrap_or_else(|| {
+                let now = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap();
+                format!("{}/follows/{}", actor, now.as_millis())
+            });
+            match follow_request(&key, &actor, &object, &inbox, &id) {
+                Ok(request) => println!("{}", request),
+                Err(e) => {
+                    eprintln!("Error signing the follow request: {}", e);
+                    std::process::exit(1);
+                }
+            }
         }
         None => {
             println!("Hello, world! {}", rap_core::add(2, 40));
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Serves an actor document for any path under `/users/`, and a 404
+    /// otherwise.
+    async fn mock_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut buf = [0; 4096];
+                    let n = socket.read(&mut buf).await.unwrap();
+                    let request = String::from_utf8_lossy(&buf[..n]);
+                    let path = request.split(' ').nth(1).unwrap_or("/").to_string();
+
+                    let response = match path.strip_prefix("/users/") {
+                        Some(name) => {
+                            let body = format!(
+                                r#"{{"id":"http://{addr}{path}","type":"Person","inbox":"http://{addr}{path}/inbox","outbox":"http://{addr}{path}/outbox","following":"http://{addr}{path}/following","followers":"http://{addr}{path}/followers","preferredUsername":"{name}","endpoints":{{}}}}"#
+                            );
+                            format!(
+                                "HTTP/1.1 200 OK\r\ncontent-type: application/activity+json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                                body.len(),
+                                body
+                            )
+                        }
+                        None => "HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\nconnection: close\r\n\r\n"
+                            .to_string(),
+                    };
+                    socket.write_all(response.as_bytes()).await.unwrap();
+                });
+            }
+        });
+        format!("http://{}", addr)
+    }
+
+    #[test]
+    fn test_log_level() {
+        assert_eq!(log_level(0), log::LevelFilter::Warn);
+        assert_eq!(log_level(1), log::LevelFilter::Info);
+        assert_eq!(log_level(2), log::LevelFilter::Debug);
+        assert_eq!(log_level(3), log::LevelFilter::Trace);
+        assert_eq!(log_level(10), log::LevelFilter::Trace);
+    }
+
+    #[tokio::test]
+    async fn test_json_format() {
+        let base = mock_server().await;
+        let resolver = Resolver::new(reqwest::Client::new());
+        let actor = fetch_actor(&resolver, &format!("{}/users/alice", base))
+            .await
+            .unwrap();
+
+        let rendered = render(&actor, Format::Json);
+        assert!(!rendered.contains('\n'));
+        let json: serde_json::Value = serde_json::from_str(&rendered).unwrap();
+        assert_eq!(json["id"], format!("{}/users/alice", base));
+        assert_eq!(json["preferredUsername"], "alice");
+        assert_eq!(json["type"], "Person");
+    }
+
+    #[test]
+    fn test_format_flag() {
+        let cli = Cli::parse_from(["rap-client-cli", "actor", "--id", "x"]);
+        assert_eq!(cli.format, Format::Pretty);
+        let cli = Cli::parse_from(["rap-client-cli", "actor", "--id", "x", "--format", "json"]);
+        assert_eq!(cli.format, Format::Json);
+    }
+
+    #[test]
+    fn test_verbose_alias() {
+        let cli = Cli::parse_from(["rap-client-cli", "-dd", "--verbose"]);
+        assert_eq!(cli.debug, 3);
+    }
+
+    /// Headers of a delivery from Mastodon 4.1.6, as captured by the server.
+    const MASTODON_HEADERS: &str = r#"host: ap.rens.page
+connection: close
+user-agent: http.rb/5.1.1 (Mastodon/4.1.6; +https://hotdog.place/)
+date: Mon, 04 Sep 2023 20:49:38 GMT
+accept-encoding: gzip
+digest: SHA-256=x0QZ2hdf3slWOdA4/DyxLEv4uEzU/FgjP9ho8EzR8sk=
+content-type: application/activity+json
+signature: keyId="https://hotdog.place/users/renning#main-key",algorithm="rsa-sha256",headers="(request-target) host date digest content-type",signature="GAoq49DfHXRwU8N5bwZAVoU3f5fUR5BPaWLVTG/6QlTJB12lRV29KLxN0pMbcHgzKoTWepdPcIPYZXVGR12+VBoSW46bSKVhFZ8thV/I6Sm/Xqmsz46LJNCETODyOvtFYAnagYUBTq5sbBznovWJNaRkM38fQII+oXV3V1Ku9Y10kPXrQL0JwRoNvzrvAzZJBLGKArdBB9yeVgfLAp3NwmZAwawSSBfh73sBqcTgfrZvjN95xvJWfFvveZINV1Fb4EIfFCZJHcNWNLG8d0PEsk5TjFqKuTjkgYWP5xogiepN8BJfPB+QPfdTPlWr+Gos2pDgo83sna5NehHowgkDiA=="
+x-request-id: 8a10afb4-180b-4599-85a7-d987e92c0086
+via: 1.1 vegur
+content-length: 222
+"#;
+
+    #[test]
+    fn test_signing_string_mastodon() {
+        let headers = parse_headers(MASTODON_HEADERS).unwrap();
+        let (covered, signing_string) =
+            signing_string(&headers, "post /users/test2/inbox", None).unwrap();
+
+        assert_eq!(
+            covered,
+            ["(request-target)", "host", "date", "digest", "content-type"]
+        );
+        assert_eq!(
+            signing_string,
+            "(request-target): post /users/test2/inbox\n\
+             host: ap.rens.page\n\
+             date: Mon, 04 Sep 2023 20:49:38 GMT\n\
+             digest: SHA-256=x0QZ2hdf3slWOdA4/DyxLEv4uEzU/FgjP9ho8EzR8sk=\n\
+             content-type: application/activity+json"
+        );
+    }
+
+    #[test]
+    fn test_signing_string_errors() {
+        let headers = parse_headers("Host: example.com").unwrap();
+        assert_eq!(headers, [("host".to_string(), "example.com".to_string())]);
+        signing_string(&headers, "get /", None).unwrap_err();
+        signing_string(&headers, "get /", Some("keyId=\"x\"")).unwrap_err();
+        let (_, signing_string) =
+            signing_string(&headers, "get /", Some("keyId=\"x\",headers=\"Host\"")).unwrap();
+        assert_eq!(signing_string, "host: example.com");
+        parse_headers("not a header").unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn test_fetch_actors() {
+        let base = mock_server().await;
+        let ids = vec![
+            format!("{}/users/alice", base),
+            format!("{}/nobody", base),
+            format!("{}/users/bob", base),
+        ];
+
+        let actors = fetch_actors(&Resolver::new(reqwest::Client::new()), &ids).await;
+        assert_eq!(actors.len(), 3);
+        assert!(actors[0].is_ok());
+        assert!(actors[1].is_err());
+        assert!(actors[2].is_ok());
+    }
+
+    #[test]
+    fn test_follow_request_signature() {
+        use base64::engine::general_purpose;
+        use base64::Engine;
+        use rsa::pkcs1v15::{Signature, VerifyingKey};
+        use rsa::pkcs8::{EncodePrivateKey, LineEnding};
+        use rsa::sha2::Sha256;
+        use rsa::signature::Verifier;
+
+        let private_key = rsa::RsaPrivateKey::new(&mut rand::thread_rng(), 2048).unwrap();
+        let key = PemKey {
+            key_id: main_key_id("https://example.com/users/alice"),
+            private_key_pem: private_key
+                .to_pkcs8_pem(LineEnding::LF)
+                .unwrap()
+                .to_string(),
+        };
+        let request = follow_request(
+            &key,
+            "https://example.com/users/alice",
+            "https://remote.example/users/bob",
+            "https://remote.example/users/bob/inbox",
+            "https://example.com/users/alice/follows/1",
+        )
+        .unwrap();
+
+        let (head, body) = request.split_once("\n\n").unwrap();
+        let (request_line, headers) = head.split_once('\n').unwrap();
+        assert_eq!(request_line, "POST https://remote.example/users/bob/inbox");
+        let follow: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(follow["type"], "Follow");
+        assert_eq!(follow["object"], "https://remote.example/users/bob");
+
+        // verify it the way a receiving server would
+        let headers = parse_headers(headers).unwrap();
+        let (covered, signing_string) =
+            signing_string(&headers, "post /users/bob/inbox", None).unwrap();
+        assert_eq!(covered, ["(request-target)", "host", "date", "digest"]);
+        let signature = headers
+            .iter()
+            .find(|(name, _)| name == "signature")
+            .map(|(_, value)| value.as_str())
+            .unwrap();
+        // the keyId rap-server would look up
+        assert!(signature.contains(r#"keyId="https://example.com/users/alice/#main-key""#));
+        let signature = signature
+            .split("signature=\"")
+            .nth(1)
+            .and_then(|rest| rest.strip_suffix('"'))
+            .unwrap();
+        let signature = Signature::try_from(
+            general_purpose::STANDARD
+                .decode(signature)
+                .unwrap()
+                .as_slice(),
+        )
+        .unwrap();
+        VerifyingKey::<Sha256>::new(private_key.to_public_key())
+            .verify(signing_string.as_bytes(), &signature)
+            .unwrap();
+    }
+
+    /// Serves an outbox at `/outbox` paged over `/outbox?page=1` to `3`,
+    /// newest first, counting the requests.
+    async fn outbox_server() -> (String, std::sync::Arc<std::sync::atomic::AtomicUsize>) {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let outbox = base.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                let outbox = outbox.clone();
+                tokio::spawn(async move {
+                    let mut buf = [0; 4096];
+                    let n = socket.read(&mut buf).await.unwrap();
+                    let request = String::from_utf8_lossy(&buf[..n]);
+                    let path = request.split(' ').nth(1).unwrap_or("/").to_string();
+
+                    let note = |n: u32, published: Option<&str>| match published {
+                        Some(published) => {
+                            serde_json::json!({"id": n, "type": "Create", "published": published})
+                        }
+                        None => serde_json::json!({"id": n, "type": "Create"}),
+                    };
+                    let body = match path.as_str() {
+                        "/outbox" => Some(serde_json::json!({
+                            "type": "OrderedCollection",
+                            "totalItems": 6,
+                            "first": format!("{}/outbox?page=1", outbox),
+                        })),
+                        "/outbox?page=1" => Some(serde_json::json!({
+                            "type": "OrderedCollectionPage",
+                            "orderedItems": [
+                                note(1, Some("2023-09-04T12:00:00Z")),
+                                note(2, None),
+                                {"id": 3, "type": "Create", "object": {"published": "2023-09-03T12:00:00Z"}},
+                            ],
+                            "next": format!("{}/outbox?page=2", outbox),
+                        })),
+                        "/outbox?page=2" => Some(serde_json::json!({
+                            "type": "OrderedCollectionPage",
+                            "orderedItems": [
+                                note(4, Some("2023-09-02T12:00:00Z")),
+                                note(5, Some("2023-09-01T12:00:00Z")),
+                            ],
+                            "next": format!("{}/outbox?page=3", outbox),
+                        })),
+                        "/outbox?page=3" => Some(serde_json::json!({
+                            "type": "OrderedCollectionPage",
+                            "orderedItems": [note(6, Some("2023-08-31T12:00:00Z"))],
+                        })),
+                        _ => None,
+                    };
+                    let response = match body {
+                        Some(body) => {
+                            let body = body.to_string();
+                            format!(
+                                "HTTP/1.1 200 OK\r\ncontent-type: application/activity+json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                                body.len(),
+                                body
+                            )
+                        }
+                        None => "HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\nconnection: close\r\n\r\n"
+                            .to_string(),
+                    };
+                    socket.write_all(response.as_bytes()).await.unwrap();
+                });
+            }
+        });
+        (format!("{}/outbox", base), requests)
+    }
+
+    #[tokio::test]
+    async fn test_outbox_since() {
+        use std::sync::atomic::Ordering;
+
+        let ids = |items: Vec<Value>| -> Vec<u64> {
+            items
+                .iter()
+                .map(|item| item["id"].as_u64().unwrap())
+                .collect()
+        };
+        let client = reqwest::Client::new();
+
+        let (outbox, requests) = outbox_server().await;
+        let all = collection_since(&client, &outbox, None).await.unwrap();
+        assert_eq!(ids(all), [1, 2, 3, 4, 5, 6]);
+        assert_eq!(requests.load(Ordering::SeqCst), 4);
+
+        // stops at 5 without fetching the last page. 2 has no date, so it is
+        // kept, and 3 is dated by its object
+        let (outbox, requests) = outbox_server().await;
+        let since = parse_timestamp("2023-09-02T00:00:00Z").unwrap();
+        let recent = collection_since(&client, &outbox, Some(since))
+            .await
+            .unwrap();
+        assert_eq!(ids(recent), [1, 2, 3, 4]);
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+
+        let (outbox, _) = outbox_server().await;
+        let since = parse_timestamp("2023-09-05T00:00:00+02:00").unwrap();
+        let none = collection_since(&client, &outbox, Some(since))
+            .await
+            .unwrap();
+        assert!(none.is_empty());
+
+        parse_timestamp("yesterday").unwrap_err();
+    }
+}
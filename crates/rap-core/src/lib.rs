@@ -1,3 +1,5 @@
+pub mod resolve;
+pub mod signing;
 pub mod types;
 
 pub fn add(left: usize, right: usize) -> usize {
@@ -0,0 +1,306 @@
+//! Finding an actor from their `user@domain` address: a [WebFinger] lookup
+//! on their server for the actor's ID, then fetching the actor itself.
+//!
+//! This is what the CLI looks people up with. The server fetches through its
+//! own `remote::Client` instead, which refuses private addresses, limits
+//! sizes and signs its requests, none of which a plain `reqwest::Client`
+//! here does, and it has no addresses to resolve.
+//!
+//! [WebFinger]: https://datatracker.ietf.org/doc/html/rfc7033
+
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::sync::Mutex;
+
+use crate::types::Actor;
+
+const ACTIVITY_JSON: &str = "application/activity+json";
+const LD_JSON: &str = "application/ld+json; profile=\"https://www.w3.org/ns/activitystreams\"";
+
+/// Resolves addresses to actors, keeping what every step found so resolving
+/// the same address or actor again doesn't go back to the network.
+pub struct Resolver {
+    client: reqwest::Client,
+    scheme: &'static str,
+    ids: Mutex<HashMap<String, String>>,
+    actors: Mutex<HashMap<String, Actor>>,
+}
+
+/// Which step of resolving failed.
+#[derive(Debug)]
+pub enum ResolveError {
+    /// The address isn't `user@domain`.
+    InvalidAddress(String),
+    /// The WebFinger lookup for the address failed.
+    Webfinger {
+        address: String,
+        source: reqwest::Error,
+    },
+    /// WebFinger answered, but without a link to an ActivityPub actor.
+    NoActor(String),
+    /// Fetching the actor failed.
+    Actor { id: String, source: reqwest::Error },
+}
+
+impl fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResolveError::InvalidAddress(address) => {
+                write!(f, "{} is not a user@domain address", address)
+            }
+            ResolveError::Webfinger { address, source } => {
+                write!(f, "WebFinger lookup of {} failed: {}", address, source)
+            }
+            ResolveError::NoActor(address) => {
+                write!(f, "WebFinger has no actor for {}", address)
+            }
+            ResolveError::Actor { id, source } => {
+                write!(f, "Fetching actor {} failed: {}", id, source)
+            }
+        }
+    }
+}
+
+impl Error for ResolveError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ResolveError::Webfinger { source, .. } | ResolveError::Actor { source, .. } => {
+                Some(source)
+            }
+            ResolveError::InvalidAddress(_) | ResolveError::NoActor(_) => None,
+        }
+    }
+}
+
+/// The parts of a WebFinger JRD we look at.
+#[derive(Deserialize)]
+struct Jrd {
+    #[serde(default)]
+    links: Vec<Link>,
+}
+
+#[derive(Deserialize)]
+struct Link {
+    rel: String,
+    #[serde(rename = "type")]
+    media_type: Option<String>,
+    href: Option<String>,
+}
+
+impl Resolver {
+    pub fn new(client: reqwest::Client) -> Self {
+        Self {
+            client,
+            scheme: "https",
+            ids: Mutex::new(HashMap::new()),
+            actors: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Does the WebFinger lookup over plain `http`, for servers running
+    /// locally.
+    pub fn insecure(mut self) -> Self {
+        self.scheme = "http";
+        self
+    }
+
+    /// The actor with the address `user@domain`, which may also be given as
+    /// `@user@domain` or `acct:user@domain`.
+    pub async fn resolve(&self, address: &str) -> Result<Actor, ResolveError> {
+        let id = self.webfinger(address).await?;
+        self.actor(&id).await
+    }
+
+    /// The ID of the actor with the address `user@domain`.
+    pub async fn webfinger(&self, address: &str) -> Result<String, ResolveError> {
+        let address = address.trim_start_matches("acct:").trim_start_matches('@');
+        let domain = match address.split_once('@') {
+            Some((user, domain)) if !user.is_empty() && !domain.is_empty() => domain,
+            _ => return Err(ResolveError::InvalidAddress(address.to_string())),
+        };
+        if let Some(id) = self.ids.lock().unwrap().get(address) {
+            return Ok(id.clone());
+        }
+
+        let url = format!("{}://{}/.well-known/webfinger", self.scheme, domain);
+        let failed = |source| ResolveError::Webfinger {
+            address: address.to_string(),
+            source,
+        };
+        let jrd: Jrd = self
+            .client
+            .get(url)
+            .query(&[("resource", format!("acct:{}", address))])
+            .header("Accept", "application/jrd+json, application/json")
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(failed)?
+            .json()
+            .await
+            .map_err(failed)?;
+        let id = jrd
+            .links
+            .into_iter()
+            .find(|link| {
+                link.rel == "self"
+                    && link
+                        .media_type
+                        .as_deref()
+                        .is_some_and(|t| t == ACTIVITY_JSON || t == LD_JSON)
+            })
+            .and_then(|link| link.href)
+            .ok_or_else(|| ResolveError::NoActor(address.to_string()))?;
+
+        self.ids
+            .lock()
+            .unwrap()
+            .insert(address.to_string(), id.clone());
+        Ok(id)
+    }
+
+    /// The actor with the ID `id`.
+    pub async fn actor(&self, id: &str) -> Result<Actor, ResolveError> {
+        if let Some(actor) = self.actors.lock().unwrap().get(id) {
+            return Ok(actor.clone());
+        }
+
+        let failed = |source| ResolveError::Actor {
+            id: id.to_string(),
+            source,
+        };
+        let actor: Actor = self
+            .client
+            .get(id)
+            .header("Accept", LD_JSON)
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(failed)?
+            .json()
+            .await
+            .map_err(failed)?;
+
+        self.actors
+            .lock()
+            .unwrap()
+            .insert(id.to_string(), actor.clone());
+        Ok(actor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Serves WebFinger for `alice`, who has an actor, `nolink`, who has no
+    /// actor link, and `gone`, whose actor is a 404, counting the requests.
+    async fn mock_server() -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let mut buf = [0; 4096];
+                    let n = socket.read(&mut buf).await.unwrap();
+                    let request = String::from_utf8_lossy(&buf[..n]);
+                    let path = request.split(' ').nth(1).unwrap_or("/").to_string();
+
+                    let link = |name: &str| {
+                        format!(
+                            r#"{{"links":[{{"rel":"self","type":"application/activity+json","href":"http://{addr}/users/{name}"}}]}}"#
+                        )
+                    };
+                    let webfinger = path
+                        .strip_prefix("/.well-known/webfinger?resource=acct%3A")
+                        .and_then(|resource| resource.split("%40").next());
+                    let body = match (webfinger, path.as_str()) {
+                        (Some(name @ ("alice" | "gone")), _) => Some(link(name)),
+                        (Some("nolink"), _) => Some(
+                            r#"{"links":[{"rel":"http://webfinger.net/rel/profile-page","href":"http://example.com/@nolink"}]}"#
+                                .to_string(),
+                        ),
+                        (None, "/users/alice") => Some(format!(
+                            r#"{{"id":"http://{addr}/users/alice","type":"Person","inbox":"http://{addr}/users/alice/inbox","outbox":"http://{addr}/users/alice/outbox","following":"http://{addr}/users/alice/following","followers":"http://{addr}/users/alice/followers"}}"#
+                        )),
+                        _ => None,
+                    };
+
+                    let response = match body {
+                        Some(body) => format!(
+                            "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                            body.len(),
+                            body
+                        ),
+                        None => "HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\nconnection: close\r\n\r\n"
+                            .to_string(),
+                    };
+                    socket.write_all(response.as_bytes()).await.unwrap();
+                });
+            }
+        });
+        (addr.to_string(), requests)
+    }
+
+    #[tokio::test]
+    async fn test_resolve() {
+        let (host, requests) = mock_server().await;
+        let resolver = Resolver::new(reqwest::Client::new()).insecure();
+
+        let actor = resolver.resolve(&format!("alice@{}", host)).await.unwrap();
+        assert_eq!(actor.id(), format!("http://{}/users/alice", host));
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+        // both hops are cached, whichever way the address is written
+        for address in [
+            format!("alice@{}", host),
+            format!("@alice@{}", host),
+            format!("acct:alice@{}", host),
+        ] {
+            let again = resolver.resolve(&address).await.unwrap();
+            assert_eq!(again.id(), actor.id());
+        }
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_resolve_errors() {
+        let (host, _) = mock_server().await;
+        let resolver = Resolver::new(reqwest::Client::new()).insecure();
+
+        for address in ["alice", "@alice", "alice@", "@example.com"] {
+            assert!(
+                matches!(
+                    resolver.resolve(address).await,
+                    Err(ResolveError::InvalidAddress(_))
+                ),
+                "{}",
+                address
+            );
+        }
+        assert!(matches!(
+            resolver.resolve(&format!("nobody@{}", host)).await,
+            Err(ResolveError::Webfinger { .. })
+        ));
+        assert!(matches!(
+            resolver.resolve(&format!("nolink@{}", host)).await,
+            Err(ResolveError::NoActor(_))
+        ));
+        match resolver.resolve(&format!("gone@{}", host)).await {
+            Err(ResolveError::Actor { id, .. }) => {
+                assert_eq!(id, format!("http://{}/users/gone", host))
+            }
+            other => panic!("expected an actor error, got {:?}", other),
+        }
+    }
+}
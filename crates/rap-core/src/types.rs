@@ -1,7 +1,7 @@
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Actor {
     id: String,
     #[serde(rename = "type")]
@@ -17,12 +17,87 @@ pub struct Actor {
     name: Option<String>,
     summary: Option<String>,
     url: Option<String>,
+    #[serde(default)]
     endpoints: HashMap<String, String>,
     icon: Option<Media>,
     image: Option<Media>,
+    #[serde(rename = "alsoKnownAs", default)]
+    also_known_as: Vec<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+impl Actor {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// The collection of activities this actor has published, newest first.
+    pub fn outbox(&self) -> &str {
+        &self.outbox
+    }
+
+    /// Other actors this one claims to be, as used to verify a `Move`.
+    pub fn also_known_as(&self) -> &[String] {
+        &self.also_known_as
+    }
+
+    /// Where to deliver activities for this actor. With `shared` the server's
+    /// `sharedInbox` is preferred, if it has one, so an activity addressed to
+    /// several of its actors only has to be sent once.
+    pub fn inbox_url(&self, shared: bool) -> &str {
+        match self.endpoints.get("sharedInbox") {
+            Some(shared_inbox) if shared => shared_inbox,
+            _ => &self.inbox,
+        }
+    }
+}
+
+/// An object such as a `Note`, with the properties we look at.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Object {
+    id: String,
+    #[serde(rename = "type")]
+    object_type: String,
+    #[serde(rename = "attributedTo", skip_serializing_if = "Option::is_none")]
+    attributed_to: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    summary: Option<String>,
+    /// `as:sensitive`, which isn't in the ActivityStreams context, so
+    /// documents carrying it need the extension in theirs.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sensitive: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    published: Option<String>,
+}
+
+impl Object {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// The actor the object is by.
+    pub fn attributed_to(&self) -> Option<&str> {
+        self.attributed_to.as_deref()
+    }
+
+    pub fn content(&self) -> Option<&str> {
+        self.content.as_deref()
+    }
+
+    /// A summary of the content. For [sensitive](Self::sensitive) content
+    /// this is its content warning.
+    pub fn summary(&self) -> Option<&str> {
+        self.summary.as_deref()
+    }
+
+    /// Whether the content should be hidden until the reader asks for it.
+    pub fn sensitive(&self) -> bool {
+        self.sensitive.unwrap_or(false)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Media {
     #[serde(rename = "type")]
     object_type: String,
@@ -30,3 +105,88 @@ pub struct Media {
     media_type: String,
     url: String,
 }
+
+impl Media {
+    /// An `Image` at `url`, such as an avatar or header.
+    pub fn image(media_type: impl Into<String>, url: impl Into<String>) -> Self {
+        Self {
+            object_type: "Image".to_string(),
+            media_type: media_type.into(),
+            url: url.into(),
+        }
+    }
+
+    pub fn media_type(&self) -> &str {
+        &self.media_type
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn actor(extra: &str) -> Actor {
+        serde_json::from_str(&format!(
+            r#"{{
+                "id": "https://new.example/users/bob",
+                "type": "Person",
+                "inbox": "https://new.example/users/bob/inbox",
+                "outbox": "https://new.example/users/bob/outbox",
+                "following": "https://new.example/users/bob/following",
+                "followers": "https://new.example/users/bob/followers"{}
+            }}"#,
+            extra
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_object_round_trip() {
+        let note = serde_json::json!({
+            "id": "https://example.com/objects/1",
+            "type": "Note",
+            "attributedTo": "https://example.com/users/alice",
+            "content": "<p>spoilers</p>",
+            "summary": "the ending of a film",
+            "sensitive": true,
+            "published": "2023-09-04T20:49:38Z",
+        });
+        let object: Object = serde_json::from_value(note.clone()).unwrap();
+        assert!(object.sensitive());
+        assert_eq!(object.summary(), Some("the ending of a film"));
+        assert_eq!(serde_json::to_value(&object).unwrap(), note);
+
+        // nothing is added to objects without a content warning
+        let plain = serde_json::json!({
+            "id": "https://example.com/objects/2",
+            "type": "Note",
+            "content": "<p>hello</p>",
+        });
+        let object: Object = serde_json::from_value(plain.clone()).unwrap();
+        assert!(!object.sensitive());
+        assert_eq!(object.summary(), None);
+        assert_eq!(serde_json::to_value(&object).unwrap(), plain);
+    }
+
+    #[test]
+    fn test_also_known_as() {
+        let bob = actor(r#", "alsoKnownAs": ["https://old.example/users/bob"]"#);
+        assert_eq!(bob.id(), "https://new.example/users/bob");
+        assert_eq!(bob.also_known_as(), ["https://old.example/users/bob"]);
+        assert!(actor("").also_known_as().is_empty());
+    }
+
+    #[test]
+    fn test_inbox_url() {
+        let bob = actor(r#", "endpoints": {"sharedInbox": "https://new.example/inbox"}"#);
+        assert_eq!(bob.inbox_url(false), "https://new.example/users/bob/inbox");
+        assert_eq!(bob.inbox_url(true), "https://new.example/inbox");
+
+        let bob = actor("");
+        assert_eq!(bob.inbox_url(true), "https://new.example/users/bob/inbox");
+    }
+}
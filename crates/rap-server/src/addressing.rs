@@ -0,0 +1,83 @@
+use serde_json::Value;
+
+/// The special collection that makes an activity public. It is not a real
+/// actor and can never be delivered to.
+pub const PUBLIC: &str = "https://www.w3.org/ns/activitystreams#Public";
+
+/// Whether `iri` is the public collection, including the compacted forms some
+/// servers send.
+pub fn is_public(iri: &str) -> bool {
+    matches!(iri, PUBLIC | "as:Public" | "Public")
+}
+
+/// Every IRI in an activity's `to`, `cc`, `bto`, `bcc`, and `audience`.
+/// Senders are meant to strip `bto` and `bcc`, but when they don't, those
+/// are addressees too.
+pub fn addressees(activity: &Value) -> Vec<&str> {
+    ["to", "cc", "bto", "bcc", "audience"]
+        .iter()
+        .flat_map(|field| match &activity[field] {
+            Value::Array(values) => values.iter().filter_map(iri_of).collect(),
+            value => iri_of(value).into_iter().collect::<Vec<_>>(),
+        })
+        .collect()
+}
+
+/// Addressees that are actual recipients, i.e. not the public collection.
+pub fn recipients(activity: &Value) -> Vec<&str> {
+    addressees(activity)
+        .into_iter()
+        .filter(|iri| !is_public(iri))
+        .collect()
+}
+
+fn iri_of(value: &Value) -> Option<&str> {
+    value.as_str().or_else(|| value["id"].as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_is_public() {
+        assert!(is_public(PUBLIC));
+        assert!(is_public("as:Public"));
+        assert!(is_public("Public"));
+        assert!(!is_public("https://example.com/users/alice"));
+        assert!(!is_public("https://example.com/users/alice/followers"));
+    }
+
+    #[test]
+    fn test_public_in_to_and_cc() {
+        for activity in [
+            json!({"to": PUBLIC, "cc": ["https://example.com/users/alice/followers"]}),
+            json!({"to": ["https://example.com/users/alice/followers"], "cc": [PUBLIC]}),
+            json!({"to": ["as:Public"], "cc": "https://example.com/users/alice/followers"}),
+        ] {
+            assert!(addressees(&activity).into_iter().any(is_public));
+            assert_eq!(
+                recipients(&activity),
+                vec!["https://example.com/users/alice/followers"]
+            );
+        }
+    }
+
+    #[test]
+    fn test_blind_addressees() {
+        let activity = json!({
+            "to": "https://example.com/users/alice",
+            "bto": ["https://example.com/users/bob"],
+            "bcc": {"id": "https://example.com/users/carol"},
+        });
+        assert_eq!(
+            recipients(&activity),
+            vec![
+                "https://example.com/users/alice",
+                "https://example.com/users/bob",
+                "https://example.com/users/carol",
+            ]
+        );
+    }
+}
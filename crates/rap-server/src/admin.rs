@@ -0,0 +1,729 @@
+use axum::async_trait;
+use axum::extract::{FromRequestParts, Path, Query};
+use axum::http::request::Parts;
+use axum::http::StatusCode;
+use axum::{Extension, Json};
+use reqwest::Url;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+use crate::collections::{ordered_collection, PageQuery};
+use crate::config::Config;
+use crate::objects::ObjectStore;
+use crate::token;
+use crate::users::{PeopleStore, Person, PersonId};
+use crate::utils::{web_err, web_err_400, web_err_401, web_err_500, WebError};
+
+/// Extractor for requests carrying the configured admin bearer token in the
+/// `Authorization` header.
+pub struct Admin;
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Admin
+where
+    S: Send + Sync,
+{
+    type Rejection = WebError;
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        use axum::RequestPartsExt;
+        let Extension(cfg) = parts
+            .extract::<Extension<Config>>()
+            .await
+            .map_err(|_| web_err_500("Could not extract config"))?;
+
+        let expected = cfg
+            .admin_token
+            .as_deref()
+            .ok_or_else(|| web_err_401("Admin endpoints are disabled"))?;
+
+        let token = parts
+            .headers
+            .get("authorization")
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.strip_prefix("Bearer "))
+            .ok_or_else(|| web_err_401("No bearer token"))?;
+
+        ring::constant_time::verify_slices_are_equal(token.as_bytes(), expected.as_bytes())
+            .map_err(|_| web_err_401("Invalid bearer token"))?;
+
+        Ok(Admin)
+    }
+}
+
+fn person_json(person: &Person) -> Value {
+    json!({
+        "username": person.username,
+        "id": person.id,
+    })
+}
+
+pub async fn list_users(
+    _admin: Admin,
+    Extension(people): Extension<Arc<dyn PeopleStore>>,
+) -> Result<Json<Value>, WebError> {
+    let people = people
+        .list()
+        .await
+        .map_err(|e| web_err_500(format!("Error listing people: {}", e)))?;
+    Ok(Json(Value::Array(people.iter().map(person_json).collect())))
+}
+
+#[derive(Deserialize)]
+pub struct NewUser {
+    username: String,
+}
+
+pub async fn create_user(
+    _admin: Admin,
+    Extension(people): Extension<Arc<dyn PeopleStore>>,
+    Json(user): Json<NewUser>,
+) -> Result<(StatusCode, Json<Value>), WebError> {
+    let username = user.username;
+    if username.is_empty()
+        || !username
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+    {
+        return Err(web_err_400(format!("Invalid username: {}", username)));
+    }
+
+    let person = people
+        .create(&username)
+        .await
+        .map_err(|e| web_err_500(format!("Error creating person: {}", e)))?
+        .ok_or_else(|| {
+            web_err(
+                StatusCode::CONFLICT,
+                format!("User {} already exists", username),
+            )
+        })?;
+    Ok((StatusCode::CREATED, Json(person_json(&person))))
+}
+
+#[derive(Deserialize)]
+pub struct Aliases {
+    #[serde(rename = "alsoKnownAs")]
+    also_known_as: Vec<String>,
+}
+
+/// Sets the accounts a user is also known as, so a `Move` from one of them to
+/// this user can be verified by other servers.
+pub async fn set_also_known_as(
+    _admin: Admin,
+    Path(username): Path<PersonId>,
+    Extension(people): Extension<Arc<dyn PeopleStore>>,
+    Json(aliases): Json<Aliases>,
+) -> Result<Json<Value>, WebError> {
+    for alias in &aliases.also_known_as {
+        match Url::parse(alias) {
+            Ok(url) if url.scheme() == "https" || url.scheme() == "http" => {}
+            _ => return Err(web_err_400(format!("Invalid alias: {}", alias))),
+        }
+    }
+
+    let person = people
+        .set_also_known_as(&username, aliases.also_known_as)
+        .await
+        .map_err(|e| web_err_500(format!("Error updating person: {}", e)))?
+        .ok_or_else(|| web_err(StatusCode::NOT_FOUND, format!("No person {}", username)))?;
+    let mut json = person_json(&person);
+    json["alsoKnownAs"] = json!(person.also_known_as);
+    Ok(Json(json))
+}
+
+/// Gives a user a new signing key, e.g. after the old one leaked. The old key
+/// stays in their actor document for the configured grace period.
+pub async fn rotate_key(
+    _admin: Admin,
+    Path(username): Path<PersonId>,
+    Extension(people): Extension<Arc<dyn PeopleStore>>,
+) -> Result<Json<Value>, WebError> {
+    let person = people
+        .rotate_key(&username)
+        .await
+        .map_err(|e| web_err_500(format!("Error rotating key: {}", e)))?
+        .ok_or_else(|| web_err(StatusCode::NOT_FOUND, format!("No person {}", username)))?;
+    let mut json = person_json(&person);
+    json["keyId"] = json!(person.key.key_id());
+    Ok(Json(json))
+}
+
+/// A user's ids and current public key, to check how they were provisioned
+/// or why a remote server can't verify their signatures.
+pub async fn keys(
+    _admin: Admin,
+    Path(username): Path<PersonId>,
+    Extension(people): Extension<Arc<dyn PeopleStore>>,
+) -> Result<Json<Value>, WebError> {
+    let person = people
+        .get_by_username(&username)
+        .await
+        .map_err(|e| web_err_500(format!("Error getting person: {}", e)))?
+        .ok_or_else(|| web_err(StatusCode::NOT_FOUND, format!("No person {}", username)))?;
+    let (pem, fingerprint) = person
+        .key
+        .public_key()
+        .and_then(|key| Ok((key.pem().to_string(), key.fingerprint()?)))
+        .map_err(|e| web_err_500(format!("Error getting public key: {}", e)))?;
+    let mut json = person_json(&person);
+    json["inbox"] = json!(person.collection_url("inbox"));
+    json["keyId"] = json!(person.key.key_id());
+    json["publicKeyPem"] = json!(pem);
+    json["fingerprint"] = json!(fingerprint);
+    Ok(Json(json))
+}
+
+/// The activities in a user's inbox, oldest first, as an `OrderedCollection`
+/// paged like the public collections.
+pub async fn inbox(
+    _admin: Admin,
+    Path(username): Path<PersonId>,
+    Query(page): Query<PageQuery>,
+    Extension(cfg): Extension<Config>,
+    Extension(people): Extension<Arc<dyn PeopleStore>>,
+) -> Result<Json<Value>, WebError> {
+    people
+        .get_by_username(&username)
+        .await
+        .map_err(|e| web_err_500(format!("Error getting person: {}", e)))?
+        .ok_or_else(|| web_err(StatusCode::NOT_FOUND, format!("No person {}", username)))?;
+    let activities = people
+        .inbox(&username)
+        .await
+        .map_err(|e| web_err_500(format!("Error getting inbox: {}", e)))?;
+    Ok(Json(ordered_collection(
+        &cfg,
+        format!("{}/admin/users/{}/inbox", cfg.base_url(), username),
+        activities,
+        page,
+    )))
+}
+
+/// A bearer token for the user, signed with `--token-secret` and good for
+/// `--token-lifetime` seconds.
+pub async fn mint_token(
+    _admin: Admin,
+    Path(username): Path<PersonId>,
+    Extension(cfg): Extension<Config>,
+    Extension(people): Extension<Arc<dyn PeopleStore>>,
+) -> Result<Json<Value>, WebError> {
+    let secret = cfg
+        .token_secret
+        .as_deref()
+        .ok_or_else(|| web_err_400("User tokens are disabled"))?;
+    people
+        .get_by_username(&username)
+        .await
+        .map_err(|e| web_err_500(format!("Error getting person: {}", e)))?
+        .ok_or_else(|| web_err(StatusCode::NOT_FOUND, format!("No person {}", username)))?;
+    let expires = chrono::Utc::now() + chrono::Duration::seconds(cfg.token_lifetime as i64);
+    Ok(Json(json!({
+        "token": token::mint(secret, &username, expires.timestamp()),
+        "expires": expires.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+    })))
+}
+
+pub async fn list_suspended(
+    _admin: Admin,
+    Extension(objects): Extension<Arc<dyn ObjectStore>>,
+) -> Result<Json<Value>, WebError> {
+    let actors = objects
+        .suspended()
+        .await
+        .map_err(|e| web_err_500(format!("Error listing suspended actors: {}", e)))?;
+    Ok(Json(json!(actors)))
+}
+
+#[derive(Deserialize)]
+pub struct Suspension {
+    actor: String,
+}
+
+/// Stops accepting activities from a remote actor.
+pub async fn suspend(
+    _admin: Admin,
+    Extension(objects): Extension<Arc<dyn ObjectStore>>,
+    Json(suspension): Json<Suspension>,
+) -> Result<StatusCode, WebError> {
+    match Url::parse(&suspension.actor) {
+        Ok(url) if url.scheme() == "https" || url.scheme() == "http" => {}
+        _ => return Err(web_err_400(format!("Invalid actor: {}", suspension.actor))),
+    }
+
+    let added = objects
+        .suspend(&suspension.actor)
+        .await
+        .map_err(|e| web_err_500(format!("Error suspending actor: {}", e)))?;
+    Ok(if added {
+        StatusCode::CREATED
+    } else {
+        StatusCode::OK
+    })
+}
+
+pub async fn unsuspend(
+    _admin: Admin,
+    Extension(objects): Extension<Arc<dyn ObjectStore>>,
+    Json(suspension): Json<Suspension>,
+) -> Result<StatusCode, WebError> {
+    let removed = objects
+        .unsuspend(&suspension.actor)
+        .await
+        .map_err(|e| web_err_500(format!("Error unsuspending actor: {}", e)))?;
+    if !removed {
+        return Err(web_err(
+            StatusCode::NOT_FOUND,
+            format!("{} is not suspended", suspension.actor),
+        ));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::{body_json, test_app};
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+
+    fn create(username: &str, token: Option<&str>) -> Request<Body> {
+        let mut request = Request::post("/admin/users").header("content-type", "application/json");
+        if let Some(token) = token {
+            request = request.header("authorization", format!("Bearer {}", token));
+        }
+        request
+            .body(Body::from(json!({ "username": username }).to_string()))
+            .unwrap()
+    }
+
+    fn list(token: Option<&str>) -> Request<Body> {
+        let mut request = Request::get("/admin/users");
+        if let Some(token) = token {
+            request = request.header("authorization", format!("Bearer {}", token));
+        }
+        request.body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_create_and_list() {
+        let app = test_app(&["--admin-token", "secret"]);
+
+        let response = app
+            .clone()
+            .oneshot(create("alice", Some("secret")))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(
+            body_json(response).await,
+            json!({"username": "alice", "id": "https://example.com/users/alice"})
+        );
+
+        let response = app
+            .clone()
+            .oneshot(create("alice", Some("secret")))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+
+        let response = app.clone().oneshot(list(Some("secret"))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            body_json(response).await,
+            json!([{"username": "alice", "id": "https://example.com/users/alice"}])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_invalid_username() {
+        let app = test_app(&["--admin-token", "secret"]);
+        let response = app.oneshot(create("Alice!", Some("secret"))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_unauthorized() {
+        let app = test_app(&["--admin-token", "secret"]);
+
+        let response = app.clone().oneshot(create("alice", None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = app
+            .clone()
+            .oneshot(create("alice", Some("wrong")))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = app.clone().oneshot(list(None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = app.clone().oneshot(list(Some("wrong"))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = app.oneshot(list(Some("secret"))).await.unwrap();
+        assert_eq!(body_json(response).await, json!([]));
+    }
+
+    #[tokio::test]
+    async fn test_disabled_without_token() {
+        let app = test_app(&[]);
+        let response = app.oneshot(list(Some(""))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_set_also_known_as() {
+        let app = test_app(&["--admin-token", "secret"]);
+        app.clone()
+            .oneshot(create("alice", Some("secret")))
+            .await
+            .unwrap();
+
+        let set = |username: &str, aliases: Value| {
+            Request::put(format!("/admin/users/{}/also_known_as", username))
+                .header("authorization", "Bearer secret")
+                .header("content-type", "application/json")
+                .body(Body::from(json!({ "alsoKnownAs": aliases }).to_string()))
+                .unwrap()
+        };
+
+        let response = app
+            .clone()
+            .oneshot(set("alice", json!(["https://old.example/users/alice"])))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = app
+            .clone()
+            .oneshot(Request::get("/users/alice").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(
+            body_json(response).await["alsoKnownAs"],
+            json!(["https://old.example/users/alice"])
+        );
+
+        let response = app
+            .clone()
+            .oneshot(set("alice", json!(["not a url"])))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = app
+            .oneshot(set("bob", json!(["https://old.example/users/bob"])))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_rotate_key() {
+        let app = test_app(&["--admin-token", "secret"]);
+        app.clone()
+            .oneshot(create("alice", Some("secret")))
+            .await
+            .unwrap();
+
+        let rotate = |username: &str| {
+            Request::post(format!("/admin/users/{}/rotate_key", username))
+                .header("authorization", "Bearer secret")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = app.clone().oneshot(rotate("alice")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            body_json(response).await["keyId"],
+            "https://example.com/users/alice/#main-key-1"
+        );
+
+        let response = app
+            .clone()
+            .oneshot(Request::get("/users/alice").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let keys = body_json(response).await["publicKey"].clone();
+        assert_eq!(keys[0]["id"], "https://example.com/users/alice/#main-key-1");
+        assert_eq!(keys[1]["id"], "https://example.com/users/alice/#main-key");
+
+        let response = app.oneshot(rotate("bob")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_keys() {
+        let app = test_app(&["--admin-token", "secret"]);
+        app.clone()
+            .oneshot(create("alice", Some("secret")))
+            .await
+            .unwrap();
+
+        let keys = |username: &str| {
+            Request::get(format!("/admin/users/{}/keys", username))
+                .header("authorization", "Bearer secret")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = app.clone().oneshot(keys("alice")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let keys_json = body_json(response).await;
+        assert_eq!(keys_json["username"], "alice");
+        assert_eq!(keys_json["id"], "https://example.com/users/alice");
+        assert_eq!(keys_json["inbox"], "https://example.com/users/alice/inbox");
+        assert_eq!(
+            keys_json["keyId"],
+            "https://example.com/users/alice/#main-key"
+        );
+        let pem = keys_json["publicKeyPem"].as_str().unwrap();
+        assert_eq!(
+            keys_json["fingerprint"],
+            crate::crypto::fingerprint(pem).unwrap()
+        );
+
+        // the same key the actor document publishes
+        let response = app
+            .clone()
+            .oneshot(Request::get("/users/alice").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(body_json(response).await["publicKey"]["publicKeyPem"], pem);
+
+        let response = app.clone().oneshot(keys("bob")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = app
+            .oneshot(
+                Request::get("/admin/users/alice/keys")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_inbox() {
+        let app = test_app(&["--admin-token", "secret"]);
+        app.clone()
+            .oneshot(create("alice", Some("secret")))
+            .await
+            .unwrap();
+
+        let inbox = |username: &str| {
+            Request::get(format!("/admin/users/{}/inbox", username))
+                .header("authorization", "Bearer secret")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = app.clone().oneshot(inbox("alice")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let collection = body_json(response).await;
+        assert_eq!(collection["type"], "OrderedCollection");
+        assert_eq!(collection["totalItems"], 0);
+
+        let response = app.oneshot(inbox("bob")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_inbox_pages() {
+        use crate::config::Config;
+        use crate::users::{InMemoryPeopleStore, PeopleStore};
+        use clap::Parser;
+        use std::sync::Arc;
+
+        let cfg = Config::parse_from([
+            "rap-server",
+            "--domain",
+            "example.com",
+            "--admin-token",
+            "secret",
+            "--collection-page-size",
+            "2",
+        ]);
+        let people = Arc::new(InMemoryPeopleStore::new(cfg.clone()));
+        let alice = "alice".to_string();
+        people.create(&alice).await.unwrap();
+        for n in 1..=3 {
+            let activity = json!({"id": format!("https://remote.example/notes/{}", n)});
+            people.add_to_inbox(&alice, activity).await.unwrap();
+        }
+        let app = crate::app(
+            &cfg,
+            people,
+            Arc::new(crate::objects::InMemoryObjectStore::new()),
+            crate::delivery::DeliveryQueue::new().0,
+            crate::tests::test_instance(&cfg),
+        );
+        let inbox = |query: &str| {
+            let request = Request::get(format!("/admin/users/alice/inbox{}", query))
+                .header("authorization", "Bearer secret")
+                .body(Body::empty())
+                .unwrap();
+            let app = app.clone();
+            async move { body_json(app.oneshot(request).await.unwrap()).await }
+        };
+        let id = "https://example.com/admin/users/alice/inbox";
+        let ids = |page: &Value| -> Vec<Value> {
+            page["orderedItems"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|a| a["id"].clone())
+                .collect()
+        };
+
+        let collection = inbox("").await;
+        assert_eq!(collection["totalItems"], 3);
+        assert_eq!(collection["first"], format!("{}?page=1", id));
+
+        let first = inbox("?page=1").await;
+        assert_eq!(first["type"], "OrderedCollectionPage");
+        assert_eq!(first["partOf"], id);
+        assert_eq!(
+            ids(&first),
+            vec![
+                json!("https://remote.example/notes/1"),
+                json!("https://remote.example/notes/2")
+            ]
+        );
+        assert_eq!(first["next"], format!("{}?page=2", id));
+        assert!(first.get("prev").is_none());
+
+        let last = inbox("?page=2").await;
+        assert_eq!(ids(&last), vec![json!("https://remote.example/notes/3")]);
+        assert!(last.get("next").is_none());
+        assert_eq!(last["prev"], format!("{}?page=1", id));
+
+        let past_the_end = inbox("?page=3").await;
+        assert_eq!(past_the_end["orderedItems"], json!([]));
+        assert!(past_the_end.get("next").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_mint_token() {
+        let app = test_app(&["--admin-token", "secret", "--token-secret", "hush"]);
+        for username in ["alice", "bob"] {
+            app.clone()
+                .oneshot(create(username, Some("secret")))
+                .await
+                .unwrap();
+        }
+        let mint = |username: &str| {
+            Request::post(format!("/admin/users/{}/token", username))
+                .header("authorization", "Bearer secret")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let read_inbox = |username: &str, token: &str| {
+            Request::get(format!("/users/{}/inbox", username))
+                .header("authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = app.clone().oneshot(mint("alice")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let minted = body_json(response).await;
+        let token = minted["token"].as_str().unwrap();
+        assert!(minted["expires"].is_string());
+
+        let response = app
+            .clone()
+            .oneshot(read_inbox("alice", token))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let collection = body_json(response).await;
+        assert_eq!(collection["id"], "https://example.com/users/alice/inbox");
+        assert_eq!(collection["type"], "OrderedCollection");
+
+        let response = app.clone().oneshot(read_inbox("bob", token)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let tampered = token.replacen("alice", "bob", 1);
+        let response = app
+            .clone()
+            .oneshot(read_inbox("bob", &tampered))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = app.clone().oneshot(mint("carol")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let app = test_app(&["--admin-token", "secret"]);
+        app.clone()
+            .oneshot(create("alice", Some("secret")))
+            .await
+            .unwrap();
+        let response = app.clone().oneshot(mint("alice")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = app.oneshot(read_inbox("alice", token)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_suspend() {
+        let app = test_app(&["--admin-token", "secret"]);
+        let suspension = |method: &str, actor: &str| {
+            Request::builder()
+                .method(method)
+                .uri("/admin/suspended")
+                .header("authorization", "Bearer secret")
+                .header("content-type", "application/json")
+                .body(Body::from(json!({ "actor": actor }).to_string()))
+                .unwrap()
+        };
+        let suspended = || {
+            Request::get("/admin/suspended")
+                .header("authorization", "Bearer secret")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let mallory = "https://remote.example/users/mallory";
+
+        let response = app
+            .clone()
+            .oneshot(suspension("PUT", mallory))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let response = app
+            .clone()
+            .oneshot(suspension("PUT", mallory))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app
+            .clone()
+            .oneshot(suspension("PUT", "mallory"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = app.clone().oneshot(suspended()).await.unwrap();
+        assert_eq!(body_json(response).await, json!([mallory]));
+
+        let response = app
+            .clone()
+            .oneshot(suspension("DELETE", mallory))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = app
+            .clone()
+            .oneshot(suspension("DELETE", mallory))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = app.oneshot(suspended()).await.unwrap();
+        assert_eq!(body_json(response).await, json!([]));
+    }
+}
@@ -0,0 +1,189 @@
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::config::Config;
+use crate::context::context;
+
+/// Most items we put on one page of a collection, whoever asks for more.
+pub const MAX_PAGE_SIZE: u64 = 100;
+
+/// Query string of a collection request. Without a `page` the collection
+/// itself is served, pointing at its first page.
+#[derive(Deserialize, Debug, Default)]
+pub struct PageQuery {
+    page: Option<usize>,
+}
+
+/// Whether a collection is served as an `OrderedCollection` or a plain
+/// `Collection`. The spec only requires ordering for some of them, e.g. the
+/// outbox and inbox.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CollectionKind {
+    /// `OrderedCollection`, with its items in `orderedItems`
+    #[default]
+    Ordered,
+    /// `Collection`, with its items in `items`
+    Unordered,
+}
+
+impl CollectionKind {
+    fn collection_type(self) -> &'static str {
+        match self {
+            CollectionKind::Ordered => "OrderedCollection",
+            CollectionKind::Unordered => "Collection",
+        }
+    }
+
+    fn page_type(self) -> &'static str {
+        match self {
+            CollectionKind::Ordered => "OrderedCollectionPage",
+            CollectionKind::Unordered => "CollectionPage",
+        }
+    }
+
+    fn items_property(self) -> &'static str {
+        match self {
+            CollectionKind::Ordered => "orderedItems",
+            CollectionKind::Unordered => "items",
+        }
+    }
+}
+
+pub fn ordered_collection<T: Serialize>(
+    cfg: &Config,
+    id: String,
+    items: Vec<T>,
+    query: PageQuery,
+) -> Value {
+    collection(cfg, CollectionKind::Ordered, id, items, query)
+}
+
+pub fn collection<T: Serialize>(
+    cfg: &Config,
+    kind: CollectionKind,
+    id: String,
+    items: Vec<T>,
+    query: PageQuery,
+) -> Value {
+    let page = match query.page {
+        Some(page) => page.max(1),
+        None => {
+            return json!({
+                "@context": context(cfg),
+                "id": id,
+                "type": kind.collection_type(),
+                "totalItems": items.len(),
+                "first": page_url(&id, 1),
+            })
+        }
+    };
+
+    let page_size = cfg.collection_page_size.min(MAX_PAGE_SIZE) as usize;
+    let start = (page - 1).saturating_mul(page_size).min(items.len());
+    let end = start.saturating_add(page_size).min(items.len());
+    let mut collection = json!({
+        "@context": context(cfg),
+        "id": page_url(&id, page),
+        "type": kind.page_type(),
+        "partOf": id,
+        "totalItems": items.len(),
+    });
+    collection[kind.items_property()] = json!(items[start..end]);
+    if end < items.len() {
+        collection["next"] = json!(page_url(&id, page + 1));
+    }
+    if page > 1 {
+        collection["prev"] = json!(page_url(&id, page - 1));
+    }
+    collection
+}
+
+fn page_url(id: &str, page: usize) -> String {
+    format!("{}?page={}", id, page)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn page(page: usize) -> PageQuery {
+        PageQuery { page: Some(page) }
+    }
+
+    #[test]
+    fn test_page_size() {
+        let cfg = Config::parse_from([
+            "rap-server",
+            "--domain",
+            "example.com",
+            "--collection-page-size",
+            "2",
+        ]);
+        let id = "https://example.com/users/alice/followers".to_string();
+        let items: Vec<String> = ["a", "b", "c"].iter().map(|s| s.to_string()).collect();
+
+        let collection = ordered_collection(&cfg, id.clone(), items.clone(), PageQuery::default());
+        assert_eq!(collection["totalItems"], 3);
+        assert_eq!(collection["first"], format!("{}?page=1", id));
+        assert!(collection.get("orderedItems").is_none());
+
+        let first = ordered_collection(&cfg, id.clone(), items.clone(), page(1));
+        assert_eq!(first["type"], "OrderedCollectionPage");
+        assert_eq!(first["partOf"], id);
+        assert_eq!(first["orderedItems"], json!(["a", "b"]));
+        assert_eq!(first["next"], format!("{}?page=2", id));
+        assert!(first.get("prev").is_none());
+
+        let second = ordered_collection(&cfg, id.clone(), items.clone(), page(2));
+        assert_eq!(second["orderedItems"], json!(["c"]));
+        assert!(second.get("next").is_none());
+        assert_eq!(second["prev"], format!("{}?page=1", id));
+
+        let past_the_end = ordered_collection(&cfg, id, items, page(usize::MAX));
+        assert_eq!(past_the_end["orderedItems"], json!([]));
+    }
+
+    #[test]
+    fn test_collection_kind() {
+        let cfg = Config::parse_from(["rap-server", "--domain", "example.com"]);
+        let id = "https://example.com/users/alice/liked".to_string();
+        let items = vec!["a".to_string()];
+
+        for (kind, collection_type, page_type, items_property) in [
+            (
+                CollectionKind::Ordered,
+                "OrderedCollection",
+                "OrderedCollectionPage",
+                "orderedItems",
+            ),
+            (
+                CollectionKind::Unordered,
+                "Collection",
+                "CollectionPage",
+                "items",
+            ),
+        ] {
+            let all = collection(&cfg, kind, id.clone(), items.clone(), PageQuery::default());
+            assert_eq!(all["type"], collection_type);
+            let first = collection(&cfg, kind, id.clone(), items.clone(), page(1));
+            assert_eq!(first["type"], page_type);
+            assert_eq!(first[items_property], json!(["a"]));
+        }
+    }
+
+    #[test]
+    fn test_page_size_bounds() {
+        for size in ["0", "101"] {
+            Config::try_parse_from([
+                "rap-server",
+                "--domain",
+                "example.com",
+                "--collection-page-size",
+                size,
+            ])
+            .unwrap_err();
+        }
+    }
+}
@@ -1,17 +1,521 @@
-use clap::Parser;
+use crate::collections::CollectionKind;
+use clap::{Parser, ValueEnum};
+use std::error::Error;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
 
 #[derive(Parser, Debug, Clone)]
 #[command(author, version, about, long_about = None)]
 pub struct Config {
-    /// Address to listen on
-    #[arg(short, long, env, default_value = "0.0.0.0")]
-    pub(crate) address: String,
+    /// Addresses to listen on, comma separated. IPv6 addresses may be bracketed
+    #[arg(short, long, env, default_value = "0.0.0.0", value_delimiter = ',')]
+    pub(crate) address: Vec<String>,
 
     /// Port to listen on
     #[arg(short, long, env, default_value = "3000")]
     pub(crate) port: String,
 
+    /// Unix socket to listen on instead of TCP, e.g. behind a local proxy.
+    /// `--address` and `--port` are ignored when this is set
+    #[arg(long, env, value_name = "PATH")]
+    pub(crate) socket: Option<PathBuf>,
+
+    /// Also serve HTTP/2 without TLS (h2c with prior knowledge), for proxies
+    /// and peers that multiplex requests over one connection. Only HTTP/1.1
+    /// is served when this isn't set
+    #[arg(long, env)]
+    pub(crate) http2: bool,
+
     /// Domain to use for the server
     #[arg(short, long, env)]
     pub(crate) domain: String,
+
+    /// Scheme the server is publicly reachable on
+    #[arg(long, env, default_value = "https")]
+    pub(crate) scheme: String,
+
+    /// Maximum number of redirects to follow when fetching remote documents
+    #[arg(long, env, default_value = "5")]
+    pub(crate) max_redirects: usize,
+
+    /// Allow fetching remote documents from private, loopback, and link-local
+    /// addresses. Only useful for local development
+    #[arg(long, env)]
+    pub(crate) allow_private_fetches: bool,
+
+    /// Maximum size in bytes of a remote document (actor, key, ...) we will read
+    #[arg(long, env, default_value = "262144")]
+    pub(crate) max_fetch_size: usize,
+
+    /// Largest `Content-Length` in bytes we accept on incoming requests.
+    /// Requests declaring more get a 413 before any of the body is read
+    #[arg(long, env, default_value = "1048576")]
+    pub(crate) max_content_length: u64,
+
+    /// Seconds to wait for a remote server while fetching a document
+    #[arg(long, env, default_value = "10")]
+    pub(crate) fetch_timeout: u64,
+
+    /// Seconds to keep a fetched remote public key before fetching it again
+    #[arg(long, env, default_value = "3600")]
+    pub(crate) key_cache_ttl: u64,
+
+    /// Seconds before a cached key that failed to verify a signature may be
+    /// refetched again, so bad signatures naming someone's key can't make us
+    /// fetch it over and over
+    #[arg(long, env, default_value = "60")]
+    pub(crate) key_refetch_cooldown: u64,
+
+    /// Most remote public keys fetched at once. Requests signed with keys we
+    /// don't have yet wait for a turn beyond that
+    #[arg(long, env, default_value = "16", value_parser = clap::value_parser!(u64).range(1..))]
+    pub(crate) max_key_fetches: u64,
+
+    /// Seconds to keep a fetched remote actor, e.g. to look up their inbox,
+    /// before fetching it again
+    #[arg(long, env, default_value = "3600")]
+    pub(crate) actor_cache_ttl: u64,
+
+    /// Seconds to keep advertising a user's previous key after rotating it, so
+    /// requests already signed with it still verify. 0 stops advertising it
+    /// right away
+    #[arg(long, env, default_value = "86400")]
+    pub(crate) key_rotation_grace: u64,
+
+    /// Seconds a request may take before we give up with a 504
+    #[arg(long, env, default_value = "30")]
+    pub(crate) request_timeout: u64,
+
+    /// Timeouts for single routes instead of `--request-timeout`, as the
+    /// route's path and seconds, e.g. `/users/:id/inbox=60` for inboxes that
+    /// may have to fetch keys. Comma separated or given more than once
+    #[arg(
+        long,
+        env,
+        value_delimiter = ',',
+        value_parser = parse_route_timeout,
+        value_name = "PATH=SECONDS"
+    )]
+    pub(crate) route_timeout: Vec<(String, u64)>,
+
+    /// How error responses are written: the message as plain text, or RFC
+    /// 9457 `application/problem+json` for clients that understand it
+    #[arg(long, env, value_enum, default_value = "plain")]
+    pub(crate) error_format: ErrorFormat,
+
+    /// Software name reported in NodeInfo and the outbound User-Agent
+    #[arg(long, env, default_value = env!("CARGO_PKG_NAME"))]
+    pub(crate) software_name: String,
+
+    /// Software version reported in NodeInfo and the outbound User-Agent
+    #[arg(long, env, default_value = env!("CARGO_PKG_VERSION"))]
+    pub(crate) software_version: String,
+
+    /// Source repository of the software, reported in NodeInfo 2.1
+    #[arg(long, env)]
+    pub(crate) software_repository: Option<String>,
+
+    /// Homepage of the software, reported in NodeInfo 2.1
+    #[arg(long, env)]
+    pub(crate) software_homepage: Option<String>,
+
+    /// Enable endpoints for debugging federation, e.g. `/debug/users/:id/inbox`
+    /// which echoes back the signing string of a request, and
+    /// `/debug/deliveries` listing the activities we queued lately, which
+    /// also takes `--admin-token`. Never enable this in production
+    #[arg(long, env)]
+    pub(crate) debug_endpoints: bool,
+
+    /// Serve the demo routes: "boo!" at `/` instead of what server this is,
+    /// and `/plain_text` and `/json`. Never enable this in production
+    #[arg(long, env)]
+    pub(crate) dev_routes: bool,
+
+    /// Log the bodies of inbox requests and of our deliveries at debug level.
+    /// They are other people's posts, so leave this off unless debugging
+    /// federation
+    #[arg(long, env)]
+    pub(crate) debug_bodies: bool,
+
+    /// Bytes of a body logged by `--debug-bodies` before cutting it short
+    #[arg(long, env, default_value = "4096")]
+    pub(crate) debug_body_limit: usize,
+
+    /// What to do with a signature covering a header the request doesn't
+    /// have: sign over an empty value (lenient) or reject the request (strict)
+    #[arg(long, env, value_enum, default_value = "lenient")]
+    pub(crate) missing_signed_headers: MissingSignedHeaders,
+
+    /// Seconds a signed request's `date` may be behind our clock. When set,
+    /// the signature has to cover a `date` within it, and a signature we
+    /// have already seen in that time is rejected as a replay
+    #[arg(long, env, value_name = "SECONDS")]
+    pub(crate) replay_window: Option<u64>,
+
+    /// Seconds a signed request's `date` may be ahead of our clock, with
+    /// `--replay-window`. Defaults to the window, but clocks running ahead
+    /// are rarer than requests taking a while to arrive, so this can be
+    /// tighter
+    #[arg(long, env, value_name = "SECONDS", requires = "replay_window")]
+    pub(crate) max_future_skew: Option<u64>,
+
+    /// Source addresses allowed to POST to our inboxes without signing,
+    /// comma separated. Only for trusted systems on an internal network;
+    /// every such request is logged as a warning. Signed requests from them
+    /// are still verified. The address checked is that of the direct peer,
+    /// never `X-Forwarded-For`, so behind a proxy it is the proxy's address
+    /// and listing it lets through everything the proxy forwards. Has no
+    /// effect with `--socket`
+    #[arg(long, env, value_delimiter = ',', value_name = "IPS")]
+    pub(crate) unsigned_peers: Vec<IpAddr>,
+
+    /// Proxies in front of us, comma separated, whose `X-Forwarded-Host` is
+    /// taken as the host a request was sent to when checking a signature
+    /// over `host`. Otherwise that is `--domain`: the `Host` that arrives is
+    /// replaced with it even when there is no proxy at all
+    #[arg(long, env, value_delimiter = ',', value_name = "IPS")]
+    pub(crate) trusted_proxies: Vec<IpAddr>,
+
+    /// Check inbox bodies against their `Digest` header, and reject signed
+    /// requests whose signature doesn't cover `Digest`, as their bodies could
+    /// be anything. Only turn this off behind a trusted proxy that strips or
+    /// rewrites the header
+    #[arg(long, env, default_value_t = true, action = clap::ArgAction::Set)]
+    pub(crate) require_digest: bool,
+
+    /// Bearer token required by the `/admin` endpoints. They are unreachable
+    /// when this isn't set
+    #[arg(long, env)]
+    pub(crate) admin_token: Option<String>,
+
+    /// Secret signing the bearer tokens minted for users through the
+    /// `/admin` endpoints, so they can be checked without a lookup. Changing
+    /// it revokes every token. Users can't authenticate when this isn't set
+    #[arg(long, env)]
+    pub(crate) token_secret: Option<String>,
+
+    /// Seconds a minted user token stays valid
+    #[arg(long, env, default_value = "86400")]
+    pub(crate) token_lifetime: u64,
+
+    /// URL every activity accepted at an inbox is also POSTed to, e.g. to hook
+    /// up other systems. Failed calls are logged and don't affect the sender
+    #[arg(long, env, requires = "webhook_secret", value_parser = parse_webhook_url)]
+    pub(crate) webhook_url: Option<reqwest::Url>,
+
+    /// Secret the webhook calls are signed with, as an HMAC-SHA256 of the
+    /// body in their `X-Hub-Signature-256` header
+    #[arg(long, env, value_parser = parse_webhook_secret)]
+    pub(crate) webhook_secret: Option<String>,
+
+    /// Reject incoming activities missing `type`, `actor`, or `id` with a 400
+    /// before doing anything with them
+    #[arg(long, env)]
+    pub(crate) validate_activities: bool,
+
+    /// What to do with activities from actors suspended through the admin
+    /// endpoints
+    #[arg(long, env, value_enum, default_value = "reject")]
+    pub(crate) suspended_actors: SuspendedActors,
+
+    /// Activity types to drop at the inbox, comma separated, e.g.
+    /// `Like,Announce`. They are still verified and answered with a 202, but
+    /// nothing is done with them and nothing is stored
+    #[arg(long, env, value_delimiter = ',', value_name = "TYPES")]
+    pub(crate) ignore_activity_types: Vec<String>,
+
+    /// Items per page of a collection (followers, likes, ...), at most 100
+    #[arg(
+        long,
+        env,
+        default_value = "20",
+        value_parser = clap::value_parser!(u64).range(1..=crate::collections::MAX_PAGE_SIZE)
+    )]
+    pub(crate) collection_page_size: u64,
+
+    /// Whether `liked` collections are served ordered or as a plain
+    /// `Collection`, which some consumers expect
+    #[arg(long, env, value_enum, default_value = "ordered")]
+    pub(crate) liked_collection: CollectionKind,
+
+    /// Extra JSON-LD context appended to the `@context` of every document we
+    /// serve, for the extensions we use. Either a context URL or an inline
+    /// context object like `{"toot": "http://joinmastodon.org/ns#"}`. May be
+    /// given more than once
+    #[arg(long, env, value_parser = crate::context::parse_extra_context)]
+    pub(crate) extra_context: Vec<serde_json::Value>,
+
+    /// Followers loaded from the store at a time when forwarding an activity
+    /// to all of them
+    #[arg(long, env, default_value = "1000", value_parser = clap::value_parser!(u64).range(1..))]
+    pub(crate) fanout_batch_size: u64,
+
+    /// Addressees of one incoming activity we act on, in the order it lists
+    /// them. The rest are ignored with a warning, so an activity addressed to
+    /// thousands can't make us fan out to all of them
+    #[arg(long, env, default_value = "1000", value_parser = clap::value_parser!(u64).range(1..))]
+    pub(crate) max_recipients: u64,
+
+    /// JSON file of local users to create at startup with fixed keys, as
+    /// `[{"username": "alice", "key": "alice.pem"}]`. Key paths are relative
+    /// to the file and point at PKCS#8 PEM private keys
+    #[arg(long, env)]
+    pub(crate) seed_file: Option<PathBuf>,
+
+    /// Passphrase the private keys of `--seed-file` are encrypted with, made
+    /// with `--seal-key`. Without it they are read as plain PEM
+    #[arg(long, env)]
+    pub(crate) key_encryption_key: Option<String>,
+
+    /// Encrypt the PEM private key in FILE with `--key-encryption-key`, print
+    /// it for use in a seed file, and exit
+    #[arg(long, value_name = "FILE", requires = "key_encryption_key")]
+    pub(crate) seal_key: Option<PathBuf>,
+
+    /// Create local users on the fly the first time their actor is requested.
+    /// When off, users only exist once created through the admin endpoints
+    #[arg(long, env)]
+    pub(crate) auto_create_users: bool,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SuspendedActors {
+    /// Refuse them with a 403, so the sender knows
+    Reject,
+    /// Accept them and throw them away, so the sender can't tell
+    Drop,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorFormat {
+    /// The error message as `text/plain`
+    Plain,
+    /// An RFC 9457 problem details object, with the message as its `detail`
+    Problem,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MissingSignedHeaders {
+    /// Take the missing header's value to be empty, as some servers do
+    Lenient,
+    /// Refuse the request with a 401, as the spec says to
+    Strict,
+}
+
+impl Config {
+    pub fn base_url(&self) -> String {
+        format!("{}://{}", self.scheme, self.domain)
+    }
+
+    /// How much of a body to log, if bodies are logged at all.
+    pub fn body_log_limit(&self) -> Option<usize> {
+        self.debug_bodies.then_some(self.debug_body_limit)
+    }
+
+    pub fn actor_url(&self, id: &str) -> String {
+        format!("{}/users/{}", self.base_url(), id)
+    }
+
+    /// The inbox shared by everyone here, advertised as `sharedInbox`.
+    pub fn shared_inbox_url(&self) -> String {
+        format!("{}/inbox", self.base_url())
+    }
+
+    /// The server's own actor, see [`crate::instance::InstanceActor`].
+    pub fn instance_actor_url(&self) -> String {
+        format!("{}/actor", self.base_url())
+    }
+
+    pub fn user_agent(&self) -> String {
+        format!(
+            "{}/{} (+{}/)",
+            self.software_name,
+            self.software_version,
+            self.base_url()
+        )
+    }
+
+    pub fn listen_addrs(&self) -> Result<Vec<SocketAddr>, Box<dyn Error>> {
+        let port: u16 = self
+            .port
+            .parse()
+            .map_err(|e| format!("Invalid port {}: {}", self.port, e))?;
+        self.address
+            .iter()
+            .map(|address| {
+                let ip = address
+                    .strip_prefix('[')
+                    .and_then(|a| a.strip_suffix(']'))
+                    .unwrap_or(address);
+                let ip: IpAddr = ip
+                    .parse()
+                    .map_err(|e| format!("Invalid address {}: {}", address, e))?;
+                Ok(SocketAddr::new(ip, port))
+            })
+            .collect()
+    }
+}
+
+/// Parses a `--route-timeout`, a route path as it was declared (with
+/// `:param`s, not filled in) and its timeout in seconds.
+fn parse_route_timeout(value: &str) -> Result<(String, u64), String> {
+    let (path, seconds) = value
+        .rsplit_once('=')
+        .ok_or_else(|| format!("{} is not PATH=SECONDS", value))?;
+    if !path.starts_with('/') {
+        return Err(format!("{} is not a route path", path));
+    }
+    let seconds = seconds
+        .parse()
+        .map_err(|e| format!("Invalid seconds {}: {}", seconds, e))?;
+    Ok((path.to_string(), seconds))
+}
+
+/// A URL we can POST to, which takes http or https.
+fn parse_webhook_url(value: &str) -> Result<reqwest::Url, String> {
+    let url = reqwest::Url::parse(value).map_err(|e| format!("Invalid URL {}: {}", value, e))?;
+    match url.scheme() {
+        "http" | "https" => Ok(url),
+        _ => Err(format!("{} is not an http or https URL", value)),
+    }
+}
+
+/// Any secret but an empty one, which would sign with a key anyone has.
+fn parse_webhook_secret(value: &str) -> Result<String, String> {
+    if value.is_empty() {
+        return Err("The webhook secret is empty".to_string());
+    }
+    Ok(value.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn listen_addrs(args: &[&str]) -> Result<Vec<SocketAddr>, Box<dyn Error>> {
+        let mut argv = vec!["rap-server", "--domain", "example.com"];
+        argv.extend_from_slice(args);
+        Config::parse_from(argv).listen_addrs()
+    }
+
+    #[test]
+    fn test_webhook() {
+        let parse = |url: &str, secret: &str| {
+            Config::try_parse_from([
+                "rap-server",
+                "--domain",
+                "example.com",
+                "--webhook-url",
+                url,
+                "--webhook-secret",
+                secret,
+            ])
+        };
+        let cfg = parse("http://10.0.0.5/hook", "hush").unwrap();
+        assert_eq!(cfg.webhook_url.unwrap().as_str(), "http://10.0.0.5/hook");
+
+        for (url, secret, error) in [
+            ("not a url", "hush", "Invalid URL"),
+            ("ftp://10.0.0.5/hook", "hush", "not an http or https URL"),
+            ("https://10.0.0.5/hook", "", "secret is empty"),
+        ] {
+            let err = parse(url, secret).unwrap_err().to_string();
+            assert!(err.contains(error), "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_route_timeout() {
+        let cfg = Config::parse_from([
+            "rap-server",
+            "--domain",
+            "example.com",
+            "--route-timeout",
+            "/users/:id/inbox=60,/actor=5",
+            "--route-timeout",
+            "/inbox=45",
+        ]);
+        assert_eq!(
+            cfg.route_timeout,
+            [
+                ("/users/:id/inbox".to_string(), 60),
+                ("/actor".to_string(), 5),
+                ("/inbox".to_string(), 45),
+            ]
+        );
+
+        for invalid in ["/inbox", "inbox=60", "/inbox=soon", "/inbox=-1"] {
+            assert!(parse_route_timeout(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_listen_addrs_ipv4() {
+        let addrs = listen_addrs(&["--address", "127.0.0.1", "--port", "8080"]).unwrap();
+        assert_eq!(addrs, vec!["127.0.0.1:8080".parse().unwrap()]);
+    }
+
+    #[test]
+    fn test_listen_addrs_ipv6() {
+        let addrs = listen_addrs(&["--address", "[::1]"]).unwrap();
+        assert_eq!(addrs, vec!["[::1]:3000".parse().unwrap()]);
+
+        let addrs = listen_addrs(&["--address", "::"]).unwrap();
+        assert_eq!(addrs, vec!["[::]:3000".parse().unwrap()]);
+    }
+
+    #[test]
+    fn test_listen_addrs_dual_stack() {
+        let addrs = listen_addrs(&["--address", "0.0.0.0,[::]"]).unwrap();
+        assert_eq!(
+            addrs,
+            vec![
+                "0.0.0.0:3000".parse().unwrap(),
+                "[::]:3000".parse().unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_listen_addrs_invalid() {
+        let err = listen_addrs(&["--address", "not-an-ip"]).unwrap_err();
+        assert!(err.to_string().contains("Invalid address not-an-ip"));
+
+        let err = listen_addrs(&["--port", "http"]).unwrap_err();
+        assert!(err.to_string().contains("Invalid port http"));
+    }
+
+    #[test]
+    fn test_urls() {
+        let cfg = Config::parse_from(["rap-server", "--domain", "example.com"]);
+        assert_eq!(cfg.base_url(), "https://example.com");
+        assert_eq!(cfg.actor_url("alice"), "https://example.com/users/alice");
+    }
+
+    #[test]
+    fn test_urls_with_scheme() {
+        let cfg = Config::parse_from([
+            "rap-server",
+            "--domain",
+            "localhost:3000",
+            "--scheme",
+            "http",
+        ]);
+        assert_eq!(cfg.base_url(), "http://localhost:3000");
+        assert_eq!(cfg.actor_url("bob"), "http://localhost:3000/users/bob");
+    }
+
+    #[test]
+    fn test_user_agent() {
+        let cfg = Config::parse_from([
+            "rap-server",
+            "--domain",
+            "example.com",
+            "--software-name",
+            "my-fork",
+            "--software-version",
+            "1.2.3",
+        ]);
+        assert_eq!(cfg.user_agent(), "my-fork/1.2.3 (+https://example.com/)");
+    }
 }
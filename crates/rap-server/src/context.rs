@@ -0,0 +1,124 @@
+//! The JSON-LD `@context` of the ActivityPub documents we serve.
+
+use reqwest::Url;
+use serde_json::{json, Value};
+
+use crate::config::Config;
+
+pub const ACTIVITYSTREAMS: &str = "https://www.w3.org/ns/activitystreams";
+/// Defines `publicKey` and friends.
+pub const SECURITY: &str = "https://w3id.org/security/v1";
+
+/// Terms from extensions of ActivityStreams we use. `sensitive` marks content
+/// to hide behind its `summary`, a content warning, as Mastodon defines it.
+fn extensions() -> Value {
+    json!({ "sensitive": "as:sensitive" })
+}
+
+/// The `@context` of every document we emit. Extensions we start using go
+/// here, or in `--extra-context`, so all of our documents keep agreeing on
+/// what terms mean.
+pub fn context(cfg: &Config) -> Value {
+    let mut context = vec![json!(ACTIVITYSTREAMS), json!(SECURITY), extensions()];
+    context.extend(cfg.extra_context.iter().cloned());
+    Value::Array(context)
+}
+
+/// Parses an `--extra-context`: an inline context object, or the `http(s)`
+/// URL of a context document.
+pub fn parse_extra_context(s: &str) -> Result<Value, String> {
+    if s.trim_start().starts_with('{') {
+        return match serde_json::from_str(s) {
+            Ok(Value::Object(context)) => Ok(Value::Object(context)),
+            Ok(_) => Err("not a JSON object".to_string()),
+            Err(e) => Err(format!("not a JSON object: {}", e)),
+        };
+    }
+    let url = Url::parse(s).map_err(|e| format!("neither a JSON object nor a URL: {}", e))?;
+    match url.scheme() {
+        "http" | "https" => Ok(json!(s)),
+        scheme => Err(format!("{} URLs can't be fetched as a context", scheme)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{body_json, test_app};
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_documents_carry_context() {
+        let app = test_app(&["--auto-create-users"]);
+        for path in [
+            "/users/alice",
+            "/actor",
+            "/users/alice/followers",
+            "/users/alice/followers?page=1",
+            "/users/alice/liked",
+        ] {
+            let response = app
+                .clone()
+                .oneshot(Request::get(path).body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK, "{}", path);
+            assert_eq!(
+                body_json(response).await["@context"],
+                json!([ACTIVITYSTREAMS, SECURITY, {"sensitive": "as:sensitive"}]),
+                "{}",
+                path
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_extra_context() {
+        let toot = r#"{"toot": "http://joinmastodon.org/ns#", "Hashtag": "as:Hashtag"}"#;
+        let app = test_app(&[
+            "--auto-create-users",
+            "--extra-context",
+            "https://example.com/ns",
+            "--extra-context",
+            toot,
+        ]);
+        for path in ["/users/alice", "/users/alice/followers"] {
+            let response = app
+                .clone()
+                .oneshot(Request::get(path).body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(
+                body_json(response).await["@context"],
+                json!([
+                    ACTIVITYSTREAMS,
+                    SECURITY,
+                    {"sensitive": "as:sensitive"},
+                    "https://example.com/ns",
+                    {"toot": "http://joinmastodon.org/ns#", "Hashtag": "as:Hashtag"},
+                ]),
+                "{}",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_extra_context() {
+        for context in [
+            "{\"toot\": ",
+            "[\"https://example.com/ns\"]",
+            "\"https://example.com/ns\"",
+            "example.com/ns",
+            "ftp://example.com/ns",
+        ] {
+            parse_extra_context(context).unwrap_err();
+        }
+        assert_eq!(
+            parse_extra_context("http://joinmastodon.org/ns").unwrap(),
+            "http://joinmastodon.org/ns"
+        );
+    }
+}
@@ -1,11 +1,18 @@
+use rand::RngCore;
+use ring::{aead, pbkdf2};
 use rsa::pkcs1v15::{Signature, SigningKey, VerifyingKey};
 use rsa::pkcs8::{
     DecodePrivateKey, DecodePublicKey, EncodePrivateKey, EncodePublicKey, LineEnding,
 };
+use rsa::pss;
 use rsa::signature::{RandomizedSigner, SignatureEncoding, Verifier};
 use rsa::{RsaPrivateKey, RsaPublicKey};
-use sha2::Sha256;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256, Sha512};
 use std::error::Error;
+use std::num::NonZeroU32;
+
+use crate::utils::{base64_decode, base64_encode};
 
 const KEY_SIZE: usize = 2048;
 pub fn generate_keypair() -> Result<(String, String), Box<dyn Error>> {
@@ -19,6 +26,24 @@ pub fn generate_keypair() -> Result<(String, String), Box<dyn Error>> {
     Ok((private_key_pem.to_string(), public_key_pem))
 }
 
+/// The SHA-256 of a PEM encoded public key's DER encoding, in hex. It's what
+/// `openssl pkey -pubin -outform der | sha256sum` gives for the same key.
+pub fn fingerprint(key_pem: &str) -> Result<String, Box<dyn Error>> {
+    let der = RsaPublicKey::from_public_key_pem(key_pem)?.to_public_key_der()?;
+    Ok(Sha256::digest(der.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+/// The PEM encoded public half of a PKCS#8 PEM encoded private key.
+pub fn public_key_pem(private_key_pem: &str) -> Result<String, Box<dyn Error>> {
+    let private_key = RsaPrivateKey::from_pkcs8_pem(private_key_pem)?;
+    Ok(private_key
+        .to_public_key()
+        .to_public_key_pem(LineEnding::LF)?)
+}
+
 pub fn sign<S, T>(key_pem: S, data: T) -> Result<Vec<u8>, Box<dyn Error>>
 where
     S: AsRef<str>,
@@ -44,6 +69,94 @@ where
     Ok(())
 }
 
+/// Verifies an RSASSA-PSS signature over SHA-512, what `hs2019` means for RSA
+/// keys according to the HTTP signatures draft.
+pub fn verify_pss<S, T1, T2>(key_pem: S, msg: T1, sig: T2) -> Result<(), Box<dyn Error>>
+where
+    S: AsRef<str>,
+    T1: AsRef<[u8]>,
+    T2: AsRef<[u8]>,
+{
+    let key = RsaPublicKey::from_public_key_pem(key_pem.as_ref())?;
+    let sig = pss::Signature::try_from(sig.as_ref())?;
+    let verifier = pss::VerifyingKey::<Sha512>::new(key);
+    verifier.verify(msg.as_ref(), &sig)?;
+    Ok(())
+}
+
+/// Data encrypted with AES-256-GCM under a key derived from a passphrase, as
+/// made by [`seal`]. Every field is base64.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Sealed {
+    salt: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+const PBKDF2_ITERATIONS: u32 = 100_000;
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> Result<aead::LessSafeKey, Box<dyn Error>> {
+    let mut key = [0; 32];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        NonZeroU32::new(PBKDF2_ITERATIONS).unwrap(),
+        salt,
+        passphrase.as_bytes(),
+        &mut key,
+    );
+    let key = aead::UnboundKey::new(&aead::AES_256_GCM, &key)
+        .map_err(|_| "Could not make an encryption key")?;
+    Ok(aead::LessSafeKey::new(key))
+}
+
+/// Encrypts `plaintext` with a key derived from `passphrase`.
+pub fn seal(plaintext: &[u8], passphrase: &str) -> Result<Sealed, Box<dyn Error>> {
+    let mut rng = rand::thread_rng();
+    let mut salt = [0; 16];
+    rng.fill_bytes(&mut salt);
+    let mut nonce = [0; aead::NONCE_LEN];
+    rng.fill_bytes(&mut nonce);
+
+    let mut ciphertext = plaintext.to_vec();
+    derive_key(passphrase, &salt)?
+        .seal_in_place_append_tag(
+            aead::Nonce::assume_unique_for_key(nonce),
+            aead::Aad::empty(),
+            &mut ciphertext,
+        )
+        .map_err(|_| "Could not encrypt")?;
+    Ok(Sealed {
+        salt: base64_encode(salt),
+        nonce: base64_encode(nonce),
+        ciphertext: base64_encode(ciphertext),
+    })
+}
+
+/// Decrypts what [`seal`] made, failing if `passphrase` is wrong or the data
+/// was tampered with.
+pub fn open(sealed: &Sealed, passphrase: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    let salt = base64_decode(&sealed.salt)?;
+    let nonce = aead::Nonce::try_assume_unique_for_key(&base64_decode(&sealed.nonce)?)
+        .map_err(|_| "Invalid nonce")?;
+    let mut ciphertext = base64_decode(&sealed.ciphertext)?;
+    let plaintext = derive_key(passphrase, &salt)?
+        .open_in_place(nonce, aead::Aad::empty(), &mut ciphertext)
+        .map_err(|_| "Could not decrypt, wrong passphrase?")?;
+    Ok(plaintext.to_vec())
+}
+
+#[cfg(test)]
+pub fn sign_pss<S, T>(key_pem: S, data: T) -> Result<Vec<u8>, Box<dyn Error>>
+where
+    S: AsRef<str>,
+    T: AsRef<[u8]>,
+{
+    let key = RsaPrivateKey::from_pkcs8_pem(key_pem.as_ref())?;
+    let signer = pss::BlindedSigningKey::<Sha512>::new(key);
+    let sig = signer.try_sign_with_rng(&mut rand::thread_rng(), data.as_ref())?;
+    Ok(sig.to_vec())
+}
+
 #[cfg(test)]
 mod tests {
     use crate::utils::base64_decode;
@@ -58,6 +171,16 @@ mod tests {
         super::verify(pubkey, comparison.as_bytes(), &sig).unwrap();
     }
 
+    #[test]
+    fn test_fingerprint() {
+        let pubkey = "-----BEGIN PUBLIC KEY-----\nMIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEAsySwvshQIlMYLP4O/a/i\ncm25Jc7lOCx40WUYOVzIS/8YUeZw3mN5IfZURRWybB5ESwZCTKlqqgQs3s/WCCqD\ndER9BGLjph14ywCsSij4yFToHg4rAkzwnuiEBpjwb9TZxoWclQ6w7/L90zuphidA\nnCgSoxqNJ+L0xtJ92wf4vHQeuimKgna76I2VHFmgD9JOaD2ISL6+9D4v2lj6biNM\n/bXffipv6LxuM6p582BI2PH7OjBj617kd8DetYn71MpAMj3Kq8zhFQcbQwIpXIXe\nRYcQ8pCEQMHNYSLNhYfaFdgQJqy/OkUlIOrGIVA/XdVcznwHsmfzVgpZLQcG4gH6\nAwIDAQAB\n-----END PUBLIC KEY-----\n";
+        assert_eq!(
+            super::fingerprint(pubkey).unwrap(),
+            "67be1219083a1c9d9d7fe4d079118db6548b6e60bc40c4488d033c0b2b84b309"
+        );
+        super::fingerprint("not a key").unwrap_err();
+    }
+
     #[test]
     fn test_sign_and_verify() {
         let (private_key_pem, public_key_pem) = super::generate_keypair().unwrap();
@@ -73,4 +196,24 @@ mod tests {
         // Verify with correct data
         super::verify(&public_key_pem, data, &signature).unwrap();
     }
+
+    #[test]
+    fn test_sign_and_verify_pss() {
+        let (private_key_pem, public_key_pem) = super::generate_keypair().unwrap();
+        let data = b"some data to sign";
+
+        let signature = super::sign_pss(&private_key_pem, data).unwrap();
+        super::verify_pss(&public_key_pem, data, &signature).unwrap();
+        // the two paddings aren't interchangeable
+        super::verify(&public_key_pem, data, &signature).unwrap_err();
+        let signature = super::sign(&private_key_pem, data).unwrap();
+        super::verify_pss(&public_key_pem, data, &signature).unwrap_err();
+    }
+
+    #[test]
+    fn test_seal_and_open() {
+        let sealed = super::seal(b"secret", "correct horse").unwrap();
+        assert_eq!(super::open(&sealed, "correct horse").unwrap(), b"secret");
+        super::open(&sealed, "battery staple").unwrap_err();
+    }
 }
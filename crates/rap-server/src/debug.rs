@@ -0,0 +1,109 @@
+use axum::extract::{ConnectInfo, Path};
+use axum::http::{HeaderMap, Method, Uri};
+use axum::{Extension, Json};
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use crate::admin::Admin;
+use crate::config::Config;
+use crate::delivery::{self, Delivery, DeliveryQueue};
+use crate::remote;
+use crate::signature::Signature;
+use crate::signed::{header_str, rebuild_sig_str, request_target, with_public_host};
+use crate::users::{PeopleStore, PersonId};
+use crate::utils::{web_err_400, WebError};
+
+/// How many queued deliveries `/debug/deliveries` lists.
+pub const RECENT_DELIVERIES: usize = 50;
+
+/// Reconstructs the signing string for a request as if it had been posted to
+/// the inbox of `id`, without fetching keys or verifying anything. `host` is
+/// taken the way the inbox takes it, see [`with_public_host`].
+pub async fn signature(
+    Path(id): Path<PersonId>,
+    Extension(cfg): Extension<Config>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    uri: Uri,
+    headers: HeaderMap,
+) -> Result<Json<Value>, WebError> {
+    let headers = with_public_host(&cfg, &headers, peer.map(|ConnectInfo(addr)| addr.ip()));
+    let signature = header_str(&headers, "signature")?;
+    let signature = Signature::from_headers(signature)
+        .map_err(|e| web_err_400(format!("Error parsing signature: {}", e)))?;
+
+    let target = request_target(&Method::POST, &format!("/users/{}/inbox", id), uri.query());
+
+    Ok(Json(json!({
+        "keyId": signature.key_id,
+        "headers": signature.headers,
+        "signingString": rebuild_sig_str(&target, &headers, &signature),
+    })))
+}
+
+/// Lists the activities we queued for delivery lately, newest first, each
+/// with the signed request that would carry it. The request is signed anew
+/// for the listing, so its `date` and signature differ from what was sent.
+/// Those are requests anyone could replay, so this takes the admin token.
+pub async fn deliveries(
+    _admin: Admin,
+    Extension(queue): Extension<DeliveryQueue>,
+    Extension(people): Extension<Arc<dyn PeopleStore>>,
+    Extension(actors): Extension<remote::ActorCache>,
+) -> Json<Value> {
+    let recent = queue.recent();
+    // the worker only knows which deliveries went out together, so go by
+    // the ones listed
+    let shared = delivery::shared_hosts(&recent);
+    let mut listed = Vec::new();
+    for delivery in &recent {
+        let use_shared = delivery::same_host_key(delivery).is_some_and(|key| shared.contains(&key));
+        let request = match preview(people.as_ref(), &actors, delivery, use_shared).await {
+            Ok(request) => request,
+            Err(e) => json!({ "error": e }),
+        };
+        listed.push(json!({
+            "from": delivery.from,
+            "to": delivery.to,
+            "activity": delivery.activity,
+            "request": request,
+        }));
+    }
+    Json(Value::Array(listed))
+}
+
+/// The POST delivering `delivery`, signed the way the delivery worker signs
+/// it, to the shared inbox if `shared` and the recipient has one.
+async fn preview(
+    people: &dyn PeopleStore,
+    actors: &remote::ActorCache,
+    delivery: &Delivery,
+    shared: bool,
+) -> Result<Value, String> {
+    let person = people
+        .get_by_username(&delivery.from)
+        .await
+        .map_err(|e| e.to_string())?
+        .ok_or_else(|| format!("No person {}", delivery.from))?;
+    let inbox = actors
+        .inbox_url(&delivery.to, shared)
+        .await
+        .map_err(|e| format!("Error fetching {}: {}", delivery.to, e))?;
+
+    let body = delivery.body().map_err(|e| e.to_string())?;
+    let headers = delivery::sign(&person.key, &inbox, &body).map_err(|e| e.to_string())?;
+    let headers: serde_json::Map<String, Value> = headers
+        .iter()
+        .map(|(name, value)| {
+            (
+                name.to_string(),
+                Value::String(String::from_utf8_lossy(value.as_bytes()).into_owned()),
+            )
+        })
+        .collect();
+    Ok(json!({
+        "method": "POST",
+        "url": inbox,
+        "headers": headers,
+    }))
+}
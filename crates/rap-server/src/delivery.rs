@@ -0,0 +1,655 @@
+use axum::body::Bytes;
+use axum::http::header::HeaderName;
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use chrono::{DateTime, Utc};
+use rap_core::signing::sign_request;
+use reqwest::Url;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::{debug, instrument, warn};
+
+use crate::addressing;
+use crate::key::Key;
+use crate::remote;
+use crate::users::{PeopleStore, PersonId};
+
+/// An activity to be POSTed to the inbox of `to`, signed by the local person
+/// `from`.
+#[derive(Debug, Clone)]
+pub struct Delivery {
+    pub from: PersonId,
+    pub to: String,
+    pub activity: Value,
+    /// `activity` exactly as another server sent it to us, which is what gets
+    /// delivered when we forward it, rather than our re-serialization.
+    pub body: Option<Bytes>,
+}
+
+impl Delivery {
+    /// What gets POSTed: the body as it was sent to us, if we are forwarding,
+    /// or else the activity serialized.
+    pub(crate) fn body(&self) -> Result<Vec<u8>, serde_json::Error> {
+        match &self.body {
+            Some(body) => Ok(body.to_vec()),
+            None => serde_json::to_vec(&self.activity),
+        }
+    }
+}
+
+/// Handle for queueing deliveries onto the background worker started with
+/// [`run`].
+#[derive(Clone)]
+pub struct DeliveryQueue {
+    sender: mpsc::UnboundedSender<Delivery>,
+    recent: Option<Arc<Recent>>,
+}
+
+/// The last `limit` deliveries queued, newest at the back.
+struct Recent {
+    limit: usize,
+    deliveries: Mutex<VecDeque<Delivery>>,
+}
+
+impl DeliveryQueue {
+    pub fn new() -> (Self, mpsc::UnboundedReceiver<Delivery>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        (
+            Self {
+                sender,
+                recent: None,
+            },
+            receiver,
+        )
+    }
+
+    /// Remembers the last `limit` deliveries queued, for [`Self::recent`].
+    pub fn keep_recent(mut self, limit: usize) -> Self {
+        self.recent = Some(Arc::new(Recent {
+            limit,
+            deliveries: Mutex::new(VecDeque::with_capacity(limit)),
+        }));
+        self
+    }
+
+    /// The deliveries remembered since [`Self::keep_recent`], newest first.
+    pub fn recent(&self) -> Vec<Delivery> {
+        match &self.recent {
+            Some(recent) => recent
+                .deliveries
+                .lock()
+                .unwrap()
+                .iter()
+                .rev()
+                .cloned()
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Queues `delivery`, unless it is addressed to the public collection,
+    /// which is only ever a marker and has no inbox.
+    pub fn enqueue(&self, delivery: Delivery) {
+        if addressing::is_public(&delivery.to) {
+            debug!("Not delivering to the public collection");
+            return;
+        }
+        if let Some(recent) = &self.recent {
+            let mut deliveries = recent.deliveries.lock().unwrap();
+            if deliveries.len() == recent.limit {
+                deliveries.pop_front();
+            }
+            deliveries.push_back(delivery.clone());
+        }
+        match self.sender.send(delivery) {
+            Ok(()) => metrics::increment_gauge!(QUEUE_DEPTH, 1.0),
+            Err(e) => warn!("Delivery worker is gone, dropping delivery to {}", e.0.to),
+        }
+    }
+}
+
+/// Gauge of deliveries queued but not yet picked up by the worker. If it
+/// keeps growing, the worker can't keep up.
+const QUEUE_DEPTH: &str = "delivery_queue_depth";
+
+pub async fn run(
+    mut receiver: mpsc::UnboundedReceiver<Delivery>,
+    people: Arc<dyn PeopleStore>,
+    client: remote::Client,
+    actors: remote::ActorCache,
+) {
+    while let Some(delivery) = receiver.recv().await {
+        // take whatever else is waiting too, so deliveries of the same
+        // activity to one server can share its inbox
+        let mut batch = vec![delivery];
+        while let Ok(delivery) = receiver.try_recv() {
+            batch.push(delivery);
+        }
+        metrics::decrement_gauge!(QUEUE_DEPTH, batch.len() as f64);
+
+        let shared = shared_hosts(&batch);
+        let mut delivered = HashSet::new();
+        for delivery in &batch {
+            if let Err(e) = client.actor_id(&delivery.to) {
+                warn!("Not delivering to {}: {}", delivery.to, e);
+                continue;
+            }
+            let use_shared = same_host_key(delivery).is_some_and(|key| shared.contains(&key));
+            // used as the actor gives it, even if it's on another host
+            let inbox = match actors.inbox_url(&delivery.to, use_shared).await {
+                Ok(inbox) => inbox,
+                Err(e) => {
+                    warn!("Error finding the inbox of {}: {}", delivery.to, e);
+                    continue;
+                }
+            };
+            if !delivered.insert((
+                delivery.from.as_str(),
+                delivery.activity.to_string(),
+                inbox.clone(),
+            )) {
+                debug!("Already delivered to {}", inbox);
+                continue;
+            }
+            match deliver(people.as_ref(), &client, delivery, &inbox).await {
+                Ok(()) => {}
+                Err(failed) if failed.retry => {
+                    debug!(
+                        "Error delivering to {}, will retry: {}",
+                        delivery.to, failed
+                    );
+                    tokio::spawn(retry(
+                        people.clone(),
+                        client.clone(),
+                        delivery.clone(),
+                        inbox,
+                        failed,
+                    ));
+                }
+                Err(failed) => warn!("Error delivering to {}: {}", delivery.to, failed),
+            }
+        }
+    }
+}
+
+/// How many times we try a delivery before giving up on it.
+const MAX_ATTEMPTS: u32 = 5;
+/// Wait before the first retry, doubling after each one.
+const BACKOFF: Duration = Duration::from_secs(30);
+/// Longest we will wait between attempts, even if the peer asks for longer.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(6 * 60 * 60);
+
+/// Keeps trying a delivery that failed in a way worth retrying, waiting as
+/// long as the peer asked us to or backing off otherwise.
+async fn retry(
+    people: Arc<dyn PeopleStore>,
+    client: remote::Client,
+    delivery: Delivery,
+    inbox: String,
+    mut failed: Failed,
+) {
+    for attempt in 1..MAX_ATTEMPTS {
+        tokio::time::sleep(retry_delay(attempt, failed.retry_after)).await;
+        match deliver(people.as_ref(), &client, &delivery, &inbox).await {
+            Ok(()) => return,
+            Err(f) if f.retry => failed = f,
+            Err(f) => {
+                warn!("Error delivering to {}: {}", delivery.to, f);
+                return;
+            }
+        }
+    }
+    warn!(
+        "Giving up delivering to {} after {} attempts: {}",
+        delivery.to, MAX_ATTEMPTS, failed
+    );
+}
+
+/// How long to wait before retry number `attempt` (starting at 1).
+fn retry_delay(attempt: u32, retry_after: Option<Duration>) -> Duration {
+    retry_after
+        .unwrap_or_else(|| BACKOFF.saturating_mul(2u32.saturating_pow(attempt - 1)))
+        .min(MAX_RETRY_DELAY)
+}
+
+/// A failed delivery, and whether trying again later might work.
+#[derive(Debug)]
+struct Failed {
+    error: String,
+    retry: bool,
+    /// What the peer asked for with `Retry-After`.
+    retry_after: Option<Duration>,
+}
+
+impl Failed {
+    fn permanent<S: Into<String>>(error: S) -> Self {
+        Self {
+            error: error.into(),
+            retry: false,
+            retry_after: None,
+        }
+    }
+
+    fn transient<S: Into<String>>(error: S) -> Self {
+        Self {
+            error: error.into(),
+            retry: true,
+            retry_after: None,
+        }
+    }
+
+    /// Classifies an unsuccessful response. Rate limits and server errors are
+    /// worth retrying, anything else the peer won't accept however often we
+    /// send it.
+    fn from_response(inbox: &str, status: StatusCode, headers: &HeaderMap) -> Self {
+        let error = format!("{} answered {}", inbox, status);
+        if status != StatusCode::TOO_MANY_REQUESTS && !status.is_server_error() {
+            return Self::permanent(error);
+        }
+        let retry_after = headers
+            .get("retry-after")
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| parse_retry_after(h, Utc::now()));
+        Self {
+            retry_after,
+            ..Self::transient(error)
+        }
+    }
+}
+
+impl fmt::Display for Failed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.error)
+    }
+}
+
+/// Parses a `Retry-After` header, either a number of seconds or an HTTP date,
+/// into how long to wait from `now`.
+fn parse_retry_after(value: &str, now: DateTime<Utc>) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let at = DateTime::parse_from_rfc2822(value).ok()?;
+    // a date in the past means we can go right away
+    Some(
+        (at.with_timezone(&Utc) - now)
+            .to_std()
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+/// Who is sending what to which host. `Value` can't be hashed, so the
+/// activity is compared serialized.
+pub(crate) type SameHostKey<'a> = (&'a str, String, String);
+
+pub(crate) fn same_host_key(delivery: &Delivery) -> Option<SameHostKey<'_>> {
+    let host = Url::parse(&delivery.to).ok()?.host_str()?.to_string();
+    Some((delivery.from.as_str(), delivery.activity.to_string(), host))
+}
+
+/// Hosts with more than one recipient of the same activity from the same
+/// person. Those get it through their shared inbox, when they have one.
+pub(crate) fn shared_hosts(batch: &[Delivery]) -> HashSet<SameHostKey<'_>> {
+    let mut recipients: HashMap<SameHostKey, usize> = HashMap::new();
+    for key in batch.iter().filter_map(same_host_key) {
+        *recipients.entry(key).or_default() += 1;
+    }
+    recipients
+        .into_iter()
+        .filter(|(_, count)| *count > 1)
+        .map(|(key, _)| key)
+        .collect()
+}
+
+#[instrument(skip_all, fields(from = %delivery.from, to = %delivery.to))]
+async fn deliver(
+    people: &dyn PeopleStore,
+    client: &remote::Client,
+    delivery: &Delivery,
+    inbox: &str,
+) -> Result<(), Failed> {
+    let person = people
+        .get_by_username(&delivery.from)
+        .await
+        .map_err(|e| Failed::transient(e.to_string()))?
+        .ok_or_else(|| Failed::permanent(format!("No person {}", delivery.from)))?;
+
+    let body = delivery
+        .body()
+        .map_err(|e| Failed::permanent(e.to_string()))?;
+    let headers = sign(&person.key, inbox, &body).map_err(|e| Failed::permanent(e.to_string()))?;
+    let response = client
+        .post(inbox, headers, body)
+        .await
+        .map_err(|e| Failed::transient(e.to_string()))?;
+    if !response.status().is_success() {
+        return Err(Failed::from_response(
+            inbox,
+            response.status(),
+            response.headers(),
+        ));
+    }
+    Ok(())
+}
+
+/// Signs a POST of `body` to `url`, adding the `content-type` we deliver
+/// activities with. The signed `host` is that of `url`, the inbox, which
+/// needn't be on the same host as the actor it belongs to.
+pub(crate) fn sign(key: &Key, url: &str, body: &[u8]) -> Result<HeaderMap, Box<dyn Error>> {
+    let mut headers = HeaderMap::new();
+    for (name, value) in sign_request("POST", url, body, key)? {
+        headers.insert(
+            HeaderName::from_bytes(name.as_bytes())?,
+            HeaderValue::from_str(&value)?,
+        );
+    }
+    headers.insert(
+        "content-type",
+        HeaderValue::from_static("application/activity+json"),
+    );
+    Ok(headers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::digest;
+    use crate::signature::Signature;
+    use crate::signed::header_str;
+    use crate::signed::rebuild_sig_str;
+    use crate::utils::base64_decode;
+
+    #[test]
+    fn test_sign() {
+        let key = Key::new("https://example.com/users/alice".to_string()).unwrap();
+        let body = br#"{"type":"Create"}"#;
+        let headers = sign(&key, "https://remote.example/users/bob/inbox", body).unwrap();
+
+        assert_eq!(header_str(&headers, "host").unwrap(), "remote.example");
+        digest::verify(header_str(&headers, "digest").unwrap(), body).unwrap();
+
+        let signature =
+            Signature::from_headers(header_str(&headers, "signature").unwrap()).unwrap();
+        assert_eq!(signature.key_id, key.key_id());
+        let signing_string = rebuild_sig_str("post /users/bob/inbox", &headers, &signature);
+        key.public_key()
+            .unwrap()
+            .verify(
+                signing_string.as_bytes(),
+                &base64_decode(&signature.signature).unwrap(),
+            )
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_outbound_header_casing() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let inbox = format!("http://{}/users/bob/inbox", listener.local_addr().unwrap());
+        let received = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 8192];
+            let n = socket.read(&mut buf).await.unwrap();
+            socket
+                .write_all(
+                    b"HTTP/1.1 202 Accepted\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                )
+                .await
+                .unwrap();
+            String::from_utf8_lossy(&buf[..n]).to_string()
+        });
+
+        let key = Key::new("https://example.com/users/alice".to_string()).unwrap();
+        let body = br#"{"type":"Create"}"#.to_vec();
+        let headers = sign(&key, &inbox, &body).unwrap();
+        let response = remote::dev_client()
+            .post(&inbox, headers, body)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+
+        let request = received.await.unwrap();
+        let names: Vec<&str> = request
+            .lines()
+            .skip(1)
+            .take_while(|line| !line.is_empty())
+            .filter_map(|line| line.split_once(':').map(|(name, _)| name))
+            .collect();
+        for name in ["Signature", "Digest", "Date", "Host", "Content-Type"] {
+            assert!(names.contains(&name), "{} not in {:?}", name, names);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_inbox_on_another_host() {
+        use crate::users::InMemoryPeopleStore;
+        use clap::Parser;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let inbox_host = listener.local_addr().unwrap().to_string();
+        let received = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 8192];
+            let n = socket.read(&mut buf).await.unwrap();
+            socket
+                .write_all(
+                    b"HTTP/1.1 202 Accepted\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                )
+                .await
+                .unwrap();
+            String::from_utf8_lossy(&buf[..n]).to_string()
+        });
+
+        let cfg = crate::config::Config::parse_from(["rap-server", "--domain", "example.com"]);
+        let people = Arc::new(InMemoryPeopleStore::new(cfg));
+        let alice = people.create(&"alice".to_string()).await.unwrap().unwrap();
+        let bob = format!(
+            r#"{{"id":"https://remote.example/users/bob","type":"Person","inbox":"http://{}/inboxes/bob","outbox":"https://remote.example/users/bob/outbox","following":"https://remote.example/users/bob/following","followers":"https://remote.example/users/bob/followers"}}"#,
+            inbox_host
+        );
+        let actors = remote::ActorCache::new(
+            remote::dev_client().with_fixtures(&[("https://remote.example/users/bob", &bob)]),
+            Duration::from_secs(60),
+        );
+
+        let (queue, receiver) = DeliveryQueue::new();
+        let worker = tokio::spawn(run(receiver, people, remote::dev_client(), actors));
+        queue.enqueue(Delivery {
+            from: "alice".to_string(),
+            to: "https://remote.example/users/bob".to_string(),
+            activity: serde_json::json!({"type": "Create"}),
+            body: None,
+        });
+        let request = tokio::time::timeout(Duration::from_secs(10), received)
+            .await
+            .expect("nothing was delivered")
+            .unwrap();
+        worker.abort();
+
+        let mut lines = request.lines();
+        assert_eq!(lines.next().unwrap(), "POST /inboxes/bob HTTP/1.1");
+        let mut headers = HeaderMap::new();
+        for line in lines.take_while(|line| !line.is_empty()) {
+            let (name, value) = line.split_once(": ").unwrap();
+            headers.append(
+                HeaderName::from_bytes(name.to_lowercase().as_bytes()).unwrap(),
+                HeaderValue::from_str(value).unwrap(),
+            );
+        }
+        assert_eq!(header_str(&headers, "host").unwrap(), inbox_host);
+
+        let signature =
+            Signature::from_headers(header_str(&headers, "signature").unwrap()).unwrap();
+        assert!(signature.headers.iter().any(|h| h == "host"));
+        let signing_string = rebuild_sig_str("post /inboxes/bob", &headers, &signature);
+        alice
+            .key
+            .public_key()
+            .unwrap()
+            .verify(
+                signing_string.as_bytes(),
+                &base64_decode(&signature.signature).unwrap(),
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn test_enqueue_skips_public() {
+        let (queue, mut rx) = DeliveryQueue::new();
+        for to in [addressing::PUBLIC, "https://remote.example/users/bob"] {
+            queue.enqueue(Delivery {
+                from: "alice".to_string(),
+                to: to.to_string(),
+                activity: serde_json::json!({"type": "Create"}),
+                body: None,
+            });
+        }
+        assert_eq!(
+            rx.try_recv().unwrap().to,
+            "https://remote.example/users/bob"
+        );
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_keep_recent() {
+        let delivery = |to: &str| Delivery {
+            from: "alice".to_string(),
+            to: to.to_string(),
+            activity: serde_json::json!({"type": "Accept"}),
+            body: None,
+        };
+        let (queue, _rx) = DeliveryQueue::new();
+        queue.enqueue(delivery("https://remote.example/users/bob"));
+        assert!(queue.recent().is_empty());
+
+        let queue = queue.keep_recent(2);
+        for to in ["bob", "carol", "dave"] {
+            queue.enqueue(delivery(&format!("https://remote.example/users/{}", to)));
+        }
+        queue.enqueue(delivery(addressing::PUBLIC));
+        let recent: Vec<String> = queue.recent().into_iter().map(|d| d.to).collect();
+        assert_eq!(
+            recent,
+            [
+                "https://remote.example/users/dave",
+                "https://remote.example/users/carol"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_shared_hosts() {
+        let delivery = |from: &str, to: &str, activity: Value| Delivery {
+            from: from.to_string(),
+            to: to.to_string(),
+            activity,
+            body: None,
+        };
+        let create = serde_json::json!({"type": "Create"});
+        let like = serde_json::json!({"type": "Like"});
+        let batch = vec![
+            delivery("alice", "https://remote.example/users/bob", create.clone()),
+            delivery(
+                "alice",
+                "https://remote.example/users/carol",
+                create.clone(),
+            ),
+            delivery("alice", "https://other.example/users/dave", create.clone()),
+            // same host, but something else or from someone else
+            delivery("alice", "https://other.example/users/erin", like.clone()),
+            delivery("frank", "https://other.example/users/dave", create.clone()),
+        ];
+
+        let shared = shared_hosts(&batch);
+        assert_eq!(shared.len(), 1);
+        assert!(shared.contains(&("alice", create.to_string(), "remote.example".to_string())));
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let now = DateTime::parse_from_rfc2822("Sun, 06 Nov 1994 08:49:37 GMT")
+            .unwrap()
+            .with_timezone(&Utc);
+        assert_eq!(
+            parse_retry_after("120", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Sun, 06 Nov 1994 08:50:07 GMT", now),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            parse_retry_after("Sun, 06 Nov 1994 08:00:00 GMT", now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        let inbox = "https://remote.example/users/bob/inbox";
+        let mut headers = HeaderMap::new();
+        headers.insert("retry-after", HeaderValue::from_static("600"));
+
+        let failed = Failed::from_response(inbox, StatusCode::TOO_MANY_REQUESTS, &headers);
+        assert!(failed.retry);
+        assert_eq!(retry_delay(1, failed.retry_after), Duration::from_secs(600));
+
+        // without one we back off on our own
+        let failed =
+            Failed::from_response(inbox, StatusCode::SERVICE_UNAVAILABLE, &HeaderMap::new());
+        assert!(failed.retry);
+        assert_eq!(retry_delay(1, failed.retry_after), BACKOFF);
+        assert_eq!(retry_delay(3, failed.retry_after), BACKOFF * 4);
+        assert_eq!(retry_delay(30, failed.retry_after), MAX_RETRY_DELAY);
+
+        // they won't take it however often we ask
+        let failed = Failed::from_response(inbox, StatusCode::FORBIDDEN, &headers);
+        assert!(!failed.retry);
+        assert_eq!(
+            failed.to_string(),
+            format!("{} answered 403 Forbidden", inbox)
+        );
+    }
+
+    #[test]
+    fn test_queue_depth() {
+        use metrics_util::debugging::{DebugValue, DebuggingRecorder, Snapshotter};
+
+        // see inbox::tests::test_digest_mismatch
+        let _ = DebuggingRecorder::per_thread().install();
+        let depth = || {
+            Snapshotter::current_thread_snapshot()?
+                .into_vec()
+                .into_iter()
+                .find(|(key, ..)| key.key().name() == QUEUE_DEPTH)
+                .map(|(.., value)| match value {
+                    DebugValue::Gauge(depth) => depth.0,
+                    other => panic!("{} is not a gauge: {:?}", QUEUE_DEPTH, other),
+                })
+        };
+
+        let (queue, _receiver) = DeliveryQueue::new();
+        let delivery = |to: &str| Delivery {
+            from: "alice".to_string(),
+            to: to.to_string(),
+            activity: serde_json::json!({"type": "Create"}),
+            body: None,
+        };
+        queue.enqueue(delivery("https://remote.example/users/bob"));
+        queue.enqueue(delivery("https://remote.example/users/carol"));
+        assert_eq!(depth(), Some(2.0));
+
+        // nothing is queued for the public collection
+        queue.enqueue(delivery(crate::addressing::PUBLIC));
+        assert_eq!(depth(), Some(2.0));
+    }
+}
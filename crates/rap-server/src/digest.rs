@@ -0,0 +1,113 @@
+use crate::utils::{base64_decode, base64_encode};
+use sha2::{Digest, Sha256, Sha512};
+use std::error::Error;
+use std::fmt;
+
+/// A digest that doesn't match the body it came with. Usually the body was
+/// changed on the way, e.g. by a proxy re-encoding it.
+#[derive(Debug)]
+pub struct Mismatch {
+    algorithm: String,
+    expected: String,
+    actual: String,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // the start of each is plenty to tell them apart in a log
+        write!(
+            f,
+            "{} digest does not match body: header has {}..., body hashes to {}...",
+            self.algorithm,
+            prefix(&self.expected),
+            prefix(&self.actual)
+        )
+    }
+}
+
+impl Error for Mismatch {}
+
+fn prefix(digest: &str) -> &str {
+    digest.get(..8).unwrap_or(digest)
+}
+
+/// Checks a `Digest` header (RFC 3230) against the request body.
+///
+/// The header may carry several comma separated `algorithm=base64` digests.
+/// Every one of them has to use an algorithm we support and match the body.
+pub fn verify(header: &str, body: &[u8]) -> Result<(), Box<dyn Error>> {
+    for digest in header.split(',') {
+        let (algorithm, expected) = digest
+            .trim()
+            .split_once('=')
+            .ok_or_else(|| format!("Invalid digest {}", digest))?;
+        let expected_bytes = base64_decode(expected)?;
+
+        let actual = match algorithm.to_ascii_uppercase().as_str() {
+            "SHA-256" => Sha256::digest(body).to_vec(),
+            "SHA-512" => Sha512::digest(body).to_vec(),
+            _ => return Err(format!("Unsupported digest algorithm {}", algorithm).into()),
+        };
+
+        if actual != expected_bytes {
+            return Err(Box::new(Mismatch {
+                algorithm: algorithm.to_string(),
+                expected: expected.to_string(),
+                actual: base64_encode(actual),
+            }));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BODY: &[u8] = br#"{"type":"Follow"}"#;
+
+    #[test]
+    fn test_sha256() {
+        let header = format!("SHA-256={}", base64_encode(Sha256::digest(BODY)));
+        verify(&header, BODY).unwrap();
+        verify(&header, b"tampered").unwrap_err();
+    }
+
+    #[test]
+    fn test_sha512() {
+        let header = format!("sha-512={}", base64_encode(Sha512::digest(BODY)));
+        verify(&header, BODY).unwrap();
+        verify(&header, b"tampered").unwrap_err();
+    }
+
+    #[test]
+    fn test_multiple() {
+        let header = format!(
+            "SHA-256={}, SHA-512={}",
+            base64_encode(Sha256::digest(BODY)),
+            base64_encode(Sha512::digest(BODY))
+        );
+        verify(&header, BODY).unwrap();
+    }
+
+    #[test]
+    fn test_unknown_algorithm() {
+        let err = verify("MD5=HUXZLQLMuI/KZ5KDcJPcOA==", BODY).unwrap_err();
+        assert!(err.to_string().contains("Unsupported digest algorithm MD5"));
+    }
+
+    #[test]
+    fn test_mismatch_message() {
+        let digest = base64_encode(Sha256::digest(BODY));
+        let err = verify(&format!("SHA-256={}", digest), b"tampered").unwrap_err();
+        assert!(err.is::<Mismatch>());
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "SHA-256 digest does not match body: header has {}..., body hashes to {}...",
+                &digest[..8],
+                &base64_encode(Sha256::digest(b"tampered"))[..8]
+            )
+        );
+    }
+}
@@ -1,22 +1,2149 @@
+use crate::actor_id::ActorId;
+use crate::addressing;
+use crate::config::{Config, SuspendedActors};
+use crate::context::context;
+use crate::delivery::{Delivery, DeliveryQueue};
+use crate::digest;
+use crate::key::KeyCache;
+use crate::objects::{Lookup, ObjectStore, Reaction};
+use crate::remote;
+use crate::scheme;
 use crate::signed::Signed;
-use crate::utils::{web_err, WebError};
-use axum::http::StatusCode;
-use axum::Json;
-use log::debug;
-use serde_json::Value;
-
-pub async fn json(_signed: Signed, Json(body): Json<Value>) -> Result<Json<Value>, WebError> {
-    debug!(
-        "Received activity: {}",
-        serde_json::to_string(&body).unwrap()
-    );
+use crate::users::{PeopleStore, Person};
+use crate::utils::{log_body, web_err, web_err_400, web_err_401, web_err_500, WebError};
+use crate::webhook::Webhook;
+use axum::body::Bytes;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::IntoResponse;
+use axum::Extension;
+use base64::engine::general_purpose;
+use base64::Engine;
+use rap_core::types::Object;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use tracing::{debug, info, instrument, warn, Span};
 
+/// Accepted activities get an empty `202 Accepted`, as we may act on them
+/// later rather than right away.
+#[allow(clippy::too_many_arguments)]
+#[instrument(skip_all, fields(activity, actor))]
+pub async fn json(
+    signed: Signed,
+    Extension(cfg): Extension<Config>,
+    Extension(objects): Extension<Arc<dyn ObjectStore>>,
+    Extension(people): Extension<Arc<dyn PeopleStore>>,
+    Extension(queue): Extension<DeliveryQueue>,
+    Extension(client): Extension<remote::Client>,
+    Extension(keys): Extension<KeyCache>,
+    Extension(webhook): Extension<Option<Webhook>>,
+    headers: HeaderMap,
+    raw_body: Bytes,
+) -> Result<impl IntoResponse, WebError> {
+    log_body(cfg.body_log_limit(), "Inbox request body", &raw_body);
+    if cfg.require_digest {
+        verify_digest(&headers, &raw_body)?;
+    }
+
+    let body = parse_activity(&raw_body)
+        .map_err(|e| web_err_400(format!("Error parsing activity: {}", e)))?;
+    let span = Span::current();
+    if let Some(id) = id_of(&body) {
+        span.record("activity", id);
+    }
+    if let Some(actor) = id_of(&body["actor"]) {
+        span.record("actor", actor);
+    }
+    let actor = sender(&signed, &body)?;
     // TODO: json-ld flatten
 
-    // TODO: store data
+    if cfg.validate_activities {
+        validate(&body).map_err(|e| web_err_400(format!("Invalid activity: {}", e)))?;
+    }
+
+    if let Some(kind) = ignored_type(&cfg, &body) {
+        debug!("Ignoring {} activity", kind);
+        return Ok(StatusCode::ACCEPTED);
+    }
+
+    if from_suspended_actor(&cfg, objects.as_ref(), &body).await? {
+        return Ok(StatusCode::ACCEPTED);
+    }
 
     // TODO: queue up any actions
 
-    // let date = chrono::Utc::now().to_rfc2822();
-    Err(web_err(StatusCode::NOT_IMPLEMENTED, "Not implemented"))
+    let addressed = addressing::recipients(&body).len();
+    if addressed as u64 > cfg.max_recipients {
+        warn!(
+            "{} is addressed to {} recipients, only acting on the first {}",
+            id_of(&body).unwrap_or("Activity"),
+            addressed,
+            cfg.max_recipients
+        );
+    }
+    let forward_for = forwarded_for(&cfg, people.as_ref(), objects.as_ref(), &body).await?;
+    let filed = file_in_inboxes(&cfg, people.as_ref(), &body).await?;
+
+    match body["type"].as_str() {
+        Some("Follow") => follow(&cfg, people.as_ref(), &queue, actor, &body).await?,
+        Some("Announce") => announce(&cfg, objects.as_ref(), &body).await?,
+        Some("Like") => like(&cfg, objects.as_ref(), &body).await?,
+        Some("Undo") => undo(objects.as_ref(), &body).await?,
+        Some("Move") => move_actor(&client, objects.as_ref(), &body).await?,
+        Some("Update") => update(objects.as_ref(), &keys, actor, &body).await?,
+        Some("Delete") => delete(objects.as_ref(), actor, &body).await?,
+        // we can't act on it ourselves, but our followers or the people it
+        // was sent to may be able to
+        _ if !forward_for.is_empty() || filed => {}
+        _ => return Err(web_err(StatusCode::NOT_IMPLEMENTED, "Not implemented")),
+    }
+    forward(
+        &cfg,
+        people.as_ref(),
+        objects.as_ref(),
+        &queue,
+        &forward_for,
+        actor,
+        &body,
+        &raw_body,
+    )
+    .await?;
+
+    if let Some(webhook) = webhook {
+        webhook.mirror(raw_body);
+    }
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// Who sent the activity. That's whoever signed it, who has to be its actor,
+/// or anyone could act in someone else's name. Activities from an unsigned
+/// peer are trusted to name their actor.
+fn sender<'a>(signed: &Signed, activity: &'a Value) -> Result<&'a str, WebError> {
+    let actor = id_of(&activity["actor"]).ok_or_else(|| web_err_400("Activity has no actor"))?;
+    match &signed.signer {
+        Some(signer) if signer != actor => Err(web_err_401(format!(
+            "Activity by {} is signed by {}",
+            actor, signer
+        ))),
+        _ => Ok(actor),
+    }
+}
+
+/// The activity's type, if it's one of `--ignore-activity-types`.
+fn ignored_type<'a>(cfg: &Config, activity: &'a Value) -> Option<&'a str> {
+    activity["type"]
+        .as_str()
+        .filter(|kind| cfg.ignore_activity_types.iter().any(|t| t == kind))
+}
+
+/// Checks the body against the `Digest` header, which a signed request's
+/// signature has to cover, or nothing ties the body to the signer. Only
+/// requests from `--unsigned-peers` come without a signature to check.
+/// Mismatches are counted in `digest_mismatch_total`, as they tend to mean
+/// something between us and the sender is mangling bodies rather than a bad
+/// actor.
+fn verify_digest(headers: &HeaderMap, body: &[u8]) -> Result<(), WebError> {
+    if headers.contains_key("signature") && !signature_covers(headers, "digest") {
+        return Err(web_err_401(
+            "Signature doesn't cover header digest, so the body isn't signed",
+        ));
+    }
+    let header = match headers.get("digest") {
+        Some(header) => header
+            .to_str()
+            .map_err(|_| web_err_400("Invalid header digest"))?,
+        None if signature_covers(headers, "digest") => {
+            return Err(web_err_400(
+                "Signature covers header digest, which the request doesn't have",
+            ))
+        }
+        None => return Ok(()),
+    };
+    digest::verify(header, body).map_err(|e| {
+        if e.is::<digest::Mismatch>() {
+            metrics::increment_counter!("digest_mismatch_total");
+        }
+        web_err_400(format!("Error verifying digest: {}", e))
+    })
+}
+
+fn signature_covers(headers: &HeaderMap, name: &str) -> bool {
+    scheme::detect(headers)
+        .parse(headers)
+        .ok()
+        .is_some_and(|s| s.headers.iter().any(|h| h.eq_ignore_ascii_case(name)))
+}
+
+/// Parses a request body as JSON, explaining what we got instead when it's
+/// clearly something else, e.g. an error page from a proxy in front of the
+/// sender.
+fn parse_activity(body: &[u8]) -> Result<Value, String> {
+    let text = std::str::from_utf8(body).map_err(|e| {
+        format!(
+            "body is not UTF-8 text (invalid byte at offset {})",
+            e.valid_up_to()
+        )
+    })?;
+    let trimmed = text.trim_start();
+    if trimmed.is_empty() {
+        return Err("body is empty".to_string());
+    }
+    if trimmed.starts_with('<') {
+        return Err("body looks like HTML or XML, not JSON".to_string());
+    }
+    serde_json::from_str(text).map_err(|e| {
+        let message = match e.classify() {
+            serde_json::error::Category::Eof => "unexpected end of JSON".to_string(),
+            // serde_json puts its own position on the end, without the comma
+            _ => {
+                let message = e.to_string();
+                let position = format!(" at line {} column {}", e.line(), e.column());
+                message
+                    .strip_suffix(&position)
+                    .unwrap_or(&message)
+                    .to_string()
+            }
+        };
+        format!("{} at line {}, column {}", message, e.line(), e.column())
+    })
+}
+
+/// Activities reference other objects either by IRI or by embedding them.
+fn id_of(value: &Value) -> Option<&str> {
+    value.as_str().or_else(|| value["id"].as_str())
+}
+
+/// Checks an activity has the fields we rely on: a `type`, an `actor`, and an
+/// `id`. Transient activities may have a null `id`, but must still say so.
+fn validate(activity: &Value) -> Result<(), String> {
+    if !activity.is_object() {
+        return Err("expected a JSON object".to_string());
+    }
+    let kind = match &activity["type"] {
+        Value::String(kind) => kind.as_str(),
+        Value::Array(kinds) if kinds.iter().all(Value::is_string) && !kinds.is_empty() => {
+            kinds[0].as_str().unwrap()
+        }
+        Value::Null => return Err("missing type".to_string()),
+        _ => return Err("type must be a string".to_string()),
+    };
+    match activity.get("id") {
+        None => return Err(format!("{} is missing id", kind)),
+        Some(Value::Null) | Some(Value::String(_)) => {}
+        Some(_) => return Err(format!("{} id must be a string", kind)),
+    }
+    if id_of(&activity["actor"]).is_none() {
+        return Err(format!("{} is missing actor", kind));
+    }
+    Ok(())
+}
+
+/// Whether the activity comes from a suspended actor and should be dropped.
+/// When configured to reject them instead, that is an error.
+async fn from_suspended_actor(
+    cfg: &Config,
+    objects: &dyn ObjectStore,
+    activity: &Value,
+) -> Result<bool, WebError> {
+    let actor = match id_of(&activity["actor"]) {
+        Some(actor) => actor,
+        None => return Ok(false),
+    };
+    let suspended = objects
+        .is_suspended(actor)
+        .await
+        .map_err(|e| web_err_500(format!("Error checking suspension: {}", e)))?;
+    if !suspended {
+        return Ok(false);
+    }
+
+    match cfg.suspended_actors {
+        SuspendedActors::Reject => Err(web_err(
+            StatusCode::FORBIDDEN,
+            format!("{} is suspended", actor),
+        )),
+        SuspendedActors::Drop => {
+            debug!("Dropping activity from suspended {}", actor);
+            Ok(true)
+        }
+    }
+}
+
+fn is_local(cfg: &Config, id: &str) -> bool {
+    id.starts_with(&format!("{}/", cfg.base_url()))
+}
+
+/// Inbox forwarding (ActivityPub section 7.1.2): when an activity addresses
+/// the followers of a local person and is about an object that person owns,
+/// e.g. a reply to one of their notes, we pass it on to those followers since
+/// the sender can't see who they are.
+///
+/// Returns the local people whose followers should get the activity, which is
+/// only [`forward`]ed to them once we have handled it ourselves.
+async fn forwarded_for(
+    cfg: &Config,
+    people: &dyn PeopleStore,
+    objects: &dyn ObjectStore,
+    activity: &Value,
+) -> Result<Vec<Person>, WebError> {
+    let referenced: Vec<&str> = [
+        &activity["object"],
+        &activity["target"],
+        &activity["inReplyTo"],
+        &activity["object"]["inReplyTo"],
+    ]
+    .into_iter()
+    .filter_map(id_of)
+    .filter(|id| is_local(cfg, id))
+    .collect();
+    if referenced.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let mut owners = vec![];
+    for addressee in acted_on(cfg, activity) {
+        let Some(owner) = addressee.strip_suffix("/followers") else {
+            continue;
+        };
+        let owner = match people.get_by_id(owner).await {
+            Ok(Some(owner)) => owner,
+            Ok(None) => continue,
+            Err(e) => return Err(web_err_500(format!("Error getting person: {}", e))),
+        };
+
+        let mut owns_referenced = false;
+        for id in &referenced {
+            let object = objects
+                .get(id)
+                .await
+                .map_err(|e| web_err_500(format!("Error getting object: {}", e)))?;
+            if let Lookup::Found(object) = object {
+                // an object without attributedTo belongs to no one
+                if id_of(&object["attributedTo"]) == Some(owner.id.as_str()) {
+                    owns_referenced = true;
+                    break;
+                }
+            }
+        }
+        if owns_referenced {
+            owners.push(owner);
+        }
+    }
+    Ok(owners)
+}
+
+/// Queues `activity` for the followers of each of `owners`, except `sender`.
+/// What goes out is `raw_body`, the activity exactly as it was sent to us, so
+/// anything signed inside it still verifies.
+///
+/// An activity is only forwarded the first time we see it, so redelivering
+/// it can't be used to have us send it out again and again.
+#[allow(clippy::too_many_arguments)]
+async fn forward(
+    cfg: &Config,
+    people: &dyn PeopleStore,
+    objects: &dyn ObjectStore,
+    queue: &DeliveryQueue,
+    owners: &[Person],
+    sender: &str,
+    activity: &Value,
+    raw_body: &Bytes,
+) -> Result<(), WebError> {
+    if owners.is_empty() {
+        return Ok(());
+    }
+    let Some(id) = id_of(activity) else {
+        debug!("Not forwarding an activity without an id");
+        return Ok(());
+    };
+    let first = objects
+        .record_forward(id)
+        .await
+        .map_err(|e| web_err_500(format!("Error recording forward: {}", e)))?;
+    if !first {
+        debug!("Already forwarded {}", id);
+        return Ok(());
+    }
+    for owner in owners {
+        // a page at a time, as popular people can have a lot of followers
+        let mut after = None;
+        loop {
+            let followers = people
+                .followers_page(
+                    &owner.username,
+                    after.as_deref(),
+                    cfg.fanout_batch_size as usize,
+                )
+                .await
+                .map_err(|e| web_err_500(format!("Error getting followers: {}", e)))?;
+            let Some(last) = followers.last() else {
+                break;
+            };
+            after = Some(last.clone());
+            for follower in followers {
+                if follower == sender {
+                    continue;
+                }
+                debug!("Forwarding to {} on behalf of {}", follower, owner.id);
+                queue.enqueue(Delivery {
+                    from: owner.username.clone(),
+                    to: follower,
+                    activity: activity.clone(),
+                    body: Some(raw_body.clone()),
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The first `--max-recipients` of the activity's recipients, the ones we
+/// act on.
+fn acted_on<'a>(cfg: &Config, activity: &'a Value) -> impl Iterator<Item = &'a str> {
+    addressing::recipients(activity)
+        .into_iter()
+        .take(cfg.max_recipients as usize)
+}
+
+/// Files the activity in the inbox of every local person it's addressed to,
+/// directly or through the followers collection of a local person they
+/// follow. This is what routes activities arriving at the shared inbox to
+/// the people they are for. Returns whether anyone got it.
+async fn file_in_inboxes(
+    cfg: &Config,
+    people: &dyn PeopleStore,
+    activity: &Value,
+) -> Result<bool, WebError> {
+    let person_err =
+        |e: Box<dyn std::error::Error>| web_err_500(format!("Error getting person: {}", e));
+
+    let mut recipients: Vec<String> = vec![];
+    for addressee in acted_on(cfg, activity) {
+        // that includes a remote sender's own followers collection. nobody
+        // here can follow anyone yet, so none of them are local
+        if !is_local(cfg, addressee) {
+            continue;
+        }
+        let actors = match addressee.strip_suffix("/followers") {
+            Some(owner) => match people.get_by_id(owner).await.map_err(person_err)? {
+                Some(owner) => people
+                    .followers(&owner.username)
+                    .await
+                    .map_err(|e| web_err_500(format!("Error getting followers: {}", e)))?,
+                None => continue,
+            },
+            None => vec![addressee.to_string()],
+        };
+        for actor in actors.iter().filter(|actor| is_local(cfg, actor)) {
+            if let Some(person) = people.get_by_id(actor).await.map_err(person_err)? {
+                if !recipients.contains(&person.username) {
+                    recipients.push(person.username);
+                }
+            }
+        }
+    }
+
+    for username in &recipients {
+        debug!("Filing in the inbox of {}", username);
+        people
+            .add_to_inbox(username, activity.clone())
+            .await
+            .map_err(|e| web_err_500(format!("Error filing activity: {}", e)))?;
+    }
+    Ok(!recipients.is_empty())
+}
+
+/// Adds `actor`, the verified sender of a `Follow` of a local person, to
+/// their followers and accepts it. A repeated `Follow`, e.g. a retried
+/// delivery, changes nothing and isn't accepted again.
+async fn follow(
+    cfg: &Config,
+    people: &dyn PeopleStore,
+    queue: &DeliveryQueue,
+    actor: &str,
+    activity: &Value,
+) -> Result<(), WebError> {
+    // we are going to deliver our accept to them
+    ActorId::parse(actor, cfg.allow_private_fetches).map_err(|e| web_err_400(e.to_string()))?;
+    let object = id_of(&activity["object"]).ok_or_else(|| web_err_400("Follow has no object"))?;
+    if !is_local(cfg, object) {
+        debug!("Ignoring follow of {}", object);
+        return Ok(());
+    }
+    let Some(person) = people
+        .get_by_id(object)
+        .await
+        .map_err(|e| web_err_500(format!("Error getting person: {}", e)))?
+    else {
+        debug!("Ignoring follow of unknown person {}", object);
+        return Ok(());
+    };
+
+    let added = people
+        .add_follower(&person.username, actor)
+        .await
+        .map_err(|e| web_err_500(format!("Error adding follower: {}", e)))?;
+    if !added {
+        debug!("{} already follows {}", actor, person.id);
+        return Ok(());
+    }
+    info!("{} followed {}", actor, person.id);
+    queue.enqueue(Delivery {
+        from: person.username.clone(),
+        to: actor.to_string(),
+        activity: accept(cfg, &person, activity),
+        body: None,
+    });
+    Ok(())
+}
+
+/// `person`'s `Accept` of `follow`, which it echoes in full. The id is derived
+/// from the follow's, so accepting the same follow twice gives the same
+/// activity.
+fn accept(cfg: &Config, person: &Person, follow: &Value) -> Value {
+    let follow_id = id_of(follow).unwrap_or_default();
+    let hash = general_purpose::URL_SAFE_NO_PAD.encode(Sha256::digest(follow_id.as_bytes()));
+    json!({
+        "@context": context(cfg),
+        "id": format!("{}#accepts/{}", person.id, hash),
+        "type": "Accept",
+        "actor": person.id,
+        "object": follow,
+    })
+}
+
+/// Parses the actor and object out of a `Like` or `Announce`, returning `None`
+/// when it targets a local object that doesn't exist.
+async fn reaction<'a>(
+    cfg: &Config,
+    objects: &dyn ObjectStore,
+    activity: &'a Value,
+) -> Result<Option<(&'a str, Reaction)>, WebError> {
+    let kind = activity["type"].as_str().unwrap_or("Activity");
+    let id = id_of(activity).ok_or_else(|| web_err_400(format!("{} has no id", kind)))?;
+    let actor =
+        id_of(&activity["actor"]).ok_or_else(|| web_err_400(format!("{} has no actor", kind)))?;
+    let object =
+        id_of(&activity["object"]).ok_or_else(|| web_err_400(format!("{} has no object", kind)))?;
+
+    if is_local(cfg, object) {
+        let found = objects
+            .get(object)
+            .await
+            .map_err(|e| web_err_500(format!("Error getting object: {}", e)))?;
+        if found.found().is_none() {
+            debug!("Ignoring {} of unknown object {}", kind, object);
+            return Ok(None);
+        }
+    }
+    // TODO: optionally fetch remote objects we have never seen
+
+    Ok(Some((
+        object,
+        Reaction {
+            actor: actor.to_string(),
+            activity: id.to_string(),
+        },
+    )))
+}
+
+async fn announce(
+    cfg: &Config,
+    objects: &dyn ObjectStore,
+    activity: &Value,
+) -> Result<(), WebError> {
+    if let Some((object, share)) = reaction(cfg, objects, activity).await? {
+        objects
+            .add_share(object, share)
+            .await
+            .map_err(|e| web_err_500(format!("Error recording announce: {}", e)))?;
+    }
+    Ok(())
+}
+
+async fn like(cfg: &Config, objects: &dyn ObjectStore, activity: &Value) -> Result<(), WebError> {
+    if let Some((object, like)) = reaction(cfg, objects, activity).await? {
+        objects
+            .add_like(object, like)
+            .await
+            .map_err(|e| web_err_500(format!("Error recording like: {}", e)))?;
+    }
+    Ok(())
+}
+
+async fn undo(objects: &dyn ObjectStore, activity: &Value) -> Result<(), WebError> {
+    let actor = id_of(&activity["actor"]).ok_or_else(|| web_err_400("Undo has no actor"))?;
+    let inner = &activity["object"];
+
+    let kind = match inner["type"].as_str() {
+        Some(kind) => kind,
+        None => {
+            // only given an IRI, we have no idea what is being undone
+            debug!("Ignoring undo of {:?}", id_of(inner));
+            return Ok(());
+        }
+    };
+
+    if let Some(inner_actor) = id_of(&inner["actor"]) {
+        if inner_actor != actor {
+            return Err(web_err(
+                StatusCode::FORBIDDEN,
+                format!("{} can't undo a {} by {}", actor, kind, inner_actor),
+            ));
+        }
+    }
+
+    match kind {
+        "Like" | "Announce" => {
+            let object = id_of(&inner["object"])
+                .ok_or_else(|| web_err_400(format!("Undone {} has no object", kind)))?;
+            let removed = if kind == "Like" {
+                objects.remove_like(object, actor).await
+            } else {
+                objects.remove_share(object, actor).await
+            };
+            removed.map_err(|e| web_err_500(format!("Error undoing {}: {}", kind, e)))
+        }
+        _ => Err(web_err(StatusCode::NOT_IMPLEMENTED, "Not implemented")),
+    }
+}
+
+/// An actor editing one of their objects, or their own profile. Edited
+/// objects replace the stored version, as long as the sender is who stored
+/// objects are attributed to. Profiles may come with new keys, or drop old
+/// ones, so every key we have cached for the actor is dropped and fetched
+/// again when next used.
+async fn update(
+    objects: &dyn ObjectStore,
+    keys: &KeyCache,
+    actor: &str,
+    activity: &Value,
+) -> Result<(), WebError> {
+    let object = &activity["object"];
+    let id = id_of(object).ok_or_else(|| web_err_400("Update has no object"))?;
+    if !object.is_object() {
+        // only given an IRI, we have nothing to replace it with
+        debug!("Ignoring update of {} without the object", id);
+        return Ok(());
+    }
+
+    if id == actor {
+        let key_ids: Vec<&str> = match &object["publicKey"] {
+            Value::Array(keys) => keys.iter().filter_map(id_of).collect(),
+            key => id_of(key).into_iter().collect(),
+        };
+        for key_id in key_ids {
+            debug!("Dropping cached key {} of updated {}", key_id, actor);
+            keys.invalidate(key_id).await;
+        }
+        keys.invalidate_owner(actor).await;
+        return Ok(());
+    }
+
+    let stored = objects
+        .get(id)
+        .await
+        .map_err(|e| web_err_500(format!("Error getting object: {}", e)))?;
+    let Lookup::Found(stored) = stored else {
+        debug!("Ignoring update of unknown object {}", id);
+        return Ok(());
+    };
+    let updated: Object = serde_json::from_value(object.clone())
+        .map_err(|e| web_err_400(format!("Invalid object in Update: {}", e)))?;
+    if id_of(&stored["attributedTo"]) != Some(actor) || updated.attributed_to() != Some(actor) {
+        return Err(web_err(
+            StatusCode::FORBIDDEN,
+            format!("{} can't update {}", actor, id),
+        ));
+    }
+
+    objects
+        .update(object.clone())
+        .await
+        .map_err(|e| web_err_500(format!("Error updating object: {}", e)))?;
+    Ok(())
+}
+
+/// Deletes an object we have a copy of, leaving a tombstone. Only whoever it
+/// is attributed to may delete it, checked against `actor`, the verified
+/// sender.
+async fn delete(objects: &dyn ObjectStore, actor: &str, activity: &Value) -> Result<(), WebError> {
+    let id = id_of(&activity["object"]).ok_or_else(|| web_err_400("Delete has no object"))?;
+
+    let stored = objects
+        .get(id)
+        .await
+        .map_err(|e| web_err_500(format!("Error getting object: {}", e)))?;
+    let Lookup::Found(stored) = stored else {
+        // actors deleting themselves end up here too, as we don't store them
+        debug!("Ignoring delete of unknown object {}", id);
+        return Ok(());
+    };
+    if id_of(&stored["attributedTo"]) != Some(actor) {
+        return Err(web_err(
+            StatusCode::FORBIDDEN,
+            format!("{} can't delete {}", actor, id),
+        ));
+    }
+
+    objects
+        .delete(id)
+        .await
+        .map_err(|e| web_err_500(format!("Error deleting object: {}", e)))?;
+    Ok(())
+}
+
+/// An account announcing it now lives at `target`. We remember where it went
+/// so the move can be surfaced to anyone here who followed it, but only once
+/// the target confirms it by listing the origin in its `alsoKnownAs`.
+/// Otherwise anyone able to post as the origin could redirect its followers.
+async fn move_actor(
+    client: &remote::Client,
+    objects: &dyn ObjectStore,
+    activity: &Value,
+) -> Result<(), WebError> {
+    let actor = id_of(&activity["actor"]).ok_or_else(|| web_err_400("Move has no actor"))?;
+    let origin = id_of(&activity["object"]).ok_or_else(|| web_err_400("Move has no object"))?;
+    let target = id_of(&activity["target"]).ok_or_else(|| web_err_400("Move has no target"))?;
+    if origin != actor {
+        return Err(web_err(
+            StatusCode::FORBIDDEN,
+            format!("{} can't move {}", actor, origin),
+        ));
+    }
+
+    let previous = objects
+        .moved_to(origin)
+        .await
+        .map_err(|e| web_err_500(format!("Error getting move: {}", e)))?;
+    if previous.as_deref() == Some(target) {
+        debug!("Already know {} moved to {}", origin, target);
+        return Ok(());
+    }
+
+    let target_actor: Value = client
+        .get_activity_json(target)
+        .await
+        .map_err(|e| web_err_400(format!("Error fetching move target {}: {}", target, e)))?;
+    let aliases = match &target_actor["alsoKnownAs"] {
+        Value::Array(aliases) => aliases.iter().filter_map(id_of).collect(),
+        alias => id_of(alias).into_iter().collect::<Vec<_>>(),
+    };
+    if !aliases.contains(&origin) {
+        return Err(web_err(
+            StatusCode::FORBIDDEN,
+            format!("{} doesn't list {} in alsoKnownAs", target, origin),
+        ));
+    }
+
+    // TODO: re-follow the target on behalf of local people following the
+    // origin, once we keep track of who we follow
+    info!("{} moved to {}", origin, target);
+    objects
+        .record_move(origin, target)
+        .await
+        .map_err(|e| web_err_500(format!("Error recording move: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key::Key;
+    use crate::objects::InMemoryObjectStore;
+    use crate::users::InMemoryPeopleStore;
+    use axum::response::Response;
+    use clap::Parser;
+
+    fn config() -> Config {
+        Config::parse_from(["rap-server", "--domain", "example.com"])
+    }
+
+    #[tokio::test]
+    async fn test_announce_local_note() {
+        let cfg = config();
+        let objects = InMemoryObjectStore::new();
+        objects
+            .insert(json!({
+                "id": "https://example.com/objects/1",
+                "type": "Note",
+                "content": "hello",
+            }))
+            .await;
+
+        let activity = json!({
+            "id": "https://remote.example/activities/1",
+            "type": "Announce",
+            "actor": "https://remote.example/users/bob",
+            "object": "https://example.com/objects/1",
+        });
+        announce(&cfg, &objects, &activity).await.unwrap();
+
+        let shares = objects
+            .shares("https://example.com/objects/1")
+            .await
+            .unwrap();
+        assert_eq!(shares.len(), 1);
+        assert_eq!(shares[0].activity, "https://remote.example/activities/1");
+
+        // a retried delivery of the same boost doesn't count twice
+        announce(&cfg, &objects, &activity).await.unwrap();
+        let shares = objects
+            .shares("https://example.com/objects/1")
+            .await
+            .unwrap();
+        assert_eq!(shares.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_announce_unknown_local_object() {
+        let cfg = config();
+        let objects = InMemoryObjectStore::new();
+
+        let activity = json!({
+            "id": "https://remote.example/activities/1",
+            "type": "Announce",
+            "actor": "https://remote.example/users/bob",
+            "object": {"id": "https://example.com/objects/missing"},
+        });
+        announce(&cfg, &objects, &activity).await.unwrap();
+
+        let shares = objects
+            .shares("https://example.com/objects/missing")
+            .await
+            .unwrap();
+        assert!(shares.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_like_is_idempotent_per_actor() {
+        let cfg = config();
+        let objects = InMemoryObjectStore::new();
+        objects
+            .insert(json!({
+                "id": "https://example.com/objects/1",
+                "type": "Note",
+                "content": "hello",
+            }))
+            .await;
+
+        for id in ["1", "2"] {
+            let activity = json!({
+                "id": format!("https://remote.example/likes/{}", id),
+                "type": "Like",
+                "actor": "https://remote.example/users/bob",
+                "object": "https://example.com/objects/1",
+            });
+            like(&cfg, &objects, &activity).await.unwrap();
+        }
+
+        let likes = objects
+            .likes("https://example.com/objects/1")
+            .await
+            .unwrap();
+        assert_eq!(likes.len(), 1);
+    }
+
+    /// Runs a `Like` of a local note through the whole inbox handler.
+    async fn post_like(cfg: Config, headers: HeaderMap) -> Response {
+        post_like_to(cfg, Arc::new(InMemoryObjectStore::new()), headers).await
+    }
+
+    /// Like [`post_like`], recording the like in `objects`.
+    async fn post_like_to(
+        cfg: Config,
+        objects: Arc<InMemoryObjectStore>,
+        headers: HeaderMap,
+    ) -> Response {
+        objects
+            .insert(json!({
+                "id": "https://example.com/objects/1",
+                "type": "Note",
+                "content": "hello",
+            }))
+            .await;
+        let activity = json!({
+            "id": "https://remote.example/likes/1",
+            "type": "Like",
+            "actor": "https://remote.example/users/bob",
+            "object": "https://example.com/objects/1",
+        });
+        let people = Arc::new(InMemoryPeopleStore::new(cfg.clone()));
+        post_activity_with(cfg, objects, people, headers, None, &activity)
+            .await
+            .0
+    }
+
+    /// Runs `activity` through the whole inbox handler, as signed by
+    /// `signer`.
+    async fn post_activity(
+        cfg: Config,
+        objects: Arc<InMemoryObjectStore>,
+        signer: Option<&str>,
+        activity: &Value,
+    ) -> Response {
+        let people = Arc::new(InMemoryPeopleStore::new(cfg.clone()));
+        post_activity_with(cfg, objects, people, HeaderMap::new(), signer, activity)
+            .await
+            .0
+    }
+
+    async fn post_activity_with(
+        cfg: Config,
+        objects: Arc<InMemoryObjectStore>,
+        people: Arc<InMemoryPeopleStore>,
+        headers: HeaderMap,
+        signer: Option<&str>,
+        activity: &Value,
+    ) -> (Response, Vec<Delivery>) {
+        let (queue, mut rx) = DeliveryQueue::new();
+        let response = json(
+            Signed {
+                signer: signer.map(str::to_string),
+            },
+            Extension(cfg.clone()),
+            Extension(objects as Arc<dyn ObjectStore>),
+            Extension(people as Arc<dyn PeopleStore>),
+            Extension(queue),
+            Extension(remote::dev_client()),
+            Extension(key_cache()),
+            Extension(Webhook::new(&cfg)),
+            headers,
+            Bytes::from(activity.to_string()),
+        )
+        .await
+        .into_response();
+        let mut deliveries = vec![];
+        while let Ok(delivery) = rx.try_recv() {
+            deliveries.push(delivery);
+        }
+        (response, deliveries)
+    }
+
+    #[tokio::test]
+    async fn test_ignore_activity_types() {
+        let objects = Arc::new(InMemoryObjectStore::new());
+        let response = post_like_to(config(), objects.clone(), HeaderMap::new()).await;
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        let likes = objects
+            .likes("https://example.com/objects/1")
+            .await
+            .unwrap();
+        assert_eq!(likes.len(), 1);
+
+        let cfg = Config::parse_from([
+            "rap-server",
+            "--domain",
+            "example.com",
+            "--ignore-activity-types",
+            "Announce,Like",
+        ]);
+        assert_eq!(cfg.ignore_activity_types, ["Announce", "Like"]);
+        let objects = Arc::new(InMemoryObjectStore::new());
+        let response = post_like_to(cfg, objects.clone(), HeaderMap::new()).await;
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        let likes = objects
+            .likes("https://example.com/objects/1")
+            .await
+            .unwrap();
+        assert!(likes.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_accepted_response_is_empty() {
+        let response = post_like(config(), HeaderMap::new()).await;
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert!(body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_webhook() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let received = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = String::new();
+            let mut buf = [0; 4096];
+            // the body may come in a separate packet from the headers
+            loop {
+                let n = socket.read(&mut buf).await.unwrap();
+                request.push_str(&String::from_utf8_lossy(&buf[..n]));
+                if let Some((head, body)) = request.split_once("\r\n\r\n") {
+                    let length = head
+                        .lines()
+                        .find_map(|line| {
+                            let (name, value) = line.split_once(':')?;
+                            name.eq_ignore_ascii_case("content-length")
+                                .then(|| value.trim().parse::<usize>().ok())?
+                        })
+                        .unwrap();
+                    if body.len() >= length {
+                        break;
+                    }
+                }
+            }
+            socket
+                .write_all(b"HTTP/1.1 204 No Content\r\nconnection: close\r\n\r\n")
+                .await
+                .unwrap();
+            request
+        });
+
+        let cfg = Config::parse_from([
+            "rap-server",
+            "--domain",
+            "example.com",
+            "--require-digest",
+            "false",
+            "--webhook-url",
+            &url,
+            "--webhook-secret",
+            "hush",
+        ]);
+        // only accepted activities are mirrored, so the hook's one request
+        // is for the like, not this
+        let rejected = json!({
+            "id": "https://remote.example/activities/1",
+            "type": "Dance",
+            "actor": "https://remote.example/users/bob",
+        });
+        let (response, _) = post_activity_with(
+            cfg.clone(),
+            Arc::new(InMemoryObjectStore::new()),
+            Arc::new(InMemoryPeopleStore::new(cfg.clone())),
+            HeaderMap::new(),
+            None,
+            &rejected,
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::NOT_IMPLEMENTED);
+        let response = post_like(cfg, HeaderMap::new()).await;
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+
+        let request = tokio::time::timeout(std::time::Duration::from_secs(10), received)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(request.starts_with("POST /hook "), "{}", request);
+        let (head, body) = request.split_once("\r\n\r\n").unwrap();
+        assert_eq!(
+            serde_json::from_str::<Value>(body).unwrap()["id"],
+            "https://remote.example/likes/1"
+        );
+        let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, b"hush");
+        let tag = ring::hmac::sign(&key, body.as_bytes());
+        let hex: String = tag.as_ref().iter().map(|b| format!("{:02x}", b)).collect();
+        assert!(
+            head.to_lowercase()
+                .contains(&format!("x-hub-signature-256: sha256={}", hex)),
+            "{}",
+            head
+        );
+    }
+
+    #[test]
+    fn test_webhook_needs_secret() {
+        Config::try_parse_from([
+            "rap-server",
+            "--domain",
+            "example.com",
+            "--webhook-url",
+            "http://localhost/hook",
+        ])
+        .unwrap_err();
+    }
+
+    #[test]
+    fn test_missing_signed_digest() {
+        let body = br#"{"type":"Follow"}"#;
+        let mut headers = HeaderMap::new();
+        // nothing to check without a signature, as from an unsigned peer
+        verify_digest(&headers, body).unwrap();
+
+        headers.insert(
+            "signature",
+            "keyId=\"https://remote.example/users/bob#main-key\",headers=\"(request-target) date\",signature=\"c2ln\""
+                .parse()
+                .unwrap(),
+        );
+        let (status, message) = verify_digest(&headers, body).unwrap_err();
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            message,
+            "Signature doesn't cover header digest, so the body isn't signed"
+        );
+        // the default when the signature doesn't list its headers
+        headers.insert(
+            "signature",
+            "keyId=\"https://remote.example/users/bob#main-key\",signature=\"c2ln\""
+                .parse()
+                .unwrap(),
+        );
+        verify_digest(&headers, body).unwrap_err();
+
+        headers.insert(
+            "signature",
+            "keyId=\"https://remote.example/users/bob#main-key\",headers=\"(request-target) date Digest\",signature=\"c2ln\""
+                .parse()
+                .unwrap(),
+        );
+        let (status, message) = verify_digest(&headers, body).unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            message,
+            "Signature covers header digest, which the request doesn't have"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_require_digest() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "digest",
+            "SHA-256=GYwYnH3BiO6aICFt0ThC5bUIJ4byvqdpWtR8m5fNkww="
+                .parse()
+                .unwrap(),
+        );
+
+        let response = post_like(config(), headers.clone()).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let cfg = Config::parse_from([
+            "rap-server",
+            "--domain",
+            "example.com",
+            "--require-digest",
+            "false",
+        ]);
+        let response = post_like(cfg, headers).await;
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+    }
+
+    #[tokio::test]
+    async fn test_file_in_inboxes() {
+        let cfg = config();
+        let people = InMemoryPeopleStore::new(cfg.clone());
+        for username in ["alice", "bob", "carol", "dave"] {
+            people.create(&username.to_string()).await.unwrap();
+        }
+        people
+            .add_follower(&"dave".to_string(), "https://example.com/users/carol")
+            .await
+            .unwrap();
+        people
+            .add_follower(&"dave".to_string(), "https://remote.example/users/erin")
+            .await
+            .unwrap();
+
+        let activity = json!({
+            "id": "https://remote.example/activities/1",
+            "type": "Create",
+            "actor": "https://remote.example/users/frank",
+            "to": ["https://example.com/users/alice", addressing::PUBLIC],
+            "cc": [
+                "https://example.com/users/bob",
+                "https://example.com/users/dave/followers",
+                "https://example.com/users/nobody",
+                "https://remote.example/users/frank/followers",
+            ],
+            "object": {"id": "https://remote.example/notes/1", "type": "Note"},
+        });
+        assert!(file_in_inboxes(&cfg, &people, &activity).await.unwrap());
+        // a retry doesn't file it twice
+        assert!(file_in_inboxes(&cfg, &people, &activity).await.unwrap());
+
+        for username in ["alice", "bob", "carol"] {
+            let inbox = people.inbox(&username.to_string()).await.unwrap();
+            assert_eq!(inbox, vec![activity.clone()], "{}", username);
+        }
+        // only their followers were addressed
+        assert!(people.inbox(&"dave".to_string()).await.unwrap().is_empty());
+
+        let activity = json!({
+            "id": "https://remote.example/activities/2",
+            "type": "Create",
+            "actor": "https://remote.example/users/frank",
+            "to": ["https://remote.example/users/frank/followers"],
+        });
+        assert!(!file_in_inboxes(&cfg, &people, &activity).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_max_recipients() {
+        let cfg = Config::parse_from([
+            "rap-server",
+            "--domain",
+            "example.com",
+            "--max-recipients",
+            "2",
+        ]);
+        let people = InMemoryPeopleStore::new(cfg.clone());
+        for username in ["alice", "bob", "carol"] {
+            people.create(&username.to_string()).await.unwrap();
+        }
+
+        // the public collection isn't a recipient, so doesn't count
+        let activity = json!({
+            "id": "https://remote.example/activities/1",
+            "type": "Create",
+            "actor": "https://remote.example/users/frank",
+            "to": [addressing::PUBLIC, "https://example.com/users/alice"],
+            "cc": [
+                "https://example.com/users/bob",
+                "https://example.com/users/carol",
+            ],
+        });
+        assert!(file_in_inboxes(&cfg, &people, &activity).await.unwrap());
+        for (username, filed) in [("alice", 1), ("bob", 1), ("carol", 0)] {
+            let inbox = people.inbox(&username.to_string()).await.unwrap();
+            assert_eq!(inbox.len(), filed, "{}", username);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_follow_accepted_once() {
+        let cfg = config();
+        let people = InMemoryPeopleStore::new(cfg.clone());
+        let alice = people.create(&"alice".to_string()).await.unwrap().unwrap();
+        let (queue, mut rx) = DeliveryQueue::new();
+
+        let bob = "https://remote.example/users/bob";
+        let activity = json!({
+            "id": "https://remote.example/follows/1",
+            "type": "Follow",
+            "actor": bob,
+            "object": "https://example.com/users/alice",
+        });
+        follow(&cfg, &people, &queue, bob, &activity).await.unwrap();
+        follow(&cfg, &people, &queue, bob, &activity).await.unwrap();
+
+        assert_eq!(
+            people.followers(&alice.username).await.unwrap(),
+            vec!["https://remote.example/users/bob"]
+        );
+        let delivery = rx.try_recv().unwrap();
+        assert_eq!(delivery.from, "alice");
+        assert_eq!(delivery.to, "https://remote.example/users/bob");
+        assert_eq!(delivery.activity["type"], "Accept");
+        assert_eq!(
+            delivery.activity["actor"],
+            "https://example.com/users/alice"
+        );
+        assert_eq!(delivery.activity["object"], activity);
+        assert!(rx.try_recv().is_err());
+
+        // the same follow is always accepted the same way
+        assert_eq!(delivery.activity, accept(&cfg, &alice, &activity));
+        let other = json!({"id": "https://remote.example/follows/2", "type": "Follow"});
+        assert_ne!(accept(&cfg, &alice, &other)["id"], delivery.activity["id"]);
+    }
+
+    #[tokio::test]
+    async fn test_follow_from_invalid_actor() {
+        let cfg = config();
+        let people = InMemoryPeopleStore::new(cfg.clone());
+        people.create(&"alice".to_string()).await.unwrap();
+        let (queue, mut rx) = DeliveryQueue::new();
+
+        let actor = "http://169.254.169.254/latest/meta-data";
+        let activity = json!({
+            "id": "https://remote.example/follows/1",
+            "type": "Follow",
+            "actor": actor,
+            "object": "https://example.com/users/alice",
+        });
+        let (status, _) = follow(&cfg, &people, &queue, actor, &activity)
+            .await
+            .unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(people
+            .followers(&"alice".to_string())
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_follow_in_someone_elses_name() {
+        let cfg = config();
+        let people = Arc::new(InMemoryPeopleStore::new(cfg.clone()));
+        people.create(&"alice".to_string()).await.unwrap();
+
+        let activity = json!({
+            "id": "https://remote.example/follows/1",
+            "type": "Follow",
+            "actor": "https://remote.example/users/bob",
+            "object": "https://example.com/users/alice",
+        });
+        let (response, _) = post_activity_with(
+            cfg,
+            Arc::new(InMemoryObjectStore::new()),
+            people.clone(),
+            HeaderMap::new(),
+            Some("https://remote.example/users/mallory"),
+            &activity,
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(people
+            .followers(&"alice".to_string())
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_follow_of_unknown_person() {
+        let cfg = config();
+        let people = InMemoryPeopleStore::new(cfg.clone());
+        let (queue, mut rx) = DeliveryQueue::new();
+
+        let bob = "https://remote.example/users/bob";
+        for object in [
+            "https://example.com/users/nobody",
+            "https://remote.example/users/carol",
+        ] {
+            let activity = json!({
+                "id": "https://remote.example/follows/1",
+                "type": "Follow",
+                "actor": bob,
+                "object": object,
+            });
+            follow(&cfg, &people, &queue, bob, &activity).await.unwrap();
+        }
+        assert!(rx.try_recv().is_err());
+    }
+
+    async fn note_with_reactions() -> InMemoryObjectStore {
+        let cfg = config();
+        let objects = InMemoryObjectStore::new();
+        objects
+            .insert(json!({
+                "id": "https://example.com/objects/1",
+                "type": "Note",
+                "content": "hello",
+            }))
+            .await;
+        for (kind, id) in [("Like", "like"), ("Announce", "announce")] {
+            let activity = json!({
+                "id": format!("https://remote.example/{}/1", id),
+                "type": kind,
+                "actor": "https://remote.example/users/bob",
+                "object": "https://example.com/objects/1",
+            });
+            if kind == "Like" {
+                like(&cfg, &objects, &activity).await.unwrap();
+            } else {
+                announce(&cfg, &objects, &activity).await.unwrap();
+            }
+        }
+        objects
+    }
+
+    #[tokio::test]
+    async fn test_undo_like() {
+        let objects = note_with_reactions().await;
+        let activity = json!({
+            "id": "https://remote.example/undo/1",
+            "type": "Undo",
+            "actor": "https://remote.example/users/bob",
+            "object": {
+                "id": "https://remote.example/like/1",
+                "type": "Like",
+                "actor": "https://remote.example/users/bob",
+                "object": "https://example.com/objects/1",
+            },
+        });
+        undo(&objects, &activity).await.unwrap();
+
+        let id = "https://example.com/objects/1";
+        assert!(objects.likes(id).await.unwrap().is_empty());
+        assert_eq!(objects.shares(id).await.unwrap().len(), 1);
+
+        // undoing again is a no-op
+        undo(&objects, &activity).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_undo_announce() {
+        let objects = note_with_reactions().await;
+        let activity = json!({
+            "id": "https://remote.example/undo/1",
+            "type": "Undo",
+            "actor": "https://remote.example/users/bob",
+            "object": {
+                "id": "https://remote.example/announce/1",
+                "type": "Announce",
+                "actor": "https://remote.example/users/bob",
+                "object": "https://example.com/objects/1",
+            },
+        });
+        undo(&objects, &activity).await.unwrap();
+
+        let id = "https://example.com/objects/1";
+        assert!(objects.shares(id).await.unwrap().is_empty());
+        assert_eq!(objects.likes(id).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_undo_someone_elses_like() {
+        let objects = note_with_reactions().await;
+        let activity = json!({
+            "id": "https://remote.example/undo/1",
+            "type": "Undo",
+            "actor": "https://remote.example/users/mallory",
+            "object": {
+                "type": "Like",
+                "actor": "https://remote.example/users/bob",
+                "object": "https://example.com/objects/1",
+            },
+        });
+        let (status, _) = undo(&objects, &activity).await.unwrap_err();
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(
+            objects
+                .likes("https://example.com/objects/1")
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reply_is_forwarded_to_followers() {
+        let cfg = config();
+        let objects = InMemoryObjectStore::new();
+        objects
+            .insert(json!({
+                "id": "https://example.com/objects/1",
+                "type": "Note",
+                "attributedTo": "https://example.com/users/alice",
+                "content": "hello",
+            }))
+            .await;
+        let people = InMemoryPeopleStore::new(cfg.clone());
+        people.create(&"alice".to_string()).await.unwrap();
+        for follower in [
+            "https://remote.example/users/bob",
+            "https://other.example/users/carol",
+        ] {
+            people
+                .add_follower(&"alice".to_string(), follower)
+                .await
+                .unwrap();
+        }
+        let (queue, mut rx) = DeliveryQueue::new();
+
+        let activity = json!({
+            "id": "https://remote.example/activities/2",
+            "type": "Create",
+            "actor": "https://remote.example/users/bob",
+            "to": ["https://example.com/users/alice/followers"],
+            "object": {
+                "id": "https://remote.example/notes/2",
+                "type": "Note",
+                "inReplyTo": "https://example.com/objects/1",
+                "content": "hi alice",
+            },
+        });
+        let owners = forwarded_for(&cfg, &people, &objects, &activity)
+            .await
+            .unwrap();
+        assert_eq!(owners.len(), 1);
+        // forwarded as sent, not as we would have written it
+        let raw_body = Bytes::from(serde_json::to_string_pretty(&activity).unwrap());
+        let bob = "https://remote.example/users/bob";
+        forward(
+            &cfg, &people, &objects, &queue, &owners, bob, &activity, &raw_body,
+        )
+        .await
+        .unwrap();
+
+        // bob sent the reply, so only carol needs it
+        let delivery = rx.try_recv().unwrap();
+        assert_eq!(delivery.from, "alice");
+        assert_eq!(delivery.to, "https://other.example/users/carol");
+        assert_eq!(delivery.activity, activity);
+        assert_eq!(delivery.body, Some(raw_body));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_forward_in_batches() {
+        let cfg = Config::parse_from([
+            "rap-server",
+            "--domain",
+            "example.com",
+            "--fanout-batch-size",
+            "2",
+        ]);
+        let objects = InMemoryObjectStore::new();
+        objects
+            .insert(json!({
+                "id": "https://example.com/objects/1",
+                "type": "Note",
+                "attributedTo": "https://example.com/users/alice",
+            }))
+            .await;
+        let people = InMemoryPeopleStore::new(cfg.clone());
+        people.create(&"alice".to_string()).await.unwrap();
+        for i in 0..5 {
+            people
+                .add_follower(
+                    &"alice".to_string(),
+                    &format!("https://remote.example/users/{}", i),
+                )
+                .await
+                .unwrap();
+        }
+        let (queue, mut rx) = DeliveryQueue::new();
+
+        let activity = json!({
+            "id": "https://remote.example/activities/3",
+            "type": "Like",
+            "actor": "https://other.example/users/carol",
+            "to": "https://example.com/users/alice/followers",
+            "object": "https://example.com/objects/1",
+        });
+        let owners = forwarded_for(&cfg, &people, &objects, &activity)
+            .await
+            .unwrap();
+        let raw_body = Bytes::from(activity.to_string());
+        let carol = "https://other.example/users/carol";
+        forward(
+            &cfg, &people, &objects, &queue, &owners, carol, &activity, &raw_body,
+        )
+        .await
+        .unwrap();
+
+        let mut delivered = vec![];
+        while let Ok(delivery) = rx.try_recv() {
+            delivered.push(delivery.to);
+        }
+        let expected: Vec<String> = (0..5)
+            .map(|i| format!("https://remote.example/users/{}", i))
+            .collect();
+        assert_eq!(delivered, expected);
+    }
+
+    #[tokio::test]
+    async fn test_reply_to_someone_elses_object_is_not_forwarded() {
+        let cfg = config();
+        let objects = InMemoryObjectStore::new();
+        objects
+            .insert(json!({
+                "id": "https://example.com/objects/1",
+                "type": "Note",
+                "attributedTo": "https://example.com/users/dave",
+                "content": "hello",
+            }))
+            .await;
+        let people = InMemoryPeopleStore::new(cfg.clone());
+        people.create(&"alice".to_string()).await.unwrap();
+        people
+            .add_follower(&"alice".to_string(), "https://other.example/users/carol")
+            .await
+            .unwrap();
+
+        let activity = json!({
+            "id": "https://remote.example/activities/2",
+            "type": "Create",
+            "actor": "https://remote.example/users/bob",
+            "cc": "https://example.com/users/alice/followers",
+            "object": {
+                "id": "https://remote.example/notes/2",
+                "type": "Note",
+                "inReplyTo": "https://example.com/objects/1",
+            },
+        });
+        assert!(forwarded_for(&cfg, &people, &objects, &activity)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_reply_to_unattributed_object_is_not_forwarded() {
+        let cfg = config();
+        let objects = InMemoryObjectStore::new();
+        objects
+            .insert(json!({
+                "id": "https://example.com/objects/1",
+                "type": "Note",
+                "content": "hello",
+            }))
+            .await;
+        let people = InMemoryPeopleStore::new(cfg.clone());
+        people.create(&"alice".to_string()).await.unwrap();
+
+        let activity = json!({
+            "id": "https://remote.example/activities/2",
+            "type": "Like",
+            "actor": "https://remote.example/users/bob",
+            "to": "https://example.com/users/alice/followers",
+            "object": "https://example.com/objects/1",
+        });
+        assert!(forwarded_for(&cfg, &people, &objects, &activity)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_failed_activity_is_not_forwarded() {
+        let cfg = config();
+        let objects = Arc::new(InMemoryObjectStore::new());
+        objects
+            .insert(json!({
+                "id": "https://example.com/objects/1",
+                "type": "Note",
+                "attributedTo": "https://example.com/users/alice",
+            }))
+            .await;
+        let people = Arc::new(InMemoryPeopleStore::new(cfg.clone()));
+        people.create(&"alice".to_string()).await.unwrap();
+        people
+            .add_follower(&"alice".to_string(), "https://other.example/users/carol")
+            .await
+            .unwrap();
+
+        // bob can't delete alice's note, so her followers don't hear of it
+        let bob = "https://remote.example/users/bob";
+        let activity = json!({
+            "id": "https://remote.example/deletes/1",
+            "type": "Delete",
+            "actor": bob,
+            "to": "https://example.com/users/alice/followers",
+            "object": "https://example.com/objects/1",
+        });
+        let (response, deliveries) =
+            post_activity_with(cfg, objects, people, HeaderMap::new(), Some(bob), &activity).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert!(deliveries.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_redelivered_reply_is_forwarded_once() {
+        let cfg = config();
+        let objects = Arc::new(InMemoryObjectStore::new());
+        objects
+            .insert(json!({
+                "id": "https://example.com/objects/1",
+                "type": "Note",
+                "attributedTo": "https://example.com/users/alice",
+            }))
+            .await;
+        let people = Arc::new(InMemoryPeopleStore::new(cfg.clone()));
+        people.create(&"alice".to_string()).await.unwrap();
+        people
+            .add_follower(&"alice".to_string(), "https://other.example/users/carol")
+            .await
+            .unwrap();
+
+        let bob = "https://remote.example/users/bob";
+        let activity = json!({
+            "id": "https://remote.example/activities/2",
+            "type": "Create",
+            "actor": bob,
+            "to": ["https://example.com/users/alice/followers"],
+            "object": {
+                "id": "https://remote.example/notes/2",
+                "type": "Note",
+                "inReplyTo": "https://example.com/objects/1",
+            },
+        });
+        for expected in [1, 0] {
+            let (response, deliveries) = post_activity_with(
+                cfg.clone(),
+                objects.clone(),
+                people.clone(),
+                HeaderMap::new(),
+                Some(bob),
+                &activity,
+            )
+            .await;
+            assert_eq!(response.status(), StatusCode::ACCEPTED);
+            assert_eq!(deliveries.len(), expected);
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        validate(&json!({
+            "id": "https://remote.example/activities/1",
+            "type": "Like",
+            "actor": "https://remote.example/users/bob",
+            "object": "https://example.com/objects/1",
+        }))
+        .unwrap();
+        // transient, and with an embedded actor
+        validate(&json!({
+            "id": null,
+            "type": "Like",
+            "actor": {"id": "https://remote.example/users/bob"},
+        }))
+        .unwrap();
+
+        for (activity, message) in [
+            (json!([]), "expected a JSON object"),
+            (
+                json!({"id": "https://remote.example/1", "actor": "https://remote.example/users/bob"}),
+                "missing type",
+            ),
+            (
+                json!({"id": "https://remote.example/1", "type": 1, "actor": "https://remote.example/users/bob"}),
+                "type must be a string",
+            ),
+            (
+                json!({"type": "Like", "actor": "https://remote.example/users/bob"}),
+                "Like is missing id",
+            ),
+            (
+                json!({"id": 1, "type": "Like", "actor": "https://remote.example/users/bob"}),
+                "Like id must be a string",
+            ),
+            (
+                json!({"id": "https://remote.example/1", "type": "Like"}),
+                "Like is missing actor",
+            ),
+            (
+                json!({"id": "https://remote.example/1", "type": "Like", "actor": {"name": "bob"}}),
+                "Like is missing actor",
+            ),
+        ] {
+            assert_eq!(validate(&activity).unwrap_err(), message);
+        }
+    }
+
+    /// Serves the actor a Move points at, returning its IRI.
+    async fn move_target(also_known_as: Value) -> String {
+        let actor = json!({
+            "id": "https://new.example/users/bob",
+            "type": "Person",
+            "alsoKnownAs": also_known_as,
+        });
+        remote::mock_server(actor.to_string(), true).await
+    }
+
+    fn move_activity(target: &str) -> Value {
+        json!({
+            "id": "https://old.example/users/bob#moves/1",
+            "type": "Move",
+            "actor": "https://old.example/users/bob",
+            "object": "https://old.example/users/bob",
+            "target": target,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_move_records_target() {
+        let objects = InMemoryObjectStore::new();
+        let target = move_target(json!(["https://old.example/users/bob"])).await;
+        move_actor(&remote::dev_client(), &objects, &move_activity(&target))
+            .await
+            .unwrap();
+        assert_eq!(
+            objects
+                .moved_to("https://old.example/users/bob")
+                .await
+                .unwrap(),
+            Some(target)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unverified_move() {
+        let objects = InMemoryObjectStore::new();
+        for also_known_as in [json!([]), json!(["https://old.example/users/alice"])] {
+            let target = move_target(also_known_as).await;
+            let (status, _) = move_actor(&remote::dev_client(), &objects, &move_activity(&target))
+                .await
+                .unwrap_err();
+            assert_eq!(status, StatusCode::FORBIDDEN);
+        }
+        assert!(objects
+            .moved_to("https://old.example/users/bob")
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_move_someone_else() {
+        let objects = InMemoryObjectStore::new();
+        let activity = json!({
+            "id": "https://old.example/users/mallory#moves/1",
+            "type": "Move",
+            "actor": "https://old.example/users/mallory",
+            "object": "https://old.example/users/bob",
+            "target": "https://new.example/users/mallory",
+        });
+        let (status, _) = move_actor(&remote::dev_client(), &objects, &activity)
+            .await
+            .unwrap_err();
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert!(objects
+            .moved_to("https://old.example/users/bob")
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_parse_activity() {
+        assert_eq!(
+            parse_activity(br#"{"type": "Like"}"#).unwrap(),
+            json!({"type": "Like"})
+        );
+
+        for (body, message) in [
+            (&b""[..], "body is empty"),
+            (b"  \n", "body is empty"),
+            (
+                b"<!DOCTYPE html><html>502 Bad Gateway</html>",
+                "body looks like HTML or XML, not JSON",
+            ),
+            (
+                b"\xff\xfe{}",
+                "body is not UTF-8 text (invalid byte at offset 0)",
+            ),
+            (
+                b"{\"type\": \"Like\",\n \"actor\": }",
+                "expected value at line 2, column 11",
+            ),
+            (
+                b"{\"type\": \"Like\"",
+                "unexpected end of JSON at line 1, column 15",
+            ),
+        ] {
+            assert_eq!(parse_activity(body).unwrap_err(), message);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_invalid_json_at_inbox() {
+        use axum::body::Body;
+        use axum::extract::ConnectInfo;
+        use axum::http::Request;
+        use std::net::SocketAddr;
+        use tower::ServiceExt;
+
+        let mut request = Request::post("/inbox")
+            .body(Body::from("{\"type\": \"Like\",\n \"actor\": }"))
+            .unwrap();
+        request
+            .extensions_mut()
+            .insert(ConnectInfo::<SocketAddr>("10.0.0.5:4711".parse().unwrap()));
+        let response = crate::tests::test_app(&["--unsigned-peers", "10.0.0.5"])
+            .oneshot(request)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(
+            body,
+            "Error parsing activity: expected value at line 2, column 11"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_suspended_actor() {
+        let objects = InMemoryObjectStore::new();
+        let activity = json!({
+            "id": "https://remote.example/activities/1",
+            "type": "Like",
+            "actor": "https://remote.example/users/mallory",
+            "object": "https://example.com/objects/1",
+        });
+        assert!(!from_suspended_actor(&config(), &objects, &activity)
+            .await
+            .unwrap());
+
+        objects
+            .suspend("https://remote.example/users/mallory")
+            .await
+            .unwrap();
+        let (status, message) = from_suspended_actor(&config(), &objects, &activity)
+            .await
+            .unwrap_err();
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(message, "https://remote.example/users/mallory is suspended");
+
+        let cfg = Config::parse_from([
+            "rap-server",
+            "--domain",
+            "example.com",
+            "--suspended-actors",
+            "drop",
+        ]);
+        assert!(from_suspended_actor(&cfg, &objects, &activity)
+            .await
+            .unwrap());
+
+        // someone else is fine
+        let mut activity = activity;
+        activity["actor"] = json!({"id": "https://remote.example/users/bob"});
+        assert!(!from_suspended_actor(&config(), &objects, &activity)
+            .await
+            .unwrap());
+    }
+
+    #[test]
+    fn test_digest_mismatch() {
+        use metrics_util::debugging::{DebugValue, DebuggingRecorder, Snapshotter};
+        use metrics_util::MetricKind;
+
+        // each thread counts separately, so other tests can't get in the way.
+        // Installing fails if another test already did, which is fine
+        let _ = DebuggingRecorder::per_thread().install();
+        let mismatches = || {
+            Snapshotter::current_thread_snapshot()?
+                .into_vec()
+                .into_iter()
+                .find(|(key, ..)| {
+                    key.kind() == MetricKind::Counter && key.key().name() == "digest_mismatch_total"
+                })
+                .map(|(.., value)| value)
+        };
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "digest",
+            // SHA-256 of `{"type":"Follow"}`
+            "SHA-256=GYwYnH3BiO6aICFt0ThC5bUIJ4byvqdpWtR8m5fNkww="
+                .parse()
+                .unwrap(),
+        );
+        verify_digest(&headers, br#"{"type":"Follow"}"#).unwrap();
+        assert_eq!(mismatches(), None);
+
+        let (status, message) = verify_digest(&headers, br#"{"type":"Follow!"}"#).unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(
+            message.starts_with(
+                "Error verifying digest: SHA-256 digest does not match body: header has GYwYnH3B..."
+            ),
+            "{}",
+            message
+        );
+        assert_eq!(mismatches(), Some(DebugValue::Counter(1)));
+
+        // a digest we can't check at all isn't a mismatch
+        headers.insert("digest", "MD5=HUXZLQLMuI/KZ5KDcJPcOA==".parse().unwrap());
+        verify_digest(&headers, br#"{"type":"Follow"}"#).unwrap_err();
+        assert_eq!(mismatches(), Some(DebugValue::Counter(1)));
+    }
+
+    fn key_cache() -> KeyCache {
+        KeyCache::new(remote::dev_client(), std::time::Duration::from_secs(60))
+    }
+
+    async fn stored_note() -> InMemoryObjectStore {
+        let objects = InMemoryObjectStore::new();
+        objects
+            .insert(json!({
+                "id": "https://remote.example/notes/1",
+                "type": "Note",
+                "attributedTo": "https://remote.example/users/bob",
+                "content": "helo",
+            }))
+            .await;
+        objects
+    }
+
+    fn update_note(actor: &str, content: &str) -> Value {
+        json!({
+            "id": "https://remote.example/updates/1",
+            "type": "Update",
+            "actor": actor,
+            "object": {
+                "id": "https://remote.example/notes/1",
+                "type": "Note",
+                "attributedTo": actor,
+                "content": content,
+            },
+        })
+    }
+
+    #[tokio::test]
+    async fn test_update_replaces_note() {
+        let objects = stored_note().await;
+        let bob = "https://remote.example/users/bob";
+        let activity = update_note(bob, "hello");
+        update(&objects, &key_cache(), bob, &activity)
+            .await
+            .unwrap();
+
+        let note = objects
+            .get("https://remote.example/notes/1")
+            .await
+            .unwrap()
+            .found()
+            .unwrap();
+        assert_eq!(note["content"], "hello");
+    }
+
+    #[tokio::test]
+    async fn test_update_adds_content_warning() {
+        let objects = stored_note().await;
+        let bob = "https://remote.example/users/bob";
+        let mut activity = update_note(bob, "hello");
+        activity["object"]["summary"] = json!("greetings");
+        activity["object"]["sensitive"] = json!(true);
+        update(&objects, &key_cache(), bob, &activity)
+            .await
+            .unwrap();
+        let note = objects
+            .get("https://remote.example/notes/1")
+            .await
+            .unwrap()
+            .found()
+            .unwrap();
+        let note: Object = serde_json::from_value(note).unwrap();
+        assert!(note.sensitive());
+        assert_eq!(note.summary(), Some("greetings"));
+
+        // an object we can't read isn't stored
+        activity["object"]["sensitive"] = json!("very");
+        let (status, _) = update(&objects, &key_cache(), bob, &activity)
+            .await
+            .unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_update_someone_elses_note() {
+        let objects = stored_note().await;
+        let mallory = "https://remote.example/users/mallory";
+        let activity = update_note(mallory, "pwned");
+        let (status, _) = update(&objects, &key_cache(), mallory, &activity)
+            .await
+            .unwrap_err();
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let note = objects
+            .get("https://remote.example/notes/1")
+            .await
+            .unwrap()
+            .found()
+            .unwrap();
+        assert_eq!(note["content"], "helo");
+    }
+
+    #[tokio::test]
+    async fn test_update_unknown_note() {
+        let objects = InMemoryObjectStore::new();
+        let bob = "https://remote.example/users/bob";
+        let activity = update_note(bob, "hello");
+        update(&objects, &key_cache(), bob, &activity)
+            .await
+            .unwrap();
+        assert_eq!(
+            objects.get("https://remote.example/notes/1").await.unwrap(),
+            Lookup::Missing
+        );
+    }
+
+    #[tokio::test]
+    async fn test_delete_note() {
+        let objects = stored_note().await;
+        let activity = |actor: &str| {
+            json!({
+                "id": "https://remote.example/deletes/1",
+                "type": "Delete",
+                "actor": actor,
+                "object": "https://remote.example/notes/1",
+            })
+        };
+
+        let mallory = "https://remote.example/users/mallory";
+        let (status, _) = delete(&objects, mallory, &activity(mallory))
+            .await
+            .unwrap_err();
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let bob = "https://remote.example/users/bob";
+        delete(&objects, bob, &activity(bob)).await.unwrap();
+        assert!(matches!(
+            objects.get("https://remote.example/notes/1").await.unwrap(),
+            Lookup::Gone(_)
+        ));
+        // a retried delete is fine
+        delete(&objects, bob, &activity(bob)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_update_actor_drops_cached_keys() {
+        let bob = "https://remote.example/users/bob";
+        let public_key = Key::new(bob.to_string()).unwrap().public_key().unwrap();
+        let keys = key_cache();
+        let key_id = "https://remote.example/users/bob#main-key";
+        keys.insert(key_id, public_key.clone()).await;
+        // a key bob had before, which the update no longer lists
+        let old_key_id = "https://remote.example/users/bob#old-key";
+        keys.insert(old_key_id, public_key).await;
+        let carol_key_id = "https://remote.example/users/carol#main-key";
+        let carol = Key::new("https://remote.example/users/carol".to_string()).unwrap();
+        keys.insert(carol_key_id, carol.public_key().unwrap()).await;
+
+        let activity = json!({
+            "id": "https://remote.example/updates/2",
+            "type": "Update",
+            "actor": bob,
+            "object": {
+                "id": bob,
+                "type": "Person",
+                "publicKey": {
+                    "id": key_id,
+                    "owner": bob,
+                    "publicKeyPem": "",
+                },
+            },
+        });
+        update(&InMemoryObjectStore::new(), &keys, bob, &activity)
+            .await
+            .unwrap();
+        assert!(!keys.contains(key_id).await);
+        assert!(!keys.contains(old_key_id).await);
+        assert!(keys.contains(carol_key_id).await);
+    }
+
+    #[tokio::test]
+    async fn test_update_in_someone_elses_name() {
+        let objects = Arc::new(stored_note().await);
+        let bob = "https://remote.example/users/bob";
+        let activity = update_note(bob, "pwned");
+
+        // mallory's valid signature doesn't make her bob
+        let response = post_activity(
+            config(),
+            objects.clone(),
+            Some("https://remote.example/users/mallory"),
+            &activity,
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let note = objects
+            .get("https://remote.example/notes/1")
+            .await
+            .unwrap()
+            .found()
+            .unwrap();
+        assert_eq!(note["content"], "helo");
+
+        let response = post_activity(config(), objects.clone(), Some(bob), &activity).await;
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        let note = objects
+            .get("https://remote.example/notes/1")
+            .await
+            .unwrap()
+            .found()
+            .unwrap();
+        assert_eq!(note["content"], "pwned");
+    }
 }
@@ -0,0 +1,91 @@
+use axum::http::HeaderMap;
+use axum::response::Response;
+use axum::{Extension, Json};
+use serde_json::{json, Value};
+use std::error::Error;
+use std::sync::Arc;
+
+use crate::config::Config;
+use crate::context::context;
+use crate::key::Key;
+use crate::utils::{negotiated_json, web_err_500, WebError};
+
+/// The server's own actor. Servers running in secure mode only serve actors
+/// to signed requests, so we sign our fetches as this actor instead of as a
+/// person, whose key they would first have to fetch from us.
+pub struct InstanceActor {
+    pub id: String,
+    pub key: Key,
+}
+
+impl InstanceActor {
+    pub fn new(cfg: &Config) -> Result<Self, Box<dyn Error>> {
+        let id = cfg.instance_actor_url();
+        Ok(Self {
+            key: Key::new(id.clone())?,
+            id,
+        })
+    }
+}
+
+/// What's at `/`: which server this is and where to find out more about it,
+/// for anyone poking at the domain.
+pub async fn root(Extension(cfg): Extension<Config>) -> Json<Value> {
+    Json(json!({
+        "domain": cfg.domain,
+        "software": {
+            "name": cfg.software_name,
+            "version": cfg.software_version,
+        },
+        "actor": cfg.instance_actor_url(),
+        "nodeinfo": format!("{}/.well-known/nodeinfo", cfg.base_url()),
+    }))
+}
+
+pub async fn json(
+    Extension(cfg): Extension<Config>,
+    Extension(instance): Extension<Arc<InstanceActor>>,
+    headers: HeaderMap,
+) -> Result<Response, WebError> {
+    let actor = json!({
+        "@context": context(&cfg),
+        "id": instance.id,
+        "type": "Application",
+        "preferredUsername": cfg.domain,
+        "inbox": format!("{}/inbox", instance.id),
+        "publicKey": instance.key.public_key().map_err(|e| {
+            web_err_500(format!("Error getting public key: {}", e))
+        })?,
+    });
+    Ok(negotiated_json(&headers, actor))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::{body_json, test_app};
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_instance_actor() {
+        let response = test_app(&[])
+            .oneshot(Request::get("/actor").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let actor = body_json(response).await;
+        assert_eq!(actor["id"], "https://example.com/actor");
+        assert_eq!(actor["type"], "Application");
+        assert_eq!(
+            actor["publicKey"]["id"],
+            "https://example.com/actor/#main-key"
+        );
+        assert_eq!(actor["publicKey"]["owner"], "https://example.com/actor");
+        assert!(actor["publicKey"]["publicKeyPem"]
+            .as_str()
+            .unwrap()
+            .starts_with("-----BEGIN PUBLIC KEY-----"));
+    }
+}
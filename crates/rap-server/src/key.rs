@@ -1,6 +1,12 @@
+use crate::actor_id::{ActorId, InvalidActorId};
 use crate::crypto;
+use crate::remote;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::error::Error;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, Semaphore};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Key {
@@ -9,6 +15,9 @@ pub struct Key {
     private_key_pem: String,
     #[serde(rename = "publicKey")]
     public_key_pem: String,
+    /// How many times this key has been rotated, see [`Key::rotate`].
+    #[serde(default)]
+    generation: u32,
 }
 
 impl Key {
@@ -18,12 +27,48 @@ impl Key {
             owner,
             private_key_pem,
             public_key_pem,
+            generation: 0,
         })
     }
 
+    /// A key for `owner` from an existing PKCS#8 PEM encoded private key.
+    pub fn from_private_key_pem(
+        owner: String,
+        private_key_pem: String,
+    ) -> Result<Self, Box<dyn Error>> {
+        let public_key_pem = crypto::public_key_pem(&private_key_pem)?;
+        Ok(Self {
+            owner,
+            private_key_pem,
+            public_key_pem,
+            generation: 0,
+        })
+    }
+
+    /// A fresh keypair for the same owner. It gets its own `keyId`, so the
+    /// old key can still be advertised while signatures made with it are in
+    /// flight.
+    pub fn rotate(&self) -> Result<Self, Box<dyn Error>> {
+        let (private_key_pem, public_key_pem) = crypto::generate_keypair()?;
+        Ok(Self {
+            owner: self.owner.clone(),
+            private_key_pem,
+            public_key_pem,
+            generation: self.generation + 1,
+        })
+    }
+
+    /// The `keyId` other servers use to fetch our [`PublicKey`].
+    pub fn key_id(&self) -> String {
+        match self.generation {
+            0 => format!("{}/#main-key", self.owner),
+            n => format!("{}/#main-key-{}", self.owner, n),
+        }
+    }
+
     pub fn public_key(&self) -> Result<PublicKey, Box<dyn Error>> {
         Ok(PublicKey {
-            id: format!("{}/#main-key", self.owner),
+            id: self.key_id(),
             owner: self.owner.clone(),
             public_key_pem: self.public_key_pem.clone(),
         })
@@ -32,6 +77,21 @@ impl Key {
     pub fn sign(&self, data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
         crypto::sign(&self.private_key_pem, data)
     }
+
+    #[cfg(test)]
+    pub fn sign_pss(&self, data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        crypto::sign_pss(&self.private_key_pem, data)
+    }
+}
+
+impl rap_core::signing::Signer for Key {
+    fn key_id(&self) -> String {
+        Key::key_id(self)
+    }
+
+    fn sign(&self, data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        Key::sign(self, data)
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
@@ -42,31 +102,188 @@ pub struct PublicKey {
     public_key_pem: String,
 }
 
+/// Actors usually have one key, but may list a few, e.g. while rotating.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+enum PublicKeys {
+    One(PublicKey),
+    Many(Vec<PublicKey>),
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct Actor {
     id: String,
     inbox: String,
     #[serde(rename = "publicKey")]
-    public_key: PublicKey,
+    public_key: PublicKeys,
 }
 
 impl PublicKey {
-    pub async fn from_remote(id: &str) -> Result<Self, Box<dyn Error>> {
-        let resp = reqwest::Client::new()
-            .get(id)
-            .header(
-                "Accept",
-                "application/ld+json; profile=\"http://www.w3.org/ns/activitystreams\"",
-            )
-            .send()
-            .await?;
-        let resp = resp.json::<Actor>().await?;
-        Ok(resp.public_key)
+    pub async fn from_remote(client: &remote::Client, id: &str) -> Result<Self, Box<dyn Error>> {
+        let actor: Actor = client.get_activity_json(id).await?;
+        match actor.public_key {
+            PublicKeys::One(key) => Ok(key),
+            PublicKeys::Many(keys) => keys
+                .into_iter()
+                .find(|key| key.id == id)
+                .ok_or_else(|| format!("Actor {} has no key {}", actor.id, id).into()),
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// The actor the key says it belongs to.
+    pub fn owner(&self) -> &str {
+        &self.owner
+    }
+
+    pub fn pem(&self) -> &str {
+        &self.public_key_pem
+    }
+
+    /// The SHA-256 fingerprint of the key, see [`crypto::fingerprint`].
+    pub fn fingerprint(&self) -> Result<String, Box<dyn Error>> {
+        crypto::fingerprint(&self.public_key_pem)
     }
 
     pub fn verify(&self, data: &[u8], sig: &[u8]) -> Result<(), Box<dyn Error>> {
         crypto::verify(&self.public_key_pem, data, sig)
     }
+
+    pub fn verify_pss(&self, data: &[u8], sig: &[u8]) -> Result<(), Box<dyn Error>> {
+        crypto::verify_pss(&self.public_key_pem, data, sig)
+    }
+}
+
+/// Histogram of how long fetching a remote key took, labeled with the
+/// `outcome` of the fetch.
+pub const KEY_FETCH_DURATION: &str = "key_fetch_duration_seconds";
+
+/// Remote public keys we have fetched, kept for `ttl` so every signed request
+/// from the same server doesn't cost us another fetch.
+#[derive(Clone)]
+pub struct KeyCache {
+    client: remote::Client,
+    ttl: Duration,
+    keys: Arc<Mutex<HashMap<String, (Instant, PublicKey)>>>,
+    fetches: Arc<Semaphore>,
+    refetch_cooldown: Duration,
+    /// When each key was last dropped by [`KeyCache::invalidate_for_refetch`].
+    refetched: Arc<Mutex<HashMap<String, Instant>>>,
+}
+
+impl KeyCache {
+    pub fn new(client: remote::Client, ttl: Duration) -> Self {
+        Self {
+            client,
+            ttl,
+            keys: Arc::new(Mutex::new(HashMap::new())),
+            fetches: Arc::new(Semaphore::new(Semaphore::MAX_PERMITS)),
+            refetch_cooldown: Duration::ZERO,
+            refetched: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Fetches at most `limit` keys at once. A burst of requests signed with
+    /// keys we don't have yet then waits its turn instead of opening a
+    /// connection each.
+    pub fn with_fetch_limit(mut self, limit: usize) -> Self {
+        self.fetches = Arc::new(Semaphore::new(limit));
+        self
+    }
+
+    /// Lets [`KeyCache::invalidate_for_refetch`] drop the same key at most
+    /// once per `cooldown`.
+    pub fn with_refetch_cooldown(mut self, cooldown: Duration) -> Self {
+        self.refetch_cooldown = cooldown;
+        self
+    }
+
+    /// Returns the key for `id` and whether it came out of the cache, i.e.
+    /// whether it might be stale.
+    pub async fn get(&self, id: &str) -> Result<(PublicKey, bool), Box<dyn Error>> {
+        if let Some(key) = self.cached(id).await {
+            return Ok((key, true));
+        }
+
+        let _permit = self.fetches.acquire().await?;
+        // someone else may have fetched it while we waited
+        if let Some(key) = self.cached(id).await {
+            return Ok((key, true));
+        }
+        let key = self.fetch(id).await?;
+        self.keys
+            .lock()
+            .await
+            .insert(id.to_string(), (Instant::now(), key.clone()));
+        Ok((key, false))
+    }
+
+    /// Fetches the key for `id`, timing it in [`KEY_FETCH_DURATION`].
+    async fn fetch(&self, id: &str) -> Result<PublicKey, Box<dyn Error>> {
+        let started = Instant::now();
+        let fetched = PublicKey::from_remote(&self.client, id).await;
+        let outcome = if fetched.is_ok() { "ok" } else { "error" };
+        metrics::histogram!(KEY_FETCH_DURATION, started.elapsed().as_secs_f64(), "outcome" => outcome);
+        fetched
+    }
+
+    async fn cached(&self, id: &str) -> Option<PublicKey> {
+        match self.keys.lock().await.get(id) {
+            Some((fetched, key)) if fetched.elapsed() < self.ttl => Some(key.clone()),
+            _ => None,
+        }
+    }
+
+    /// Checks that `id` is a key IRI we may fetch.
+    pub fn key_id(&self, id: &str) -> Result<ActorId, InvalidActorId> {
+        self.client.actor_id(id)
+    }
+
+    /// Forgets `id`, so the next [`KeyCache::get`] fetches it again.
+    pub async fn invalidate(&self, id: &str) {
+        self.keys.lock().await.remove(id);
+    }
+
+    /// Forgets `id` like [`KeyCache::invalidate`], unless it was already
+    /// forgotten this way within the refetch cooldown. Returns whether it was.
+    ///
+    /// Anyone can send us a bad signature naming someone else's key, so this
+    /// keeps them from making us refetch it with every request.
+    pub async fn invalidate_for_refetch(&self, id: &str) -> bool {
+        let mut refetched = self.refetched.lock().await;
+        refetched.retain(|_, at| at.elapsed() < self.refetch_cooldown);
+        if refetched.contains_key(id) {
+            return false;
+        }
+        refetched.insert(id.to_string(), Instant::now());
+        drop(refetched);
+        self.invalidate(id).await;
+        true
+    }
+
+    /// Drops every cached key `owner` says is theirs.
+    pub async fn invalidate_owner(&self, owner: &str) {
+        self.keys
+            .lock()
+            .await
+            .retain(|_, (_, key)| key.owner() != owner);
+    }
+
+    #[cfg(test)]
+    pub async fn contains(&self, id: &str) -> bool {
+        self.keys.lock().await.contains_key(id)
+    }
+
+    #[cfg(test)]
+    pub async fn insert(&self, id: &str, key: PublicKey) {
+        self.keys
+            .lock()
+            .await
+            .insert(id.to_string(), (Instant::now(), key));
+    }
 }
 
 #[cfg(test)]
@@ -123,10 +340,82 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_rotate() {
+        let key = Key::new("https://example.com/users/alice".to_string()).unwrap();
+        let rotated = key.rotate().unwrap();
+        assert_eq!(rotated.owner, key.owner);
+        assert_ne!(rotated.public_key_pem, key.public_key_pem);
+        assert_eq!(key.key_id(), "https://example.com/users/alice/#main-key");
+        assert_eq!(
+            rotated.key_id(),
+            "https://example.com/users/alice/#main-key-1"
+        );
+
+        let data = b"some data to sign";
+        let signature = rotated.sign(data).unwrap();
+        let public_key = rotated.public_key().unwrap();
+        public_key.verify(data, &signature).unwrap();
+        // the old key can't vouch for the new one
+        key.public_key()
+            .unwrap()
+            .verify(data, &signature)
+            .unwrap_err();
+    }
+
+    #[test]
+    fn test_key_without_generation() {
+        let key = Key::new("owner5".to_string()).unwrap();
+        let mut json = serde_json::to_value(&key).unwrap();
+        json.as_object_mut().unwrap().remove("generation");
+        let key: Key = serde_json::from_value(json).unwrap();
+        assert_eq!(key.key_id(), "owner5/#main-key");
+    }
+
+    fn client() -> remote::Client {
+        use clap::Parser;
+        let cfg = crate::config::Config::parse_from(["rap-server", "--domain", "example.com"]);
+        remote::Client::new(&cfg)
+    }
+
     #[tokio::test]
     async fn test_remote_public_key() {
-        let key = PublicKey::from_remote("https://hotdog.place/users/renning#main-key").await;
-        assert!(key.is_ok(), "Failed to fetch remote public key");
+        let client = client().with_fixtures(&[remote::RENNING]);
+        let key = PublicKey::from_remote(&client, "https://hotdog.place/users/renning#main-key")
+            .await
+            .unwrap();
+        assert_eq!(key.id(), "https://hotdog.place/users/renning#main-key");
+        assert_eq!(key.owner(), "https://hotdog.place/users/renning");
+        assert!(key.pem().starts_with("-----BEGIN PUBLIC KEY-----\n"));
+    }
+
+    #[tokio::test]
+    async fn test_remote_public_key_of_many() {
+        let actor = r#"{"id":"https://remote.example/users/bob","inbox":"https://remote.example/users/bob/inbox","publicKey":[{"id":"https://remote.example/users/bob#main-key","owner":"https://remote.example/users/bob","publicKeyPem":"main"},{"id":"https://remote.example/users/bob#other-key","owner":"https://remote.example/users/bob","publicKeyPem":"other"}]}"#;
+        let client = client().with_fixtures(&[("https://remote.example/users/bob", actor)]);
+
+        let key = PublicKey::from_remote(&client, "https://remote.example/users/bob#other-key")
+            .await
+            .unwrap();
+        assert_eq!(key.pem(), "other");
+
+        let err = PublicKey::from_remote(&client, "https://remote.example/users/bob#gone-key")
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Actor https://remote.example/users/bob has no key https://remote.example/users/bob#gone-key"
+        );
+
+        let missing =
+            PublicKey::from_remote(&client, "https://remote.example/users/carol#main-key").await;
+        assert!(missing.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_remote_public_key_loopback() {
+        let key = PublicKey::from_remote(&client(), "http://127.0.0.1/users/alice#main-key").await;
+        assert!(key.is_err(), "Fetched a key from a loopback address");
     }
 
     #[test]
@@ -157,4 +446,122 @@ content-type: application/activity+json"#;
         let comparison = "Hello world";
         pubkey.verify(comparison.as_bytes(), &sig).unwrap();
     }
+
+    /// Serves an actor for every request, each only once `release` has a
+    /// permit for it, recording the most requests it was ever holding at
+    /// once. Every request is announced on the returned channel as it
+    /// comes in.
+    async fn held_actor_server(
+        most: Arc<std::sync::atomic::AtomicUsize>,
+        release: Arc<Semaphore>,
+    ) -> (String, tokio::sync::mpsc::UnboundedReceiver<()>) {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (arrived, arrivals) = tokio::sync::mpsc::unbounded_channel();
+        let active = Arc::new(AtomicUsize::new(0));
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let (active, most) = (active.clone(), most.clone());
+                let (arrived, release) = (arrived.clone(), release.clone());
+                tokio::spawn(async move {
+                    let mut buf = [0; 4096];
+                    let _ = socket.read(&mut buf).await.unwrap();
+                    let now = active.fetch_add(1, Ordering::SeqCst) + 1;
+                    most.fetch_max(now, Ordering::SeqCst);
+                    let _ = arrived.send(());
+                    release.acquire().await.unwrap().forget();
+                    let body = r#"{"id":"https://remote.example/users/bob","inbox":"https://remote.example/users/bob/inbox","publicKey":{"id":"https://remote.example/users/bob#main-key","owner":"https://remote.example/users/bob","publicKeyPem":""}}"#;
+                    let response = format!(
+                        "HTTP/1.1 200 OK\r\ncontent-type: application/activity+json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                        body.len(),
+                        body
+                    );
+                    active.fetch_sub(1, Ordering::SeqCst);
+                    let _ = socket.write_all(response.as_bytes()).await;
+                });
+            }
+        });
+        (format!("http://{}/", addr), arrivals)
+    }
+
+    #[tokio::test]
+    async fn test_fetch_limit() {
+        let most = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let release = Arc::new(Semaphore::new(0));
+        let (url, mut arrivals) = held_actor_server(most.clone(), release.clone()).await;
+        let keys = KeyCache::new(remote::dev_client(), Duration::from_secs(60)).with_fetch_limit(2);
+
+        let fetches = (0..8).map(|i| {
+            let keys = keys.clone();
+            let id = format!("{}users/{}#main-key", url, i);
+            tokio::spawn(async move { keys.get(&id).await.map(|_| ()).map_err(|e| e.to_string()) })
+        });
+        let fetches: Vec<_> = fetches.collect();
+
+        // two fetches reach the server and are held there, which leaves the
+        // other six waiting their turn
+        arrivals.recv().await.unwrap();
+        arrivals.recv().await.unwrap();
+        assert_eq!(keys.fetches.available_permits(), 0);
+
+        release.add_permits(fetches.len());
+        for fetch in fetches {
+            fetch.await.unwrap().unwrap();
+        }
+        assert_eq!(most.load(std::sync::atomic::Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_duration() {
+        use metrics_util::debugging::{DebugValue, DebuggingRecorder, Snapshotter};
+
+        // see inbox::tests::test_digest_mismatch
+        let _ = DebuggingRecorder::per_thread().install();
+        let observations = |outcome: &str| {
+            Snapshotter::current_thread_snapshot()
+                .map(|snapshot| snapshot.into_vec())
+                .unwrap_or_default()
+                .into_iter()
+                .find(|(key, ..)| {
+                    let key = key.key();
+                    key.name() == KEY_FETCH_DURATION
+                        && key
+                            .labels()
+                            .any(|l| l.key() == "outcome" && l.value() == outcome)
+                })
+                .map(|(.., value)| match value {
+                    DebugValue::Histogram(values) => values.len(),
+                    other => panic!("{} is not a histogram: {:?}", KEY_FETCH_DURATION, other),
+                })
+        };
+
+        let keys = KeyCache::new(remote::dev_client(), Duration::from_secs(60));
+        let actor = serde_json::json!({
+            "id": "https://remote.example/users/bob",
+            "inbox": "https://remote.example/users/bob/inbox",
+            "publicKey": Key::new("https://remote.example/users/bob".to_string())
+                .unwrap()
+                .public_key()
+                .unwrap(),
+        });
+        let url = remote::mock_server(actor.to_string(), true).await;
+        let id = format!("{}users/bob#main-key", url);
+        keys.get(&id).await.unwrap();
+        assert_eq!(observations("ok"), Some(1));
+
+        // cached keys aren't fetched, so there is nothing to time. Taking a
+        // snapshot drains histograms, hence no observations at all
+        keys.get(&id).await.unwrap();
+        assert_eq!(observations("ok"), Some(0));
+
+        let url = remote::mock_server("not an actor".to_string(), true).await;
+        keys.get(&format!("{}users/bob#main-key", url))
+            .await
+            .unwrap_err();
+        assert_eq!(observations("error"), Some(1));
+    }
 }
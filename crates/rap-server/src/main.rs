@@ -1,28 +1,57 @@
 extern crate core;
 
+mod actor_id;
+mod addressing;
+mod admin;
+mod collections;
 mod config;
+mod context;
 mod crypto;
+mod debug;
+mod delivery;
+mod digest;
 mod inbox;
+mod instance;
 mod key;
+mod nodeinfo;
+mod objects;
+mod remote;
+mod replay;
+mod scheme;
+mod seed;
 mod signature;
 mod signed;
+mod socket;
+mod token;
 mod users;
 mod utils;
 mod webfinger;
+mod webhook;
 
-use crate::config::Config;
-use crate::users::InMemoryPeopleStore;
+use crate::config::{Config, ErrorFormat};
+use crate::delivery::DeliveryQueue;
+use crate::instance::InstanceActor;
+use crate::objects::{InMemoryObjectStore, ObjectStore};
+use crate::users::{InMemoryPeopleStore, PeopleStore};
+use crate::utils::{problem_details, web_err};
+use axum::body::Body;
+use axum::extract::MatchedPath;
+use axum::http::header::CONTENT_LENGTH;
 use axum::http::{Request, StatusCode};
 use axum::middleware::Next;
-use axum::response::Response;
-use axum::routing::post;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{post, put};
 use axum::{middleware, response::Json, routing::get, Extension, Router};
 use axum_prometheus::PrometheusMetricLayerBuilder;
 use clap::Parser;
-use log::info;
+use hyper::server::conn::AddrIncoming;
 use serde_json::{json, Value};
+use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinSet;
 use tower::ServiceBuilder;
+use tracing::{error, info, info_span, warn, Instrument};
 
 // `&'static str` becomes a `200 OK` with `content-type: text/plain; charset=utf-8`
 async fn plain_text() -> &'static str {
@@ -35,44 +64,285 @@ async fn json() -> Json<Value> {
     Json(json!({ "data": 42 }))
 }
 
+fn app(
+    cfg: &Config,
+    people: Arc<dyn PeopleStore>,
+    objects: Arc<dyn ObjectStore>,
+    queue: DeliveryQueue,
+    instance: Arc<InstanceActor>,
+) -> Router {
+    let mut app = Router::new()
+        .route("/.well-known/webfinger", get(webfinger::json))
+        .route("/.well-known/host-meta", get(webfinger::host_meta))
+        .route("/actor", get(instance::json))
+        .route(
+            "/actor/inbox",
+            post(inbox::json).route_layer(middleware::from_fn(signed::require_signature)),
+        )
+        .route(
+            "/inbox",
+            post(inbox::json).route_layer(middleware::from_fn(signed::require_signature)),
+        )
+        .route("/.well-known/nodeinfo", get(nodeinfo::well_known))
+        .route("/nodeinfo/2.0", get(nodeinfo::v2_0))
+        .route("/nodeinfo/2.1", get(nodeinfo::v2_1))
+        .route("/users/:id", get(users::json))
+        .route(
+            "/users/:id/inbox",
+            post(inbox::json)
+                .route_layer(middleware::from_fn(signed::require_signature))
+                .get(users::inbox),
+        )
+        .route("/users/:id/public_key", get(users::public_key))
+        .route("/users/:id/outbox", get(users::outbox))
+        .route("/users/:id/followers", get(users::followers))
+        .route("/users/:id/following", get(users::following))
+        .route("/users/:id/liked", get(users::liked))
+        .route("/objects/:id", get(objects::object))
+        .route("/objects/:id/shares", get(objects::shares))
+        .route("/objects/:id/likes", get(objects::likes))
+        .route(
+            "/admin/users",
+            get(admin::list_users).post(admin::create_user),
+        )
+        .route(
+            "/admin/users/:id/also_known_as",
+            put(admin::set_also_known_as),
+        )
+        .route("/admin/users/:id/rotate_key", post(admin::rotate_key))
+        .route("/admin/users/:id/inbox", get(admin::inbox))
+        .route("/admin/users/:id/token", post(admin::mint_token))
+        .route("/admin/users/:id/keys", get(admin::keys))
+        .route(
+            "/admin/suspended",
+            get(admin::list_suspended)
+                .put(admin::suspend)
+                .delete(admin::unsuspend),
+        );
+
+    app = if cfg.dev_routes {
+        app.route("/", get(plain_text))
+            .route("/plain_text", get(plain_text))
+            .route("/json", get(json))
+    } else {
+        app.route("/", get(instance::root))
+    };
+    if cfg.debug_endpoints {
+        app = app
+            .route("/debug/users/:id/inbox", post(debug::signature))
+            .route("/debug/deliveries", get(debug::deliveries));
+    }
+    let queue = if cfg.debug_endpoints {
+        queue.keep_recent(debug::RECENT_DELIVERIES)
+    } else {
+        queue
+    };
+
+    let client = remote::Client::new(cfg).signed_by(instance.key.clone());
+    let app = app.layer(
+        ServiceBuilder::new()
+            .layer(Extension(people))
+            .layer(Extension(objects))
+            .layer(Extension(queue))
+            .layer(Extension(
+                key::KeyCache::new(client.clone(), Duration::from_secs(cfg.key_cache_ttl))
+                    .with_fetch_limit(cfg.max_key_fetches as usize)
+                    .with_refetch_cooldown(Duration::from_secs(cfg.key_refetch_cooldown)),
+            ))
+            .layer(Extension(remote::ActorCache::new(
+                client.clone(),
+                Duration::from_secs(cfg.actor_cache_ttl),
+            )))
+            .layer(Extension(client))
+            .layer(Extension(webhook::Webhook::new(cfg)))
+            .layer(Extension(replay::ReplayCache::new(cfg)))
+            .layer(Extension(instance))
+            .layer(Extension(cfg.clone())),
+    );
+    let app = with_content_length_limit(app, cfg.max_content_length);
+    let routes = cfg
+        .route_timeout
+        .iter()
+        .map(|(path, seconds)| (path.clone(), Duration::from_secs(*seconds)))
+        .collect();
+    let app = with_timeout(app, Duration::from_secs(cfg.request_timeout), routes);
+    match cfg.error_format {
+        ErrorFormat::Plain => app,
+        ErrorFormat::Problem => app.layer(middleware::map_response(problem_details)),
+    }
+}
+
+/// Answers requests declaring a `Content-Length` over `limit` with a 413
+/// straight away, so we never start buffering an obviously oversized body.
+fn with_content_length_limit(app: Router, limit: u64) -> Router {
+    app.layer(middleware::from_fn(
+        move |request: Request<Body>, next: Next<Body>| async move {
+            let length = request
+                .headers()
+                .get(CONTENT_LENGTH)
+                .and_then(|h| h.to_str().ok())
+                .and_then(|h| h.parse::<u64>().ok());
+            match length {
+                Some(length) if length > limit => Err(web_err(
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    format!("Content-Length {} is over the limit of {}", length, limit),
+                )),
+                _ => Ok(next.run(request).await),
+            }
+        },
+    ))
+}
+
+/// Gives up on requests taking longer than `timeout` with a 504, so a slow
+/// handler (say one stuck fetching a remote key) can't hold a connection
+/// forever. Requests for a route in `routes`, by the path it was declared
+/// with, get the timeout given there instead.
+fn with_timeout(app: Router, timeout: Duration, routes: HashMap<String, Duration>) -> Router {
+    let routes = Arc::new(routes);
+    app.layer(middleware::from_fn(
+        move |request: Request<Body>, next: Next<Body>| {
+            let timeout = request
+                .extensions()
+                .get::<MatchedPath>()
+                .and_then(|path| routes.get(path.as_str()))
+                .copied()
+                .unwrap_or(timeout);
+            async move {
+                tokio::time::timeout(timeout, next.run(request))
+                    .await
+                    .unwrap_or_else(|_| {
+                        web_err(StatusCode::GATEWAY_TIMEOUT, "Request timed out").into_response()
+                    })
+            }
+        },
+    ))
+}
+
 #[tokio::main]
 async fn main() {
-    env_logger::init();
+    tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+        .init();
     let cfg = Config::parse();
+    if let (Some(path), Some(passphrase)) = (&cfg.seal_key, &cfg.key_encryption_key) {
+        match seed::seal_key(path, passphrase) {
+            Ok(sealed) => println!("{}", sealed),
+            Err(e) => {
+                error!("Could not seal {}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
 
     let (prometheus_layer, metric_handle) = PrometheusMetricLayerBuilder::new()
         .with_prefix("rap_server")
         .with_default_metrics()
         .build_pair();
 
-    // TODO: create a background task processor
+    let people: Arc<dyn PeopleStore> = Arc::new(InMemoryPeopleStore::new(cfg.clone()));
+    let objects: Arc<dyn ObjectStore> = Arc::new(InMemoryObjectStore::new());
+    if let Some(seed_file) = &cfg.seed_file {
+        if let Err(e) = seed::load(&cfg, people.as_ref(), seed_file).await {
+            error!("Could not seed people from {}: {}", seed_file.display(), e);
+            std::process::exit(1);
+        }
+    }
 
-    let people: Arc<dyn users::PeopleStore> = Arc::new(InMemoryPeopleStore::new());
+    let instance = match InstanceActor::new(&cfg) {
+        Ok(instance) => Arc::new(instance),
+        Err(e) => {
+            error!("Could not create the instance actor: {}", e);
+            std::process::exit(1);
+        }
+    };
 
-    let app = Router::new()
-        .route("/", get(plain_text))
-        .route("/.well-known/webfinger", get(webfinger::json))
-        .route("/users/:id", get(users::json))
-        .route("/users/:id/inbox", post(inbox::json))
-        .route("/plain_text", get(plain_text))
-        .route("/json", get(json))
-        .route("/metrics", get(|| async move { metric_handle.render() }))
+    let (queue, deliveries) = DeliveryQueue::new();
+    let client = remote::Client::new(&cfg).signed_by(instance.key.clone());
+    tokio::spawn(delivery::run(
+        deliveries,
+        people.clone(),
+        client.clone(),
+        remote::ActorCache::new(client, Duration::from_secs(cfg.actor_cache_ttl)),
+    ));
+
+    let metrics_people = people.clone();
+    let app = app(&cfg, people, objects, queue, instance)
+        .route(
+            "/metrics",
+            get(|| async move {
+                record_gauges(metrics_people.as_ref()).await;
+                metric_handle.render()
+            }),
+        )
         .layer(
             ServiceBuilder::new()
                 .layer(middleware::from_fn(request_logger))
-                .layer(prometheus_layer)
-                .layer(Extension(people))
-                .layer(Extension(cfg.clone())),
+                .layer(prometheus_layer),
+        );
+
+    if let Some(path) = &cfg.socket {
+        let listener = match socket::bind(path) {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("Could not listen on {}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        };
+        info!("Listening on {}", path.display());
+        if let Err(e) = socket::serve(listener, app, &cfg).await {
+            error!("Server error: {}", e);
+        }
+        return;
+    }
+
+    let addrs = match cfg.listen_addrs() {
+        Ok(addrs) => addrs,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let mut servers = JoinSet::new();
+    for addr in addrs {
+        let server = match AddrIncoming::bind(&addr) {
+            Ok(incoming) => http_server(incoming, &cfg),
+            Err(e) => {
+                error!("Could not listen on {}: {}", addr, e);
+                std::process::exit(1);
+            }
+        };
+        info!("Listening on {}", addr);
+        // with the peer's address, for `--unsigned-peers`
+        servers.spawn(
+            server.serve(
+                app.clone()
+                    .into_make_service_with_connect_info::<std::net::SocketAddr>(),
+            ),
         );
+    }
 
-    let addr = format!("{}:{}", cfg.address, cfg.port);
-    info!("Listening on {}", addr);
+    while let Some(result) = servers.join_next().await {
+        if let Err(e) = result.unwrap() {
+            error!("Server error: {}", e);
+        }
+    }
+}
+
+/// A server for the connections from `incoming`, speaking only HTTP/1.1
+/// unless `--http2` allows HTTP/2 as well.
+fn http_server<I>(incoming: I, cfg: &Config) -> hyper::server::Builder<I> {
+    axum::Server::builder(incoming).http1_only(!cfg.http2)
+}
 
-    let addr = addr.parse().unwrap();
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service())
-        .await
-        .unwrap();
+/// Updates gauges that are cheaper to compute when scraped than to keep up to
+/// date as things change.
+async fn record_gauges(people: &dyn PeopleStore) {
+    match people.count().await {
+        Ok(count) => metrics::gauge!("local_users_total", count as f64),
+        Err(e) => warn!("Error counting people: {}", e),
+    }
 }
 
 async fn request_logger<B>(
@@ -87,15 +357,396 @@ async fn request_logger<B>(
     let uri = request.uri().clone();
     let path = uri.path().to_string();
     let query = uri.query().unwrap_or("");
-    let response = next.run(request).await;
+    let request_id = request
+        .headers()
+        .get("x-request-id")
+        .and_then(|h| h.to_str().ok())
+        .map(String::from)
+        .unwrap_or_else(|| format!("{:016x}", rand::random::<u64>()));
+    let span = info_span!("request", %request_id, %method, %path);
+    let response = next.run(request).instrument(span.clone()).await;
     // log request and response details here
-    info!(
-        "{} {}?{} ({} {}ms)",
-        method,
-        path,
-        query,
-        response.status(),
-        start.elapsed().as_millis()
-    );
+    span.in_scope(|| {
+        info!(
+            "{} {}?{} ({} {}ms)",
+            method,
+            path,
+            query,
+            response.status(),
+            start.elapsed().as_millis()
+        )
+    });
     Ok(response)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tower::ServiceExt;
+
+    pub(crate) fn test_app(args: &[&str]) -> Router {
+        let mut argv = vec!["rap-server", "--domain", "example.com"];
+        argv.extend_from_slice(args);
+        let cfg = Config::parse_from(argv);
+        app(
+            &cfg,
+            Arc::new(InMemoryPeopleStore::new(cfg.clone())),
+            Arc::new(InMemoryObjectStore::new()),
+            DeliveryQueue::new().0,
+            test_instance(&cfg),
+        )
+    }
+
+    /// Generating a key is slow, so every test shares one instance actor.
+    pub(crate) fn test_instance(cfg: &Config) -> Arc<InstanceActor> {
+        static INSTANCE: std::sync::OnceLock<Arc<InstanceActor>> = std::sync::OnceLock::new();
+        INSTANCE
+            .get_or_init(|| Arc::new(InstanceActor::new(cfg).unwrap()))
+            .clone()
+    }
+
+    pub(crate) async fn body_json(response: Response) -> Value {
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    fn signed_request(uri: &str) -> Request<Body> {
+        Request::post(uri)
+            .header("host", "example.com")
+            .header("date", "Sun, 06 Nov 2021 08:49:37 GMT")
+            .header(
+                "signature",
+                r#"keyId="https://remote.example/users/bob#main-key",headers="(request-target) host date",signature="c2lnbmF0dXJl""#,
+            )
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_http2() {
+        for (args, http2) in [(&[][..], false), (&["--http2"][..], true)] {
+            let mut argv = vec!["rap-server", "--domain", "example.com"];
+            argv.extend_from_slice(args);
+            let cfg = Config::parse_from(argv);
+            let incoming = AddrIncoming::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+            let url = format!("http://{}/", incoming.local_addr());
+            tokio::spawn(http_server(incoming, &cfg).serve(test_app(args).into_make_service()));
+
+            let h2 = reqwest::Client::builder()
+                .http2_prior_knowledge()
+                .build()
+                .unwrap()
+                .get(&url)
+                .send()
+                .await;
+            if http2 {
+                let response = h2.unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                assert_eq!(response.version(), reqwest::Version::HTTP_2);
+            } else {
+                assert!(h2.is_err(), "HTTP/2 is served without --http2");
+            }
+
+            // HTTP/1.1 works either way
+            let response = reqwest::get(&url).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.version(), reqwest::Version::HTTP_11);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_debug_endpoints_disabled() {
+        let response = test_app(&[])
+            .oneshot(signed_request("/debug/users/alice/inbox"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_debug_signature() {
+        let response = test_app(&["--debug-endpoints"])
+            .oneshot(signed_request("/debug/users/alice/inbox"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = body_json(response).await;
+        assert_eq!(body["keyId"], "https://remote.example/users/bob#main-key");
+        assert_eq!(body["headers"], json!(["(request-target)", "host", "date"]));
+        assert_eq!(
+            body["signingString"],
+            "(request-target): post /users/alice/inbox\nhost: example.com\ndate: Sun, 06 Nov 2021 08:49:37 GMT"
+        );
+
+        // the host a proxy rewrote is put back, like the inbox does
+        let mut request = signed_request("/debug/users/alice/inbox");
+        request
+            .headers_mut()
+            .insert("host", "127.0.0.1:8080".parse().unwrap());
+        let response = test_app(&["--debug-endpoints"])
+            .oneshot(request)
+            .await
+            .unwrap();
+        let body = body_json(response).await;
+        assert!(
+            body["signingString"]
+                .as_str()
+                .unwrap()
+                .contains("\nhost: example.com\n"),
+            "{}",
+            body["signingString"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_debug_deliveries() {
+        use axum::extract::ConnectInfo;
+        use std::net::SocketAddr;
+
+        // the inbox may be anywhere, it's only signed for and never posted to
+        let inbox = "https://remote.example/inboxes/bob";
+        let actor = json!({
+            "id": "https://remote.example/users/bob",
+            "type": "Person",
+            "inbox": inbox,
+            "outbox": "https://remote.example/users/bob/outbox",
+            "following": "https://remote.example/users/bob/following",
+            "followers": "https://remote.example/users/bob/followers",
+        });
+        let url = remote::mock_server(actor.to_string(), true).await;
+        let bob = format!("{}users/bob", url);
+
+        let cfg = Config::parse_from([
+            "rap-server",
+            "--domain",
+            "example.com",
+            "--debug-endpoints",
+            "--allow-private-fetches",
+            "--unsigned-peers",
+            "10.0.0.5",
+            "--admin-token",
+            "secret",
+        ]);
+        let people = Arc::new(InMemoryPeopleStore::new(cfg.clone()));
+        let alice = people.create(&"alice".to_string()).await.unwrap().unwrap();
+        let app = app(
+            &cfg,
+            people,
+            Arc::new(InMemoryObjectStore::new()),
+            DeliveryQueue::new().0,
+            test_instance(&cfg),
+        );
+
+        let follow = json!({
+            "id": format!("{}follows/1", url),
+            "type": "Follow",
+            "actor": bob,
+            "object": "https://example.com/users/alice",
+        });
+        let mut request = Request::post("/users/alice/inbox")
+            .body(Body::from(follow.to_string()))
+            .unwrap();
+        request
+            .extensions_mut()
+            .insert(ConnectInfo::<SocketAddr>("10.0.0.5:4711".parse().unwrap()));
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+
+        // freshly signed requests are for admins only
+        let response = app
+            .clone()
+            .oneshot(
+                Request::get("/debug/deliveries")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = app
+            .oneshot(
+                Request::get("/debug/deliveries")
+                    .header("authorization", "Bearer secret")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let listed = body_json(response).await;
+        let listed = listed.as_array().unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0]["from"], "alice");
+        assert_eq!(listed[0]["to"], bob);
+        assert_eq!(listed[0]["activity"]["type"], "Accept");
+        assert_eq!(listed[0]["activity"]["object"], follow);
+        assert_eq!(listed[0]["request"]["url"], inbox);
+        let signature = listed[0]["request"]["headers"]["signature"]
+            .as_str()
+            .unwrap();
+        assert!(
+            signature.contains(&format!("keyId=\"{}\"", alice.key.key_id())),
+            "{}",
+            signature
+        );
+
+        let response = test_app(&[])
+            .oneshot(
+                Request::get("/debug/deliveries")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_slow_handler_times_out() {
+        let app = with_timeout(
+            Router::new().route(
+                "/slow",
+                get(|| async {
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                    "done"
+                }),
+            ),
+            Duration::from_millis(10),
+            HashMap::new(),
+        );
+        let response = app
+            .oneshot(Request::get("/slow").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn test_route_timeouts() {
+        let slow = || async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            "done"
+        };
+        let app = with_timeout(
+            Router::new()
+                .route("/actor", get(slow))
+                .route("/users/:id/inbox", post(slow)),
+            Duration::from_millis(10),
+            HashMap::from([("/users/:id/inbox".to_string(), Duration::from_secs(5))]),
+        );
+
+        let response = app
+            .clone()
+            .oneshot(Request::get("/actor").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+
+        let response = app
+            .oneshot(
+                Request::post("/users/alice/inbox")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_problem_details() {
+        let request = || Request::post("/inbox").body(Body::empty()).unwrap();
+
+        let response = test_app(&[]).oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            response.headers()["content-type"],
+            "text/plain; charset=utf-8"
+        );
+
+        let response = test_app(&["--error-format", "problem"])
+            .oneshot(request())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            response.headers()["content-type"],
+            "application/problem+json"
+        );
+        assert_eq!(
+            body_json(response).await,
+            json!({
+                "type": "about:blank",
+                "title": "Unauthorized",
+                "status": 401,
+                "detail": "No header signature",
+            })
+        );
+
+        // routes we don't have 404 without a body, so there's no detail
+        let response = test_app(&["--error-format", "problem"])
+            .oneshot(Request::get("/nowhere").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(
+            body_json(response).await,
+            json!({ "type": "about:blank", "title": "Not Found", "status": 404 })
+        );
+
+        // successes are left alone
+        let response = test_app(&["--error-format", "problem"])
+            .oneshot(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_json(response).await["domain"], "example.com");
+    }
+
+    #[tokio::test]
+    async fn test_dev_routes() {
+        let get =
+            |app: Router, path: &str| app.oneshot(Request::get(path).body(Body::empty()).unwrap());
+
+        let app = test_app(&[]);
+        for path in ["/plain_text", "/json"] {
+            let response = get(app.clone(), path).await.unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{}", path);
+        }
+        let response = get(app, "/").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let root = body_json(response).await;
+        assert_eq!(root["domain"], "example.com");
+        assert_eq!(root["actor"], "https://example.com/actor");
+        assert_eq!(root["nodeinfo"], "https://example.com/.well-known/nodeinfo");
+
+        let app = test_app(&["--dev-routes"]);
+        let response = get(app.clone(), "/json").await.unwrap();
+        assert_eq!(body_json(response).await, json!({ "data": 42 }));
+        let response = get(app, "/").await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "boo!");
+    }
+
+    #[tokio::test]
+    async fn test_content_length_limit() {
+        let app = test_app(&["--max-content-length", "1024"]);
+
+        // the body never arrives, so reading it would hang
+        let (_sender, body) = Body::channel();
+        let request = Request::post("/users/alice/inbox")
+            .header(CONTENT_LENGTH, "1025")
+            .body(body)
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let request = Request::get("/")
+            .header(CONTENT_LENGTH, "1024")
+            .body(Body::from(vec![b'a'; 1024]))
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,160 @@
+use axum::{Extension, Json};
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+use crate::config::Config;
+use crate::users::PeopleStore;
+
+/// Schema versions we serve, newest first.
+const VERSIONS: [&str; 2] = ["2.1", "2.0"];
+
+pub async fn well_known(Extension(cfg): Extension<Config>) -> Json<Value> {
+    let links: Vec<Value> = VERSIONS
+        .iter()
+        .map(|version| {
+            json!({
+                "rel": format!("http://nodeinfo.diaspora.software/ns/schema/{}", version),
+                "href": format!("{}/nodeinfo/{}", cfg.base_url(), version),
+            })
+        })
+        .collect();
+    Json(json!({ "links": links }))
+}
+
+/// The parts of the document every schema version has.
+fn nodeinfo(version: &str, cfg: &Config, people: &dyn PeopleStore) -> Value {
+    let people = people.snapshot();
+    json!({
+        "version": version,
+        "software": {
+            "name": cfg.software_name,
+            "version": cfg.software_version,
+        },
+        "protocols": ["activitypub"],
+        "services": {
+            "inbound": [],
+            "outbound": [],
+        },
+        "openRegistrations": false,
+        "usage": {
+            "users": {
+                "total": people.total,
+            },
+        },
+        "metadata": {},
+    })
+}
+
+pub async fn v2_0(
+    Extension(cfg): Extension<Config>,
+    Extension(people): Extension<Arc<dyn PeopleStore>>,
+) -> Json<Value> {
+    Json(nodeinfo("2.0", &cfg, people.as_ref()))
+}
+
+pub async fn v2_1(
+    Extension(cfg): Extension<Config>,
+    Extension(people): Extension<Arc<dyn PeopleStore>>,
+) -> Json<Value> {
+    let mut nodeinfo = nodeinfo("2.1", &cfg, people.as_ref());
+    // 2.1 added where to find the software
+    if let Some(repository) = &cfg.software_repository {
+        nodeinfo["software"]["repository"] = json!(repository);
+    }
+    if let Some(homepage) = &cfg.software_homepage {
+        nodeinfo["software"]["homepage"] = json!(homepage);
+    }
+    Json(nodeinfo)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::users::InMemoryPeopleStore;
+    use clap::Parser;
+
+    fn people(cfg: &Config) -> Extension<Arc<dyn PeopleStore>> {
+        Extension(Arc::new(InMemoryPeopleStore::new(cfg.clone())))
+    }
+
+    #[tokio::test]
+    async fn test_software() {
+        let cfg = Config::parse_from([
+            "rap-server",
+            "--domain",
+            "example.com",
+            "--software-name",
+            "my-fork",
+            "--software-version",
+            "1.2.3",
+        ]);
+        let Json(nodeinfo) = v2_1(Extension(cfg.clone()), people(&cfg)).await;
+        assert_eq!(nodeinfo["software"]["name"], "my-fork");
+        assert_eq!(nodeinfo["software"]["version"], "1.2.3");
+    }
+
+    #[tokio::test]
+    async fn test_default_software() {
+        let cfg = Config::parse_from(["rap-server", "--domain", "example.com"]);
+        let Json(nodeinfo) = v2_1(Extension(cfg.clone()), people(&cfg)).await;
+        assert_eq!(nodeinfo["software"]["name"], "rap-server");
+        assert_eq!(nodeinfo["software"]["version"], env!("CARGO_PKG_VERSION"));
+    }
+
+    #[tokio::test]
+    async fn test_user_count() {
+        let cfg = Config::parse_from(["rap-server", "--domain", "example.com"]);
+        let people = people(&cfg);
+        people.0.create(&"alice".to_string()).await.unwrap();
+        people.0.create(&"bob".to_string()).await.unwrap();
+
+        let Json(nodeinfo) = v2_1(Extension(cfg), people).await;
+        assert_eq!(nodeinfo["usage"]["users"]["total"], 2);
+    }
+
+    #[tokio::test]
+    async fn test_well_known() {
+        let cfg = Config::parse_from(["rap-server", "--domain", "example.com"]);
+        let Json(links) = well_known(Extension(cfg)).await;
+        assert_eq!(
+            links,
+            serde_json::json!({"links": [
+                {
+                    "rel": "http://nodeinfo.diaspora.software/ns/schema/2.1",
+                    "href": "https://example.com/nodeinfo/2.1",
+                },
+                {
+                    "rel": "http://nodeinfo.diaspora.software/ns/schema/2.0",
+                    "href": "https://example.com/nodeinfo/2.0",
+                },
+            ]})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_versions() {
+        let cfg = Config::parse_from([
+            "rap-server",
+            "--domain",
+            "example.com",
+            "--software-repository",
+            "https://github.com/nullren/rens-activity-pub",
+        ]);
+        let people = people(&cfg);
+        people.0.create(&"alice".to_string()).await.unwrap();
+
+        let Json(v2_0) = v2_0(Extension(cfg.clone()), people.clone()).await;
+        assert_eq!(v2_0["version"], "2.0");
+        assert_eq!(v2_0["usage"]["users"]["total"], 1);
+        assert!(v2_0["software"].get("repository").is_none());
+
+        let Json(v2_1) = v2_1(Extension(cfg), people).await;
+        assert_eq!(v2_1["version"], "2.1");
+        assert_eq!(v2_1["usage"]["users"]["total"], 1);
+        assert_eq!(
+            v2_1["software"]["repository"],
+            "https://github.com/nullren/rens-activity-pub"
+        );
+        assert!(v2_1["software"].get("homepage").is_none());
+    }
+}
@@ -0,0 +1,684 @@
+use axum::extract::{ConnectInfo, Path, Query};
+use axum::http::{HeaderMap, Method, StatusCode, Uri};
+use axum::response::Response;
+use axum::{Extension, Json};
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use crate::addressing;
+use crate::collections::{ordered_collection, PageQuery};
+use crate::config::Config;
+use crate::key::KeyCache;
+use crate::signed;
+use crate::users::PeopleStore;
+use crate::utils::{negotiated_json, web_err, web_err_500, WebError};
+use tokio::sync::Mutex;
+use tracing::debug;
+
+pub type ObjectId = String;
+
+/// What looking something up in a store found.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Lookup<T> {
+    Found(T),
+    /// It was there once but has been deleted, leaving this tombstone.
+    Gone(T),
+    /// It was never there, as far as we know.
+    Missing,
+}
+
+impl<T> Lookup<T> {
+    /// The thing looked up, if it is still there.
+    pub fn found(self) -> Option<T> {
+        match self {
+            Lookup::Found(found) => Some(found),
+            Lookup::Gone(_) | Lookup::Missing => None,
+        }
+    }
+}
+
+/// An activity (`Like`, `Announce`, ...) some actor performed on an object.
+#[derive(Debug, Clone)]
+pub struct Reaction {
+    pub actor: String,
+    pub activity: String,
+}
+
+#[async_trait::async_trait]
+pub trait ObjectStore: Send + Sync {
+    async fn get(&self, id: &str) -> Result<Lookup<Value>, Box<dyn Error>>;
+    /// Replaces the stored object with the same `id` by `object`. Objects we
+    /// don't have are not stored, and `false` is returned.
+    async fn update(&self, object: Value) -> Result<bool, Box<dyn Error>>;
+    /// Deletes an object, leaving a tombstone so later lookups find it
+    /// [`Lookup::Gone`]. Returns `false` if we didn't have it.
+    async fn delete(&self, id: &str) -> Result<bool, Box<dyn Error>>;
+
+    /// Records an `Announce` of `object`. An actor can only share an object
+    /// once, so repeated announces replace the earlier one.
+    async fn add_share(&self, object: &str, share: Reaction) -> Result<(), Box<dyn Error>>;
+    async fn shares(&self, object: &str) -> Result<Vec<Reaction>, Box<dyn Error>>;
+    async fn remove_share(&self, object: &str, actor: &str) -> Result<(), Box<dyn Error>>;
+
+    /// Records a `Like` of `object`. Likes are idempotent per actor.
+    async fn add_like(&self, object: &str, like: Reaction) -> Result<(), Box<dyn Error>>;
+    async fn likes(&self, object: &str) -> Result<Vec<Reaction>, Box<dyn Error>>;
+    async fn remove_like(&self, object: &str, actor: &str) -> Result<(), Box<dyn Error>>;
+
+    /// Records that the remote actor `origin` has moved to `target`.
+    async fn record_move(&self, origin: &str, target: &str) -> Result<(), Box<dyn Error>>;
+    async fn moved_to(&self, origin: &str) -> Result<Option<String>, Box<dyn Error>>;
+
+    /// Records that the activity `id` was forwarded to our followers,
+    /// returning `false` if it had been already.
+    async fn record_forward(&self, id: &str) -> Result<bool, Box<dyn Error>>;
+
+    /// Suspends a remote actor, so we stop accepting activities from them.
+    /// Returns whether they weren't suspended already.
+    async fn suspend(&self, actor: &str) -> Result<bool, Box<dyn Error>>;
+    /// Lifts a suspension, returning whether the actor was suspended.
+    async fn unsuspend(&self, actor: &str) -> Result<bool, Box<dyn Error>>;
+    async fn is_suspended(&self, actor: &str) -> Result<bool, Box<dyn Error>>;
+    async fn suspended(&self) -> Result<Vec<String>, Box<dyn Error>>;
+}
+
+async fn local_object(
+    cfg: &Config,
+    objects: &dyn ObjectStore,
+    id: ObjectId,
+) -> Result<ObjectId, WebError> {
+    let id = format!("{}/objects/{}", cfg.base_url(), id);
+    match objects
+        .get(&id)
+        .await
+        .map_err(|e| web_err_500(format!("Error getting object: {}", e)))?
+    {
+        Lookup::Found(_) => Ok(id),
+        Lookup::Gone(_) => Err(web_err(StatusCode::GONE, format!("{} was deleted", id))),
+        Lookup::Missing => Err(web_err(StatusCode::NOT_FOUND, format!("No object {}", id))),
+    }
+}
+
+/// A local object. Objects not addressed to the public are only served to
+/// actors they are addressed to, directly or through the followers of a local
+/// person they follow, which takes a signed request (authorized fetch).
+/// Everyone else gets the same 404 as for objects we never had, so as not to
+/// give away that there is something there. That goes for deleted ones too,
+/// and `bto` and `bcc` are left out for everyone, blind recipients included.
+#[allow(clippy::too_many_arguments)]
+pub async fn object(
+    Path(id): Path<ObjectId>,
+    Extension(cfg): Extension<Config>,
+    Extension(objects): Extension<Arc<dyn ObjectStore>>,
+    Extension(people): Extension<Arc<dyn PeopleStore>>,
+    Extension(keys): Extension<KeyCache>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+) -> Result<Response, WebError> {
+    let id = format!("{}/objects/{}", cfg.base_url(), id);
+    let not_found = || web_err(StatusCode::NOT_FOUND, format!("No object {}", id));
+    let mut object = match objects
+        .get(&id)
+        .await
+        .map_err(|e| web_err_500(format!("Error getting object: {}", e)))?
+    {
+        Lookup::Found(object) => object,
+        Lookup::Gone(tombstone) if is_public(&tombstone) => {
+            return Err(web_err(StatusCode::GONE, format!("{} was deleted", id)))
+        }
+        Lookup::Gone(_) | Lookup::Missing => return Err(not_found()),
+    };
+
+    if !is_public(&object) {
+        if !headers.contains_key("signature") {
+            return Err(not_found());
+        }
+        let actor = signed::signer(
+            &keys,
+            &cfg,
+            &method,
+            &uri,
+            &headers,
+            connect_info.map(|ConnectInfo(addr)| addr.ip()),
+        )
+        .await
+        .map_err(|_| not_found())?;
+        if !addressed_to(people.as_ref(), &object, &actor).await? {
+            debug!("{} isn't addressed to {}", id, actor);
+            return Err(not_found());
+        }
+    }
+    if let Some(fields) = object.as_object_mut() {
+        fields.remove("bto");
+        fields.remove("bcc");
+    }
+    Ok(negotiated_json(&headers, object))
+}
+
+fn is_public(object: &Value) -> bool {
+    addressing::addressees(object)
+        .into_iter()
+        .any(addressing::is_public)
+}
+
+/// What's left of a deleted object: enough to tell who could see it.
+fn tombstone(object: &Value) -> Value {
+    let mut tombstone = json!({
+        "id": object["id"],
+        "type": "Tombstone",
+        "formerType": object["type"],
+    });
+    for field in ["to", "cc", "audience"] {
+        if let Some(addressees) = object.get(field) {
+            tombstone[field] = addressees.clone();
+        }
+    }
+    tombstone
+}
+
+/// Whether `actor` is among the addressees of `object`, directly or as a
+/// follower of a local person whose followers it is addressed to.
+async fn addressed_to(
+    people: &dyn PeopleStore,
+    object: &Value,
+    actor: &str,
+) -> Result<bool, WebError> {
+    for addressee in addressing::recipients(object) {
+        if addressee == actor {
+            return Ok(true);
+        }
+        let Some(owner) = addressee.strip_suffix("/followers") else {
+            continue;
+        };
+        let Some(owner) = people
+            .get_by_id(owner)
+            .await
+            .map_err(|e| web_err_500(format!("Error getting person: {}", e)))?
+        else {
+            continue;
+        };
+        let followers = people
+            .followers(&owner.username)
+            .await
+            .map_err(|e| web_err_500(format!("Error getting followers: {}", e)))?;
+        if followers.iter().any(|follower| follower == actor) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+pub async fn shares(
+    Path(id): Path<ObjectId>,
+    Query(page): Query<PageQuery>,
+    Extension(cfg): Extension<Config>,
+    Extension(objects): Extension<Arc<dyn ObjectStore>>,
+) -> Result<Json<Value>, WebError> {
+    let id = local_object(&cfg, objects.as_ref(), id).await?;
+    let shares = objects
+        .shares(&id)
+        .await
+        .map_err(|e| web_err_500(format!("Error getting shares: {}", e)))?;
+    Ok(Json(ordered_collection(
+        &cfg,
+        format!("{}/shares", id),
+        shares.into_iter().map(|s| s.activity).collect(),
+        page,
+    )))
+}
+
+pub async fn likes(
+    Path(id): Path<ObjectId>,
+    Query(page): Query<PageQuery>,
+    Extension(cfg): Extension<Config>,
+    Extension(objects): Extension<Arc<dyn ObjectStore>>,
+) -> Result<Json<Value>, WebError> {
+    let id = local_object(&cfg, objects.as_ref(), id).await?;
+    let likes = objects
+        .likes(&id)
+        .await
+        .map_err(|e| web_err_500(format!("Error getting likes: {}", e)))?;
+    Ok(Json(ordered_collection(
+        &cfg,
+        format!("{}/likes", id),
+        likes.into_iter().map(|l| l.activity).collect(),
+        page,
+    )))
+}
+
+pub struct InMemoryObjectStore {
+    objects: Mutex<HashMap<ObjectId, Value>>,
+    tombstones: Mutex<HashMap<ObjectId, Value>>,
+    shares: Mutex<HashMap<ObjectId, Vec<Reaction>>>,
+    likes: Mutex<HashMap<ObjectId, Vec<Reaction>>>,
+    moves: Mutex<HashMap<String, String>>,
+    forwarded: Mutex<HashSet<String>>,
+    suspended: Mutex<HashSet<String>>,
+}
+
+impl InMemoryObjectStore {
+    pub fn new() -> Self {
+        Self {
+            objects: Mutex::new(HashMap::new()),
+            tombstones: Mutex::new(HashMap::new()),
+            shares: Mutex::new(HashMap::new()),
+            likes: Mutex::new(HashMap::new()),
+            moves: Mutex::new(HashMap::new()),
+            forwarded: Mutex::new(HashSet::new()),
+            suspended: Mutex::new(HashSet::new()),
+        }
+    }
+
+    #[cfg(test)]
+    pub async fn insert(&self, object: Value) {
+        let id = object["id"].as_str().unwrap().to_string();
+        self.objects.lock().await.insert(id, object);
+    }
+}
+
+#[async_trait::async_trait]
+impl ObjectStore for InMemoryObjectStore {
+    async fn get(&self, id: &str) -> Result<Lookup<Value>, Box<dyn Error>> {
+        if let Some(object) = self.objects.lock().await.get(id) {
+            return Ok(Lookup::Found(object.clone()));
+        }
+        Ok(match self.tombstones.lock().await.get(id) {
+            Some(tombstone) => Lookup::Gone(tombstone.clone()),
+            None => Lookup::Missing,
+        })
+    }
+
+    async fn update(&self, object: Value) -> Result<bool, Box<dyn Error>> {
+        let id = object["id"].as_str().ok_or("object has no id")?;
+        Ok(match self.objects.lock().await.get_mut(id) {
+            Some(stored) => {
+                *stored = object;
+                true
+            }
+            None => false,
+        })
+    }
+
+    async fn delete(&self, id: &str) -> Result<bool, Box<dyn Error>> {
+        let mut objects = self.objects.lock().await;
+        let Some(object) = objects.remove(id) else {
+            return Ok(false);
+        };
+        self.tombstones
+            .lock()
+            .await
+            .insert(id.to_string(), tombstone(&object));
+        Ok(true)
+    }
+
+    async fn add_share(&self, object: &str, share: Reaction) -> Result<(), Box<dyn Error>> {
+        add_reaction(&self.shares, object, share).await;
+        Ok(())
+    }
+
+    async fn shares(&self, object: &str) -> Result<Vec<Reaction>, Box<dyn Error>> {
+        Ok(reactions(&self.shares, object).await)
+    }
+
+    async fn remove_share(&self, object: &str, actor: &str) -> Result<(), Box<dyn Error>> {
+        remove_reaction(&self.shares, object, actor).await;
+        Ok(())
+    }
+
+    async fn add_like(&self, object: &str, like: Reaction) -> Result<(), Box<dyn Error>> {
+        add_reaction(&self.likes, object, like).await;
+        Ok(())
+    }
+
+    async fn likes(&self, object: &str) -> Result<Vec<Reaction>, Box<dyn Error>> {
+        Ok(reactions(&self.likes, object).await)
+    }
+
+    async fn remove_like(&self, object: &str, actor: &str) -> Result<(), Box<dyn Error>> {
+        remove_reaction(&self.likes, object, actor).await;
+        Ok(())
+    }
+
+    async fn record_move(&self, origin: &str, target: &str) -> Result<(), Box<dyn Error>> {
+        self.moves
+            .lock()
+            .await
+            .insert(origin.to_string(), target.to_string());
+        Ok(())
+    }
+
+    async fn moved_to(&self, origin: &str) -> Result<Option<String>, Box<dyn Error>> {
+        Ok(self.moves.lock().await.get(origin).cloned())
+    }
+
+    async fn record_forward(&self, id: &str) -> Result<bool, Box<dyn Error>> {
+        Ok(self.forwarded.lock().await.insert(id.to_string()))
+    }
+
+    async fn suspend(&self, actor: &str) -> Result<bool, Box<dyn Error>> {
+        Ok(self.suspended.lock().await.insert(actor.to_string()))
+    }
+
+    async fn unsuspend(&self, actor: &str) -> Result<bool, Box<dyn Error>> {
+        Ok(self.suspended.lock().await.remove(actor))
+    }
+
+    async fn is_suspended(&self, actor: &str) -> Result<bool, Box<dyn Error>> {
+        Ok(self.suspended.lock().await.contains(actor))
+    }
+
+    async fn suspended(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        let mut actors: Vec<String> = self.suspended.lock().await.iter().cloned().collect();
+        actors.sort();
+        Ok(actors)
+    }
+}
+
+async fn add_reaction(
+    reactions: &Mutex<HashMap<ObjectId, Vec<Reaction>>>,
+    object: &str,
+    reaction: Reaction,
+) {
+    let mut reactions = reactions.lock().await;
+    let reactions = reactions.entry(object.to_string()).or_default();
+    reactions.retain(|r| r.actor != reaction.actor);
+    reactions.push(reaction);
+}
+
+async fn remove_reaction(
+    reactions: &Mutex<HashMap<ObjectId, Vec<Reaction>>>,
+    object: &str,
+    actor: &str,
+) {
+    if let Some(reactions) = reactions.lock().await.get_mut(object) {
+        reactions.retain(|r| r.actor != actor);
+    }
+}
+
+async fn reactions(
+    reactions: &Mutex<HashMap<ObjectId, Vec<Reaction>>>,
+    object: &str,
+) -> Vec<Reaction> {
+    reactions
+        .lock()
+        .await
+        .get(object)
+        .cloned()
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_instance;
+    use axum::body::Body;
+    use axum::http::Request;
+    use clap::Parser;
+    use std::time::Duration;
+    use tower::ServiceExt;
+
+    async fn note_and_tombstone() -> InMemoryObjectStore {
+        let objects = InMemoryObjectStore::new();
+        for id in ["1", "2"] {
+            objects
+                .insert(json!({
+                    "id": format!("https://example.com/objects/{}", id),
+                    "type": "Note",
+                }))
+                .await;
+        }
+        assert!(objects
+            .delete("https://example.com/objects/2")
+            .await
+            .unwrap());
+        objects
+    }
+
+    #[tokio::test]
+    async fn test_lookup() {
+        let objects = note_and_tombstone().await;
+        assert_eq!(
+            objects.get("https://example.com/objects/1").await.unwrap(),
+            Lookup::Found(json!({"id": "https://example.com/objects/1", "type": "Note"}))
+        );
+        assert_eq!(
+            objects.get("https://example.com/objects/2").await.unwrap(),
+            Lookup::Gone(json!({
+                "id": "https://example.com/objects/2",
+                "type": "Tombstone",
+                "formerType": "Note",
+            }))
+        );
+        assert_eq!(
+            objects.get("https://example.com/objects/3").await.unwrap(),
+            Lookup::Missing
+        );
+
+        // nothing to delete, and deleted objects can't be updated back
+        assert!(!objects
+            .delete("https://example.com/objects/3")
+            .await
+            .unwrap());
+        assert!(!objects
+            .update(json!({"id": "https://example.com/objects/2"}))
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_collections_of_deleted_object() {
+        let cfg = Config::parse_from(["rap-server", "--domain", "example.com"]);
+        let app = crate::app(
+            &cfg,
+            Arc::new(crate::users::InMemoryPeopleStore::new(cfg.clone())),
+            Arc::new(note_and_tombstone().await),
+            crate::delivery::DeliveryQueue::new().0,
+            test_instance(&cfg),
+        );
+
+        for (id, status) in [
+            ("1", StatusCode::OK),
+            ("2", StatusCode::GONE),
+            ("3", StatusCode::NOT_FOUND),
+        ] {
+            for collection in ["likes", "shares"] {
+                let uri = format!("/objects/{}/{}", id, collection);
+                let response = app
+                    .clone()
+                    .oneshot(Request::get(&uri).body(Body::empty()).unwrap())
+                    .await
+                    .unwrap();
+                assert_eq!(response.status(), status, "{}", uri);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sensitive_note() {
+        let cfg = Config::parse_from(["rap-server", "--domain", "example.com"]);
+        let note = json!({
+            "@context": crate::context::context(&cfg),
+            "id": "https://example.com/objects/cw",
+            "type": "Note",
+            "to": [addressing::PUBLIC],
+            "content": "<p>spoilers</p>",
+            "summary": "the ending of a film",
+            "sensitive": true,
+        });
+        let objects = InMemoryObjectStore::new();
+        objects.insert(note.clone()).await;
+        let app = crate::app(
+            &cfg,
+            Arc::new(crate::users::InMemoryPeopleStore::new(cfg.clone())),
+            Arc::new(objects),
+            crate::delivery::DeliveryQueue::new().0,
+            test_instance(&cfg),
+        );
+
+        let response = app
+            .oneshot(Request::get("/objects/cw").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let served = crate::tests::body_json(response).await;
+        assert_eq!(served, note);
+        assert_eq!(served["@context"][2]["sensitive"], "as:sensitive");
+
+        let object: rap_core::types::Object = serde_json::from_value(served).unwrap();
+        assert!(object.sensitive());
+        assert_eq!(object.summary(), Some("the ending of a film"));
+    }
+
+    #[tokio::test]
+    async fn test_object_visibility() {
+        let cfg = Config::parse_from(["rap-server", "--domain", "example.com"]);
+        let objects = note_and_tombstone().await;
+        objects
+            .insert(json!({
+                "id": "https://example.com/objects/public",
+                "type": "Note",
+                "to": [addressing::PUBLIC],
+                "cc": ["https://example.com/users/alice/followers"],
+            }))
+            .await;
+        objects
+            .insert(json!({
+                "id": "https://example.com/objects/followers-only",
+                "type": "Note",
+                "to": ["https://example.com/users/alice/followers"],
+            }))
+            .await;
+        objects
+            .insert(json!({
+                "id": "https://example.com/objects/deleted",
+                "type": "Note",
+                "to": [addressing::PUBLIC],
+            }))
+            .await;
+        objects
+            .delete("https://example.com/objects/deleted")
+            .await
+            .unwrap();
+        let app = crate::app(
+            &cfg,
+            Arc::new(crate::users::InMemoryPeopleStore::new(cfg.clone())),
+            Arc::new(objects),
+            crate::delivery::DeliveryQueue::new().0,
+            test_instance(&cfg),
+        );
+        let get = |id: &str| Request::get(format!("/objects/{}", id));
+
+        let response = app
+            .clone()
+            .oneshot(get("public").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let note = crate::tests::body_json(response).await;
+        assert_eq!(note["id"], "https://example.com/objects/public");
+
+        for request in [
+            get("followers-only").body(Body::empty()).unwrap(),
+            get("followers-only")
+                .header("signature", "not a signature")
+                .body(Body::empty())
+                .unwrap(),
+        ] {
+            let response = app.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        }
+
+        // deleted, only saying so if it was public
+        for (id, status) in [("deleted", StatusCode::GONE), ("2", StatusCode::NOT_FOUND)] {
+            let response = app
+                .clone()
+                .oneshot(get(id).body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), status, "{}", id);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_blind_recipients_are_not_served() {
+        let cfg = Config::parse_from(["rap-server", "--domain", "example.com"]);
+        let bob = "https://remote.example/users/bob";
+        let objects = InMemoryObjectStore::new();
+        objects
+            .insert(json!({
+                "id": "https://example.com/objects/dm",
+                "type": "Note",
+                "to": [bob],
+                "bto": ["https://remote.example/users/carol"],
+                "bcc": ["https://remote.example/users/dave"],
+            }))
+            .await;
+        let people = crate::users::InMemoryPeopleStore::new(cfg.clone());
+        let key = crate::key::Key::new(bob.to_string()).unwrap();
+        let keys = KeyCache::new(crate::remote::dev_client(), Duration::from_secs(60));
+        keys.insert(&key.key_id(), key.public_key().unwrap()).await;
+
+        let signed =
+            rap_core::signing::sign_request("GET", "https://example.com/objects/dm", b"", &key)
+                .unwrap();
+        let mut headers = HeaderMap::new();
+        for (name, value) in &signed {
+            headers.insert(
+                axum::http::HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                value.parse().unwrap(),
+            );
+        }
+        let response = object(
+            Path("dm".to_string()),
+            Extension(cfg),
+            Extension(Arc::new(objects) as Arc<dyn ObjectStore>),
+            Extension(Arc::new(people) as Arc<dyn PeopleStore>),
+            Extension(keys),
+            None,
+            Method::GET,
+            "/objects/dm".parse().unwrap(),
+            headers,
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let note = crate::tests::body_json(response).await;
+        assert_eq!(note["to"], json!([bob]));
+        assert!(note.get("bto").is_none());
+        assert!(note.get("bcc").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_addressed_to() {
+        let cfg = Config::parse_from(["rap-server", "--domain", "example.com"]);
+        let people = crate::users::InMemoryPeopleStore::new(cfg);
+        let alice = "alice".to_string();
+        people.create(&alice).await.unwrap();
+        people
+            .add_follower(&alice, "https://remote.example/users/bob")
+            .await
+            .unwrap();
+        let note = json!({
+            "to": ["https://example.com/users/alice/followers"],
+            "cc": ["https://remote.example/users/carol"],
+        });
+
+        for (actor, addressed) in [
+            ("https://remote.example/users/bob", true),
+            ("https://remote.example/users/carol", true),
+            ("https://remote.example/users/mallory", false),
+        ] {
+            assert_eq!(
+                addressed_to(&people, &note, actor).await.unwrap(),
+                addressed,
+                "{}",
+                actor
+            );
+        }
+    }
+}
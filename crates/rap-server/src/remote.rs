@@ -0,0 +1,852 @@
+use crate::actor_id::{ActorId, InvalidActorId};
+use crate::config::Config;
+use crate::key::Key;
+use crate::utils::log_body;
+use axum::async_trait;
+use rap_core::signing::sign_request;
+use reqwest::header::HeaderMap;
+use reqwest::redirect::Policy;
+use reqwest::{Response, StatusCode, Url};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tracing::debug;
+
+pub const ACTIVITY_JSON: &str = "application/activity+json";
+pub const LD_JSON: &str = "application/ld+json; profile=\"https://www.w3.org/ns/activitystreams\"";
+
+/// Client for fetching documents from other servers.
+///
+/// Everything we fetch is addressed by IRIs taken from untrusted input (a
+/// `keyId`, an activity's `actor`, ...), so every hop, including redirects,
+/// is resolved up front and refused if it points into a private network.
+#[derive(Clone, Debug)]
+pub struct Client {
+    user_agent: String,
+    max_redirects: usize,
+    allow_private: bool,
+    max_size: usize,
+    timeout: Duration,
+    signer: Option<Key>,
+    body_log_limit: Option<usize>,
+    transport: Arc<dyn Transport>,
+}
+
+/// Where a [`Client`] gets documents from: the network, or in tests the
+/// canned documents of [`Client::with_fixtures`].
+#[async_trait]
+trait Transport: fmt::Debug + Send + Sync {
+    async fn fetch(
+        &self,
+        client: &Client,
+        url: &str,
+        accept: &str,
+    ) -> Result<(HeaderMap, Vec<u8>), Box<dyn Error>>;
+}
+
+#[derive(Debug)]
+struct Network;
+
+#[async_trait]
+impl Transport for Network {
+    /// Fetches a document's headers and body, giving up as soon as the body
+    /// grows past the configured maximum size.
+    async fn fetch(
+        &self,
+        client: &Client,
+        url: &str,
+        accept: &str,
+    ) -> Result<(HeaderMap, Vec<u8>), Box<dyn Error>> {
+        let mut resp = client.get(url, accept).await?.error_for_status()?;
+        if resp.content_length().unwrap_or(0) > client.max_size as u64 {
+            return Err(format!(
+                "Response from {} is larger than {} bytes",
+                url, client.max_size
+            )
+            .into());
+        }
+
+        let headers = resp.headers().clone();
+        let mut body = Vec::new();
+        while let Some(chunk) = resp.chunk().await? {
+            if body.len() + chunk.len() > client.max_size {
+                return Err(format!(
+                    "Response from {} is larger than {} bytes",
+                    url, client.max_size
+                )
+                .into());
+            }
+            body.extend_from_slice(&chunk);
+        }
+        Ok((headers, body))
+    }
+}
+
+/// Canned documents by URL without a fragment. Anything else fails as if it
+/// were a 404, so a test never reaches a live server by accident.
+#[cfg(test)]
+#[derive(Debug)]
+struct Fixtures(HashMap<String, String>);
+
+#[cfg(test)]
+#[async_trait]
+impl Transport for Fixtures {
+    async fn fetch(
+        &self,
+        _client: &Client,
+        url: &str,
+        _accept: &str,
+    ) -> Result<(HeaderMap, Vec<u8>), Box<dyn Error>> {
+        let mut url = Url::parse(url)?;
+        url.set_fragment(None);
+        let body = self
+            .0
+            .get(url.as_str())
+            .ok_or_else(|| format!("HTTP status client error (404 Not Found) for url ({})", url))?;
+        let mut headers = HeaderMap::new();
+        headers.insert("content-type", ACTIVITY_JSON.parse()?);
+        Ok((headers, body.clone().into_bytes()))
+    }
+}
+
+impl Client {
+    pub fn new(cfg: &Config) -> Self {
+        Self {
+            user_agent: cfg.user_agent(),
+            max_redirects: cfg.max_redirects,
+            allow_private: cfg.allow_private_fetches,
+            max_size: cfg.max_fetch_size,
+            timeout: Duration::from_secs(cfg.fetch_timeout),
+            signer: None,
+            body_log_limit: cfg.body_log_limit(),
+            transport: Arc::new(Network),
+        }
+    }
+
+    /// Answers fetches with the documents in `fixtures` instead of going to
+    /// the network, see [`Fixtures`].
+    #[cfg(test)]
+    pub(crate) fn with_fixtures(mut self, fixtures: &[(&str, &str)]) -> Self {
+        let fixtures = fixtures
+            .iter()
+            .map(|(url, body)| (url.to_string(), body.to_string()))
+            .collect();
+        self.transport = Arc::new(Fixtures(fixtures));
+        self
+    }
+
+    /// Checks that `iri` is an actor we may fetch from or deliver to.
+    pub fn actor_id(&self, iri: &str) -> Result<ActorId, InvalidActorId> {
+        ActorId::parse(iri, self.allow_private)
+    }
+
+    /// Signs every GET with `key`, as servers in secure mode require.
+    pub fn signed_by(mut self, key: Key) -> Self {
+        self.signer = Some(key);
+        self
+    }
+
+    async fn fetch(&self, url: &str, accept: &str) -> Result<(HeaderMap, Vec<u8>), Box<dyn Error>> {
+        self.transport.fetch(self, url, accept).await
+    }
+
+    /// Fetches an ActivityStreams document. Both media types are asked for at
+    /// once, but some servers answer anything but an exact match with a 406,
+    /// so on a 406 each is tried on its own.
+    ///
+    /// Web pages, e.g. of a status, often answer with HTML anyway. Those
+    /// point at their ActivityStreams representation with an alternate link,
+    /// which is followed once, but only on the page's own origin. The page
+    /// could otherwise pass off any document as the one at `url`.
+    pub async fn get_activity_json<T: DeserializeOwned>(
+        &self,
+        url: &str,
+    ) -> Result<T, Box<dyn Error>> {
+        let (headers, body) = self.get_activity(url).await?;
+        if !is_html(&headers) {
+            return Ok(serde_json::from_slice(&body)?);
+        }
+
+        let alternate = alternate_link(&headers, &String::from_utf8_lossy(&body))
+            .ok_or_else(|| format!("{} is HTML without an ActivityStreams alternate", url))?;
+        let page = Url::parse(url)?;
+        let alternate = page.join(&alternate)?;
+        if alternate.origin() != page.origin() {
+            return Err(format!(
+                "{} has an ActivityStreams alternate on another origin, {}",
+                url, alternate
+            )
+            .into());
+        }
+        debug!("Following alternate link from {} to {}", url, alternate);
+        let (_, body) = self.get_activity(alternate.as_str()).await?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    async fn get_activity(&self, url: &str) -> Result<(HeaderMap, Vec<u8>), Box<dyn Error>> {
+        let combined = format!("{}, {}", ACTIVITY_JSON, LD_JSON);
+        for accept in [combined.as_str(), LD_JSON] {
+            match self.fetch(url, accept).await {
+                Err(e) if is_not_acceptable(e.as_ref()) => {
+                    debug!("{} is not acceptable to {}", accept, url);
+                }
+                result => return result,
+            }
+        }
+        self.fetch(url, ACTIVITY_JSON).await
+    }
+
+    pub async fn get(&self, url: &str, accept: &str) -> Result<Response, Box<dyn Error>> {
+        let mut url = Url::parse(url)?;
+        let mut redirects = 0;
+        loop {
+            let addrs = self.resolve(&url).await?;
+            let host = url.host_str().ok_or("url has no host")?;
+            let mut request = self
+                .pinned_client(host, &addrs)?
+                .get(url.clone())
+                .header("Accept", accept);
+            if let Some(key) = &self.signer {
+                for (name, value) in sign_request("GET", url.as_str(), b"", key)? {
+                    request = request.header(name, value);
+                }
+            }
+            let resp = request.send().await?;
+
+            if !resp.status().is_redirection() {
+                return Ok(resp);
+            }
+            if redirects >= self.max_redirects {
+                return Err(format!("Too many redirects fetching {}", url).into());
+            }
+            redirects += 1;
+
+            let location = resp
+                .headers()
+                .get("location")
+                .ok_or_else(|| format!("Redirect from {} has no location", url))?
+                .to_str()?;
+            url = url.join(location)?;
+        }
+    }
+
+    /// POSTs `body` to `url` with the given (already signed) headers. Unlike
+    /// [`Client::get`], redirects are not followed since the signature covers
+    /// the request target.
+    pub async fn post(
+        &self,
+        url: &str,
+        headers: HeaderMap,
+        body: Vec<u8>,
+    ) -> Result<Response, Box<dyn Error>> {
+        let url = Url::parse(url)?;
+        let addrs = self.resolve(&url).await?;
+        let host = url.host_str().ok_or("url has no host")?;
+        log_body(self.body_log_limit, &format!("POST {} body", url), &body);
+        Ok(self
+            .pinned_client(host, &addrs)?
+            .post(url.clone())
+            .headers(headers)
+            .body(body)
+            .send()
+            .await?)
+    }
+
+    /// A client connecting to `host` only at `addrs`, the addresses we
+    /// checked, so a second lookup can't hand back something else.
+    ///
+    /// Header names go out title-cased (`Signature`, `Digest`, `Date`) rather
+    /// than lowercased. They are case-insensitive, but some servers only look
+    /// for the capitalization their own examples use.
+    fn pinned_client(
+        &self,
+        host: &str,
+        addrs: &[SocketAddr],
+    ) -> Result<reqwest::Client, reqwest::Error> {
+        reqwest::Client::builder()
+            .user_agent(&self.user_agent)
+            .redirect(Policy::none())
+            .timeout(self.timeout)
+            .resolve_to_addrs(host, addrs)
+            .http1_title_case_headers()
+            .build()
+    }
+
+    async fn resolve(&self, url: &Url) -> Result<Vec<SocketAddr>, Box<dyn Error>> {
+        if url.scheme() != "https" && url.scheme() != "http" {
+            return Err(format!("Unsupported scheme in {}", url).into());
+        }
+        let host = url.host_str().ok_or("url has no host")?;
+        let host = host
+            .strip_prefix('[')
+            .and_then(|h| h.strip_suffix(']'))
+            .unwrap_or(host);
+        let port = url.port_or_known_default().unwrap_or(443);
+
+        let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port)).await?.collect();
+        if !self.allow_private {
+            if let Some(addr) = addrs.iter().find(|addr| !is_public(addr.ip())) {
+                return Err(format!("Refusing to fetch {} from {}", url, addr.ip()).into());
+            }
+        }
+        Ok(addrs)
+    }
+}
+
+/// The parts of a remote actor we need to deliver to it. Everything else is
+/// left alone, so an actor missing properties we don't use, e.g. `outbox`, can
+/// still be delivered to.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Recipient {
+    pub id: String,
+    pub inbox: String,
+    #[serde(default)]
+    pub endpoints: Endpoints,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Endpoints {
+    pub shared_inbox: Option<String>,
+}
+
+impl Recipient {
+    /// Where to deliver activities for this actor. With `shared` the server's
+    /// `sharedInbox` is preferred, if it has one, so an activity addressed to
+    /// several of its actors only has to be sent once.
+    pub fn inbox_url(&self, shared: bool) -> &str {
+        match &self.endpoints.shared_inbox {
+            Some(shared_inbox) if shared => shared_inbox,
+            _ => &self.inbox,
+        }
+    }
+}
+
+/// Remote actors we have fetched, kept for `ttl` so delivering to the same
+/// people over and over doesn't fetch them every time.
+#[derive(Clone)]
+pub struct ActorCache {
+    client: Client,
+    ttl: Duration,
+    actors: Arc<Mutex<HashMap<String, (Instant, Recipient)>>>,
+}
+
+impl ActorCache {
+    pub fn new(client: Client, ttl: Duration) -> Self {
+        Self {
+            client,
+            ttl,
+            actors: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub async fn get(&self, id: &str) -> Result<Recipient, Box<dyn Error>> {
+        if let Some((fetched, actor)) = self.actors.lock().await.get(id) {
+            if fetched.elapsed() < self.ttl {
+                return Ok(actor.clone());
+            }
+        }
+
+        let actor: Recipient = self.client.get_activity_json(id).await?;
+        debug!("Fetched actor {} with inbox {}", actor.id, actor.inbox);
+        self.actors
+            .lock()
+            .await
+            .insert(id.to_string(), (Instant::now(), actor.clone()));
+        Ok(actor)
+    }
+
+    /// The inbox to deliver to `id` at, see [`Recipient::inbox_url`].
+    pub async fn inbox_url(&self, id: &str, shared: bool) -> Result<String, Box<dyn Error>> {
+        Ok(self.get(id).await?.inbox_url(shared).to_string())
+    }
+}
+
+fn is_not_acceptable(err: &(dyn Error + 'static)) -> bool {
+    err.downcast_ref::<reqwest::Error>()
+        .and_then(|e| e.status())
+        == Some(StatusCode::NOT_ACCEPTABLE)
+}
+
+fn is_html(headers: &HeaderMap) -> bool {
+    headers
+        .get("content-type")
+        .and_then(|h| h.to_str().ok())
+        .is_some_and(|h| h.trim_start().to_lowercase().starts_with("text/html"))
+}
+
+fn is_activity_type(media_type: &str) -> bool {
+    let media_type = media_type.trim().to_lowercase();
+    media_type.starts_with(ACTIVITY_JSON) || media_type.starts_with("application/ld+json")
+}
+
+/// Where an HTML page says its ActivityStreams representation is, from a
+/// `Link: <...>; rel="alternate"; type="application/activity+json"` header or
+/// the equivalent `<link>` tag.
+fn alternate_link(headers: &HeaderMap, html: &str) -> Option<String> {
+    headers
+        .get_all("link")
+        .iter()
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(','))
+        .find_map(link_header_alternate)
+        .or_else(|| link_tag_alternate(html))
+}
+
+fn link_header_alternate(link: &str) -> Option<String> {
+    let mut parts = link.split(';');
+    let target = parts.next()?.trim().strip_prefix('<')?.strip_suffix('>')?;
+    let mut alternate = false;
+    let mut activity = false;
+    for param in parts {
+        let Some((name, value)) = param.split_once('=') else {
+            continue;
+        };
+        let value = value.trim().trim_matches('"');
+        match name.trim().to_lowercase().as_str() {
+            "rel" => alternate = value.split_whitespace().any(|rel| rel == "alternate"),
+            "type" => activity = is_activity_type(value),
+            _ => {}
+        }
+    }
+    (alternate && activity).then(|| target.to_string())
+}
+
+fn link_tag_alternate(html: &str) -> Option<String> {
+    // lowercasing ASCII keeps byte offsets, so they index into `html` too
+    let lower = html.to_ascii_lowercase();
+    let mut rest = lower.as_str();
+    while let Some(start) = rest.find("<link") {
+        let tag_start = lower.len() - rest.len() + start;
+        let tag_len = lower[tag_start..].find('>')?;
+        let tag = &html[tag_start..tag_start + tag_len];
+        let alternate = tag_attribute(tag, "rel").is_some_and(|rel| {
+            rel.split_whitespace()
+                .any(|r| r.eq_ignore_ascii_case("alternate"))
+        });
+        let activity = tag_attribute(tag, "type").is_some_and(is_activity_type);
+        if alternate && activity {
+            if let Some(href) = tag_attribute(tag, "href") {
+                return Some(href.replace("&amp;", "&"));
+            }
+        }
+        rest = &lower[tag_start + tag_len..];
+    }
+    None
+}
+
+/// The value of attribute `name` in `tag`, quoted or not.
+fn tag_attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let lower = tag.to_ascii_lowercase();
+    let mut from = 0;
+    while let Some(found) = lower[from..].find(name) {
+        let start = from + found;
+        from = start + name.len();
+        // skip matches inside other names, e.g. `rel` in `data-rel`
+        let preceded = lower[..start]
+            .chars()
+            .last()
+            .is_some_and(|c| !c.is_whitespace());
+        let value = lower[from..].trim_start();
+        if preceded || !value.starts_with('=') {
+            continue;
+        }
+        let value_start = tag.len() - value.len() + 1;
+        let value = tag[value_start..].trim_start();
+        return match value.chars().next()? {
+            quote @ ('"' | '\'') => value[1..].split(quote).next(),
+            _ => value.split(|c: char| c.is_whitespace() || c == '/').next(),
+        };
+    }
+    None
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                // carrier-grade NAT, 100.64.0.0/10
+                || (a == 100 && (b & 0xc0) == 64))
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_public(IpAddr::V4(ip));
+            }
+            let first = ip.segments()[0];
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                // unique local, fc00::/7
+                || (first & 0xfe00) == 0xfc00
+                // link local, fe80::/10
+                || (first & 0xffc0) == 0xfe80)
+        }
+    }
+}
+
+/// Serves `body` to every request, optionally without a content-length so
+/// the client has to stream it.
+#[cfg(test)]
+pub(crate) async fn mock_server(body: String, content_length: bool) -> String {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let body = body.clone();
+            tokio::spawn(async move {
+                let mut buf = [0; 4096];
+                let _ = socket.read(&mut buf).await.unwrap();
+                let length = if content_length {
+                    format!("content-length: {}\r\n", body.len())
+                } else {
+                    String::new()
+                };
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\ncontent-type: application/activity+json\r\n{}connection: close\r\n\r\n{}",
+                    length, body
+                );
+                let _ = socket.write_all(response.as_bytes()).await;
+            });
+        }
+    });
+    format!("http://{}/", addr)
+}
+
+/// hotdog.place's actor for `renning` as it was when the request in
+/// `signed`'s tests was signed, for serving with [`Client::with_fixtures`].
+#[cfg(test)]
+pub(crate) const RENNING: (&str, &str) = (
+    "https://hotdog.place/users/renning",
+    r#"{"@context":["https://www.w3.org/ns/activitystreams","https://w3id.org/security/v1"],"id":"https://hotdog.place/users/renning","type":"Person","preferredUsername":"renning","inbox":"https://hotdog.place/users/renning/inbox","outbox":"https://hotdog.place/users/renning/outbox","followers":"https://hotdog.place/users/renning/followers","following":"https://hotdog.place/users/renning/following","publicKey":{"id":"https://hotdog.place/users/renning#main-key","owner":"https://hotdog.place/users/renning","publicKeyPem":"-----BEGIN PUBLIC KEY-----\nMIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEAokhkD5QZh/eEb1mB9NRx\nfEm/aK05jSveg3X43s8LVoPQYY4030ql+IfHnsRtEJuzH5VWsYovjweT7ButDRX2\nAmk8IS94cqF7frDPDfBrNKJXfapmL7d3VuXU+BGOfLJZBK0NaEXvLK+Tssla4u+G\nUNinYnbOjnXvDOEkTOVpwTpcutHWSZrOcI8AdBXU3dv/c57sKXoIDZbVF9ZWEudL\n6/LsW0bpvXcBDPq1njOC9/WQcgtoe40WF6tROopyTZ/J+jlIKDuySW2/tsTrP6lg\nQ9TBzkj19leFDvCo6oWZ8aD6z8k5N6/ZAVjFtnivujc4rcoyPDPZArhIEP3n6R0d\n2QIDAQAB\n-----END PUBLIC KEY-----\n"}}"#,
+);
+
+/// A client that may fetch from loopback, as mock servers in tests listen there.
+#[cfg(test)]
+pub(crate) fn dev_client() -> Client {
+    use clap::Parser;
+    let cfg = Config::parse_from([
+        "rap-server",
+        "--domain",
+        "example.com",
+        "--allow-private-fetches",
+        "--max-fetch-size",
+        "1024",
+    ]);
+    Client::new(&cfg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[test]
+    fn test_is_public() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{} should be private", ip);
+        }
+        for ip in ["93.184.216.34", "2606:2800:220:1:248:1893:25c8:1946"] {
+            assert!(is_public(ip.parse().unwrap()), "{} should be public", ip);
+        }
+    }
+
+    /// Serves `body`, but only to requests whose Accept header is exactly
+    /// `accept`; everything else gets a 406.
+    async fn picky_server(body: String, accept: &'static str) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let body = body.clone();
+                tokio::spawn(async move {
+                    let mut buf = [0; 4096];
+                    let n = socket.read(&mut buf).await.unwrap();
+                    let request = String::from_utf8_lossy(&buf[..n]).to_lowercase();
+                    let wanted = format!("\r\naccept: {}\r\n", accept.to_lowercase());
+                    let response = if request.contains(&wanted) {
+                        format!(
+                            "HTTP/1.1 200 OK\r\ncontent-type: application/ld+json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                            body.len(),
+                            body
+                        )
+                    } else {
+                        "HTTP/1.1 406 Not Acceptable\r\ncontent-length: 0\r\nconnection: close\r\n\r\n".to_string()
+                    };
+                    let _ = socket.write_all(response.as_bytes()).await;
+                });
+            }
+        });
+        format!("http://{}/", addr)
+    }
+
+    #[tokio::test]
+    async fn test_fetch() {
+        let url = mock_server(
+            r#"{"id":"https://remote.example/users/bob"}"#.to_string(),
+            true,
+        )
+        .await;
+        let (headers, body) = dev_client()
+            .fetch(&url, "application/activity+json")
+            .await
+            .unwrap();
+        assert_eq!(headers["content-type"], "application/activity+json");
+        let value: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(value["id"], "https://remote.example/users/bob");
+    }
+
+    #[tokio::test]
+    async fn test_get_activity_json_retries_on_406() {
+        let url = picky_server(
+            r#"{"id":"https://remote.example/users/bob"}"#.to_string(),
+            LD_JSON,
+        )
+        .await;
+        let value: serde_json::Value = dev_client().get_activity_json(&url).await.unwrap();
+        assert_eq!(value["id"], "https://remote.example/users/bob");
+    }
+
+    /// Serves `/status` as an HTML page with `head` in its headers and `body`
+    /// as its body, and `/status.json` as its ActivityStreams representation.
+    async fn status_page_server(head: &'static str, body: &'static str) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut buf = [0; 4096];
+                    let n = socket.read(&mut buf).await.unwrap();
+                    let request = String::from_utf8_lossy(&buf[..n]).to_string();
+                    let response = if request.starts_with("GET /status.json ") {
+                        let json = r#"{"id":"https://remote.example/notes/1","type":"Note"}"#;
+                        format!(
+                            "HTTP/1.1 200 OK\r\ncontent-type: application/activity+json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                            json.len(),
+                            json
+                        )
+                    } else {
+                        format!(
+                            "HTTP/1.1 200 OK\r\ncontent-type: text/html; charset=utf-8\r\n{}content-length: {}\r\nconnection: close\r\n\r\n{}",
+                            head,
+                            body.len(),
+                            body
+                        )
+                    };
+                    let _ = socket.write_all(response.as_bytes()).await;
+                });
+            }
+        });
+        format!("http://{}/status", addr)
+    }
+
+    #[tokio::test]
+    async fn test_alternate_link_header() {
+        let url = status_page_server(
+            "link: <https://remote.example/atom>; rel=\"alternate\"; type=\"application/atom+xml\", </status.json>; rel=\"alternate\"; type=\"application/activity+json\"\r\n",
+            "<html></html>",
+        )
+        .await;
+        let value: serde_json::Value = dev_client().get_activity_json(&url).await.unwrap();
+        assert_eq!(value["id"], "https://remote.example/notes/1");
+    }
+
+    #[tokio::test]
+    async fn test_alternate_link_tag() {
+        let url = status_page_server(
+            "",
+            r#"<html><head>
+<link rel="alternate" type="application/rss+xml" href="/feed.rss">
+<LINK href='/status.json' rel='alternate' type='application/activity+json'/>
+</head></html>"#,
+        )
+        .await;
+        let value: serde_json::Value = dev_client().get_activity_json(&url).await.unwrap();
+        assert_eq!(value["type"], "Note");
+
+        let url = status_page_server("", "<html>no links</html>").await;
+        let err = dev_client()
+            .get_activity_json::<serde_json::Value>(&url)
+            .await
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("without an ActivityStreams alternate"));
+    }
+
+    #[tokio::test]
+    async fn test_alternate_link_on_another_origin() {
+        let url = status_page_server(
+            "link: <https://elsewhere.example/status.json>; rel=\"alternate\"; type=\"application/activity+json\"\r\n",
+            "<html></html>",
+        )
+        .await;
+        let err = dev_client()
+            .get_activity_json::<serde_json::Value>(&url)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("on another origin"), "{}", err);
+    }
+
+    #[test]
+    fn test_tag_attribute() {
+        let tag = r#"<link data-rel="x" rel=alternate href="/a b" type='t'"#;
+        assert_eq!(tag_attribute(tag, "rel"), Some("alternate"));
+        assert_eq!(tag_attribute(tag, "href"), Some("/a b"));
+        assert_eq!(tag_attribute(tag, "type"), Some("t"));
+        assert_eq!(tag_attribute(tag, "title"), None);
+    }
+
+    #[tokio::test]
+    async fn test_get_activity_json_gives_up() {
+        let url = picky_server("{}".to_string(), "text/html").await;
+        let err = dev_client()
+            .get_activity_json::<serde_json::Value>(&url)
+            .await
+            .unwrap_err();
+        assert!(is_not_acceptable(err.as_ref()), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_signed_get() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/users/bob", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 4096];
+            let n = socket.read(&mut buf).await.unwrap();
+            let _ = socket
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\nconnection: close\r\n\r\n{}")
+                .await;
+            String::from_utf8_lossy(&buf[..n]).to_lowercase()
+        });
+
+        let key = Key::new("https://example.com/actor".to_string()).unwrap();
+        dev_client()
+            .signed_by(key)
+            .fetch(&url, ACTIVITY_JSON)
+            .await
+            .unwrap();
+        let request = server.await.unwrap();
+        assert!(
+            request.contains("signature: keyid=\"https://example.com/actor/#main-key\""),
+            "{}",
+            request
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fetch_too_large() {
+        let body = format!(r#"{{"padding":"{}"}}"#, "a".repeat(2048));
+        for content_length in [true, false] {
+            let url = mock_server(body.clone(), content_length).await;
+            let err = dev_client()
+                .fetch(&url, "application/activity+json")
+                .await
+                .unwrap_err();
+            assert!(
+                err.to_string().contains("larger than 1024 bytes"),
+                "{}",
+                err
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_refuses_loopback() {
+        let cfg = Config::parse_from(["rap-server", "--domain", "example.com"]);
+        let err = Client::new(&cfg)
+            .get("http://127.0.0.1/users/alice#main-key", "application/json")
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("Refusing to fetch"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_refuses_non_http() {
+        let cfg = Config::parse_from(["rap-server", "--domain", "example.com"]);
+        let err = Client::new(&cfg)
+            .get("file:///etc/passwd", "application/json")
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("Unsupported scheme"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_actor_cache_inbox_url() {
+        let url = mock_server(
+            serde_json::json!({
+                "id": "https://remote.example/users/bob",
+                "type": "Person",
+                "inbox": "https://remote.example/users/bob/inbox",
+                "outbox": "https://remote.example/users/bob/outbox",
+                "following": "https://remote.example/users/bob/following",
+                "followers": "https://remote.example/users/bob/followers",
+                "endpoints": {"sharedInbox": "https://remote.example/inbox"},
+            })
+            .to_string(),
+            true,
+        )
+        .await;
+        let actors = ActorCache::new(dev_client(), Duration::from_secs(60));
+        assert_eq!(
+            actors.inbox_url(&url, false).await.unwrap(),
+            "https://remote.example/users/bob/inbox"
+        );
+        assert_eq!(
+            actors.inbox_url(&url, true).await.unwrap(),
+            "https://remote.example/inbox"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_actor_cache_minimal_actor() {
+        let client = dev_client().with_fixtures(&[(
+            "https://remote.example/users/bob",
+            r#"{"id": "https://remote.example/users/bob", "inbox": "https://remote.example/users/bob/inbox"}"#,
+        )]);
+        let actors = ActorCache::new(client, Duration::from_secs(60));
+        for shared in [false, true] {
+            assert_eq!(
+                actors
+                    .inbox_url("https://remote.example/users/bob", shared)
+                    .await
+                    .unwrap(),
+                "https://remote.example/users/bob/inbox"
+            );
+        }
+    }
+}
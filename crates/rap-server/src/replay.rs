@@ -0,0 +1,207 @@
+use axum::http::HeaderMap;
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+use crate::scheme;
+use crate::signed::header_str;
+use crate::utils::{web_err_400, web_err_401, WebError};
+
+/// Signatures seen within `--replay-window`, so a captured request can't be
+/// sent to us again while its `date` still passes as recent.
+#[derive(Clone)]
+pub struct ReplayCache {
+    /// How far a `date` may be behind our clock.
+    past: Duration,
+    /// How far a `date` may be ahead of our clock.
+    future: Duration,
+    seen: Arc<Mutex<HashMap<(String, String), Instant>>>,
+}
+
+impl ReplayCache {
+    /// The cache for the configured window, if there is one.
+    pub fn new(cfg: &Config) -> Option<Self> {
+        let window = cfg.replay_window?;
+        Some(Self::with_skew(
+            Duration::from_secs(window),
+            Duration::from_secs(cfg.max_future_skew.unwrap_or(window)),
+        ))
+    }
+
+    fn with_skew(past: Duration, future: Duration) -> Self {
+        Self {
+            past,
+            future,
+            seen: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Checks a request whose signature verified: the signature has to cover
+    /// a `date` no further from `now` than allowed, and must not have been
+    /// seen before.
+    pub fn check(&self, headers: &HeaderMap, now: DateTime<Utc>) -> Result<(), WebError> {
+        let signature = scheme::detect(headers).parse(headers)?;
+        if !signature
+            .headers
+            .iter()
+            .any(|h| h.eq_ignore_ascii_case("date"))
+        {
+            return Err(web_err_401(format!(
+                "Signature by {} doesn't cover date",
+                signature.key_id
+            )));
+        }
+
+        let date = DateTime::parse_from_rfc2822(header_str(headers, "date")?)
+            .map_err(|e| web_err_400(format!("Invalid header date: {}", e)))?;
+        let age = (now - date.with_timezone(&Utc)).num_seconds();
+        if age > self.past.as_secs() as i64 {
+            return Err(web_err_401(format!(
+                "Date {} is more than {} seconds old",
+                date,
+                self.past.as_secs()
+            )));
+        }
+        if -age > self.future.as_secs() as i64 {
+            return Err(web_err_401(format!(
+                "Date {} is more than {} seconds ahead",
+                date,
+                self.future.as_secs()
+            )));
+        }
+
+        if !self.first_seen(&signature.key_id, &signature.signature) {
+            return Err(web_err_401(format!(
+                "Signature by {} was already used",
+                signature.key_id
+            )));
+        }
+        Ok(())
+    }
+
+    /// Records a signature, returning whether it is new. A request passes for
+    /// as long as its `date` is from `future` ahead of us to `past` behind,
+    /// so signatures are kept for both together.
+    fn first_seen(&self, key_id: &str, signature: &str) -> bool {
+        let now = Instant::now();
+        let mut seen = self.seen.lock().unwrap();
+        seen.retain(|_, at| now.duration_since(*at) < self.past + self.future);
+        seen.insert((key_id.to_string(), signature.to_string()), now)
+            .is_none()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::{HeaderValue, StatusCode};
+
+    const DATE: &str = "Sun, 06 Nov 1994 08:49:37 GMT";
+
+    fn headers(covered: &str, signature: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("date", HeaderValue::from_static(DATE));
+        headers.insert(
+            "signature",
+            HeaderValue::from_str(&format!(
+                "keyId=\"https://remote.example/users/bob#main-key\",headers=\"{}\",signature=\"{}\"",
+                covered, signature
+            ))
+            .unwrap(),
+        );
+        headers
+    }
+
+    fn now(offset: i64) -> DateTime<Utc> {
+        DateTime::parse_from_rfc2822(DATE)
+            .unwrap()
+            .with_timezone(&Utc)
+            + chrono::Duration::seconds(offset)
+    }
+
+    #[test]
+    fn test_replay() {
+        let replays = ReplayCache::with_skew(Duration::from_secs(300), Duration::from_secs(300));
+        let signed = headers("(request-target) host date", "c2lnbmF0dXJl");
+        replays.check(&signed, now(0)).unwrap();
+        let (status, message) = replays.check(&signed, now(0)).unwrap_err();
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            message,
+            "Signature by https://remote.example/users/bob#main-key was already used"
+        );
+
+        // another signature by the same key is fine
+        let other = headers("(request-target) host date", "b3RoZXI=");
+        replays.check(&other, now(0)).unwrap();
+    }
+
+    #[test]
+    fn test_date() {
+        let replays = ReplayCache::with_skew(Duration::from_secs(300), Duration::from_secs(60));
+        // `now(offset)` is `offset` seconds after the date, so negative
+        // offsets are dates from the future
+        for (offset, ok) in [
+            (120, true),
+            (300, true),
+            (-60, true),
+            (301, false),
+            (-61, false),
+        ] {
+            let signed = headers("(request-target) date", &format!("c2ln{}", offset));
+            let result = replays.check(&signed, now(offset));
+            assert_eq!(result.is_ok(), ok, "{}: {:?}", offset, result);
+        }
+
+        let future = headers("(request-target) date", "ZnV0dXJl");
+        let (_, message) = replays.check(&future, now(-120)).unwrap_err();
+        assert_eq!(
+            message,
+            "Date 1994-11-06 08:49:37 +00:00 is more than 60 seconds ahead"
+        );
+
+        let unsigned_date = headers("(request-target) host", "c2lnbmF0dXJl");
+        let (status, _) = replays.check(&unsigned_date, now(0)).unwrap_err();
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn test_future_skew_defaults_to_window() {
+        use clap::Parser;
+        let cfg = Config::parse_from([
+            "rap-server",
+            "--domain",
+            "example.com",
+            "--replay-window",
+            "300",
+        ]);
+        let replays = ReplayCache::new(&cfg).unwrap();
+        assert_eq!(replays.past, Duration::from_secs(300));
+        assert_eq!(replays.future, Duration::from_secs(300));
+
+        let cfg = Config::parse_from([
+            "rap-server",
+            "--domain",
+            "example.com",
+            "--replay-window",
+            "300",
+            "--max-future-skew",
+            "60",
+        ]);
+        assert_eq!(
+            ReplayCache::new(&cfg).unwrap().future,
+            Duration::from_secs(60)
+        );
+
+        assert!(Config::try_parse_from([
+            "rap-server",
+            "--domain",
+            "example.com",
+            "--max-future-skew",
+            "60"
+        ])
+        .is_err());
+    }
+}
@@ -0,0 +1,445 @@
+use crate::key::PublicKey;
+use crate::signature::Signature;
+use crate::signed::{header_str, rebuild_sig_str};
+use crate::utils::{web_err_400, web_err_401, WebError};
+use axum::http::HeaderMap;
+use std::error::Error;
+
+/// A way of signing HTTP requests. Each reads the signature from the headers
+/// it uses into a [`Signature`], rebuilds what it covers from the request,
+/// and checks it against the signer's key, so verifying a request doesn't
+/// have to know which one it was signed with.
+pub trait SignatureScheme: Send + Sync {
+    /// Reads the signature from the request's headers, rejecting algorithms
+    /// we can't verify before any key is fetched.
+    fn parse(&self, headers: &HeaderMap) -> Result<Signature, WebError>;
+
+    /// The string `signature` claims to cover, for the request to `target`,
+    /// a `(request-target)` as built by [`request_target`].
+    ///
+    /// [`request_target`]: crate::signed::request_target
+    fn canonicalize(
+        &self,
+        target: &str,
+        headers: &HeaderMap,
+        signature: &Signature,
+    ) -> Result<String, WebError>;
+
+    /// Checks the decoded `signature` of `comparison` against `pubkey`.
+    fn verify(
+        &self,
+        pubkey: &PublicKey,
+        signature: &Signature,
+        comparison: &str,
+        decoded: &[u8],
+    ) -> Result<(), Box<dyn Error>>;
+}
+
+/// The scheme a request was signed with: RFC 9421 if it has a
+/// `signature-input` header, the Cavage draft everyone else uses otherwise.
+pub fn detect(headers: &HeaderMap) -> &'static dyn SignatureScheme {
+    if headers.contains_key("signature-input") {
+        &Rfc9421
+    } else {
+        &Cavage
+    }
+}
+
+/// The draft-cavage-http-signatures `signature` header, with its `keyId`,
+/// `algorithm`, `headers` and `signature` parameters.
+pub struct Cavage;
+
+impl SignatureScheme for Cavage {
+    fn parse(&self, headers: &HeaderMap) -> Result<Signature, WebError> {
+        let signature = Signature::from_headers(header_str(headers, "signature")?)
+            .map_err(|e| web_err_400(format!("Error parsing signature header: {}", e)))?;
+        Algorithm::from_param(signature.algorithm.as_deref())?;
+        Ok(signature)
+    }
+
+    fn canonicalize(
+        &self,
+        target: &str,
+        headers: &HeaderMap,
+        signature: &Signature,
+    ) -> Result<String, WebError> {
+        Ok(rebuild_sig_str(target, headers, signature))
+    }
+
+    fn verify(
+        &self,
+        pubkey: &PublicKey,
+        signature: &Signature,
+        comparison: &str,
+        decoded: &[u8],
+    ) -> Result<(), Box<dyn Error>> {
+        let algorithm =
+            Algorithm::from_param(signature.algorithm.as_deref()).map_err(|(_, e)| e)?;
+        verify_signature(pubkey, algorithm, comparison, decoded)
+    }
+}
+
+/// RFC 9421 HTTP Message Signatures: the covered components and parameters
+/// in `signature-input`, the signature itself in `signature`. Only the first
+/// signature of a request is looked at.
+pub struct Rfc9421;
+
+impl SignatureScheme for Rfc9421 {
+    fn parse(&self, headers: &HeaderMap) -> Result<Signature, WebError> {
+        let input = SignatureInput::from_headers(headers)?;
+        let algorithm = input.param("alg").map(str::to_string);
+        Algorithm::from_alg(algorithm.as_deref())?;
+        let key_id = input
+            .param("keyid")
+            .ok_or_else(|| web_err_400("Error parsing signature-input header: keyid not found"))?
+            .to_string();
+
+        let signature = header_str(headers, "signature")?
+            .split(',')
+            .filter_map(|member| member.split_once('='))
+            .find(|(label, _)| label.trim() == input.label)
+            .and_then(|(_, value)| {
+                value
+                    .trim()
+                    .strip_prefix(':')
+                    .and_then(|v| v.strip_suffix(':'))
+            })
+            .ok_or_else(|| {
+                web_err_400(format!(
+                    "Error parsing signature header: no signature {}",
+                    input.label
+                ))
+            })?
+            .to_string();
+
+        Ok(Signature {
+            key_id,
+            algorithm,
+            headers: input.components,
+            signature,
+        })
+    }
+
+    fn canonicalize(
+        &self,
+        target: &str,
+        headers: &HeaderMap,
+        signature: &Signature,
+    ) -> Result<String, WebError> {
+        let input = SignatureInput::from_headers(headers)?;
+        let mut lines = Vec::new();
+        for name in &signature.headers {
+            lines.push(format!(
+                "\"{}\": {}",
+                name,
+                component(target, headers, name)?
+            ));
+        }
+        lines.push(format!("\"@signature-params\": {}", input.value));
+        Ok(lines.join("\n"))
+    }
+
+    fn verify(
+        &self,
+        pubkey: &PublicKey,
+        signature: &Signature,
+        comparison: &str,
+        decoded: &[u8],
+    ) -> Result<(), Box<dyn Error>> {
+        let algorithm = Algorithm::from_alg(signature.algorithm.as_deref()).map_err(|(_, e)| e)?;
+        verify_signature(pubkey, algorithm, comparison, decoded)
+    }
+}
+
+/// The first member of a `signature-input` header, e.g.
+/// `sig1=("@method" "@path" "date");keyid="…";alg="rsa-v1_5-sha256"`.
+struct SignatureInput<'a> {
+    label: &'a str,
+    /// Everything after the label, which the signature base ends with as
+    /// sent.
+    value: &'a str,
+    components: Vec<String>,
+    params: Vec<(&'a str, &'a str)>,
+}
+
+impl<'a> SignatureInput<'a> {
+    fn from_headers(headers: &'a HeaderMap) -> Result<Self, WebError> {
+        let input = header_str(headers, "signature-input")?;
+        Self::parse(input)
+            .ok_or_else(|| web_err_400(format!("Error parsing signature-input header: {}", input)))
+    }
+
+    fn parse(input: &'a str) -> Option<Self> {
+        let (label, value) = input.split_once('=')?;
+        let value = value.trim_start();
+        let inner = value.strip_prefix('(')?;
+        let end = inner.find(')')?;
+        let components = inner[..end]
+            .split_whitespace()
+            .map(|c| c.strip_prefix('"')?.strip_suffix('"').map(str::to_string))
+            .collect::<Option<Vec<_>>>()?;
+
+        let mut params = Vec::new();
+        let mut rest = &inner[end + 1..];
+        while let Some(param) = rest.strip_prefix(';') {
+            let (name, tail) = param.split_once('=')?;
+            let (value, tail) = match tail.strip_prefix('"') {
+                Some(quoted) => {
+                    let end = quoted.find('"')?;
+                    (&quoted[..end], &quoted[end + 1..])
+                }
+                None => tail.split_at(tail.find([';', ',']).unwrap_or(tail.len())),
+            };
+            params.push((name.trim(), value));
+            rest = tail;
+        }
+
+        Some(Self {
+            label: label.trim(),
+            value: &value[..value.len() - rest.len()],
+            components,
+            params,
+        })
+    }
+
+    fn param(&self, name: &str) -> Option<&'a str> {
+        self.params
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, value)| *value)
+    }
+}
+
+/// The value of a covered component: a derived `@` component from `target`
+/// or the `host` header, or a header, which has to be there.
+fn component(target: &str, headers: &HeaderMap, name: &str) -> Result<String, WebError> {
+    let (method, request_target) = target.split_once(' ').unwrap_or((target, ""));
+    let (path, query) = match request_target.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (request_target, None),
+    };
+    match name {
+        "@method" => Ok(method.to_uppercase()),
+        "@path" => Ok(path.to_string()),
+        "@query" => Ok(format!("?{}", query.unwrap_or(""))),
+        "@request-target" => Ok(request_target.to_string()),
+        "@authority" => Ok(header_str(headers, "host")?.to_lowercase()),
+        name if name.starts_with('@') => Err(web_err_400(format!(
+            "Unsupported signature component {}",
+            name
+        ))),
+        name => headers
+            .get(name)
+            .and_then(|h| h.to_str().ok())
+            .map(|h| h.trim().to_string())
+            .ok_or_else(|| {
+                web_err_401(format!(
+                    "Signature covers header {}, which the request doesn't have",
+                    name
+                ))
+            }),
+    }
+}
+
+/// The signature algorithms we accept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Algorithm {
+    RsaSha256,
+    RsaPssSha512,
+    /// "Derive it from the key". For RSA keys the draft means PSS with
+    /// SHA-512, but Mastodon and most others actually sign `hs2019` with
+    /// PKCS#1 v1.5 and SHA-256, so both are tried.
+    Hs2019,
+}
+
+impl Algorithm {
+    /// From the Cavage `algorithm` parameter.
+    fn from_param(algorithm: Option<&str>) -> Result<Self, WebError> {
+        match algorithm.map(str::to_lowercase).as_deref() {
+            None | Some("rsa-sha256") => Ok(Algorithm::RsaSha256),
+            Some("hs2019") => Ok(Algorithm::Hs2019),
+            Some(other) => Err(web_err_400(format!(
+                "Unsupported signature algorithm {}",
+                other
+            ))),
+        }
+    }
+
+    /// From the RFC 9421 `alg` parameter. Without one the key decides, the
+    /// same as `hs2019`.
+    fn from_alg(alg: Option<&str>) -> Result<Self, WebError> {
+        match alg {
+            None => Ok(Algorithm::Hs2019),
+            Some("rsa-v1_5-sha256") => Ok(Algorithm::RsaSha256),
+            Some("rsa-pss-sha512") => Ok(Algorithm::RsaPssSha512),
+            Some(other) => Err(web_err_400(format!(
+                "Unsupported signature algorithm {}",
+                other
+            ))),
+        }
+    }
+}
+
+fn verify_signature(
+    pubkey: &PublicKey,
+    algorithm: Algorithm,
+    comparison: &str,
+    signature: &[u8],
+) -> Result<(), Box<dyn Error>> {
+    let data = comparison.as_bytes();
+    match algorithm {
+        Algorithm::RsaSha256 => pubkey.verify(data, signature),
+        Algorithm::RsaPssSha512 => pubkey.verify_pss(data, signature),
+        Algorithm::Hs2019 => pubkey
+            .verify_pss(data, signature)
+            .or_else(|_| pubkey.verify(data, signature)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key::Key;
+    use axum::http::{HeaderValue, StatusCode};
+
+    const INPUT: &str = r#"sig1=("@method" "@path" "@query" "@authority" "date");created=1618884473;keyid="https://remote.example/users/bob#main-key";alg="rsa-v1_5-sha256""#;
+
+    fn rfc9421_headers() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("host", HeaderValue::from_static("AP.rens.page"));
+        headers.insert(
+            "date",
+            HeaderValue::from_static("Tue, 20 Apr 2021 02:07:55 GMT"),
+        );
+        headers.insert("signature-input", HeaderValue::from_static(INPUT));
+        headers.insert("signature", HeaderValue::from_static("sig1=:c2ln:"));
+        headers
+    }
+
+    #[test]
+    fn test_detect() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "signature",
+            HeaderValue::from_static(
+                r#"keyId="https://remote.example/users/bob#main-key",signature="c2ln""#,
+            ),
+        );
+        let signature = detect(&headers).parse(&headers).unwrap();
+        assert_eq!(signature.headers, ["date"]);
+
+        let headers = rfc9421_headers();
+        let signature = detect(&headers).parse(&headers).unwrap();
+        assert_eq!(
+            signature.key_id,
+            "https://remote.example/users/bob#main-key"
+        );
+        assert_eq!(signature.algorithm.as_deref(), Some("rsa-v1_5-sha256"));
+        assert_eq!(
+            signature.headers,
+            ["@method", "@path", "@query", "@authority", "date"]
+        );
+        assert_eq!(signature.signature, "c2ln");
+    }
+
+    #[test]
+    fn test_rfc9421_canonicalize() {
+        let headers = rfc9421_headers();
+        let signature = Rfc9421.parse(&headers).unwrap();
+        assert_eq!(
+            Rfc9421
+                .canonicalize("post /users/alice/inbox?page=1", &headers, &signature)
+                .unwrap(),
+            format!(
+                "\"@method\": POST\n\
+                 \"@path\": /users/alice/inbox\n\
+                 \"@query\": ?page=1\n\
+                 \"@authority\": ap.rens.page\n\
+                 \"date\": Tue, 20 Apr 2021 02:07:55 GMT\n\
+                 \"@signature-params\": {}",
+                INPUT.strip_prefix("sig1=").unwrap()
+            )
+        );
+
+        let mut headers = rfc9421_headers();
+        headers.remove("date");
+        let (status, message) = Rfc9421
+            .canonicalize("post /users/alice/inbox", &headers, &signature)
+            .unwrap_err();
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert!(message.contains("date"), "{}", message);
+    }
+
+    #[test]
+    fn test_rfc9421_parse_errors() {
+        for (input, signature) in [
+            (r#"sig1=("date" "#, "sig1=:c2ln:"),
+            (r#"sig1=("date");alg="rsa-v1_5-sha256""#, "sig1=:c2ln:"),
+            (INPUT, "sig2=:c2ln:"),
+            (
+                r#"sig1=("date");keyid="https://remote.example/users/bob#main-key";alg="hmac-sha256""#,
+                "sig1=:c2ln:",
+            ),
+        ] {
+            let mut headers = HeaderMap::new();
+            headers.insert("signature-input", HeaderValue::from_static(input));
+            headers.insert("signature", HeaderValue::from_static(signature));
+            let (status, _) = Rfc9421.parse(&headers).unwrap_err();
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_verify_signature_invalid() {
+        let key = Key::new("https://example.com/users/bob".to_string()).unwrap();
+        let signature = key.sign(b"something else").unwrap();
+
+        verify_signature(
+            &key.public_key().unwrap(),
+            Algorithm::RsaSha256,
+            "date: Sun, 06 Nov 2021 08:49:37 GMT",
+            &signature,
+        )
+        .unwrap_err();
+    }
+
+    #[test]
+    fn test_algorithm_param() {
+        assert_eq!(Algorithm::from_param(None).unwrap(), Algorithm::RsaSha256);
+        assert_eq!(
+            Algorithm::from_param(Some("rsa-sha256")).unwrap(),
+            Algorithm::RsaSha256
+        );
+        assert_eq!(
+            Algorithm::from_param(Some("hs2019")).unwrap(),
+            Algorithm::Hs2019
+        );
+        let (status, _) = Algorithm::from_param(Some("hmac-sha256")).unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        assert_eq!(Algorithm::from_alg(None).unwrap(), Algorithm::Hs2019);
+        assert_eq!(
+            Algorithm::from_alg(Some("rsa-pss-sha512")).unwrap(),
+            Algorithm::RsaPssSha512
+        );
+    }
+
+    #[test]
+    fn test_verify_signature_algorithms() {
+        let key = Key::new("https://example.com/users/bob".to_string()).unwrap();
+        let public_key = key.public_key().unwrap();
+        let data = "date: Sun, 06 Nov 2021 08:49:37 GMT";
+        let pkcs1 = key.sign(data.as_bytes()).unwrap();
+        let pss = key.sign_pss(data.as_bytes()).unwrap();
+
+        // rsa-sha256 is always PKCS#1 v1.5, rsa-pss-sha512 always PSS
+        verify_signature(&public_key, Algorithm::RsaSha256, data, &pkcs1).unwrap();
+        verify_signature(&public_key, Algorithm::RsaSha256, data, &pss).unwrap_err();
+        verify_signature(&public_key, Algorithm::RsaPssSha512, data, &pss).unwrap();
+        verify_signature(&public_key, Algorithm::RsaPssSha512, data, &pkcs1).unwrap_err();
+        // hs2019 could be either
+        verify_signature(&public_key, Algorithm::Hs2019, data, &pkcs1).unwrap();
+        verify_signature(&public_key, Algorithm::Hs2019, data, &pss).unwrap();
+        verify_signature(&public_key, Algorithm::Hs2019, "something else", &pss).unwrap_err();
+    }
+}
@@ -0,0 +1,172 @@
+//! Local users created at startup from a seed file, so a deployment always
+//! comes up with the same people and keys.
+
+use serde::Deserialize;
+use std::error::Error;
+use std::path::Path;
+use tracing::info;
+
+use crate::config::Config;
+use crate::crypto;
+use crate::key::Key;
+use crate::users::{PeopleStore, Person};
+
+#[derive(Deserialize, Debug)]
+struct Seed {
+    username: String,
+    /// Path of the PEM private key, relative to the seed file.
+    key: String,
+}
+
+/// Adds everyone listed in the seed file at `path` to `people`, returning how
+/// many of them weren't there already.
+pub async fn load(
+    cfg: &Config,
+    people: &dyn PeopleStore,
+    path: &Path,
+) -> Result<usize, Box<dyn Error>> {
+    let seeds: Vec<Seed> = serde_json::from_str(&std::fs::read_to_string(path)?)
+        .map_err(|e| format!("Error parsing {}: {}", path.display(), e))?;
+    let dir = path.parent().unwrap_or(Path::new("."));
+
+    let mut added = 0;
+    for seed in seeds {
+        let key_path = dir.join(&seed.key);
+        let pem = read_key(cfg, &key_path)
+            .map_err(|e| format!("Error reading {}: {}", key_path.display(), e))?;
+        let key = Key::from_private_key_pem(cfg.actor_url(&seed.username), pem)
+            .map_err(|e| format!("Error loading key of {}: {}", seed.username, e))?;
+        if people
+            .add(Person::with_key(cfg, &seed.username, key))
+            .await?
+        {
+            info!("Seeded {}", seed.username);
+            added += 1;
+        }
+    }
+    Ok(added)
+}
+
+/// The PEM private key at `path`, decrypted with `--key-encryption-key` when
+/// that is set.
+fn read_key(cfg: &Config, path: &Path) -> Result<String, Box<dyn Error>> {
+    let contents = std::fs::read_to_string(path)?;
+    let Some(passphrase) = &cfg.key_encryption_key else {
+        return Ok(contents);
+    };
+    let sealed: crypto::Sealed = serde_json::from_str(&contents)?;
+    Ok(String::from_utf8(crypto::open(&sealed, passphrase)?)?)
+}
+
+/// Encrypts the PEM private key at `path` with `passphrase`, as a seed file
+/// key read with `--key-encryption-key`.
+pub fn seal_key(path: &Path, passphrase: &str) -> Result<String, Box<dyn Error>> {
+    let pem = std::fs::read_to_string(path)?;
+    Ok(serde_json::to_string(&crypto::seal(
+        pem.as_bytes(),
+        passphrase,
+    )?)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{body_json, test_instance};
+    use crate::users::InMemoryPeopleStore;
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use clap::Parser;
+    use std::sync::Arc;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_seeded_actors_present() {
+        let dir = std::env::temp_dir().join(format!("rap-seed-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("keys")).unwrap();
+        let (private_key_pem, public_key_pem) = crypto::generate_keypair().unwrap();
+        std::fs::write(dir.join("keys/alice.pem"), private_key_pem).unwrap();
+        let seed_file = dir.join("seed.json");
+        std::fs::write(
+            &seed_file,
+            r#"[{"username": "alice", "key": "keys/alice.pem"}]"#,
+        )
+        .unwrap();
+
+        let cfg = Config::parse_from(["rap-server", "--domain", "example.com"]);
+        let people = Arc::new(InMemoryPeopleStore::new(cfg.clone()));
+        assert_eq!(load(&cfg, people.as_ref(), &seed_file).await.unwrap(), 1);
+        // loading again leaves them be
+        assert_eq!(load(&cfg, people.as_ref(), &seed_file).await.unwrap(), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let app = crate::app(
+            &cfg,
+            people,
+            Arc::new(crate::objects::InMemoryObjectStore::new()),
+            crate::delivery::DeliveryQueue::new().0,
+            test_instance(&cfg),
+        );
+        let response = app
+            .oneshot(Request::get("/users/alice").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let actor = body_json(response).await;
+        assert_eq!(actor["publicKey"]["publicKeyPem"], public_key_pem);
+    }
+
+    #[tokio::test]
+    async fn test_sealed_keys() {
+        let dir = std::env::temp_dir().join(format!("rap-seed-sealed-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (private_key_pem, public_key_pem) = crypto::generate_keypair().unwrap();
+        std::fs::write(dir.join("carol.pem"), private_key_pem).unwrap();
+        let sealed = seal_key(&dir.join("carol.pem"), "hunter2").unwrap();
+        assert!(!sealed.contains("PRIVATE KEY"));
+        std::fs::write(dir.join("carol.sealed"), sealed).unwrap();
+        let seed_file = dir.join("seed.json");
+        std::fs::write(
+            &seed_file,
+            r#"[{"username": "carol", "key": "carol.sealed"}]"#,
+        )
+        .unwrap();
+
+        let with_passphrase = |passphrase: &str| {
+            Config::parse_from([
+                "rap-server",
+                "--domain",
+                "example.com",
+                "--key-encryption-key",
+                passphrase,
+            ])
+        };
+        let cfg = with_passphrase("hunter3");
+        let people = InMemoryPeopleStore::new(cfg.clone());
+        let err = load(&cfg, &people, &seed_file).await.unwrap_err();
+        assert!(err.to_string().contains("carol.sealed"), "{}", err);
+        assert_eq!(people.count().await.unwrap(), 0);
+
+        let cfg = with_passphrase("hunter2");
+        let people = InMemoryPeopleStore::new(cfg.clone());
+        let loaded = load(&cfg, &people, &seed_file).await;
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(loaded.unwrap(), 1);
+        let carol = people.get_by_username("carol").await.unwrap().unwrap();
+        assert_eq!(carol.key.public_key().unwrap().pem(), public_key_pem);
+    }
+
+    #[tokio::test]
+    async fn test_missing_key() {
+        let dir = std::env::temp_dir().join(format!("rap-seed-missing-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let seed_file = dir.join("seed.json");
+        std::fs::write(&seed_file, r#"[{"username": "bob", "key": "bob.pem"}]"#).unwrap();
+
+        let cfg = Config::parse_from(["rap-server", "--domain", "example.com"]);
+        let people = InMemoryPeopleStore::new(cfg.clone());
+        let err = load(&cfg, &people, &seed_file).await.unwrap_err();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(err.to_string().contains("bob.pem"), "{}", err);
+        assert_eq!(people.count().await.unwrap(), 0);
+    }
+}
@@ -52,9 +52,16 @@ fn params(input: &str) -> IResult<&str, Vec<(&str, String)>> {
         .map(|(remaining, (_prefix, list))| (remaining, list))
 }
 
+/// What a signature without a `headers` parameter covers. The latest drafts
+/// say `(created)`, but earlier ones said `date`, which is what signers
+/// leaving it out actually sign, and we don't support `(created)` anyway.
+pub const DEFAULT_HEADERS: &str = "date";
+
 #[derive(Deserialize, Serialize, Debug)]
 pub struct Signature {
     pub key_id: String,
+    /// The `algorithm` parameter, if the signer sent one.
+    pub algorithm: Option<String>,
     pub headers: Vec<String>,
     pub signature: String,
 }
@@ -63,21 +70,24 @@ impl Signature {
     pub fn from_headers(signature: &str) -> Result<Self, Box<dyn Error + '_>> {
         let (_, params) = params(signature)?;
         let mut key_id = None;
+        let mut algorithm = None;
         let mut headers = None;
         let mut signature = None;
         for (key, value) in params {
             match key {
                 "keyId" => key_id = Some(value),
+                "algorithm" => algorithm = Some(value),
                 "headers" => headers = Some(value),
                 "signature" => signature = Some(value),
                 _ => {}
             }
         }
         let key_id = key_id.ok_or("keyId not found")?;
-        let headers = headers.ok_or("headers not found")?;
+        let headers = headers.unwrap_or_else(|| DEFAULT_HEADERS.to_string());
         let signature = signature.ok_or("signature not found")?;
         Ok(Self {
             key_id,
+            algorithm,
             headers: headers.split(' ').map(|s| s.to_string()).collect(),
             signature,
         })
@@ -86,6 +96,8 @@ impl Signature {
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     fn assert_params(input: &str, expected: &[(&str, &str)]) {
         let (_, parsed) = super::params(input).unwrap();
         let parsed: Vec<(&str, &str)> = parsed
@@ -106,4 +118,22 @@ mod tests {
             ],
         );
     }
+
+    #[test]
+    fn test_default_headers() {
+        let signature = Signature::from_headers(
+            r#"keyId="https://example.com/users/alice#main-key",signature="c2ln""#,
+        )
+        .unwrap();
+        assert_eq!(signature.headers, ["date"]);
+
+        let signature = Signature::from_headers(
+            r#"keyId="https://example.com/users/alice#main-key",headers="(request-target) host",signature="c2ln""#,
+        )
+        .unwrap();
+        assert_eq!(signature.headers, ["(request-target)", "host"]);
+
+        Signature::from_headers(r#"headers="date",signature="c2ln""#).unwrap_err();
+        Signature::from_headers(r#"keyId="https://example.com/users/alice#main-key""#).unwrap_err();
+    }
 }
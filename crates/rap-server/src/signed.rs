@@ -1,12 +1,21 @@
-use crate::key::PublicKey;
+use crate::actor_id::ActorId;
+use crate::config::{Config, MissingSignedHeaders};
+use crate::key::{KeyCache, PublicKey};
+use crate::replay::ReplayCache;
+use crate::scheme;
 use crate::signature::Signature;
-use crate::users::PersonId;
-use crate::utils::{base64_decode, web_err_400, web_err_500, WebError};
+use crate::utils::{base64_decode, web_err_400, web_err_401, web_err_500, WebError};
 use axum::async_trait;
-use axum::extract::{FromRequestParts, Path};
+use axum::extract::{ConnectInfo, FromRequestParts};
+use axum::http::header::HOST;
 use axum::http::request::Parts;
-use axum::http::HeaderMap;
-use log::debug;
+use axum::http::{HeaderMap, HeaderValue, Method, Request, Uri};
+use axum::middleware::Next;
+use axum::response::Response;
+use axum::Extension;
+use rap_core::signing::build_signing_string;
+use std::net::{IpAddr, SocketAddr};
+use tracing::{debug, instrument, warn, Span};
 
 /// # Signed Extractor
 ///
@@ -46,19 +55,26 @@ use log::debug;
 /// The `Signed` extractor depends on the following external crates:
 ///
 /// - `axum`: The Axum web framework for routing and handling HTTP requests.
-/// - `log`: A logging framework used for logging warning messages.
+/// - `tracing`: Used for logging and for the `verify_headers` span.
 /// - `crate::signature::Signature`: Your custom signature implementation.
-/// - `crate::users::PersonId`: Your custom user identifier type.
 /// - `crate::utils::base64_decode`: A utility function for base64 decoding.
 ///
 /// ## Errors
 ///
 /// If the headers are invalid or missing, the `Signed` extractor will return an
-/// error with a status code and an error message. The possible status codes include:
+/// error with a status code and an error message. The status codes map as follows:
 ///
-/// - `StatusCode::BAD_REQUEST`: Indicates that the request headers are invalid or
-///   missing required headers.
-/// - Other status codes as needed based on your application's requirements.
+/// - `StatusCode::UNAUTHORIZED`: The request carries no `signature` header at all,
+///   the signer's public key could not be loaded, or the signature is well-formed
+///   but does not verify against that key. With `--missing-signed-headers strict`,
+///   also when the signature covers a header the request doesn't have. In each
+///   case the request is simply not authenticated.
+///   With `--replay-window`, also when the signature doesn't cover a recent
+///   enough `date` or was already seen.
+/// - `StatusCode::BAD_REQUEST`: A `signature` header is present but is malformed,
+///   e.g. it is missing required parameters or the signature is not valid base64.
+/// - `StatusCode::INTERNAL_SERVER_ERROR`: The extractor was used on a route
+///   without the extensions it needs.
 ///
 /// ## See Also
 ///
@@ -66,18 +82,24 @@ use log::debug;
 ///   verify request headers.
 /// - [`rebuild_sig_str`](fn.rebuild_sig_str.html): The internal function used to
 ///   rebuild the signature string for verification.
+/// - [`request_target`](fn.request_target.html): Builds the `(request-target)`
+///   pseudo-header from the request's method, path, and query.
 /// - [`header_str`](fn.header_str.html): A utility function used to retrieve header
 ///   values from a `HeaderMap`.
 ///
 /// [Axum]: https://docs.rs/axum
-/// [log]: https://docs.rs/log
+/// [tracing]: https://docs.rs/tracing
 /// [`crate::signature::Signature`]: ./struct.Signature.html
-/// [`crate::users::PersonId`]: ./struct.PersonId.html
 /// [`crate::utils::base64_decode`]: ./fn.base64_decode.html
 /// [`verify_headers`]: ./fn.verify_headers.html
 /// [`rebuild_sig_str`]: ./fn.rebuild_sig_str.html
+/// [`request_target`]: ./fn.request_target.html
 /// [`header_str`]: ./fn.header_str.html
-pub struct Signed;
+pub struct Signed {
+    /// The actor whose key signed the request, see [`signer`]. Requests let
+    /// through from `--unsigned-peers` have none.
+    pub signer: Option<String>,
+}
 
 #[async_trait]
 impl<S> FromRequestParts<S> for Signed
@@ -86,22 +108,135 @@ where
 {
     type Rejection = WebError;
     async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
-        // need to get Path
         use axum::RequestPartsExt;
-        let Path(person_id) = parts
-            .extract::<Path<PersonId>>()
+        if parts.extensions.get::<UnsignedPeer>().is_some() {
+            return Ok(Signed { signer: None });
+        }
+        let Extension(keys) = parts
+            .extract::<Extension<KeyCache>>()
+            .await
+            .map_err(|_| web_err_500("Could not extract key cache"))?;
+        let Extension(cfg) = parts
+            .extract::<Extension<Config>>()
             .await
-            .map_err(|_| web_err_500("Could not extract person_id from path"))?;
+            .map_err(|_| web_err_500("Could not extract config"))?;
+        let Extension(replays) = parts
+            .extract::<Extension<Option<ReplayCache>>>()
+            .await
+            .map_err(|_| web_err_500("Could not extract replay cache"))?;
+
+        let peer = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip());
+        let headers = with_public_host(&cfg, &parts.headers, peer);
+        let target = request_target(&parts.method, parts.uri.path(), parts.uri.query());
 
-        let headers = parts.headers.clone();
+        let (key_id, key) =
+            verify_headers(&keys, &headers, &target, cfg.missing_signed_headers).await?;
+        let signer = owner(&key_id, &key)?;
+        if let Some(replays) = replays {
+            replays.check(&headers, chrono::Utc::now())?;
+        }
+
+        Ok(Signed {
+            signer: Some(signer),
+        })
+    }
+}
 
-        verify_headers(&headers, &person_id).await?;
+/// The actor who signed a request, for handlers that only sometimes need to
+/// know, e.g. to serve something that isn't public, and so don't want
+/// [`Signed`] to fetch keys for every request. The owner the signer's key
+/// names has to be on the same host as the key itself, or anyone could sign
+/// as anyone by claiming their actor in a key of their own.
+pub async fn signer(
+    keys: &KeyCache,
+    cfg: &Config,
+    method: &Method,
+    uri: &Uri,
+    headers: &HeaderMap,
+    peer: Option<IpAddr>,
+) -> Result<String, WebError> {
+    let target = request_target(method, uri.path(), uri.query());
+    let headers = with_public_host(cfg, headers, peer);
+    let (key_id, key) = verify_headers(keys, &headers, &target, cfg.missing_signed_headers).await?;
+    owner(&key_id, &key)
+}
+
+/// The actor `key` says it belongs to, as long as that's on the key's host.
+fn owner(key_id: &ActorId, key: &PublicKey) -> Result<String, WebError> {
+    // the owner is never fetched, only its host compared, so http is fine
+    match ActorId::parse(key.owner(), true) {
+        Ok(owner) if owner.host() == key_id.host() => Ok(owner.to_string()),
+        _ => Err(web_err_401(format!(
+            "Key {} belongs to {}, which isn't on its host",
+            key_id,
+            key.owner()
+        ))),
+    }
+}
+
+/// Marks a request from one of the `--unsigned-peers` that came without a
+/// signature, which [`Signed`] then lets through unverified.
+#[derive(Clone, Copy)]
+struct UnsignedPeer;
+
+/// Turns away POSTs without a `signature` header before the handler runs.
+/// [`Signed`] does the actual verifying; this is for the inbox routes, so an
+/// activity can't get processed unsigned even if a handler ends up without
+/// the extractor.
+///
+/// The exception are peers in `--unsigned-peers`, which is also why only
+/// routes behind this middleware can be posted to unsigned.
+pub async fn require_signature<B>(
+    mut request: Request<B>,
+    next: Next<B>,
+) -> Result<Response, WebError> {
+    if request.method() == Method::POST && !request.headers().contains_key("signature") {
+        let peer = request
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip());
+        let allowed = request
+            .extensions()
+            .get::<Config>()
+            .zip(peer)
+            .is_some_and(|(cfg, peer)| cfg.unsigned_peers.contains(&peer));
+        if !allowed {
+            return Err(web_err_401("No header signature"));
+        }
+        warn!(
+            "Accepting unsigned {} to {} from allowlisted peer {}",
+            request.method(),
+            request.uri().path(),
+            peer.unwrap()
+        );
+        request.extensions_mut().insert(UnsignedPeer);
+    }
+    Ok(next.run(request).await)
+}
 
-        Ok(Signed)
+/// `headers` with `host` set to the host the sender addressed: the
+/// `X-Forwarded-Host` from one of the `--trusted-proxies`, or else our own
+/// domain. A proxy in front of us may well rewrite `Host` to its upstream's,
+/// which isn't the one that was signed.
+pub fn with_public_host(cfg: &Config, headers: &HeaderMap, peer: Option<IpAddr>) -> HeaderMap {
+    let forwarded = peer
+        .filter(|peer| cfg.trusted_proxies.contains(peer))
+        .and_then(|_| headers.get("x-forwarded-host"))
+        .and_then(|h| h.to_str().ok())
+        // proxies in a chain each append theirs, so the first is the sender's
+        .and_then(|h| h.split(',').next())
+        .map(str::trim);
+    let mut headers = headers.clone();
+    if let Ok(host) = HeaderValue::from_str(forwarded.unwrap_or(&cfg.domain)) {
+        headers.insert(HOST, host);
     }
+    headers
 }
 
-fn header_str<'a>(headers: &'a HeaderMap, name: &str) -> Result<&'a str, WebError> {
+pub fn header_str<'a>(headers: &'a HeaderMap, name: &str) -> Result<&'a str, WebError> {
     headers
         .get(name)
         .ok_or_else(|| web_err_400(format!("No header {}", name)))
@@ -111,54 +246,159 @@ fn header_str<'a>(headers: &'a HeaderMap, name: &str) -> Result<&'a str, WebErro
         })
 }
 
-fn rebuild_sig_str(account: &PersonId, headers: &HeaderMap, signature: &Signature) -> String {
-    signature
-        .headers
-        .iter()
-        .map(|header| {
-            if header == "(request-target)" {
-                // we can take this shortcut because we run this endpoint
-                format!("(request-target): post /users/{}/inbox", account)
-            } else {
-                let header = header.to_lowercase();
-                let value = header_str(headers, &header).unwrap_or("");
-                format!("{}: {}", header, value)
-            }
-        })
-        .collect::<Vec<String>>()
-        .join("\n")
+/// The value of the `(request-target)` pseudo-header: the lowercased method
+/// followed by the path and, if there is one, the query string.
+pub fn request_target(method: &Method, path: &str, query: Option<&str>) -> String {
+    let method = method.as_str().to_lowercase();
+    match query {
+        Some(query) => format!("{} {}?{}", method, path, query),
+        None => format!("{} {}", method, path),
+    }
 }
 
-async fn verify_headers(headers: &HeaderMap, actor: &PersonId) -> Result<(), WebError> {
-    // TODO: check date header and other ways to prevent replay attacks
+/// The string `signature` claims to cover, rebuilt from the request with the
+/// same [`build_signing_string`] our own deliveries are signed with.
+pub fn rebuild_sig_str(target: &str, headers: &HeaderMap, signature: &Signature) -> String {
+    build_signing_string(&signature.headers, target, |name| {
+        header_str(headers, name).ok()
+    })
+}
 
-    let signature = header_str(headers, "signature")?;
-    let signature = Signature::from_headers(signature)
-        .map_err(|e| web_err_400(format!("Error parsing signature: {}", e)))?;
+#[instrument(skip_all, fields(target = %target, key_id))]
+async fn verify_headers(
+    keys: &KeyCache,
+    headers: &HeaderMap,
+    target: &str,
+    missing: MissingSignedHeaders,
+) -> Result<(ActorId, PublicKey), WebError> {
+    // the date and replays are checked by `ReplayCache`, with `--replay-window`
 
-    let decoded_signature = base64_decode(&signature.signature)
-        .map_err(|e| web_err_400(format!("Error decoding signature: {}", e)))?;
+    if !headers.contains_key("signature") {
+        return Err(web_err_401("No header signature"));
+    }
+    let scheme = scheme::detect(headers);
+    let signature = scheme.parse(headers)?;
+    Span::current().record("key_id", signature.key_id.as_str());
+    let key_id = keys
+        .key_id(&signature.key_id)
+        .map_err(|e| web_err_400(format!("Invalid keyId: {}", e)))?;
 
-    let comparison = rebuild_sig_str(actor, headers, &signature);
+    let decoded_signature = base64_decode(&signature.signature).map_err(|e| {
+        web_err_400(format!(
+            "Error decoding signature by {} from base64: {}",
+            key_id, e
+        ))
+    })?;
 
-    let pubkey = PublicKey::from_remote(&signature.key_id)
-        .await
-        .map_err(|e| web_err_400(format!("Error loading public key: {}", e)))?;
+    if missing == MissingSignedHeaders::Strict {
+        check_signed_headers_present(headers, &signature)?;
+    }
+    let comparison = scheme.canonicalize(target, headers, &signature)?;
+
+    let (pubkey, cached) = load_key(keys, key_id.as_str()).await?;
 
     debug!("pubkey: {}", serde_json::to_string(&pubkey).unwrap());
     debug!("comparison: {}", comparison);
 
-    pubkey
-        .verify(comparison.as_bytes(), &decoded_signature)
-        .map_err(|e| web_err_400(format!("Error verifying signature: {}", e)))?;
+    // errors are made strings right away, as they can't be held across the
+    // refetch
+    match scheme
+        .verify(&pubkey, &signature, &comparison, &decoded_signature)
+        .map_err(|e| e.to_string())
+    {
+        Ok(()) => Ok((key_id, pubkey)),
+        // the signer may have rotated their key since we cached it, so give
+        // them one more chance with a fresh copy. we verify against that copy
+        // only once, whether or not it came from the cache, and the same key
+        // isn't refetched again within the cooldown.
+        Err(e) if cached => {
+            if !keys.invalidate_for_refetch(key_id.as_str()).await {
+                debug!("Not refetching {}, it was refetched lately", key_id);
+                return Err(not_verified(&key_id, headers, &signature, false, e));
+            }
+            debug!("Refetching {} after failed verification", key_id);
+            let (pubkey, _) = load_key(keys, key_id.as_str()).await?;
+            scheme
+                .verify(&pubkey, &signature, &comparison, &decoded_signature)
+                .map_err(|e| not_verified(&key_id, headers, &signature, true, e.to_string()))?;
+            Ok((key_id, pubkey))
+        }
+        Err(e) => Err(not_verified(&key_id, headers, &signature, false, e)),
+    }
+}
+
+/// The error for a signature that doesn't verify against the signer's key,
+/// with what it covered to tell a tampered or misbuilt request from a
+/// rotated key. Nothing of the signature or the key itself is included.
+fn not_verified(
+    key_id: &ActorId,
+    headers: &HeaderMap,
+    signature: &Signature,
+    refetched: bool,
+    e: String,
+) -> WebError {
+    let mut message = format!(
+        "Signature by {} over \"{}\" doesn't verify{}: {}",
+        key_id,
+        signature.headers.join(" "),
+        if refetched {
+            ", even with a refetched key"
+        } else {
+            ""
+        },
+        e
+    );
+    let missing = missing_headers(headers, signature);
+    if !missing.is_empty() {
+        message.push_str(&format!(
+            " (the request doesn't have {}, signed as empty)",
+            missing.join(", ")
+        ));
+    }
+    web_err_401(message)
+}
 
-    Ok(())
+/// The headers the signature covers that the request doesn't have. Pseudo
+/// headers like `(request-target)` and derived components like `@method`
+/// aren't sent, so they don't count.
+fn missing_headers<'a>(headers: &HeaderMap, signature: &'a Signature) -> Vec<&'a str> {
+    signature
+        .headers
+        .iter()
+        .filter(|h| !h.starts_with(['(', '@']) && !headers.contains_key(h.to_lowercase().as_str()))
+        .map(String::as_str)
+        .collect()
+}
+
+/// Fails if the signature covers a header the request doesn't have.
+fn check_signed_headers_present(
+    headers: &HeaderMap,
+    signature: &Signature,
+) -> Result<(), WebError> {
+    match missing_headers(headers, signature).first() {
+        Some(header) => Err(web_err_401(format!(
+            "Signature covers header {}, which the request doesn't have",
+            header
+        ))),
+        None => Ok(()),
+    }
+}
+
+async fn load_key(keys: &KeyCache, key_id: &str) -> Result<(PublicKey, bool), WebError> {
+    keys.get(key_id)
+        .await
+        .map_err(|e| web_err_401(format!("Error fetching public key {}: {}", key_id, e)))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use axum::http::HeaderValue;
+    use crate::key::Key;
+    use crate::remote;
+    use crate::utils::base64_encode;
+    use axum::http::StatusCode;
+    use clap::Parser;
+    use std::time::Duration;
     use std::{assert_eq, vec};
 
     #[test]
@@ -174,6 +414,7 @@ mod tests {
         // Create a mock Signature
         let signature = Signature {
             key_id: "".to_string(),
+            algorithm: None,
             headers: vec![
                 String::from("(request-target)"),
                 String::from("Host"),
@@ -183,11 +424,9 @@ mod tests {
             signature: "".to_string(),
         };
 
-        // Create a mock PersonId
-        let person_id = "alice".to_string();
-
         // Call the function
-        let result = rebuild_sig_str(&person_id, &headers, &signature);
+        let target = request_target(&Method::POST, "/users/alice/inbox", None);
+        let result = rebuild_sig_str(&target, &headers, &signature);
 
         // Expected signature string
         let expected_result = "(request-target): post /users/alice/inbox\nhost: example.com\ndate: Sun, 06 Nov 2021 08:49:37 GMT";
@@ -198,6 +437,751 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_rebuild_sig_str_with_query() {
+        let mut headers = HeaderMap::new();
+        headers.insert("Host", HeaderValue::from_static("example.com"));
+
+        let signature = Signature {
+            key_id: "".to_string(),
+            algorithm: None,
+            headers: vec![String::from("(request-target)"), String::from("host")],
+            signature: "".to_string(),
+        };
+
+        let target = request_target(&Method::GET, "/users/alice/followers", Some("page=2"));
+        let result = rebuild_sig_str(&target, &headers, &signature);
+
+        assert_eq!(
+            result,
+            "(request-target): get /users/alice/followers?page=2\nhost: example.com"
+        );
+    }
+
+    #[test]
+    fn test_rebuild_sig_str_matches_signer() {
+        let key = Key::new("https://example.com/users/alice".to_string()).unwrap();
+        let signed = rap_core::signing::sign_request(
+            "POST",
+            "https://remote.example/users/bob/inbox?x=1",
+            br#"{"type":"Follow"}"#,
+            &key,
+        )
+        .unwrap();
+        let signature = Signature::from_headers(&signed["signature"]).unwrap();
+
+        let mut headers = HeaderMap::new();
+        for (name, value) in &signed {
+            headers.insert(
+                axum::http::HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                HeaderValue::from_str(value).unwrap(),
+            );
+        }
+        let target = request_target(&Method::POST, "/users/bob/inbox", Some("x=1"));
+        let rebuilt = rebuild_sig_str(&target, &headers, &signature);
+
+        let signed_string = build_signing_string(&signature.headers, &target, |name| {
+            signed.get(name).map(String::as_str)
+        });
+        assert_eq!(rebuilt, signed_string);
+        key.public_key()
+            .unwrap()
+            .verify(
+                rebuilt.as_bytes(),
+                &base64_decode(&signature.signature).unwrap(),
+            )
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_require_signature() {
+        use axum::body::Body;
+        use axum::routing::post;
+        use tower::ServiceExt;
+
+        // a handler that would happily take anything
+        let app = axum::Router::new().route(
+            "/inbox",
+            post(|| async { StatusCode::ACCEPTED })
+                .route_layer(axum::middleware::from_fn(require_signature)),
+        );
+        let unsigned = Request::post("/inbox").body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(unsigned).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let signed = Request::post("/inbox")
+            .header("signature", "keyId=\"x\"")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(signed).await.unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+
+        for path in ["/users/alice/inbox", "/actor/inbox"] {
+            let response = crate::tests::test_app(&[])
+                .oneshot(Request::post(path).body(Body::from("{}")).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unsigned_peers() {
+        use axum::body::Body;
+        use tower::ServiceExt;
+
+        let follow = |peer: &str| {
+            let body = serde_json::json!({
+                "id": "https://intranet.example/follows/1",
+                "type": "Follow",
+                "actor": "https://intranet.example/users/bot",
+                "object": "https://example.com/users/nobody",
+            });
+            let mut request = Request::post("/users/nobody/inbox")
+                .body(Body::from(body.to_string()))
+                .unwrap();
+            request
+                .extensions_mut()
+                .insert(ConnectInfo::<SocketAddr>(peer.parse().unwrap()));
+            request
+        };
+
+        let app = crate::tests::test_app(&["--unsigned-peers", "10.0.0.5,fd00::5"]);
+        for peer in ["10.0.0.5:4711", "[fd00::5]:4711"] {
+            let response = app.clone().oneshot(follow(peer)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::ACCEPTED, "{}", peer);
+        }
+        let response = app.oneshot(follow("10.0.0.6:4711")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        // nobody is allowlisted by default
+        let response = crate::tests::test_app(&[])
+            .oneshot(follow("10.0.0.5:4711"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_proxy_rewritten_host() {
+        use axum::body::Body;
+        use axum::routing::post;
+        use tower::ServiceExt;
+
+        let key = Key::new("https://remote.example/users/bob".to_string()).unwrap();
+        let key_id = "https://remote.example/users/bob#main-key";
+        let keys = KeyCache::new(
+            remote::dev_client().with_fixtures(&[]),
+            Duration::from_secs(60),
+        );
+        keys.insert(key_id, key.public_key().unwrap()).await;
+        let app = |args: &[&str]| {
+            let mut argv = vec!["rap-server", "--domain", "example.com"];
+            argv.extend_from_slice(args);
+            axum::Router::new()
+                .route(
+                    "/users/alice/inbox",
+                    post(|signed: Signed| async move {
+                        assert_eq!(
+                            signed.signer.as_deref(),
+                            Some("https://remote.example/users/bob")
+                        );
+                        StatusCode::ACCEPTED
+                    }),
+                )
+                .layer(Extension(keys.clone()))
+                .layer(Extension(None::<ReplayCache>))
+                .layer(Extension(Config::parse_from(argv)))
+        };
+
+        // signed for `signed_host`, arriving as a proxy passed it on
+        let request = |signed_host: &str, forwarded_host: Option<&str>, peer: &str| {
+            let date = "Sun, 06 Nov 2021 08:49:37 GMT";
+            let signing_string = format!(
+                "(request-target): post /users/alice/inbox\nhost: {}\ndate: {}",
+                signed_host, date
+            );
+            let signature = base64_encode(key.sign(signing_string.as_bytes()).unwrap());
+            let mut request = Request::post("/users/alice/inbox")
+                .header("host", "rap-server.internal:8080")
+                .header("date", date)
+                .header(
+                    "signature",
+                    format!(
+                        "keyId=\"{}\",headers=\"(request-target) host date\",signature=\"{}\"",
+                        key_id, signature
+                    ),
+                );
+            if let Some(host) = forwarded_host {
+                request = request.header("x-forwarded-host", host);
+            }
+            let mut request = request.body(Body::empty()).unwrap();
+            request
+                .extensions_mut()
+                .insert(ConnectInfo::<SocketAddr>(peer.parse().unwrap()));
+            request
+        };
+
+        // `Host` was rewritten, but the signature is checked against our domain
+        let response = app(&[])
+            .oneshot(request("example.com", None, "10.0.0.5:4711"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        let response = app(&[])
+            .oneshot(request("rap-server.internal:8080", None, "10.0.0.5:4711"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        // a trusted proxy says which host the sender addressed. the failed
+        // verification dropped the key, which can't be refetched here
+        keys.insert(key_id, key.public_key().unwrap()).await;
+        let trusted = ["--trusted-proxies", "10.0.0.5"];
+        let forwarded = Some("ap.example.com, example.com");
+        let response = app(&trusted)
+            .oneshot(request("ap.example.com", forwarded, "10.0.0.5:4711"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        let response = app(&trusted)
+            .oneshot(request("ap.example.com", forwarded, "10.0.0.6:4711"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    fn keys() -> KeyCache {
+        let cfg = crate::config::Config::parse_from(["rap-server", "--domain", "ap.rens.page"]);
+        KeyCache::new(remote::Client::new(&cfg), Duration::from_secs(60))
+    }
+
+    /// Records the names of every span created while it is the default
+    /// subscriber.
+    struct SpanNames(std::sync::Arc<std::sync::Mutex<Vec<String>>>);
+
+    impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for SpanNames {
+        fn on_new_span(
+            &self,
+            attrs: &tracing::span::Attributes<'_>,
+            _id: &tracing::span::Id,
+            _ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(attrs.metadata().name().to_string());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_verify_headers_span() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let names = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let subscriber = tracing_subscriber::registry().with(SpanNames(names.clone()));
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        verify_headers(
+            &keys(),
+            &HeaderMap::new(),
+            "post /users/alice/inbox",
+            MissingSignedHeaders::Lenient,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(*names.lock().unwrap(), vec!["verify_headers"]);
+    }
+
+    #[tokio::test]
+    async fn test_verify_headers_missing_signature() {
+        let mut headers = HeaderMap::new();
+        headers.insert("host", HeaderValue::from_static("ap.rens.page"));
+
+        let (status, _) = verify_headers(
+            &keys(),
+            &headers,
+            "post /users/alice/inbox",
+            MissingSignedHeaders::Lenient,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_verify_headers_malformed_signature() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "signature",
+            HeaderValue::from_static("keyId=\"https://example.com/users/bob#main-key\""),
+        );
+
+        let (status, _) = verify_headers(
+            &keys(),
+            &headers,
+            "post /users/alice/inbox",
+            MissingSignedHeaders::Lenient,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        headers.insert(
+            "signature",
+            HeaderValue::from_static("keyId=\"https://example.com/users/bob#main-key\",headers=\"date\",signature=\"not base64!\""),
+        );
+        let (status, _) = verify_headers(
+            &keys(),
+            &headers,
+            "post /users/alice/inbox",
+            MissingSignedHeaders::Lenient,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_verify_headers_invalid_key_id() {
+        let key = Key::new("https://remote.example/users/bob".to_string()).unwrap();
+        for key_id in [
+            "/users/bob#main-key",
+            "http://remote.example/users/bob#main-key",
+        ] {
+            let (status, message) = verify_headers(
+                &keys(),
+                &signed_headers(&key, key_id),
+                "post /users/alice/inbox",
+                MissingSignedHeaders::Lenient,
+            )
+            .await
+            .unwrap_err();
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{}", key_id);
+            assert!(message.starts_with("Invalid keyId: "), "{}", message);
+        }
+    }
+
+    /// Signs `post /users/alice/inbox` with `key`, claiming it to be `key_id`.
+    fn signed_headers(key: &Key, key_id: &str) -> HeaderMap {
+        let date = "Sun, 06 Nov 2021 08:49:37 GMT";
+        let signing_string = format!("(request-target): post /users/alice/inbox\ndate: {}", date);
+        let signature = base64_encode(key.sign(signing_string.as_bytes()).unwrap());
+        let mut headers = HeaderMap::new();
+        headers.insert("date", HeaderValue::from_static(date));
+        headers.insert(
+            "signature",
+            HeaderValue::from_str(&format!(
+                "keyId=\"{}\",headers=\"(request-target) date\",signature=\"{}\"",
+                key_id, signature
+            ))
+            .unwrap(),
+        );
+        headers
+    }
+
+    #[tokio::test]
+    async fn test_missing_signed_header() {
+        let key = Key::new("https://remote.example/users/bob".to_string()).unwrap();
+        let key_id = "https://remote.example/users/bob#main-key";
+        let keys = KeyCache::new(remote::dev_client(), Duration::from_secs(60));
+        keys.insert(key_id, key.public_key().unwrap()).await;
+
+        // signed as if x-missing was sent empty
+        let date = "Sun, 06 Nov 2021 08:49:37 GMT";
+        let signing_string = format!(
+            "(request-target): post /users/alice/inbox\ndate: {}\nx-missing: ",
+            date
+        );
+        let signature = base64_encode(key.sign(signing_string.as_bytes()).unwrap());
+        let mut headers = HeaderMap::new();
+        headers.insert("date", HeaderValue::from_static(date));
+        headers.insert(
+            "signature",
+            HeaderValue::from_str(&format!(
+                "keyId=\"{}\",headers=\"(request-target) date x-missing\",signature=\"{}\"",
+                key_id, signature
+            ))
+            .unwrap(),
+        );
+
+        verify_headers(
+            &keys,
+            &headers,
+            "post /users/alice/inbox",
+            MissingSignedHeaders::Lenient,
+        )
+        .await
+        .unwrap();
+        let (status, message) = verify_headers(
+            &keys,
+            &headers,
+            "post /users/alice/inbox",
+            MissingSignedHeaders::Strict,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert!(message.contains("x-missing"), "{}", message);
+
+        // headers that are there pass either way
+        headers.insert("x-missing", HeaderValue::from_static(""));
+        verify_headers(
+            &keys,
+            &headers,
+            "post /users/alice/inbox",
+            MissingSignedHeaders::Strict,
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_verify_headers_schemes() {
+        let key = Key::new("https://remote.example/users/bob".to_string()).unwrap();
+        let key_id = "https://remote.example/users/bob#main-key";
+        let keys = KeyCache::new(
+            remote::dev_client().with_fixtures(&[]),
+            Duration::from_secs(60),
+        );
+
+        let cavage = signed_headers(&key, key_id);
+
+        let date = "Sun, 06 Nov 2021 08:49:37 GMT";
+        let params = format!(
+            "(\"@method\" \"@path\" \"date\");created=1636188577;keyid=\"{}\";alg=\"rsa-v1_5-sha256\"",
+            key_id
+        );
+        let base = format!(
+            "\"@method\": POST\n\"@path\": /users/alice/inbox\n\"date\": {}\n\"@signature-params\": {}",
+            date, params
+        );
+        let signature = base64_encode(key.sign(base.as_bytes()).unwrap());
+        let mut rfc9421 = HeaderMap::new();
+        rfc9421.insert("date", HeaderValue::from_static(date));
+        rfc9421.insert(
+            "signature-input",
+            HeaderValue::from_str(&format!("sig1={}", params)).unwrap(),
+        );
+        rfc9421.insert(
+            "signature",
+            HeaderValue::from_str(&format!("sig1=:{}:", signature)).unwrap(),
+        );
+
+        for headers in [&cavage, &rfc9421] {
+            // a failed verification drops the key, which can't be refetched
+            keys.insert(key_id, key.public_key().unwrap()).await;
+            let (actor, _) = verify_headers(
+                &keys,
+                headers,
+                "post /users/alice/inbox",
+                MissingSignedHeaders::Strict,
+            )
+            .await
+            .unwrap();
+            assert_eq!(actor.as_str(), key_id);
+
+            let (status, _) = verify_headers(
+                &keys,
+                headers,
+                "post /users/alice/outbox",
+                MissingSignedHeaders::Strict,
+            )
+            .await
+            .unwrap_err();
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_signer() {
+        let cfg = Config::parse_from(["rap-server", "--domain", "example.com"]);
+        let keys = KeyCache::new(remote::dev_client(), Duration::from_secs(60));
+        let uri = Uri::from_static("/users/alice/inbox");
+
+        let key = Key::new("https://remote.example/users/bob".to_string()).unwrap();
+        let key_id = "https://remote.example/users/bob#main-key";
+        keys.insert(key_id, key.public_key().unwrap()).await;
+        let headers = signed_headers(&key, key_id);
+        assert_eq!(
+            signer(&keys, &cfg, &Method::POST, &uri, &headers, None)
+                .await
+                .unwrap(),
+            "https://remote.example/users/bob"
+        );
+
+        // a key claiming an actor somewhere else
+        let key = Key::new("https://example.com/users/alice".to_string()).unwrap();
+        let key_id = "https://remote.example/users/mallory#main-key";
+        keys.insert(key_id, key.public_key().unwrap()).await;
+        let headers = signed_headers(&key, key_id);
+        let (status, _) = signer(&keys, &cfg, &Method::POST, &uri, &headers, None)
+            .await
+            .unwrap_err();
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_replayed_request() {
+        use axum::body::Body;
+        use axum::routing::post;
+        use tower::ServiceExt;
+
+        let key = Key::new("https://remote.example/users/bob".to_string()).unwrap();
+        let key_id = "https://remote.example/users/bob#main-key";
+        let keys = KeyCache::new(remote::dev_client(), Duration::from_secs(60));
+        keys.insert(key_id, key.public_key().unwrap()).await;
+
+        let date = chrono::Utc::now()
+            .format("%a, %d %b %Y %H:%M:%S GMT")
+            .to_string();
+        let signing_string = format!("(request-target): post /users/alice/inbox\ndate: {}", date);
+        let signature = base64_encode(key.sign(signing_string.as_bytes()).unwrap());
+        let request = || {
+            Request::post("/users/alice/inbox")
+                .header("date", &date)
+                .header(
+                    "signature",
+                    format!(
+                        "keyId=\"{}\",headers=\"(request-target) date\",signature=\"{}\"",
+                        key_id, signature
+                    ),
+                )
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        for (args, replayed) in [
+            (&[][..], StatusCode::ACCEPTED),
+            (&["--replay-window", "300"][..], StatusCode::UNAUTHORIZED),
+        ] {
+            let mut argv = vec!["rap-server", "--domain", "example.com"];
+            argv.extend_from_slice(args);
+            let cfg = Config::parse_from(argv);
+            let app = axum::Router::new()
+                .route(
+                    "/users/alice/inbox",
+                    post(|_: Signed| async { StatusCode::ACCEPTED }),
+                )
+                .layer(Extension(keys.clone()))
+                .layer(Extension(ReplayCache::new(&cfg)))
+                .layer(Extension(cfg));
+
+            let response = app.clone().oneshot(request()).await.unwrap();
+            assert_eq!(response.status(), StatusCode::ACCEPTED);
+            let response = app.oneshot(request()).await.unwrap();
+            assert_eq!(response.status(), replayed, "{:?}", args);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_verify_headers_without_headers_param() {
+        let key = Key::new("https://remote.example/users/bob".to_string()).unwrap();
+        let key_id = "https://remote.example/users/bob#main-key";
+        let keys = KeyCache::new(remote::dev_client(), Duration::from_secs(60));
+        keys.insert(key_id, key.public_key().unwrap()).await;
+
+        // covering only the date, as the default says
+        let date = "Sun, 06 Nov 1994 08:49:37 GMT";
+        let signature = base64_encode(key.sign(format!("date: {}", date).as_bytes()).unwrap());
+        let mut headers = HeaderMap::new();
+        headers.insert("date", HeaderValue::from_static(date));
+        headers.insert(
+            "signature",
+            HeaderValue::from_str(&format!("keyId=\"{}\",signature=\"{}\"", key_id, signature))
+                .unwrap(),
+        );
+        let parsed = Signature::from_headers(header_str(&headers, "signature").unwrap()).unwrap();
+        assert_eq!(
+            rebuild_sig_str("post /users/alice/inbox", &headers, &parsed),
+            format!("date: {}", date)
+        );
+        verify_headers(
+            &keys,
+            &headers,
+            "post /users/alice/inbox",
+            MissingSignedHeaders::Strict,
+        )
+        .await
+        .unwrap();
+    }
+
+    /// Serves an actor document for `key`, returning the IRI to fetch it at.
+    async fn actor_server(key: &Key) -> String {
+        let actor = serde_json::json!({
+            "id": "https://remote.example/users/bob",
+            "inbox": "https://remote.example/users/bob/inbox",
+            "publicKey": key.public_key().unwrap(),
+        });
+        let url = remote::mock_server(actor.to_string(), true).await;
+        format!("{}users/bob#main-key", url)
+    }
+
+    #[tokio::test]
+    async fn test_verify_headers_messages() {
+        let key = Key::new("https://remote.example/users/bob".to_string()).unwrap();
+        let forged = Key::new("https://remote.example/users/mallory".to_string()).unwrap();
+        let key_id = &actor_server(&key).await;
+        let keys = KeyCache::new(remote::dev_client(), Duration::from_secs(60));
+        keys.insert(key_id, key.public_key().unwrap()).await;
+        let verify = |headers: HeaderMap| {
+            let keys = keys.clone();
+            async move {
+                verify_headers(
+                    &keys,
+                    &headers,
+                    "post /users/alice/inbox",
+                    MissingSignedHeaders::Lenient,
+                )
+                .await
+                .unwrap_err()
+            }
+        };
+
+        let mut headers = signed_headers(&key, key_id);
+        headers.insert(
+            "signature",
+            HeaderValue::from_str(&format!(
+                "keyId=\"{}\",headers=\"date\",signature=\"not base64!\"",
+                key_id
+            ))
+            .unwrap(),
+        );
+        let (status, message) = verify(headers).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(
+            message.starts_with(&format!(
+                "Error decoding signature by {} from base64",
+                key_id
+            )),
+            "{}",
+            message
+        );
+
+        // a crypto failure names what was signed, but not the signature. the
+        // key is cached, so it is refetched first
+        let headers = signed_headers(&forged, key_id);
+        let signature = Signature::from_headers(header_str(&headers, "signature").unwrap())
+            .unwrap()
+            .signature;
+        let (status, message) = verify(headers).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert!(
+            message.starts_with(&format!(
+                "Signature by {} over \"(request-target) date\" doesn't verify",
+                key_id
+            )),
+            "{}",
+            message
+        );
+        assert!(message.contains("even with a refetched key"), "{}", message);
+        assert!(!message.contains(&signature), "{}", message);
+
+        let mut headers = signed_headers(&key, key_id);
+        headers.remove("date");
+        let (_, message) = verify(headers).await;
+        assert!(
+            message.ends_with("(the request doesn't have date, signed as empty)"),
+            "{}",
+            message
+        );
+
+        // a fetch failure names the key it was after
+        let url = remote::mock_server("not an actor".to_string(), true).await;
+        let key_id = format!("{}users/bob#main-key", url);
+        let (status, message) = verify(signed_headers(&key, &key_id)).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert!(
+            message.starts_with(&format!("Error fetching public key {}: ", key_id)),
+            "{}",
+            message
+        );
+    }
+
+    #[tokio::test]
+    async fn test_verify_headers_refetches_rotated_key() {
+        let old = Key::new("https://remote.example/users/bob".to_string()).unwrap();
+        let new = Key::new("https://remote.example/users/bob".to_string()).unwrap();
+        let key_id = actor_server(&new).await;
+
+        let keys = KeyCache::new(remote::dev_client(), Duration::from_secs(60));
+        keys.insert(&key_id, old.public_key().unwrap()).await;
+
+        let headers = signed_headers(&new, &key_id);
+        verify_headers(
+            &keys,
+            &headers,
+            "post /users/alice/inbox",
+            MissingSignedHeaders::Lenient,
+        )
+        .await
+        .unwrap();
+
+        // the fresh key replaced the stale one
+        let (key, cached) = keys.get(&key_id).await.unwrap();
+        assert!(cached);
+        assert_eq!(
+            serde_json::to_string(&key).unwrap(),
+            serde_json::to_string(&new.public_key().unwrap()).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_verify_headers_refetches_only_once() {
+        let key = Key::new("https://remote.example/users/bob".to_string()).unwrap();
+        let forged = Key::new("https://remote.example/users/mallory".to_string()).unwrap();
+        let key_id = actor_server(&key).await;
+
+        let keys = KeyCache::new(remote::dev_client(), Duration::from_secs(60));
+        keys.insert(&key_id, key.public_key().unwrap()).await;
+
+        let headers = signed_headers(&forged, &key_id);
+        let (status, _) = verify_headers(
+            &keys,
+            &headers,
+            "post /users/alice/inbox",
+            MissingSignedHeaders::Lenient,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_verify_headers_refetch_cooldown() {
+        let old = Key::new("https://remote.example/users/bob".to_string()).unwrap();
+        let new = Key::new("https://remote.example/users/bob".to_string()).unwrap();
+        let key_id = actor_server(&new).await;
+        let keys = KeyCache::new(remote::dev_client(), Duration::from_secs(60))
+            .with_refetch_cooldown(Duration::from_secs(60));
+        let verify = |key: &Key| {
+            let keys = keys.clone();
+            let headers = signed_headers(key, &key_id);
+            async move {
+                verify_headers(
+                    &keys,
+                    &headers,
+                    "post /users/alice/inbox",
+                    MissingSignedHeaders::Lenient,
+                )
+                .await
+            }
+        };
+
+        // a forged signature makes us refetch bob's key once
+        let forged = Key::new("https://remote.example/users/mallory".to_string()).unwrap();
+        keys.insert(&key_id, old.public_key().unwrap()).await;
+        let (status, _) = verify(&forged).await.unwrap_err();
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        // so a real rotation right after has to wait out the cooldown
+        keys.insert(&key_id, old.public_key().unwrap()).await;
+        let (status, message) = verify(&new).await.unwrap_err();
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert!(!message.contains("refetched"), "{}", message);
+        verify(&old).await.unwrap();
+    }
+
     #[tokio::test]
     async fn test_verify_headers_from_remote() {
         // Create a mock HeaderMap
@@ -239,9 +1223,19 @@ mod tests {
         headers.insert("total-route-time", HeaderValue::from_static("0"));
         headers.insert("content-length", HeaderValue::from_static("222"));
 
-        // Create a mock PersonId
-        let person_id = "test2".to_string();
-
-        verify_headers(&headers, &person_id).await.unwrap();
+        let client = remote::Client::new(&crate::config::Config::parse_from([
+            "rap-server",
+            "--domain",
+            "ap.rens.page",
+        ]))
+        .with_fixtures(&[remote::RENNING]);
+        verify_headers(
+            &KeyCache::new(client, Duration::from_secs(60)),
+            &headers,
+            "post /users/test2/inbox",
+            MissingSignedHeaders::Lenient,
+        )
+        .await
+        .unwrap();
     }
 }
@@ -0,0 +1,80 @@
+use crate::config::Config;
+use axum::Router;
+use hyper::server::accept::Accept;
+use std::io;
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::net::{UnixListener, UnixStream};
+
+/// Hands hyper the connections accepted on a Unix socket.
+struct UnixAccept(UnixListener);
+
+impl Accept for UnixAccept {
+    type Conn = UnixStream;
+    type Error = io::Error;
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        self.0
+            .poll_accept(cx)
+            .map(|accepted| Some(accepted.map(|(stream, _)| stream)))
+    }
+}
+
+/// Listens on a Unix socket at `path`. A socket left behind by an earlier run
+/// is replaced, but any other file is left alone and binding fails.
+pub fn bind(path: &Path) -> io::Result<UnixListener> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+        _ => {}
+    }
+    UnixListener::bind(path)
+}
+
+pub async fn serve(listener: UnixListener, app: Router, cfg: &Config) -> hyper::Result<()> {
+    crate::http_server(UnixAccept(listener), cfg)
+        .serve(app.into_make_service())
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_app;
+    use clap::Parser;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_serve_on_socket() {
+        let dir = std::env::temp_dir().join(format!("rap-socket-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("rap.sock");
+
+        // a stale socket from an earlier run doesn't get in the way
+        drop(bind(&path).unwrap());
+        let listener = bind(&path).unwrap();
+        let cfg = Config::parse_from(["rap-server", "--domain", "example.com"]);
+        tokio::spawn(async move { serve(listener, test_app(&["--dev-routes"]), &cfg).await });
+
+        let mut stream = UnixStream::connect(&path).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nhost: example.com\r\nconnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert!(response.ends_with("boo!"), "{}", response);
+
+        // other files aren't replaced
+        let file = dir.join("not-a-socket");
+        std::fs::write(&file, "").unwrap();
+        bind(&file).unwrap_err();
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
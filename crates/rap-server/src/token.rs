@@ -0,0 +1,163 @@
+use axum::async_trait;
+use axum::extract::FromRequestParts;
+use axum::http::request::Parts;
+use axum::Extension;
+use base64::engine::general_purpose;
+use base64::Engine;
+use ring::hmac;
+use std::error::Error;
+use std::fmt;
+
+use crate::config::Config;
+use crate::users::PersonId;
+use crate::utils::{web_err_401, web_err_500, WebError};
+
+/// Bearer tokens for local users that can be checked without looking
+/// anything up: `<username>.<expiry>.<signature>`, where the expiry is in
+/// seconds since the epoch and the signature is an HMAC-SHA256 of everything
+/// before it, keyed with `--token-secret`. Changing the secret revokes every
+/// token minted so far.
+pub fn mint(secret: &str, username: &PersonId, expires: i64) -> String {
+    let payload = format!("{}.{}", username, expires);
+    let tag = hmac::sign(&key(secret), payload.as_bytes());
+    format!(
+        "{}.{}",
+        payload,
+        general_purpose::URL_SAFE_NO_PAD.encode(tag.as_ref())
+    )
+}
+
+/// The username a token minted by [`mint`] was for, if it was signed with
+/// `secret` and hasn't expired by `now`.
+pub fn verify(secret: &str, token: &str, now: i64) -> Result<PersonId, InvalidToken> {
+    // The signature and expiry never contain a dot, so split from the right
+    // in case the username does.
+    let (payload, signature) = token.rsplit_once('.').ok_or(InvalidToken::Malformed)?;
+    let (username, expires) = payload.rsplit_once('.').ok_or(InvalidToken::Malformed)?;
+    let signature = general_purpose::URL_SAFE_NO_PAD
+        .decode(signature)
+        .map_err(|_| InvalidToken::Malformed)?;
+    hmac::verify(&key(secret), payload.as_bytes(), &signature)
+        .map_err(|_| InvalidToken::BadSignature)?;
+    let expires: i64 = expires.parse().map_err(|_| InvalidToken::Malformed)?;
+    if expires <= now {
+        return Err(InvalidToken::Expired);
+    }
+    Ok(username.to_string())
+}
+
+fn key(secret: &str) -> hmac::Key {
+    hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes())
+}
+
+/// Why [`verify`] turned a token down.
+#[derive(Debug, PartialEq, Eq)]
+pub enum InvalidToken {
+    Malformed,
+    BadSignature,
+    Expired,
+}
+
+impl fmt::Display for InvalidToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            InvalidToken::Malformed => "Malformed token",
+            InvalidToken::BadSignature => "Token signature doesn't match",
+            InvalidToken::Expired => "Token has expired",
+        })
+    }
+}
+
+impl Error for InvalidToken {}
+
+/// Extractor for requests carrying a valid token from [`mint`] in the
+/// `Authorization` header, holding the username it was minted for.
+pub struct User(pub PersonId);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for User
+where
+    S: Send + Sync,
+{
+    type Rejection = WebError;
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        use axum::RequestPartsExt;
+        let Extension(cfg) = parts
+            .extract::<Extension<Config>>()
+            .await
+            .map_err(|_| web_err_500("Could not extract config"))?;
+
+        let secret = cfg
+            .token_secret
+            .as_deref()
+            .ok_or_else(|| web_err_401("User tokens are disabled"))?;
+
+        let token = parts
+            .headers
+            .get("authorization")
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.strip_prefix("Bearer "))
+            .ok_or_else(|| web_err_401("No bearer token"))?;
+
+        let username = verify(secret, token, chrono::Utc::now().timestamp())
+            .map_err(|e| web_err_401(e.to_string()))?;
+        Ok(User(username))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "secret";
+    const NOW: i64 = 1_700_000_000;
+
+    #[test]
+    fn test_mint_and_verify() {
+        let token = mint(SECRET, &"alice".to_string(), NOW + 60);
+        assert!(token.starts_with("alice.1700000060."));
+        assert_eq!(verify(SECRET, &token, NOW).unwrap(), "alice");
+
+        let dotted = mint(SECRET, &"alice.bob".to_string(), NOW + 60);
+        assert_eq!(verify(SECRET, &dotted, NOW).unwrap(), "alice.bob");
+    }
+
+    #[test]
+    fn test_expired() {
+        let token = mint(SECRET, &"alice".to_string(), NOW);
+        assert_eq!(verify(SECRET, &token, NOW), Err(InvalidToken::Expired));
+        assert_eq!(verify(SECRET, &token, NOW + 1), Err(InvalidToken::Expired));
+        assert!(verify(SECRET, &token, NOW - 1).is_ok());
+    }
+
+    #[test]
+    fn test_tampered() {
+        let token = mint(SECRET, &"alice".to_string(), NOW + 60);
+        let signature = token.rsplit_once('.').unwrap().1;
+
+        for tampered in [
+            format!("bob.{}.{}", NOW + 60, signature),
+            format!("alice.{}.{}", NOW + 3600, signature),
+        ] {
+            assert_eq!(
+                verify(SECRET, &tampered, NOW),
+                Err(InvalidToken::BadSignature)
+            );
+        }
+        assert_eq!(
+            verify("another secret", &token, NOW),
+            Err(InvalidToken::BadSignature)
+        );
+
+        for malformed in [
+            "",
+            "alice",
+            "alice.123",
+            "alice.123.not base64!",
+            "alice.soon.AAAA",
+        ] {
+            assert!(verify(SECRET, malformed, NOW).is_err());
+        }
+        assert_eq!(verify(SECRET, "alice", NOW), Err(InvalidToken::Malformed));
+    }
+}
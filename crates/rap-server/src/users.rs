@@ -1,12 +1,20 @@
-use axum::extract::Path;
+use axum::extract::{Path, Query};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
 use axum::{Extension, Json};
 use serde_json::{json, Value};
 use std::collections::HashMap;
 use std::error::Error;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 
+use crate::collections::{collection, ordered_collection, PageQuery};
+use crate::config::Config;
+use crate::context::context;
 use crate::key;
-use crate::utils::{web_err_500, WebError};
+use crate::token::User;
+use crate::utils::{negotiated_json, web_err, web_err_500, WebError};
+use rap_core::types::Media;
 use serde::{Deserialize, Serialize};
 use tokio::sync::Mutex;
 
@@ -14,71 +22,1065 @@ pub type PersonId = String;
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Person {
+    pub username: PersonId,
     pub id: String,
     pub key: key::Key,
+    /// Other actors this person also is, e.g. the account they are moving
+    /// here from. Servers check this before accepting a `Move`.
+    #[serde(rename = "alsoKnownAs", default)]
+    pub also_known_as: Vec<String>,
+    /// Keys replaced by [`Person::rotate_key`] that are still within their
+    /// grace period, oldest first.
+    #[serde(rename = "previousKeys", default)]
+    pub previous_keys: Vec<RetiredKey>,
+    /// The person's avatar.
+    #[serde(default)]
+    pub icon: Option<Media>,
+    /// The header shown above the person's profile.
+    #[serde(default)]
+    pub image: Option<Media>,
 }
 
+/// A key that was rotated out, and when.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct RetiredKey {
+    pub key: key::PublicKey,
+    /// Unix timestamp in seconds.
+    #[serde(rename = "retiredAt")]
+    pub retired_at: i64,
+}
+
+/// Cheap to read statistics about a [`PeopleStore`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeopleSnapshot {
+    pub total: usize,
+}
+
+/// Storage for local people.
+///
+/// Every method takes `&self`, so a store can be shared between handlers and
+/// background tasks either as an `Arc<dyn PeopleStore>` or through a generic
+/// `P: PeopleStore + Clone` (an `Arc` of any store is itself a store).
 #[async_trait::async_trait]
 pub trait PeopleStore: Send + Sync {
+    /// Looks a person up by their local username, e.g. `alice`.
+    async fn get_by_username(&self, username: &str) -> Result<Option<Person>, Box<dyn Error>>;
+    /// Looks a person up by their canonical actor id, e.g.
+    /// `https://example.com/users/alice`.
+    async fn get_by_id(&self, id: &str) -> Result<Option<Person>, Box<dyn Error>>;
     async fn get_or_create(&self, id: &PersonId) -> Result<Person, Box<dyn Error>>;
+
+    /// Creates a new person, returning `None` if one already exists.
+    async fn create(&self, id: &PersonId) -> Result<Option<Person>, Box<dyn Error>>;
+    /// Stores a person made elsewhere, e.g. seeded at startup. Returns
+    /// `false`, leaving the existing one alone, if the username is taken.
+    async fn add(&self, person: Person) -> Result<bool, Box<dyn Error>>;
+    async fn list(&self) -> Result<Vec<Person>, Box<dyn Error>>;
+
+    /// How many people there are. Unlike [`PeopleStore::snapshot`] this asks
+    /// the backend, so it is exact but may have to wait.
+    async fn count(&self) -> Result<usize, Box<dyn Error>>;
+
+    /// Replaces a person's `alsoKnownAs`, returning `None` if they don't exist.
+    async fn set_also_known_as(
+        &self,
+        id: &PersonId,
+        aliases: Vec<String>,
+    ) -> Result<Option<Person>, Box<dyn Error>>;
+
+    /// Gives a person a new key, see [`Person::rotate_key`]. Returns `None` if
+    /// they don't exist.
+    async fn rotate_key(&self, id: &PersonId) -> Result<Option<Person>, Box<dyn Error>>;
+
+    /// Adds `follower` to the person's followers, returning `false` if they
+    /// were following already.
+    async fn add_follower(&self, id: &PersonId, follower: &str) -> Result<bool, Box<dyn Error>>;
+    /// Actor IRIs following the person `id`.
+    async fn followers(&self, id: &PersonId) -> Result<Vec<String>, Box<dyn Error>>;
+
+    /// At most `limit` of the person's followers, ordered by their IRI and
+    /// starting after the follower `after`. Pass the last follower of one page
+    /// as `after` to get the next, which stays correct while followers come
+    /// and go in between.
+    async fn followers_page(
+        &self,
+        id: &PersonId,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<String>, Box<dyn Error>>;
+
+    /// Files `activity` in the person's inbox. An activity already there,
+    /// going by its `id`, replaces the earlier copy, so retried deliveries
+    /// only show up once.
+    async fn add_to_inbox(&self, id: &PersonId, activity: Value) -> Result<(), Box<dyn Error>>;
+    /// The activities in the person's inbox, oldest first.
+    async fn inbox(&self, id: &PersonId) -> Result<Vec<Value>, Box<dyn Error>>;
+
+    /// Counts for NodeInfo and metrics. This must not wait on the lock guarding
+    /// the people themselves.
+    fn snapshot(&self) -> PeopleSnapshot;
+}
+
+#[async_trait::async_trait]
+impl<P: PeopleStore + ?Sized> PeopleStore for Arc<P> {
+    async fn get_by_username(&self, username: &str) -> Result<Option<Person>, Box<dyn Error>> {
+        (**self).get_by_username(username).await
+    }
+
+    async fn get_by_id(&self, id: &str) -> Result<Option<Person>, Box<dyn Error>> {
+        (**self).get_by_id(id).await
+    }
+
+    async fn get_or_create(&self, id: &PersonId) -> Result<Person, Box<dyn Error>> {
+        (**self).get_or_create(id).await
+    }
+
+    async fn create(&self, id: &PersonId) -> Result<Option<Person>, Box<dyn Error>> {
+        (**self).create(id).await
+    }
+
+    async fn add(&self, person: Person) -> Result<bool, Box<dyn Error>> {
+        (**self).add(person).await
+    }
+
+    async fn list(&self) -> Result<Vec<Person>, Box<dyn Error>> {
+        (**self).list().await
+    }
+
+    async fn count(&self) -> Result<usize, Box<dyn Error>> {
+        (**self).count().await
+    }
+
+    async fn set_also_known_as(
+        &self,
+        id: &PersonId,
+        aliases: Vec<String>,
+    ) -> Result<Option<Person>, Box<dyn Error>> {
+        (**self).set_also_known_as(id, aliases).await
+    }
+
+    async fn rotate_key(&self, id: &PersonId) -> Result<Option<Person>, Box<dyn Error>> {
+        (**self).rotate_key(id).await
+    }
+
+    async fn add_follower(&self, id: &PersonId, follower: &str) -> Result<bool, Box<dyn Error>> {
+        (**self).add_follower(id, follower).await
+    }
+
+    async fn followers(&self, id: &PersonId) -> Result<Vec<String>, Box<dyn Error>> {
+        (**self).followers(id).await
+    }
+
+    async fn followers_page(
+        &self,
+        id: &PersonId,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<String>, Box<dyn Error>> {
+        (**self).followers_page(id, after, limit).await
+    }
+
+    async fn add_to_inbox(&self, id: &PersonId, activity: Value) -> Result<(), Box<dyn Error>> {
+        (**self).add_to_inbox(id, activity).await
+    }
+
+    async fn inbox(&self, id: &PersonId) -> Result<Vec<Value>, Box<dyn Error>> {
+        (**self).inbox(id).await
+    }
+
+    fn snapshot(&self) -> PeopleSnapshot {
+        (**self).snapshot()
+    }
 }
 
 impl Person {
-    pub fn new(id: PersonId) -> Result<Self, Box<dyn Error>> {
-        let id = format!("https://ap.rens.page/users/{}", id);
-        Ok(Self {
-            id: id.clone(),
-            key: key::Key::new(id)?,
-        })
+    pub fn new(cfg: &Config, id: &PersonId) -> Result<Self, Box<dyn Error>> {
+        Ok(Self::with_key(cfg, id, key::Key::new(cfg.actor_url(id))?))
+    }
+
+    /// A person with an existing key, which must be owned by their actor id.
+    pub fn with_key(cfg: &Config, id: &PersonId, key: key::Key) -> Self {
+        Self {
+            username: id.clone(),
+            id: cfg.actor_url(id),
+            key,
+            also_known_as: vec![],
+            previous_keys: vec![],
+            icon: None,
+            image: None,
+        }
+    }
+
+    /// Replaces the person's key with a new one, keeping the public half of
+    /// the old one in [`Person::previous_keys`]. Previous keys retired
+    /// `grace` or more seconds ago are dropped.
+    pub fn rotate_key(&mut self, grace: u64) -> Result<(), Box<dyn Error>> {
+        let key = self.key.rotate()?;
+        let now = chrono::Utc::now().timestamp();
+        self.previous_keys
+            .retain(|previous| now - previous.retired_at < grace as i64);
+        self.previous_keys.push(RetiredKey {
+            key: self.key.public_key()?,
+            retired_at: now,
+        });
+        self.key = key;
+        Ok(())
+    }
+
+    /// Public keys to put in the actor document: the current one, followed by
+    /// the previous ones retired less than `grace` seconds ago, newest first.
+    pub fn public_keys(&self, grace: u64) -> Result<Vec<key::PublicKey>, Box<dyn Error>> {
+        let mut keys = vec![self.key.public_key()?];
+        let now = chrono::Utc::now().timestamp();
+        for previous in self.previous_keys.iter().rev() {
+            if now - previous.retired_at < grace as i64 {
+                keys.push(previous.key.clone());
+            }
+        }
+        Ok(keys)
+    }
+
+    /// URL of one of the person's collections (`inbox`, `followers`, ...).
+    /// These hang off the person's id rather than the configured domain, so
+    /// they stay put for people created under an earlier configuration. That
+    /// is why this takes the place of a `Config::inbox_url(id)`.
+    pub fn collection_url(&self, collection: &str) -> String {
+        format!("{}/{}", self.id, collection)
     }
 }
 
+async fn find_person(
+    cfg: &Config,
+    people: &dyn PeopleStore,
+    actor: &PersonId,
+) -> Result<Person, WebError> {
+    let person = if cfg.auto_create_users {
+        people.get_or_create(actor).await.map(Some)
+    } else {
+        people.get_by_username(actor).await
+    };
+    person
+        .map_err(|e| web_err_500(format!("Error getting person: {}", e)))?
+        .ok_or_else(|| web_err(StatusCode::NOT_FOUND, format!("No person {}", actor)))
+}
+
 pub async fn json(
     Path(actor): Path<PersonId>,
+    Extension(cfg): Extension<Config>,
     Extension(people): Extension<Arc<dyn PeopleStore>>,
-) -> Result<Json<Value>, WebError> {
-    let person = people
-        .get_or_create(&actor)
-        .await
-        .map_err(|e| web_err_500(format!("Error getting person: {}", e)))?;
-    Ok(Json(json!({
-        "@context": [
-            "https://www.w3.org/ns/activitystreams",
-            "https://w3id.org/security/v1"
-        ],
+    headers: HeaderMap,
+) -> Result<Response, WebError> {
+    let person = find_person(&cfg, people.as_ref(), &actor).await?;
+    let public_key = match person.public_keys(cfg.key_rotation_grace) {
+        Ok(keys) if keys.len() == 1 => json!(keys[0]),
+        Ok(keys) => json!(keys),
+        Err(e) => return Err(web_err_500(format!("Error getting public key: {}", e))),
+    };
+    let mut actor = json!({
+        "@context": context(&cfg),
         "id": person.id,
         "preferredUsername": actor,
         "type": "Person",
-        "inbox": format!("{}/inbox", person.id),
-        "publicKey": person.key.public_key().map_err(|e| {
-            web_err_500(format!("Error getting public key: {}", e))
-        })?,
-    })))
+        "inbox": person.collection_url("inbox"),
+        "outbox": person.collection_url("outbox"),
+        "followers": person.collection_url("followers"),
+        "following": person.collection_url("following"),
+        "liked": person.collection_url("liked"),
+        "endpoints": {"sharedInbox": cfg.shared_inbox_url()},
+        "alsoKnownAs": person.also_known_as,
+        "publicKey": public_key,
+    });
+    if let Some(icon) = &person.icon {
+        actor["icon"] = json!(icon);
+    }
+    if let Some(image) = &person.image {
+        actor["image"] = json!(image);
+    }
+    Ok(negotiated_json(&headers, actor))
+}
+
+/// Just the person's current public key, for tools that don't want to dig it
+/// out of the actor document: the PEM as text, or `id` and `publicKeyPem` as
+/// JSON when that is what's accepted.
+pub async fn public_key(
+    Path(actor): Path<PersonId>,
+    Extension(cfg): Extension<Config>,
+    Extension(people): Extension<Arc<dyn PeopleStore>>,
+    headers: HeaderMap,
+) -> Result<Response, WebError> {
+    let person = find_person(&cfg, people.as_ref(), &actor).await?;
+    let key = person
+        .key
+        .public_key()
+        .map_err(|e| web_err_500(format!("Error getting public key: {}", e)))?;
+
+    let accept = headers
+        .get("accept")
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or_default();
+    if accept.contains("json") {
+        return Ok(Json(json!({
+            "id": key.id(),
+            "publicKeyPem": key.pem(),
+        }))
+        .into_response());
+    }
+    Ok(key.pem().to_string().into_response())
+}
+
+pub async fn followers(
+    Path(actor): Path<PersonId>,
+    Query(page): Query<PageQuery>,
+    Extension(cfg): Extension<Config>,
+    Extension(people): Extension<Arc<dyn PeopleStore>>,
+) -> Result<Json<Value>, WebError> {
+    let person = find_person(&cfg, people.as_ref(), &actor).await?;
+    let followers = people
+        .followers(&actor)
+        .await
+        .map_err(|e| web_err_500(format!("Error getting followers: {}", e)))?;
+    Ok(Json(ordered_collection(
+        &cfg,
+        person.collection_url("followers"),
+        followers,
+        page,
+    )))
 }
 
+/// The activities in the person's inbox, oldest first, for the person only.
+pub async fn inbox(
+    User(user): User,
+    Path(actor): Path<PersonId>,
+    Query(page): Query<PageQuery>,
+    Extension(cfg): Extension<Config>,
+    Extension(people): Extension<Arc<dyn PeopleStore>>,
+) -> Result<Json<Value>, WebError> {
+    if user != actor {
+        return Err(web_err(
+            StatusCode::FORBIDDEN,
+            format!("{} can't read the inbox of {}", user, actor),
+        ));
+    }
+    let person = find_person(&cfg, people.as_ref(), &actor).await?;
+    let activities = people
+        .inbox(&actor)
+        .await
+        .map_err(|e| web_err_500(format!("Error getting inbox: {}", e)))?;
+    Ok(Json(ordered_collection(
+        &cfg,
+        person.collection_url("inbox"),
+        activities,
+        page,
+    )))
+}
+
+// We don't keep an outbox, follow anyone, or like anything yet, so these are
+// always empty. They exist so the URLs in the actor document resolve.
+
+pub async fn outbox(
+    Path(actor): Path<PersonId>,
+    Query(page): Query<PageQuery>,
+    Extension(cfg): Extension<Config>,
+    Extension(people): Extension<Arc<dyn PeopleStore>>,
+) -> Result<Json<Value>, WebError> {
+    let person = find_person(&cfg, people.as_ref(), &actor).await?;
+    Ok(Json(ordered_collection(
+        &cfg,
+        person.collection_url("outbox"),
+        Vec::<String>::new(),
+        page,
+    )))
+}
+
+pub async fn following(
+    Path(actor): Path<PersonId>,
+    Query(page): Query<PageQuery>,
+    Extension(cfg): Extension<Config>,
+    Extension(people): Extension<Arc<dyn PeopleStore>>,
+) -> Result<Json<Value>, WebError> {
+    let person = find_person(&cfg, people.as_ref(), &actor).await?;
+    Ok(Json(ordered_collection(
+        &cfg,
+        person.collection_url("following"),
+        Vec::<String>::new(),
+        page,
+    )))
+}
+
+pub async fn liked(
+    Path(actor): Path<PersonId>,
+    Query(page): Query<PageQuery>,
+    Extension(cfg): Extension<Config>,
+    Extension(people): Extension<Arc<dyn PeopleStore>>,
+) -> Result<Json<Value>, WebError> {
+    let person = find_person(&cfg, people.as_ref(), &actor).await?;
+    Ok(Json(collection(
+        &cfg,
+        cfg.liked_collection,
+        person.collection_url("liked"),
+        Vec::<String>::new(),
+        page,
+    )))
+}
+
+#[derive(Clone)]
 pub struct InMemoryPeopleStore {
-    people: Mutex<HashMap<PersonId, Person>>,
+    cfg: Config,
+    people: Arc<Mutex<HashMap<PersonId, Person>>>,
+    followers: Arc<Mutex<HashMap<PersonId, Vec<String>>>>,
+    inboxes: Arc<Mutex<HashMap<PersonId, Vec<Value>>>>,
+    total: Arc<AtomicUsize>,
 }
 
 impl InMemoryPeopleStore {
-    pub fn new() -> Self {
+    pub fn new(cfg: Config) -> Self {
         Self {
-            people: Mutex::new(HashMap::new()),
+            cfg,
+            people: Arc::new(Mutex::new(HashMap::new())),
+            followers: Arc::new(Mutex::new(HashMap::new())),
+            inboxes: Arc::new(Mutex::new(HashMap::new())),
+            total: Arc::new(AtomicUsize::new(0)),
         }
     }
+
+    fn insert(&self, people: &mut HashMap<PersonId, Person>, person: Person) {
+        people.insert(person.username.clone(), person);
+        self.total.store(people.len(), Ordering::Relaxed);
+    }
 }
 
 #[async_trait::async_trait]
 impl PeopleStore for InMemoryPeopleStore {
+    async fn get_by_username(&self, username: &str) -> Result<Option<Person>, Box<dyn Error>> {
+        Ok(self.people.lock().await.get(username).cloned())
+    }
+
+    async fn get_by_id(&self, id: &str) -> Result<Option<Person>, Box<dyn Error>> {
+        Ok(self
+            .people
+            .lock()
+            .await
+            .values()
+            .find(|person| person.id == id)
+            .cloned())
+    }
+
     async fn get_or_create(&self, id: &PersonId) -> Result<Person, Box<dyn Error>> {
         let mut people = self.people.lock().await;
 
-        if !people.contains_key(id) {
-            let person = Person::new(id.clone()).unwrap();
-            people.insert(id.clone(), person);
+        if let Some(person) = people.get(id) {
+            return Ok(person.clone());
+        }
+
+        let person = Person::new(&self.cfg, id)?;
+        self.insert(&mut people, person.clone());
+        Ok(person)
+    }
+
+    async fn create(&self, id: &PersonId) -> Result<Option<Person>, Box<dyn Error>> {
+        let mut people = self.people.lock().await;
+        if people.contains_key(id) {
+            return Ok(None);
+        }
+
+        let person = Person::new(&self.cfg, id)?;
+        self.insert(&mut people, person.clone());
+        Ok(Some(person))
+    }
+
+    async fn add(&self, person: Person) -> Result<bool, Box<dyn Error>> {
+        let mut people = self.people.lock().await;
+        if people.contains_key(&person.username) {
+            return Ok(false);
+        }
+        self.insert(&mut people, person);
+        Ok(true)
+    }
+
+    async fn list(&self) -> Result<Vec<Person>, Box<dyn Error>> {
+        let mut people: Vec<Person> = self.people.lock().await.values().cloned().collect();
+        people.sort_by(|a, b| a.username.cmp(&b.username));
+        Ok(people)
+    }
+
+    async fn count(&self) -> Result<usize, Box<dyn Error>> {
+        Ok(self.people.lock().await.len())
+    }
+
+    async fn set_also_known_as(
+        &self,
+        id: &PersonId,
+        aliases: Vec<String>,
+    ) -> Result<Option<Person>, Box<dyn Error>> {
+        Ok(self.people.lock().await.get_mut(id).map(|person| {
+            person.also_known_as = aliases;
+            person.clone()
+        }))
+    }
+
+    async fn rotate_key(&self, id: &PersonId) -> Result<Option<Person>, Box<dyn Error>> {
+        let mut people = self.people.lock().await;
+        let Some(person) = people.get_mut(id) else {
+            return Ok(None);
+        };
+        person.rotate_key(self.cfg.key_rotation_grace)?;
+        Ok(Some(person.clone()))
+    }
+
+    async fn add_follower(&self, id: &PersonId, follower: &str) -> Result<bool, Box<dyn Error>> {
+        let mut followers = self.followers.lock().await;
+        let followers = followers.entry(id.clone()).or_default();
+        if followers.iter().any(|f| f == follower) {
+            return Ok(false);
+        }
+        followers.push(follower.to_string());
+        Ok(true)
+    }
+
+    async fn followers(&self, id: &PersonId) -> Result<Vec<String>, Box<dyn Error>> {
+        Ok(self
+            .followers
+            .lock()
+            .await
+            .get(id)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    async fn followers_page(
+        &self,
+        id: &PersonId,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<String>, Box<dyn Error>> {
+        let followers = self.followers.lock().await;
+        let mut page: Vec<String> = followers
+            .get(id)
+            .into_iter()
+            .flatten()
+            .filter(|follower| after.map_or(true, |after| follower.as_str() > after))
+            .cloned()
+            .collect();
+        page.sort();
+        page.truncate(limit);
+        Ok(page)
+    }
+
+    async fn add_to_inbox(&self, id: &PersonId, activity: Value) -> Result<(), Box<dyn Error>> {
+        let mut inboxes = self.inboxes.lock().await;
+        let inbox = inboxes.entry(id.clone()).or_default();
+        if activity["id"].is_string() {
+            inbox.retain(|a| a["id"] != activity["id"]);
+        }
+        inbox.push(activity);
+        Ok(())
+    }
+
+    async fn inbox(&self, id: &PersonId) -> Result<Vec<Value>, Box<dyn Error>> {
+        Ok(self
+            .inboxes
+            .lock()
+            .await
+            .get(id)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    fn snapshot(&self) -> PeopleSnapshot {
+        PeopleSnapshot {
+            total: self.total.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::objects::InMemoryObjectStore;
+    use crate::tests::{body_json, test_app};
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use clap::Parser;
+    use tower::ServiceExt;
+
+    /// A store whose backend is always broken.
+    struct FailingPeopleStore;
+
+    #[async_trait::async_trait]
+    impl PeopleStore for FailingPeopleStore {
+        async fn get_by_username(&self, _username: &str) -> Result<Option<Person>, Box<dyn Error>> {
+            Err("store is down".into())
+        }
+
+        async fn get_by_id(&self, _id: &str) -> Result<Option<Person>, Box<dyn Error>> {
+            Err("store is down".into())
+        }
+
+        async fn get_or_create(&self, _id: &PersonId) -> Result<Person, Box<dyn Error>> {
+            Err("store is down".into())
+        }
+
+        async fn create(&self, _id: &PersonId) -> Result<Option<Person>, Box<dyn Error>> {
+            Err("store is down".into())
+        }
+
+        async fn add(&self, _person: Person) -> Result<bool, Box<dyn Error>> {
+            Err("store is down".into())
+        }
+
+        async fn list(&self) -> Result<Vec<Person>, Box<dyn Error>> {
+            Err("store is down".into())
+        }
+
+        async fn count(&self) -> Result<usize, Box<dyn Error>> {
+            Err("store is down".into())
+        }
+
+        async fn set_also_known_as(
+            &self,
+            _id: &PersonId,
+            _aliases: Vec<String>,
+        ) -> Result<Option<Person>, Box<dyn Error>> {
+            Err("store is down".into())
+        }
+
+        async fn rotate_key(&self, _id: &PersonId) -> Result<Option<Person>, Box<dyn Error>> {
+            Err("store is down".into())
+        }
+
+        async fn add_follower(
+            &self,
+            _id: &PersonId,
+            _follower: &str,
+        ) -> Result<bool, Box<dyn Error>> {
+            Err("store is down".into())
+        }
+
+        async fn followers(&self, _id: &PersonId) -> Result<Vec<String>, Box<dyn Error>> {
+            Err("store is down".into())
+        }
+
+        async fn followers_page(
+            &self,
+            _id: &PersonId,
+            _after: Option<&str>,
+            _limit: usize,
+        ) -> Result<Vec<String>, Box<dyn Error>> {
+            Err("store is down".into())
+        }
+
+        async fn add_to_inbox(
+            &self,
+            _id: &PersonId,
+            _activity: Value,
+        ) -> Result<(), Box<dyn Error>> {
+            Err("store is down".into())
+        }
+
+        async fn inbox(&self, _id: &PersonId) -> Result<Vec<Value>, Box<dyn Error>> {
+            Err("store is down".into())
+        }
+
+        fn snapshot(&self) -> PeopleSnapshot {
+            PeopleSnapshot { total: 0 }
+        }
+    }
+
+    fn get(uri: &str) -> Request<Body> {
+        Request::get(uri).body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_unknown_user_not_created() {
+        let app = test_app(&[]);
+        let response = app.oneshot(get("/users/alice")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_user_auto_created() {
+        let app = test_app(&["--auto-create-users"]);
+        let response = app.oneshot(get("/users/alice")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let actor = body_json(response).await;
+        assert_eq!(actor["id"], "https://example.com/users/alice");
+    }
+
+    #[tokio::test]
+    async fn test_collection_urls() {
+        let app = test_app(&["--auto-create-users"]);
+        let response = app.clone().oneshot(get("/users/alice")).await.unwrap();
+        let actor = body_json(response).await;
+
+        for collection in ["outbox", "followers", "following", "liked"] {
+            let url = actor[collection].as_str().unwrap();
+            assert_eq!(
+                url,
+                format!("https://example.com/users/alice/{}", collection)
+            );
+
+            // and each one is served
+            let path = url.strip_prefix("https://example.com").unwrap();
+            let response = app.clone().oneshot(get(path)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK, "{}", path);
+            let body = body_json(response).await;
+            assert_eq!(body["id"], url);
+            assert_eq!(body["type"], "OrderedCollection");
+            assert_eq!(body["totalItems"], 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_collection_urls_follow_person_id() {
+        // alice was created back when we were old.example
+        let old = Config::parse_from(["rap-server", "--domain", "old.example"]);
+        let people = InMemoryPeopleStore::new(old);
+        people.create(&"alice".to_string()).await.unwrap();
+
+        let cfg = Config::parse_from(["rap-server", "--domain", "example.com"]);
+        let app = crate::app(
+            &cfg,
+            Arc::new(people),
+            Arc::new(InMemoryObjectStore::new()),
+            crate::delivery::DeliveryQueue::new().0,
+            crate::tests::test_instance(&cfg),
+        );
+        for collection in ["outbox", "followers", "following", "liked"] {
+            let path = format!("/users/alice/{}?page=1", collection);
+            let response = app.clone().oneshot(get(&path)).await.unwrap();
+            let body = body_json(response).await;
+            let url = format!("https://old.example/users/alice/{}", collection);
+            assert_eq!(body["id"], format!("{}?page=1", url));
+            assert_eq!(body["partOf"], url);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unordered_liked() {
+        let app = test_app(&["--auto-create-users", "--liked-collection", "unordered"]);
+        let response = app.oneshot(get("/users/alice/liked")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = body_json(response).await;
+        assert_eq!(body["type"], "Collection");
+    }
+
+    #[tokio::test]
+    async fn test_all_collection_urls_in_actor() {
+        let app = test_app(&["--auto-create-users"]);
+        let response = app.oneshot(get("/users/alice")).await.unwrap();
+        let actor = body_json(response).await;
+        assert_eq!(actor["id"], "https://example.com/users/alice");
+        for collection in ["inbox", "outbox", "followers", "following", "liked"] {
+            assert_eq!(
+                actor[collection],
+                format!("https://example.com/users/alice/{}", collection)
+            );
+        }
+        assert_eq!(
+            actor["endpoints"]["sharedInbox"],
+            "https://example.com/inbox"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_icon_and_image() {
+        let cfg = Config::parse_from(["rap-server", "--domain", "example.com"]);
+        let people = Arc::new(InMemoryPeopleStore::new(cfg.clone()));
+        let mut alice = Person::new(&cfg, &"alice".to_string()).unwrap();
+        alice.icon = Some(Media::image(
+            "image/png",
+            "https://example.com/media/avatar.png",
+        ));
+        alice.image = Some(Media::image(
+            "image/jpeg",
+            "https://example.com/media/header.jpg",
+        ));
+        people.add(alice).await.unwrap();
+        people.create(&"bob".to_string()).await.unwrap();
+        let app = crate::app(
+            &cfg,
+            people,
+            Arc::new(InMemoryObjectStore::new()),
+            crate::delivery::DeliveryQueue::new().0,
+            crate::tests::test_instance(&cfg),
+        );
+
+        let response = app.clone().oneshot(get("/users/alice")).await.unwrap();
+        let actor = body_json(response).await;
+        assert_eq!(
+            actor["icon"],
+            json!({
+                "type": "Image",
+                "mediaType": "image/png",
+                "url": "https://example.com/media/avatar.png"
+            })
+        );
+        assert_eq!(
+            actor["image"],
+            json!({
+                "type": "Image",
+                "mediaType": "image/jpeg",
+                "url": "https://example.com/media/header.jpg"
+            })
+        );
+
+        // without them, neither is in the document
+        let response = app.oneshot(get("/users/bob")).await.unwrap();
+        let actor = body_json(response).await;
+        assert!(actor.get("icon").is_none());
+        assert!(actor.get("image").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_public_key() {
+        use rsa::pkcs8::DecodePublicKey;
+
+        let app = test_app(&["--auto-create-users"]);
+        let response = app
+            .clone()
+            .oneshot(get("/users/alice/public_key"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers()["content-type"]
+            .to_str()
+            .unwrap()
+            .starts_with("text/plain"));
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let pem = std::str::from_utf8(&body).unwrap();
+        rsa::RsaPublicKey::from_public_key_pem(pem).unwrap();
+
+        let response = app
+            .oneshot(
+                Request::get("/users/alice/public_key")
+                    .header("accept", "application/json")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let key = body_json(response).await;
+        assert_eq!(key["id"], "https://example.com/users/alice/#main-key");
+        assert_eq!(key["publicKeyPem"], pem);
+    }
+
+    #[tokio::test]
+    async fn test_content_negotiation() {
+        let app = test_app(&["--auto-create-users"]);
+        for (accept, content_type) in [
+            (None, "application/json"),
+            (
+                Some("application/activity+json"),
+                "application/activity+json",
+            ),
+            (Some(crate::remote::LD_JSON), "application/activity+json"),
+            (Some("text/html"), "application/json"),
+        ] {
+            let mut request = Request::get("/users/alice");
+            if let Some(accept) = accept {
+                request = request.header("accept", accept);
+            }
+            let response = app
+                .clone()
+                .oneshot(request.body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers()["content-type"], content_type);
+            assert_eq!(response.headers()["vary"], "Accept");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_collections_of_unknown_user() {
+        let app = test_app(&[]);
+        for path in ["/users/alice/followers", "/users/alice/outbox"] {
+            let response = app.clone().oneshot(get(path)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_admin_created_user() {
+        let app = test_app(&["--admin-token", "secret"]);
+        let response = app
+            .clone()
+            .oneshot(
+                Request::post("/admin/users")
+                    .header("authorization", "Bearer secret")
+                    .header("content-type", "application/json")
+                    .body(Body::from(r#"{"username":"alice"}"#))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        let response = app.oneshot(get("/users/alice")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_store_error() {
+        for args in [vec![], vec!["--auto-create-users"]] {
+            let mut argv = vec!["rap-server", "--domain", "example.com"];
+            argv.extend(args);
+            let cfg = Config::parse_from(argv);
+            let app = crate::app(
+                &cfg,
+                Arc::new(FailingPeopleStore),
+                Arc::new(InMemoryObjectStore::new()),
+                crate::delivery::DeliveryQueue::new().0,
+                crate::tests::test_instance(&cfg),
+            );
+
+            let response = app.oneshot(get("/users/alice")).await.unwrap();
+            assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            assert_eq!(body, "Error getting person: store is down");
+        }
+    }
+
+    /// Hammers `get_or_create` for the same few people from many tasks.
+    async fn get_or_create_concurrently<P: PeopleStore + Clone + 'static>(people: P) {
+        let mut tasks = tokio::task::JoinSet::new();
+        for i in 0..16 {
+            let people = people.clone();
+            tasks.spawn(async move {
+                let id = format!("user{}", i % 2);
+                let person = people.get_or_create(&id).await.map_err(|e| e.to_string());
+                person.map(|p| (id, serde_json::to_string(&p.key).unwrap()))
+            });
+        }
+
+        let mut keys = HashMap::new();
+        while let Some(result) = tasks.join_next().await {
+            let (id, key) = result.unwrap().unwrap();
+            // everyone must have been handed the same person
+            assert_eq!(keys.entry(id).or_insert_with(|| key.clone()), &key);
+        }
+        assert_eq!(keys.len(), 2);
+        assert_eq!(people.snapshot(), PeopleSnapshot { total: 2 });
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_concurrent_get_or_create() {
+        let cfg = Config::parse_from(["rap-server", "--domain", "example.com"]);
+        get_or_create_concurrently(InMemoryPeopleStore::new(cfg.clone())).await;
+
+        let people: Arc<dyn PeopleStore> = Arc::new(InMemoryPeopleStore::new(cfg));
+        get_or_create_concurrently(people).await;
+    }
+
+    #[tokio::test]
+    async fn test_count() {
+        let cfg = Config::parse_from(["rap-server", "--domain", "example.com"]);
+        let people = InMemoryPeopleStore::new(cfg);
+        assert_eq!(people.count().await.unwrap(), 0);
+
+        people.create(&"alice".to_string()).await.unwrap();
+        people.get_or_create(&"bob".to_string()).await.unwrap();
+        // neither of these make anyone new
+        people.create(&"alice".to_string()).await.unwrap();
+        people.get_by_username("carol").await.unwrap();
+        assert_eq!(people.count().await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_username_and_id_lookups() {
+        let cfg = Config::parse_from(["rap-server", "--domain", "example.com"]);
+        let people = InMemoryPeopleStore::new(cfg);
+        people.create(&"alice".to_string()).await.unwrap();
+        let id = "https://example.com/users/alice";
+
+        let by_username = people.get_by_username("alice").await.unwrap().unwrap();
+        assert_eq!(by_username.id, id);
+        let by_id = people.get_by_id(id).await.unwrap().unwrap();
+        assert_eq!(by_id.username, "alice");
+
+        // neither lookup takes the other kind of key
+        assert!(people.get_by_username(id).await.unwrap().is_none());
+        assert!(people.get_by_id("alice").await.unwrap().is_none());
+        assert!(people
+            .get_by_id("https://remote.example/users/alice")
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_rotated_key_grace() {
+        let cfg = Config::parse_from(["rap-server", "--domain", "example.com"]);
+        let people = InMemoryPeopleStore::new(cfg);
+        let alice = people.create(&"alice".to_string()).await.unwrap().unwrap();
+        assert!(people
+            .rotate_key(&"bob".to_string())
+            .await
+            .unwrap()
+            .is_none());
+
+        let rotated = people.rotate_key(&alice.username).await.unwrap().unwrap();
+        let stored = people
+            .get_by_username(&alice.username)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.key.key_id(), rotated.key.key_id());
+
+        // the new key signs and its advertised public key verifies
+        let data = b"some data to sign";
+        let signature = stored.key.sign(data).unwrap();
+        let keys = stored.public_keys(60).unwrap();
+        assert_eq!(keys.len(), 2);
+        keys[0].verify(data, &signature).unwrap();
+        // the old key is still there for signatures made before rotating
+        let old_signature = alice.key.sign(data).unwrap();
+        keys[1].verify(data, &old_signature).unwrap();
+
+        assert_eq!(stored.public_keys(0).unwrap().len(), 1);
+
+        // rotating again while the first old key is in grace keeps both
+        let again = people.rotate_key(&alice.username).await.unwrap().unwrap();
+        let keys = again.public_keys(60).unwrap();
+        assert_eq!(keys.len(), 3);
+        keys[0]
+            .verify(data, &again.key.sign(data).unwrap())
+            .unwrap();
+        keys[1].verify(data, &signature).unwrap();
+        keys[2].verify(data, &old_signature).unwrap();
+
+        // keys past their grace are dropped on the next rotation
+        let mut person = again.clone();
+        person.rotate_key(0).unwrap();
+        assert_eq!(person.previous_keys.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_followers_page_after() {
+        let cfg = Config::parse_from(["rap-server", "--domain", "example.com"]);
+        let people = InMemoryPeopleStore::new(cfg);
+        let alice = "alice".to_string();
+        people.create(&alice).await.unwrap();
+        for follower in ["https://b.example/c", "https://b.example/a"] {
+            people.add_follower(&alice, follower).await.unwrap();
         }
 
-        let p = people.get(id).unwrap();
-        return Ok(p.clone());
+        let first = people.followers_page(&alice, None, 1).await.unwrap();
+        assert_eq!(first, vec!["https://b.example/a"]);
+        // someone following between pages neither shifts nor repeats the rest
+        people
+            .add_follower(&alice, "https://a.example/z")
+            .await
+            .unwrap();
+        let rest = people
+            .followers_page(&alice, Some(&first[0]), 10)
+            .await
+            .unwrap();
+        assert_eq!(rest, vec!["https://b.example/c"]);
+        assert!(people
+            .followers_page(&alice, Some(&rest[0]), 10)
+            .await
+            .unwrap()
+            .is_empty());
     }
 }
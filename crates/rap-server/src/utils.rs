@@ -1,13 +1,23 @@
-use axum::http::StatusCode;
+use axum::http::header::{ACCEPT, CONTENT_LENGTH, CONTENT_TYPE, VARY};
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
 use base64::engine::general_purpose;
 use base64::Engine;
-use log::warn;
+use serde_json::{json, Value};
+use tracing::{debug, warn};
 
 pub fn base64_decode<T: AsRef<[u8]>>(data: T) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
     let decoded = general_purpose::STANDARD.decode(data)?;
     Ok(decoded)
 }
 
+/// Encodes `data` with the same standard, padded alphabet [`base64_decode`]
+/// decodes, as used in `Digest` and `Signature` headers.
+pub fn base64_encode<T: AsRef<[u8]>>(data: T) -> String {
+    general_purpose::STANDARD.encode(data)
+}
+
 pub type WebError = (StatusCode, String);
 pub fn web_err<S: Into<String>>(status: StatusCode, msg: S) -> WebError {
     let msg = msg.into();
@@ -22,3 +32,144 @@ pub fn web_err_500<S: Into<String>>(msg: S) -> WebError {
 pub fn web_err_400<S: Into<String>>(msg: S) -> WebError {
     web_err(StatusCode::BAD_REQUEST, msg)
 }
+
+pub fn web_err_401<S: Into<String>>(msg: S) -> WebError {
+    web_err(StatusCode::UNAUTHORIZED, msg)
+}
+
+/// The content type of RFC 9457 problem details.
+pub const PROBLEM_JSON: &str = "application/problem+json";
+
+/// Rewrites an error response into RFC 9457 problem details. Only errors
+/// that are plain text, as a [`WebError`] is, or have no body at all are
+/// rewritten; anything already structured is left alone.
+pub async fn problem_details(response: Response) -> Response {
+    let status = response.status();
+    let plain = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .map_or(true, |h| h.as_bytes().starts_with(b"text/plain"));
+    if !(status.is_client_error() || status.is_server_error()) || !plain {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let detail = hyper::body::to_bytes(body).await.unwrap_or_default();
+    let mut problem = json!({
+        "type": "about:blank",
+        "title": status.canonical_reason().unwrap_or_default(),
+        "status": status.as_u16(),
+    });
+    if !detail.is_empty() {
+        problem["detail"] = String::from_utf8_lossy(&detail).into();
+    }
+    parts.headers.remove(CONTENT_LENGTH);
+    parts
+        .headers
+        .insert(CONTENT_TYPE, HeaderValue::from_static(PROBLEM_JSON));
+    let mut response = Json(problem).into_response();
+    *response.status_mut() = status;
+    response.headers_mut().extend(parts.headers);
+    response
+}
+
+/// Serves an actor document as `application/activity+json` to clients asking
+/// for ActivityStreams and as plain JSON to everyone else. The answer depends
+/// on `Accept`, so caches are told with `Vary` not to mix them up.
+pub fn negotiated_json(headers: &HeaderMap, body: Value) -> Response {
+    let accept = headers
+        .get(ACCEPT)
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or_default();
+    let content_type =
+        if accept.contains("application/activity+json") || accept.contains("application/ld+json") {
+            crate::remote::ACTIVITY_JSON
+        } else {
+            "application/json"
+        };
+    ([(CONTENT_TYPE, content_type), (VARY, "Accept")], Json(body)).into_response()
+}
+
+/// Logs `body` at debug level, cut down to `limit` bytes. Bodies are only
+/// logged with `--debug-bodies`, which is when there is a `limit`.
+pub fn log_body(limit: Option<usize>, what: &str, body: &[u8]) {
+    if let Some(limit) = limit {
+        debug!("{}: {}", what, truncate_body(body, limit));
+    }
+}
+
+fn truncate_body(body: &[u8], limit: usize) -> String {
+    let text = String::from_utf8_lossy(&body[..body.len().min(limit)]);
+    if body.len() > limit {
+        format!("{}... ({} bytes in total)", text, body.len())
+    } else {
+        text.into_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use std::sync::{Arc, Mutex};
+    use tracing::field::{Field, Visit};
+
+    /// Records the message of every event logged while it is the default
+    /// subscriber.
+    struct Messages(Arc<Mutex<Vec<String>>>);
+
+    impl Visit for Messages {
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            if field.name() == "message" {
+                self.0.lock().unwrap().push(format!("{:?}", value));
+            }
+        }
+    }
+
+    impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for Messages {
+        fn on_event(
+            &self,
+            event: &tracing::Event<'_>,
+            _ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            event.record(&mut Messages(self.0.clone()));
+        }
+    }
+
+    fn logged_bodies(args: &[&str]) -> Vec<String> {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let cfg = crate::config::Config::parse_from(
+            ["rap-server", "--domain", "example.com"].iter().chain(args),
+        );
+        let messages = Arc::new(Mutex::new(Vec::new()));
+        let subscriber = tracing_subscriber::registry().with(Messages(messages.clone()));
+        tracing::subscriber::with_default(subscriber, || {
+            log_body(cfg.body_log_limit(), "Inbox body", br#"{"type":"Note"}"#);
+        });
+        let messages = messages.lock().unwrap().clone();
+        messages
+    }
+
+    #[test]
+    fn test_debug_bodies_flag() {
+        assert!(logged_bodies(&[]).is_empty());
+        assert_eq!(
+            logged_bodies(&["--debug-bodies"]),
+            vec![r#"Inbox body: {"type":"Note"}"#]
+        );
+        assert_eq!(
+            logged_bodies(&["--debug-bodies", "--debug-body-limit", "5"]),
+            vec![r#"Inbox body: {"typ... (15 bytes in total)"#]
+        );
+    }
+
+    #[test]
+    fn test_base64_round_trip() {
+        for data in [&b""[..], b"f", b"fo", b"foo", &[0, 255, 254, 62, 63]] {
+            assert_eq!(base64_decode(base64_encode(data)).unwrap(), data);
+        }
+        assert_eq!(base64_encode(b"foo\xff"), "Zm9v/w==");
+        base64_decode("not base64!").unwrap_err();
+    }
+}
@@ -1,8 +1,12 @@
 use axum::extract::Query;
+use axum::http::{header, StatusCode};
+use axum::response::IntoResponse;
 use axum::{Extension, Json};
 use serde_json::{json, Value};
+use std::sync::Arc;
 
-use crate::utils::{web_err_400, WebError};
+use crate::users::PeopleStore;
+use crate::utils::{web_err, web_err_400, web_err_500, WebError};
 use crate::Config;
 use serde::Deserialize;
 
@@ -11,37 +15,252 @@ pub struct Webfinger {
     resource: String,
 }
 
+/// `/.well-known/host-meta`, which some clients read to find webfinger rather
+/// than assuming where it is.
+pub async fn host_meta(Extension(cfg): Extension<Config>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "application/xrd+xml; charset=utf-8")],
+        format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<XRD xmlns="http://docs.oasis-open.org/ns/xri/xrd-1.0">
+  <Link rel="lrdd" template="{}/.well-known/webfinger?resource={{uri}}"/>
+</XRD>
+"#,
+            cfg.base_url()
+        ),
+    )
+}
+
 pub async fn json(
     webfinger: Query<Webfinger>,
     Extension(cfg): Extension<Config>,
+    Extension(people): Extension<Arc<dyn PeopleStore>>,
 ) -> Result<Json<Value>, WebError> {
-    let resource = webfinger.resource.clone().to_lowercase();
-    let domain = cfg.domain;
+    // the query string is decoded already, but clients filling in the host-meta
+    // template sometimes encode the resource a second time
+    let resource = if webfinger.resource.contains('%') {
+        percent_encoding::percent_decode_str(&webfinger.resource)
+            .decode_utf8_lossy()
+            .to_string()
+    } else {
+        webfinger.resource.clone()
+    };
+    let resource = resource.to_lowercase();
+    let domain = &cfg.domain;
 
     let error = || web_err_400(format!("Invalid resource: {}", resource));
 
     let id = resource
         .strip_prefix("acct:")
         .ok_or_else(error)?
-        .strip_suffix(&domain)
+        .strip_suffix(domain.as_str())
         .ok_or_else(error)?
         .strip_suffix('@')
         .ok_or_else(error)?;
 
-    // TODO: check if id exists
+    let person = people
+        .get_by_username(id)
+        .await
+        .map_err(|e| web_err_500(format!("Error getting person: {}", e)))?;
+    // with --auto-create-users the actor comes into being once it's fetched
+    if person.is_none() && !cfg.auto_create_users {
+        return Err(web_err(StatusCode::NOT_FOUND, format!("No person {}", id)));
+    }
 
-    Ok(Json(json!({
+    let mut webfinger = json!({
       "subject": format!("acct:{}@{}", id, domain),
       "aliases": [
-        format!("https://{}/@{}", domain, id),
-        format!("https://{}/users/{}", domain, id)
+        format!("{}/@{}", cfg.base_url(), id),
+        cfg.actor_url(id)
       ],
       "links": [
+        {
+          "rel": "http://webfinger.net/rel/profile-page",
+          "type": "text/html",
+          "href": format!("{}/@{}", cfg.base_url(), id)
+        },
         {
           "rel": "self",
           "type": "application/activity+json",
-          "href": format!("https://{}/users/{}", domain, id)
+          "href": cfg.actor_url(id)
         }
       ]
-    })))
+    });
+    if let Some(icon) = person.and_then(|p| p.icon) {
+        webfinger["links"].as_array_mut().unwrap().push(json!({
+            "rel": "http://webfinger.net/rel/avatar",
+            "type": icon.media_type(),
+            "href": icon.url(),
+        }));
+    }
+    Ok(Json(webfinger))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::users::{InMemoryPeopleStore, Person};
+    use clap::Parser;
+    use rap_core::types::Media;
+
+    fn config() -> Config {
+        Config::parse_from(["rap-server", "--domain", "example.com"])
+    }
+
+    async fn webfinger_with(
+        people: Arc<dyn PeopleStore>,
+        resource: &str,
+    ) -> Result<Json<Value>, WebError> {
+        json(
+            Query(Webfinger {
+                resource: resource.to_string(),
+            }),
+            Extension(config()),
+            Extension(people),
+        )
+        .await
+    }
+
+    async fn webfinger(resource: &str) -> Result<Json<Value>, WebError> {
+        let people = InMemoryPeopleStore::new(config());
+        people.create(&"alice".to_string()).await.unwrap();
+        webfinger_with(Arc::new(people), resource).await
+    }
+
+    #[tokio::test]
+    async fn test_links() {
+        let Json(webfinger) = webfinger("acct:alice@example.com").await.unwrap();
+        assert_eq!(webfinger["subject"], "acct:alice@example.com");
+        assert_eq!(
+            webfinger["links"],
+            json!([
+                {
+                    "rel": "http://webfinger.net/rel/profile-page",
+                    "type": "text/html",
+                    "href": "https://example.com/@alice"
+                },
+                {
+                    "rel": "self",
+                    "type": "application/activity+json",
+                    "href": "https://example.com/users/alice"
+                }
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_avatar_link() {
+        let cfg = config();
+        let people = InMemoryPeopleStore::new(cfg.clone());
+        let mut alice = Person::new(&cfg, &"alice".to_string()).unwrap();
+        alice.icon = Some(Media::image(
+            "image/png",
+            "https://example.com/media/alice.png",
+        ));
+        people.add(alice).await.unwrap();
+        people.create(&"bob".to_string()).await.unwrap();
+        let people: Arc<dyn PeopleStore> = Arc::new(people);
+
+        let Json(webfinger) = webfinger_with(people.clone(), "acct:alice@example.com")
+            .await
+            .unwrap();
+        assert_eq!(
+            webfinger["links"][2],
+            json!({
+                "rel": "http://webfinger.net/rel/avatar",
+                "type": "image/png",
+                "href": "https://example.com/media/alice.png"
+            })
+        );
+
+        // no avatar, no link
+        let Json(webfinger) = webfinger_with(people, "acct:bob@example.com")
+            .await
+            .unwrap();
+        assert_eq!(webfinger["links"].as_array().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_person() {
+        let (status, _) = webfinger("acct:bob@example.com").await.unwrap_err();
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        // unless they would be created by fetching their actor
+        let cfg = Config::parse_from([
+            "rap-server",
+            "--domain",
+            "example.com",
+            "--auto-create-users",
+        ]);
+        let Json(webfinger) = json(
+            Query(Webfinger {
+                resource: "acct:bob@example.com".to_string(),
+            }),
+            Extension(cfg.clone()),
+            Extension(Arc::new(InMemoryPeopleStore::new(cfg)) as Arc<dyn PeopleStore>),
+        )
+        .await
+        .unwrap();
+        assert_eq!(webfinger["subject"], "acct:bob@example.com");
+    }
+
+    #[tokio::test]
+    async fn test_other_domain() {
+        webfinger("acct:alice@remote.example").await.unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn test_double_encoded() {
+        let Json(webfinger) = webfinger("acct%3Aalice%40example.com").await.unwrap();
+        assert_eq!(webfinger["subject"], "acct:alice@example.com");
+    }
+
+    #[tokio::test]
+    async fn test_host_meta_template() {
+        use crate::tests::{body_json, test_app};
+        use axum::body::Body;
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let app = test_app(&["--auto-create-users"]);
+        let response = app
+            .clone()
+            .oneshot(
+                Request::get("/.well-known/host-meta")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()["content-type"],
+            "application/xrd+xml; charset=utf-8"
+        );
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body = std::str::from_utf8(&body).unwrap();
+        let template = body
+            .split("template=\"")
+            .nth(1)
+            .and_then(|rest| rest.split('"').next())
+            .unwrap();
+        assert_eq!(
+            template,
+            "https://example.com/.well-known/webfinger?resource={uri}"
+        );
+
+        // fill in the template the way a client would
+        let uri = template
+            .replace("{uri}", "acct%3Aalice%40example.com")
+            .replace("https://example.com", "");
+        let response = app
+            .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            body_json(response).await["subject"],
+            "acct:alice@example.com"
+        );
+    }
 }
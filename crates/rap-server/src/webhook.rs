@@ -0,0 +1,91 @@
+use axum::body::Bytes;
+use reqwest::Url;
+use ring::hmac;
+use std::error::Error;
+use std::time::Duration;
+use tracing::{debug, warn};
+
+use crate::config::Config;
+
+/// Header carrying the HMAC-SHA256 of the body, hex encoded after `sha256=`,
+/// named and formatted the way GitHub signs its webhooks so receivers can
+/// reuse what they have for those.
+pub const SIGNATURE_HEADER: &str = "X-Hub-Signature-256";
+
+/// Mirrors accepted inbox activities to `--webhook-url`, for operators
+/// hooking other systems up to the server. The webhook is the operator's own,
+/// so unlike [`crate::remote::Client`] this goes anywhere, private networks
+/// included.
+#[derive(Clone)]
+pub struct Webhook {
+    url: Url,
+    key: hmac::Key,
+    client: reqwest::Client,
+}
+
+impl Webhook {
+    /// The configured webhook, if there is one. clap has already refused
+    /// to start with a URL we can't POST to or an empty secret.
+    pub fn new(cfg: &Config) -> Option<Self> {
+        let url = cfg.webhook_url.clone()?;
+        // clap makes sure the secret comes with the URL
+        let secret = cfg.webhook_secret.as_deref().unwrap_or_default();
+        Some(Self {
+            url,
+            key: hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes()),
+            // only fails if there's no TLS backend, which is nothing to run
+            // without
+            client: reqwest::Client::builder()
+                .user_agent(cfg.user_agent())
+                .timeout(Duration::from_secs(cfg.fetch_timeout))
+                .build()
+                .expect("Could not build the webhook client"),
+        })
+    }
+
+    /// POSTs `activity` to the webhook in the background. The sender is
+    /// answered without waiting for it, and failures are only logged.
+    pub fn mirror(&self, activity: Bytes) {
+        let webhook = self.clone();
+        tokio::spawn(async move {
+            match webhook.post(activity).await {
+                Ok(()) => debug!("Mirrored activity to {}", webhook.url),
+                Err(e) => warn!("Error mirroring activity to {}: {}", webhook.url, e),
+            }
+        });
+    }
+
+    async fn post(&self, activity: Bytes) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let signature = signature(&self.key, &activity);
+        self.client
+            .post(self.url.clone())
+            .header("content-type", "application/activity+json")
+            .header(SIGNATURE_HEADER, signature)
+            .body(activity)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+fn signature(key: &hmac::Key, body: &[u8]) -> String {
+    let tag = hmac::sign(key, body);
+    let hex: String = tag.as_ref().iter().map(|b| format!("{:02x}", b)).collect();
+    format!("sha256={}", hex)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signature() {
+        // the example from GitHub's documentation on validating webhooks
+        let key = hmac::Key::new(hmac::HMAC_SHA256, b"It's a Secret to Everybody");
+        assert_eq!(
+            signature(&key, b"Hello, World!"),
+            "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17"
+        );
+    }
+}